@@ -6,21 +6,67 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     Abort,
     Config(String),
+    /// Data could not be decrypted, either because the key is wrong or the
+    /// data was tampered with.
+    Decryption,
     Internal(String),
+    /// An I/O error, preserving its kind so callers can react to it.
+    Io {
+        #[serde(with = "io_kind")]
+        kind: std::io::ErrorKind,
+        message: String,
+        /// The original error, returned by [`std::error::Error::source`].
+        #[serde(skip)]
+        source: Source,
+    },
+    NotFound(String),
     Parse(String),
     ReadOnly,
     Serialization,
     Value(String),
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => source.get(),
+            _ => None,
+        }
+    }
+}
+
+/// The underlying cause of an error, if any. It's shared such that the error
+/// remains cloneable, isn't serialized, and is ignored when comparing errors,
+/// so errors compare equal across serialization.
+#[derive(Clone, Debug, Default)]
+pub struct Source(Option<std::sync::Arc<dyn std::error::Error + Send + Sync>>);
+
+impl Source {
+    pub fn new(error: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self(Some(std::sync::Arc::new(error)))
+    }
+
+    /// Returns the underlying error, if any.
+    pub fn get(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.as_deref().map(|error| error as _)
+    }
+}
+
+impl PartialEq for Source {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Abort => write!(f, "Operation aborted"),
+            Self::Decryption => write!(f, "Decryption failed"),
             Self::ReadOnly => write!(f, "Read-only transaction"),
             Self::Serialization => write!(f, "Serialization error"),
+            Self::Io { message, .. } => write!(f, "I/O error: {}", message),
+            Self::NotFound(s) => write!(f, "Not found: {}", s),
             Self::Config(s) | Self::Internal(s) | Self::Parse(s) | Self::Value(s) => {
                 write!(f, "{}", s)
             }
@@ -30,6 +76,115 @@ impl std::fmt::Display for Error {
 
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
-        Self::Internal(value.to_string())
+        Self::Io {
+            kind: value.kind(),
+            message: value.to_string(),
+            source: Source::new(value),
+        }
+    }
+}
+
+/// Serializes [`std::io::ErrorKind`] as a stable string, since it does not
+/// implement serde traits itself. Unknown kinds are mapped to `Other`.
+mod io_kind {
+    use std::io::ErrorKind;
+
+    const KINDS: &[(ErrorKind, &str)] = &[
+        (ErrorKind::NotFound, "NotFound"),
+        (ErrorKind::PermissionDenied, "PermissionDenied"),
+        (ErrorKind::ConnectionRefused, "ConnectionRefused"),
+        (ErrorKind::ConnectionReset, "ConnectionReset"),
+        (ErrorKind::ConnectionAborted, "ConnectionAborted"),
+        (ErrorKind::NotConnected, "NotConnected"),
+        (ErrorKind::AddrInUse, "AddrInUse"),
+        (ErrorKind::AddrNotAvailable, "AddrNotAvailable"),
+        (ErrorKind::BrokenPipe, "BrokenPipe"),
+        (ErrorKind::AlreadyExists, "AlreadyExists"),
+        (ErrorKind::WouldBlock, "WouldBlock"),
+        (ErrorKind::InvalidInput, "InvalidInput"),
+        (ErrorKind::InvalidData, "InvalidData"),
+        (ErrorKind::TimedOut, "TimedOut"),
+        (ErrorKind::WriteZero, "WriteZero"),
+        (ErrorKind::StorageFull, "StorageFull"),
+        (ErrorKind::Interrupted, "Interrupted"),
+        (ErrorKind::Unsupported, "Unsupported"),
+        (ErrorKind::UnexpectedEof, "UnexpectedEof"),
+        (ErrorKind::OutOfMemory, "OutOfMemory"),
+        (ErrorKind::Other, "Other"),
+    ];
+
+    pub fn to_str(kind: &ErrorKind) -> &'static str {
+        KINDS
+            .iter()
+            .find(|(k, _)| k == kind)
+            .map_or("Other", |(_, name)| name)
+    }
+
+    pub fn from_str(name: &str) -> ErrorKind {
+        KINDS
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map_or(ErrorKind::Other, |(kind, _)| *kind)
+    }
+
+    pub fn serialize<S: serde::Serializer>(
+        kind: &ErrorKind,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(to_str(kind))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ErrorKind, D::Error> {
+        let name: std::borrow::Cow<'de, str> = serde::Deserialize::deserialize(deserializer)?;
+        Ok(from_str(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn io_error() {
+        let error: Error = std::io::Error::new(ErrorKind::NotFound, "no such file").into();
+        assert_eq!(
+            error,
+            Error::Io {
+                kind: ErrorKind::NotFound,
+                message: "no such file".to_string(),
+                source: Source::default(),
+            }
+        );
+        assert_eq!(error.to_string(), "I/O error: no such file");
+    }
+
+    #[test]
+    /// Tests that I/O errors keep the original error as their source, also
+    /// when cloned.
+    fn io_error_source() {
+        use std::error::Error as _;
+
+        let error: Error = std::io::Error::new(ErrorKind::NotFound, "no such file").into();
+        let source = error.source().expect("source should be set");
+        assert_eq!(source.to_string(), "no such file");
+        let io_error = source.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io_error.kind(), ErrorKind::NotFound);
+        assert!(error.clone().source().is_some());
+        assert!(Error::Internal("internal".to_string()).source().is_none());
+    }
+
+    #[test]
+    fn io_kind_names() {
+        for kind in [ErrorKind::NotFound, ErrorKind::WriteZero, ErrorKind::Other] {
+            assert_eq!(io_kind::from_str(io_kind::to_str(&kind)), kind);
+        }
+        assert_eq!(
+            io_kind::to_str(&ErrorKind::PermissionDenied),
+            "PermissionDenied"
+        );
+        assert_eq!(io_kind::from_str("Unknown"), ErrorKind::Other);
     }
 }
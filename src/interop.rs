@@ -0,0 +1,46 @@
+//! Import and export of engine contents in common text formats.
+
+pub mod csv;
+pub mod jsonl;
+
+use base64::Engine as _;
+
+use crate::error::{Error, Result};
+
+/// How raw key and value bytes are represented as text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Bytes are written as-is, and must be valid UTF-8.
+    #[default]
+    Text,
+    /// Bytes are written as lowercase hex.
+    Hex,
+    /// Bytes are written as standard, padded base64.
+    Base64,
+}
+
+impl Encoding {
+    /// Encodes bytes as text, erroring if they are not valid UTF-8 text.
+    pub fn encode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Self::Text => std::str::from_utf8(bytes)
+                .map(str::to_string)
+                .map_err(|error| Error::Value(format!("Invalid UTF-8 text: {error}"))),
+            Self::Hex => Ok(hex::encode(bytes)),
+            Self::Base64 => Ok(base64::engine::general_purpose::STANDARD.encode(bytes)),
+        }
+    }
+
+    /// Decodes text produced by [`Encoding::encode`].
+    pub fn decode(self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            Self::Text => Ok(text.as_bytes().to_vec()),
+            Self::Hex => {
+                hex::decode(text).map_err(|error| Error::Parse(format!("Invalid hex: {error}")))
+            }
+            Self::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(text)
+                .map_err(|error| Error::Parse(format!("Invalid base64: {error}"))),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! JSON Lines dump and restore, for debugging and migrations. Each line holds
+//! one key/value pair as `{"key": "...", "value": "..."}`, with the bytes
+//! base64-encoded.
+
+use std::io::{BufRead as _, Write as _};
+
+use super::Encoding;
+use crate::error::{Error, Result};
+use crate::storage::engine::Engine;
+
+/// A dumped key/value pair, with base64-encoded bytes.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Line {
+    key: String,
+    value: String,
+}
+
+/// Dumps every key/value pair in the engine as JSON Lines, streaming them
+/// from a scan. Returns the number of pairs dumped.
+pub fn dump_jsonl(engine: &mut impl Engine, writer: impl std::io::Write) -> Result<u64> {
+    let mut writer = std::io::BufWriter::new(writer);
+    let mut count = 0;
+    for item in engine.scan(..) {
+        let (key, value) = item?;
+        let line = Line {
+            key: Encoding::Base64.encode(&key)?,
+            value: Encoding::Base64.encode(&value)?,
+        };
+        serde_json::to_writer(&mut writer, &line).map_err(std::io::Error::from)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Restores key/value pairs dumped by [`dump_jsonl`] into the engine, skipping
+/// blank lines. Returns the number of pairs restored. Malformed lines return
+/// [`Error::Parse`] with the line number, after any preceding pairs have been
+/// restored.
+pub fn restore_jsonl(engine: &mut impl Engine, reader: impl std::io::Read) -> Result<u64> {
+    let mut count = 0;
+    for (i, line) in std::io::BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parse_error = |message: String| Error::Parse(format!("Line {}: {message}", i + 1));
+        let line: Line =
+            serde_json::from_str(&line).map_err(|error| parse_error(error.to_string()))?;
+        let decode = |text: &str| {
+            Encoding::Base64.decode(text).map_err(|error| match error {
+                Error::Parse(message) => parse_error(message),
+                error => error,
+            })
+        };
+        engine.set(&decode(&line.key)?, decode(&line.value)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::storage::bitcask::BitCask;
+    use crate::storage::memory::Memory;
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests that a BitCask dump restores into an identical Memory engine.
+    fn dump_restore() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path)?;
+        s.set(b"text", b"{\"key\": \"value\"}\n".to_vec())?;
+        s.set(&[0, 0xff], vec![0xc3, 0x28, 0])?;
+        s.set(b"", vec![])?;
+        s.set(b"deleted", vec![1])?;
+        s.delete(b"deleted")?;
+
+        let mut buffer = Vec::new();
+        assert_eq!(dump_jsonl(&mut s, &mut buffer)?, 3);
+        assert_eq!(
+            String::from_utf8(buffer.clone()).unwrap().lines().next(),
+            Some(r#"{"key":"","value":""}"#)
+        );
+
+        let mut m = Memory::new();
+        assert_eq!(restore_jsonl(&mut m, buffer.as_slice())?, 3);
+        assert_eq!(
+            m.scan(..).collect::<Result<Vec<_>>>()?,
+            s.scan(..).collect::<Result<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that malformed lines are rejected with their line number.
+    fn restore_malformed() -> Result<()> {
+        let valid = r#"{"key": "YQ==", "value": "AQ=="}"#;
+        for malformed in [
+            "{",
+            r#"{"key": "YQ=="}"#,
+            r#"{"key": 1, "value": "AQ=="}"#,
+            r#"{"key": "!", "value": "AQ=="}"#,
+        ] {
+            let mut m = Memory::new();
+            let input = format!("{valid}\n\n{malformed}\n{valid}\n");
+            match restore_jsonl(&mut m, input.as_bytes()) {
+                Err(Error::Parse(message)) => assert!(message.starts_with("Line 3: "), "{message}"),
+                result => panic!("unexpected result {result:?}"),
+            }
+            assert_eq!(m.get(b"a")?, Some(vec![1]));
+        }
+        Ok(())
+    }
+}
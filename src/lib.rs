@@ -1,2 +1,4 @@
 pub mod error;
+pub mod interop;
+pub mod server;
 pub mod storage;
@@ -0,0 +1,324 @@
+/*!
+A TCP key/value server, which serves any [`Engine`] over the network.
+
+The protocol is a minimal request/response protocol. Each message is a frame
+with a 4-byte big-endian length followed by a bincode-encoded [`Request`] or
+response. Responses are a `Result<Response>`, so errors are returned as the
+serialized [`Error`]. A client can send any number of requests on a
+connection, each of which is answered in order.
+
+The server can also speak the Redis protocol, see [`resp`].
+*/
+
+pub mod resp;
+
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, Result};
+use crate::storage::engine::Engine;
+
+/// A client request.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Request {
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Scan(Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+/// A server response to a successful request.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Response {
+    Get(Option<Vec<u8>>),
+    Set,
+    Delete,
+    Scan(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+/// The maximum frame payload size, such that a client can't make the server
+/// allocate arbitrary amounts of memory with a bogus length prefix.
+pub const MAX_FRAME_SIZE: u32 = 256 * 1024 * 1024;
+
+/// Encodes a length-prefixed message frame, failing with [`Error::Value`] if
+/// the payload exceeds the given maximum size.
+fn encode_frame(message: &impl serde::Serialize, max_size: u32) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(message)
+        .map_err(|error| Error::Internal(format!("Failed to encode message: {error}")))?;
+    let length = u32::try_from(payload.len())
+        .ok()
+        .filter(|length| *length <= max_size)
+        .ok_or_else(|| Error::Value(format!("Message too large: {} bytes", payload.len())))?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Writes a length-prefixed message frame.
+fn write_frame(writer: &mut impl Write, message: &impl serde::Serialize) -> Result<()> {
+    writer.write_all(&encode_frame(message, MAX_FRAME_SIZE)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a response frame. A response that exceeds the maximum frame size,
+/// e.g. a large scan, is replaced by the error, such that the client learns
+/// why the request failed and can keep using the connection.
+fn write_response(
+    writer: &mut impl Write,
+    response: Result<Response>,
+    max_size: u32,
+) -> Result<()> {
+    let frame = match encode_frame(&response, max_size) {
+        Err(error @ Error::Value(_)) => encode_frame(&Err::<Response, _>(error), max_size)?,
+        frame => frame?,
+    };
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a length-prefixed message frame, or returns `None` if the
+/// connection was closed before the frame.
+fn read_frame<T: serde::de::DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    let length = u32::from_be_bytes(length);
+    if length > MAX_FRAME_SIZE {
+        return Err(Error::Parse(format!(
+            "Frame of {length} bytes exceeds the maximum of {MAX_FRAME_SIZE} bytes"
+        )));
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    bincode::deserialize(&payload)
+        .map(Some)
+        .map_err(|error| Error::Parse(format!("Invalid message: {error}")))
+}
+
+/// A server serving an engine over TCP. The engine is shared by all
+/// connections behind a mutex, so requests are executed one at a time.
+pub struct Server<E: Engine> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E: Engine + 'static> Server<E> {
+    pub fn new(engine: E) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// Serves connections from the listener until it fails, handling each
+    /// connection on its own thread.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.listen(listener, Self::serve_connection)
+    }
+
+    /// Accepts connections from the listener until it fails, handling each
+    /// one on its own thread with the given connection handler.
+    fn listen(
+        &self,
+        listener: TcpListener,
+        handler: fn(&Mutex<E>, TcpStream) -> Result<()>,
+    ) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let engine = self.engine.clone();
+            std::thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(error) = handler(&engine, stream) {
+                    log::error!("Connection from {peer:?} failed: {error}");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Serves requests on a connection until the client disconnects.
+    fn serve_connection(engine: &Mutex<E>, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        while let Some(request) = read_frame(&mut reader)? {
+            let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+            let response = Self::execute(&mut *engine, request);
+            drop(engine);
+            write_response(&mut writer, response, MAX_FRAME_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Executes a request against the engine.
+    fn execute(engine: &mut E, request: Request) -> Result<Response> {
+        Ok(match request {
+            Request::Get(key) => Response::Get(engine.get(&key)?),
+            Request::Set(key, value) => {
+                engine.set(&key, value)?;
+                Response::Set
+            }
+            Request::Delete(key) => {
+                engine.delete(&key)?;
+                Response::Delete
+            }
+            Request::Scan(start, end) => {
+                Response::Scan(engine.scan((start, end)).collect::<Result<_>>()?)
+            }
+        })
+    }
+}
+
+/// A client for a [`Server`].
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    /// Connects to a server.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Sends a request and waits for the response.
+    pub fn request(&mut self, request: Request) -> Result<Response> {
+        write_frame(&mut self.writer, &request)?;
+        match read_frame::<Result<Response>>(&mut self.reader)? {
+            Some(response) => response,
+            None => Err(Error::Internal("Server closed the connection".to_string())),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(Request::Get(key.to_vec()))? {
+            Response::Get(value) => Ok(value),
+            response => Err(Error::Internal(format!("Unexpected response {response:?}"))),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        match self.request(Request::Set(key.to_vec(), value))? {
+            Response::Set => Ok(()),
+            response => Err(Error::Internal(format!("Unexpected response {response:?}"))),
+        }
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        match self.request(Request::Delete(key.to_vec()))? {
+            Response::Delete => Ok(()),
+            response => Err(Error::Internal(format!("Unexpected response {response:?}"))),
+        }
+    }
+
+    pub fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan(range.start_bound().cloned(), range.end_bound().cloned());
+        match self.request(request)? {
+            Response::Scan(items) => Ok(items),
+            response => Err(Error::Internal(format!("Unexpected response {response:?}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{memory::Memory, read_only::ReadOnly};
+
+    /// Starts a server for the engine on an ephemeral port, returning its
+    /// address.
+    fn start(engine: impl Engine + 'static) -> Result<std::net::SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(engine);
+        std::thread::spawn(move || server.serve(listener));
+        Ok(addr)
+    }
+
+    #[test]
+    /// Tests that requests round-trip through the server.
+    fn round_trip() -> Result<()> {
+        let addr = start(Memory::new())?;
+        let mut client = Client::connect(addr)?;
+
+        client.set(b"a", vec![1])?;
+        client.set(b"b", vec![2])?;
+        client.set(b"c", vec![])?;
+        assert_eq!(client.get(b"a")?, Some(vec![1]));
+        assert_eq!(client.get(b"missing")?, None);
+        client.delete(b"b")?;
+
+        // Other connections see the same engine.
+        let mut other = Client::connect(addr)?;
+        assert_eq!(
+            other.scan(..)?,
+            vec![(b"a".to_vec(), vec![1]), (b"c".to_vec(), vec![])]
+        );
+        assert_eq!(other.scan(b"b".to_vec()..)?, vec![(b"c".to_vec(), vec![])]);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that an oversized length prefix closes the connection, without
+    /// allocating the frame.
+    fn oversized_frame() -> Result<()> {
+        let addr = start(Memory::new())?;
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(&(MAX_FRAME_SIZE + 1).to_be_bytes())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        assert!(response.is_empty());
+
+        // Other connections are unaffected.
+        let mut client = Client::connect(addr)?;
+        client.set(b"a", vec![1])?;
+        assert_eq!(client.get(b"a")?, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a response exceeding the maximum frame size is replaced by
+    /// an error response.
+    fn oversized_response() -> Result<()> {
+        let mut buffer = Vec::new();
+        let response = Ok(Response::Get(Some(vec![1; 100])));
+        write_response(&mut buffer, response.clone(), 200)?;
+        write_response(&mut buffer, response, 50)?;
+        write_response(&mut buffer, Ok(Response::Set), 50)?;
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(
+            read_frame::<Result<Response>>(&mut reader)?,
+            Some(Ok(Response::Get(Some(vec![1; 100]))))
+        );
+        assert!(matches!(
+            read_frame::<Result<Response>>(&mut reader)?,
+            Some(Err(Error::Value(message))) if message.contains("too large")
+        ));
+        assert_eq!(
+            read_frame::<Result<Response>>(&mut reader)?,
+            Some(Ok(Response::Set))
+        );
+        assert_eq!(read_frame::<Result<Response>>(&mut reader)?, None);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that engine errors are returned to the client.
+    fn error() -> Result<()> {
+        let addr = start(ReadOnly::new(Memory::new()))?;
+        let mut client = Client::connect(addr)?;
+        assert_eq!(client.set(b"a", vec![1]), Err(Error::ReadOnly));
+        assert_eq!(client.get(b"a")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+/*!
+Redis protocol (RESP) support, so that existing Redis clients can talk to a
+[`Server`]. Commands are arrays of bulk strings, and the following commands
+are supported:
+
+- `GET key`: returns the value, or nil if the key does not exist.
+- `SET key value`: sets the value, without any options, and returns `OK`.
+- `DEL key [key ...]`: deletes the keys, and returns how many existed.
+- `EXISTS key [key ...]`: returns how many of the keys exist.
+
+Unknown commands, wrong arguments and engine errors are returned as RESP
+errors. Malformed input is answered with an error before the connection is
+closed.
+*/
+
+use std::io::{BufRead, BufReader, BufWriter, Read as _, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+
+use super::{Server, MAX_FRAME_SIZE};
+use crate::error::{Error, Result};
+use crate::storage::engine::Engine;
+
+/// A RESP value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or nil.
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Writes the value in RESP encoding.
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        match self {
+            Self::Simple(s) => write!(writer, "+{s}\r\n")?,
+            Self::Error(s) => write!(writer, "-{s}\r\n")?,
+            Self::Integer(i) => write!(writer, ":{i}\r\n")?,
+            Self::Bulk(None) => write!(writer, "$-1\r\n")?,
+            Self::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Self::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The maximum length of a line, excluding the CRLF. Lines only carry type
+/// prefixes and lengths, so this is plenty, and prevents a client from
+/// growing the buffer without bound by never sending a CRLF.
+const MAX_LINE_SIZE: usize = 1024;
+
+/// The maximum total length of the bulk strings in a command, the same as
+/// for native frames, such that a client can't make the server buffer an
+/// arbitrary amount of data by announcing a huge length.
+const MAX_COMMAND_SIZE: usize = MAX_FRAME_SIZE as usize;
+
+/// Reads a CRLF-terminated line, without the CRLF, or returns `None` if the
+/// connection was closed before the line.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE_SIZE as u64 + 2;
+    if reader.take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.len() as u64 == limit && !line.ends_with(b"\r\n") {
+        return Err(Error::Parse(format!(
+            "Line exceeds the maximum of {MAX_LINE_SIZE} bytes"
+        )));
+    }
+    match line.strip_suffix(b"\r\n") {
+        Some(stripped) => Ok(Some(stripped.to_vec())),
+        None => Err(Error::Parse("Expected CRLF line ending".to_string())),
+    }
+}
+
+/// Parses the length following a RESP type prefix, e.g. `*2` or `$5`.
+fn parse_length(line: &[u8], prefix: u8) -> Result<usize> {
+    line.strip_prefix(&[prefix])
+        .and_then(|length| std::str::from_utf8(length).ok())
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| {
+            let line = String::from_utf8_lossy(line);
+            Error::Parse(format!("Expected {}<length>, got {line:?}", prefix as char))
+        })
+}
+
+/// Reads a command as an array of bulk strings, or returns `None` if the
+/// connection was closed before the command.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let count = parse_length(&line, b'*')?;
+    let mut args = Vec::new();
+    let mut size = 0usize;
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| Error::Parse("Truncated command".into()))?;
+        let length = parse_length(&line, b'$')?;
+        size = (size.checked_add(length))
+            .filter(|size| *size <= MAX_COMMAND_SIZE)
+            .ok_or_else(|| {
+                Error::Parse(format!(
+                    "Command exceeds the maximum of {MAX_COMMAND_SIZE} bytes"
+                ))
+            })?;
+        let mut arg = Vec::new();
+        reader.take(length as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() != length + 2 || !arg.ends_with(b"\r\n") {
+            return Err(Error::Parse("Invalid bulk string".to_string()));
+        }
+        arg.truncate(length);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Executes a command against the engine.
+fn execute(engine: &mut impl Engine, command: &[Vec<u8>]) -> Value {
+    let Some((name, args)) = command.split_first() else {
+        return Value::Error("ERR empty command".to_string());
+    };
+    let name = String::from_utf8_lossy(name).to_lowercase();
+    let result = match (name.as_str(), args) {
+        ("get", [key]) => engine.get(key).map(Value::Bulk),
+        ("set", [key, value]) => engine
+            .set(key, value.clone())
+            .map(|_| Value::Simple("OK".to_string())),
+        ("del", keys) if !keys.is_empty() => (|| {
+            let mut deleted = 0;
+            for key in keys {
+                if engine.get(key)?.is_some() {
+                    engine.delete(key)?;
+                    deleted += 1;
+                }
+            }
+            Ok(Value::Integer(deleted))
+        })(),
+        ("exists", keys) if !keys.is_empty() => (|| {
+            let mut exists = 0;
+            for key in keys {
+                if engine.get(key)?.is_some() {
+                    exists += 1;
+                }
+            }
+            Ok(Value::Integer(exists))
+        })(),
+        ("get" | "set" | "del" | "exists", _) => {
+            return Value::Error(format!(
+                "ERR wrong number of arguments for '{name}' command"
+            ))
+        }
+        _ => return Value::Error(format!("ERR unknown command '{name}'")),
+    };
+    result.unwrap_or_else(|error| Value::Error(format!("ERR {error}")))
+}
+
+/// Serves RESP commands on a connection until the client disconnects.
+fn serve_connection(engine: &Mutex<impl Engine>, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let command = match read_command(&mut reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(()),
+            Err(error) => {
+                Value::Error(format!("ERR Protocol error: {error}")).write(&mut writer)?;
+                writer.flush()?;
+                return Err(error);
+            }
+        };
+        let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+        let response = execute(&mut *engine, &command);
+        drop(engine);
+        response.write(&mut writer)?;
+        writer.flush()?;
+    }
+}
+
+impl<E: Engine + 'static> Server<E> {
+    /// Serves connections from the listener using the Redis protocol until
+    /// it fails, handling each connection on its own thread.
+    pub fn serve_resp(&self, listener: TcpListener) -> Result<()> {
+        self.listen(listener, serve_connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::Memory;
+    use redis::Commands as _;
+
+    /// Starts a RESP server on an ephemeral port, returning a Redis client.
+    fn start() -> Result<redis::Client> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(Memory::new());
+        std::thread::spawn(move || server.serve_resp(listener));
+        redis::Client::open(format!("redis://{addr}"))
+            .map_err(|error| Error::Internal(error.to_string()))
+    }
+
+    #[test]
+    /// Tests the supported commands using a Redis client.
+    fn commands() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut conn = start()?.get_connection()?;
+
+        conn.set::<_, _, ()>(b"a", b"1")?;
+        conn.set::<_, _, ()>(vec![0u8, 0xff], vec![0u8, 1, 2])?;
+        assert_eq!(conn.get::<_, Option<Vec<u8>>>(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(
+            conn.get::<_, Option<Vec<u8>>>(vec![0u8, 0xff])?,
+            Some(vec![0, 1, 2])
+        );
+        assert_eq!(conn.get::<_, Option<Vec<u8>>>(b"missing")?, None);
+
+        assert_eq!(conn.exists::<_, i64>(&["a", "a", "missing"])?, 2);
+        assert_eq!(conn.del::<_, i64>(&["a", "missing", "a"])?, 1);
+        assert_eq!(conn.exists::<_, i64>("a")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that unknown commands and wrong arguments return errors, without
+    /// closing the connection.
+    fn errors() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut conn = start()?.get_connection()?;
+
+        let error = redis::cmd("FOO")
+            .arg("a")
+            .query::<()>(&mut conn)
+            .unwrap_err();
+        assert_eq!(error.code(), Some("ERR"));
+        assert_eq!(error.detail(), Some("unknown command 'foo'"));
+
+        let error = redis::cmd("SET")
+            .arg("a")
+            .query::<()>(&mut conn)
+            .unwrap_err();
+        assert_eq!(
+            error.detail(),
+            Some("wrong number of arguments for 'set' command")
+        );
+
+        let error = redis::cmd("SET")
+            .arg("a")
+            .arg("1")
+            .arg("NX")
+            .query::<()>(&mut conn);
+        assert!(error.is_err());
+
+        conn.set::<_, _, ()>("a", "1")?;
+        assert_eq!(conn.get::<_, String>("a")?, "1");
+        Ok(())
+    }
+
+    #[test]
+    /// Tests parsing and encoding of raw RESP.
+    fn protocol() -> Result<()> {
+        let input = b"*2\r\n$3\r\nGET\r\n$0\r\n\r\n*1\r\n$4\r\nPI";
+        let mut reader = &input[..];
+        assert_eq!(
+            read_command(&mut reader)?,
+            Some(vec![b"GET".to_vec(), vec![]])
+        );
+        assert!(matches!(read_command(&mut reader), Err(Error::Parse(_))));
+        assert_eq!(read_command(&mut &b""[..])?, None);
+        assert!(matches!(
+            read_command(&mut &b"GET\r\n"[..]),
+            Err(Error::Parse(_))
+        ));
+
+        // Lines without a CRLF can't grow beyond MAX_LINE_SIZE.
+        let mut line = vec![b'*'; MAX_LINE_SIZE];
+        line.extend_from_slice(b"\r\n");
+        assert!(matches!(
+            read_line(&mut &line[..]),
+            Ok(Some(line)) if line.len() == MAX_LINE_SIZE
+        ));
+        let input = vec![b'*'; 10 * MAX_LINE_SIZE];
+        let mut reader = &input[..];
+        assert!(matches!(read_line(&mut reader), Err(Error::Parse(_))));
+        assert_eq!(reader.len(), 10 * MAX_LINE_SIZE - MAX_LINE_SIZE - 2);
+
+        // Bulk strings can't exceed MAX_COMMAND_SIZE, nor overflow.
+        for length in [MAX_COMMAND_SIZE as u64 + 1, u64::MAX] {
+            let input = format!("*1\r\n${length}\r\nabc\r\n");
+            assert!(matches!(
+                read_command(&mut input.as_bytes()),
+                Err(Error::Parse(message)) if message.contains("maximum")
+            ));
+        }
+
+        let mut output = Vec::new();
+        Value::Array(vec![
+            Value::Simple("OK".to_string()),
+            Value::Error("ERR oops".to_string()),
+            Value::Integer(-1),
+            Value::Bulk(None),
+            Value::Bulk(Some(b"a\r\n".to_vec())),
+        ])
+        .write(&mut output)?;
+        assert_eq!(
+            output,
+            b"*5\r\n+OK\r\n-ERR oops\r\n:-1\r\n$-1\r\n$3\r\na\r\n\r\n"
+        );
+        Ok(())
+    }
+}
@@ -1,3 +1,27 @@
+pub mod append_only;
+#[cfg(feature = "tokio")]
+pub mod async_engine;
+#[cfg(feature = "std")]
 pub mod bitcask;
+pub mod bloom;
+pub mod compressed;
+pub mod cursor;
+#[cfg(feature = "std")]
+pub mod durable;
+pub mod encrypted;
 pub mod engine;
-pub mod memory;
\ No newline at end of file
+pub mod indexed;
+pub mod limited;
+#[cfg(feature = "std")]
+pub mod lsm;
+pub mod map_view;
+pub mod memory;
+pub mod metered;
+pub mod mvcc;
+pub mod prefixed;
+pub mod read_only;
+pub mod retry;
+pub mod scan_builder;
+pub mod sharded;
+pub mod shared;
+pub mod tiered;
@@ -0,0 +1,245 @@
+use super::engine::{Engine, ReadEngine, SizeHistogram, Status, WriteBatch};
+use crate::error::{Error, Result};
+
+/// An engine wrapper that only accepts writes of keys greater than all
+/// existing keys, e.g. big-endian timestamps in a time-series log. Writes of
+/// out-of-order or duplicate keys, as well as deletes, are rejected with
+/// [`Error::Value`]. Clearing the engine starts over with an empty log.
+pub struct AppendOnly<E: Engine> {
+    inner: E,
+    /// The last written key, if any.
+    last_key: Option<Vec<u8>>,
+}
+
+impl<E: Engine> AppendOnly<E> {
+    /// Wraps the engine, continuing after its last existing key.
+    pub fn new(mut inner: E) -> Result<Self> {
+        let last_key = inner.last()?.map(|(key, _)| key);
+        Ok(Self { inner, last_key })
+    }
+
+    /// Returns the last written key, if any.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.last_key.as_deref()
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// Checks that the key can be written after the given last key.
+    fn check_order(key: &[u8], last_key: Option<&[u8]>) -> Result<()> {
+        match last_key {
+            Some(last_key) if key <= last_key => Err(Error::Value(format!(
+                "Key {} is not after last key {}",
+                key.escape_ascii(),
+                last_key.escape_ascii()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns an error for deletes, which aren't allowed.
+    fn deletes_disallowed() -> Error {
+        Error::Value("Deletes are not allowed in an append-only engine".to_string())
+    }
+}
+
+impl<E: Engine> std::fmt::Display for AppendOnly<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (append-only)", self.inner)
+    }
+}
+
+impl<E: Engine> Engine for AppendOnly<E> {
+    type ScanIterator<'a>
+        = E::ScanIterator<'a>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        Self::check_order(key, self.last_key())?;
+        self.inner.set(key, value)?;
+        self.last_key = Some(key.to_vec());
+        Ok(())
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.get_many(keys)
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<()> {
+        Err(Self::deletes_disallowed())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn sync_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        self.inner.sync_range(range)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.inner.clear()?;
+        self.last_key = None;
+        Ok(())
+    }
+
+    fn delete_prefix(&mut self, _prefix: &[u8]) -> Result<u64> {
+        Err(Self::deletes_disallowed())
+    }
+
+    fn delete_range(&mut self, _range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        Err(Self::deletes_disallowed())
+    }
+
+    /// Checks the whole batch before writing it, so that it's either written
+    /// atomically by the inner engine or not at all. Keys must be in strictly
+    /// increasing order within the batch too.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut last_key = self.last_key();
+        for (key, value) in batch.iter() {
+            if value.is_none() {
+                return Err(Self::deletes_disallowed());
+            }
+            Self::check_order(key, last_key)?;
+            last_key = Some(key);
+        }
+        let last_key = last_key.map(|key| key.to_vec());
+        self.inner.write_batch(batch)?;
+        self.last_key = last_key;
+        Ok(())
+    }
+
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        self.inner.stats_histogram()
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.estimate_garbage(range)
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        self.inner.status()
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan(range)
+    }
+
+    fn scan_bytes(
+        &mut self,
+        start: std::ops::Bound<&[u8]>,
+        end: std::ops::Bound<&[u8]>,
+    ) -> Self::ScanIterator<'_> {
+        self.inner.scan_bytes(start, end)
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.inner.scan_keys(range)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.inner.count(range)
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for AppendOnly<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_shared(key)
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan_shared(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::memory::Memory, *};
+
+    #[test]
+    /// Tests that in-order writes succeed, and continue after the existing
+    /// keys of the inner engine.
+    fn in_order() -> Result<()> {
+        let mut inner = Memory::new();
+        inner.set(&1u64.to_be_bytes(), vec![1])?;
+        let mut s = AppendOnly::new(inner)?;
+        assert_eq!(s.last_key(), Some(1u64.to_be_bytes().as_slice()));
+
+        s.set(&2u64.to_be_bytes(), vec![2])?;
+        s.set(&256u64.to_be_bytes(), vec![3])?;
+        let mut batch = WriteBatch::new();
+        batch.set(&257u64.to_be_bytes(), vec![4]);
+        batch.set(&300u64.to_be_bytes(), vec![5]);
+        s.write_batch(batch)?;
+        assert_eq!(s.last_key(), Some(300u64.to_be_bytes().as_slice()));
+        assert_eq!(
+            s.scan(..)
+                .map(|r| r.map(|(_, v)| v))
+                .collect::<Result<Vec<_>>>()?,
+            vec![vec![1], vec![2], vec![3], vec![4], vec![5]]
+        );
+
+        s.clear()?;
+        assert_eq!(s.last_key(), None);
+        s.set(&0u64.to_be_bytes(), vec![0])?;
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that out-of-order and duplicate writes and deletes are rejected
+    /// without reaching the inner engine.
+    fn out_of_order() -> Result<()> {
+        let mut s = AppendOnly::new(Memory::new())?;
+        s.set(&10u64.to_be_bytes(), vec![1])?;
+
+        assert!(matches!(
+            s.set(&10u64.to_be_bytes(), vec![2]),
+            Err(Error::Value(_))
+        ));
+        assert!(matches!(
+            s.set(&9u64.to_be_bytes(), vec![2]),
+            Err(Error::Value(_))
+        ));
+        s.set(&[0xff], vec![2])?;
+        assert!(matches!(
+            s.set(&11u64.to_be_bytes(), vec![2]),
+            Err(Error::Value(_))
+        ));
+        assert!(matches!(s.delete(&[0xff]), Err(Error::Value(_))));
+        assert!(matches!(s.delete_prefix(&[]), Err(Error::Value(_))));
+        assert!(matches!(s.delete_range(..), Err(Error::Value(_))));
+
+        // Batches are rejected as a whole.
+        let mut batch = WriteBatch::new();
+        batch.set(&[0xff, 2], vec![3]);
+        batch.set(&[0xff, 1], vec![3]);
+        assert!(matches!(s.write_batch(batch), Err(Error::Value(_))));
+        let mut batch = WriteBatch::new();
+        batch.set(&[0xff, 1], vec![3]);
+        batch.delete(&[0xff]);
+        assert!(matches!(s.write_batch(batch), Err(Error::Value(_))));
+        assert_eq!(s.last_key(), Some([0xff].as_slice()));
+
+        assert_eq!(s.into_inner().count(..)?, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+/*!
+An async adapter for engines, for use inside Tokio services.
+
+Engine calls block on disk I/O, which would stall the async executor, so the
+adapter runs them on Tokio's blocking thread pool via
+[`tokio::task::spawn_blocking`]. The engine is shared behind a mutex, so the
+adapter can be cloned and used from many tasks, but calls are executed one at
+a time. Scan iterators borrow the engine and can't be held across await
+points, so scans are collected into a `Vec` instead.
+*/
+
+use std::sync::{Arc, Mutex};
+
+use super::engine::{clone_bounds, Engine, Status};
+use crate::error::{Error, Result};
+
+/// An async engine adapter, which runs the wrapped engine on Tokio's blocking
+/// thread pool. Cloning it yields another handle to the same engine.
+pub struct AsyncEngine<E: Engine> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E: Engine> Clone for AsyncEngine<E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+        }
+    }
+}
+
+impl<E: Engine + 'static> AsyncEngine<E> {
+    pub fn new(engine: E) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// Runs the closure against the engine on the blocking thread pool.
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut engine)
+        })
+        .await
+        .map_err(|error| Error::Internal(format!("Engine task failed: {error}")))?
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.run(move |engine| engine.get(&key)).await
+    }
+
+    pub async fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let key = key.to_vec();
+        self.run(move |engine| engine.set(&key, value)).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<()> {
+        let key = key.to_vec();
+        self.run(move |engine| engine.delete(&key)).await
+    }
+
+    /// Scans the range, collecting the key/value pairs into a vector.
+    pub async fn scan_collect(
+        &self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = clone_bounds(&range);
+        self.run(move |engine| engine.scan(range).collect()).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|engine| engine.flush()).await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.run(|engine| engine.sync()).await
+    }
+
+    pub async fn status(&self) -> Result<Status> {
+        self.run(|engine| engine.status()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    #[cfg(feature = "std")]
+    #[tokio::test(flavor = "multi_thread")]
+    /// Tests concurrent async gets against a shared engine.
+    async fn concurrent_gets() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let s = AsyncEngine::new(BitCask::new(path)?);
+        for i in 0..100u8 {
+            s.set(&[i], vec![i; 100]).await?;
+        }
+
+        let tasks = (0..100u8)
+            .map(|i| {
+                let s = s.clone();
+                tokio::spawn(async move { s.get(&[i]).await })
+            })
+            .collect::<Vec<_>>();
+        for (i, task) in (0..100u8).zip(tasks) {
+            assert_eq!(task.await.unwrap()?, Some(vec![i; 100]));
+        }
+        assert_eq!(s.status().await?.key_count, 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    /// Tests writes and scans through the adapter.
+    async fn scan_collect() -> Result<()> {
+        let s = AsyncEngine::new(Memory::new());
+        s.set(b"a", vec![1]).await?;
+        s.set(b"b", vec![2]).await?;
+        s.set(b"c", vec![3]).await?;
+        s.delete(b"b").await?;
+        s.sync().await?;
+
+        assert_eq!(s.get(b"b").await?, None);
+        assert_eq!(
+            s.scan_collect(..).await?,
+            vec![(b"a".to_vec(), vec![1]), (b"c".to_vec(), vec![3])]
+        );
+        assert_eq!(
+            s.scan_collect(b"b".to_vec()..).await?,
+            vec![(b"c".to_vec(), vec![3])]
+        );
+        Ok(())
+    }
+}
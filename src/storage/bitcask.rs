@@ -1,606 +1,7082 @@
 /*!
-A variant of bitcask.
-One file only, no hint files, no checksums or timestamps, locks the database while compacting.
+A variant of bitcask, with one active data file and an optional hint file.
+
+Compaction runs in three steps: preparing it snapshots the key dir entries of
+the data files to compact, running it rewrites their live entries into a new
+data file without access to the database, and finishing it swaps in the new
+file and updates the key dir. [`BitCask::compact`] runs all steps under
+`&mut self`, blocking other operations. [`BitCask::spawn_compactor`] runs
+them on a background thread for a database shared behind an `Arc<Mutex<_>>`,
+and only holds the mutex while preparing and finishing, such that reads and
+writes continue while the data files are rewritten.
+
+If a maximum file size is configured, the active data file is rolled over once
+it reaches that size: it is renamed to `<path>.<id>` and becomes immutable, and
+a new active data file is started at `<path>`. File ids increase with age, so
+the key dir is built by scanning the immutable files in id order followed by
+the active file. Compaction then merges only the immutable files, leaving the
+active file alone.
+
+Compaction writes the compacted data file to `<path>.new`, fsyncs it and
+renames it over the oldest compacted data file. A `<path>.new` file left
+behind by a crash is removed on open, and one left by a failed compaction is
+removed right away unless configured to keep it.
+
+Log file format:
+- Header: magic bytes `YUUB` followed by a u8 format version
+- Followed by a sequence of log entries
 
 Log entry format:
 - Key length: big-endian u32
-- Value length: big-endian i32, -1 for tombstones
+- Value length: big-endian i64, -1 for tombstones, -2 for batch markers
+- Expiry: big-endian u64, milliseconds since the UNIX epoch, 0 for no expiry
 - Key: raw bytes
 - Value raw bytes
+- Checksum: big-endian u32, CRC32 of all the preceding bytes of the entry
+
+A write batch is written as a batch marker entry, whose key is the big-endian
+u32 number of entries in the batch, followed by the batch entries. Incomplete
+trailing batches are discarded when the log is opened.
+
+Incomplete trailing entries and batches, e.g. from a crash during a write, are
+handled on open according to the [`RecoveryPolicy`]. With
+[`RecoveryPolicy::Repair`], the discarded bytes are appended to
+`<path>.discarded` along with their offset, in hex.
+
+The hint file contains the key dir, and is used instead of scanning the data
+files on open if it was written for the current data files:
+- Header: magic bytes `YUH2` and the big-endian u32 number of data files
+- For each data file, oldest first: big-endian u32 file id, u8 log version and
+  u64 file length
+- For each live key: big-endian u32 key length, u32 file id, u64 value offset,
+  u64 value length, u64 expiry, followed by the raw key bytes
+- Checksum: big-endian u32, CRC32 of all the preceding bytes
+
+The key dir holds every live key in memory, in key order, which is what
+allows scans to iterate in order without reading the data files. With
+[`BitCaskConfig::hashed_key_dir`], it instead holds a hash of each key along
+with its entry, and keys are read from the data files when needed: lookups
+compare the key stored before the value, such that colliding hashes are told
+apart, and scans read and sort all keys. This shrinks the key dir for long
+keys at the cost of disk reads. Use [`Engine::approximate_memory_usage`] to
+estimate the key dir footprint.
+
+Older log formats are still readable, and are upgraded to the current format
+on the next compaction:
+- Version 0: legacy logs without a header, expiries or checksums.
+- Version 1: no expiries.
+- Version 2: no write batches.
+- Version 3: i32 value lengths, limiting values to 2GB.
 
 Bitcask is a fast log-structured key/value engine.
 Original paper: https://riak.com/assets/bitcask-intro.pdf
 */
 
-use super::engine::{Engine, Status};
-use crate::error::Result;
+use super::bloom::BloomFilter;
+use super::engine::{
+    check_size, Engine, EntryMeta, KeyStat, ReadEngine, SizeHistogram, Status, WriteBatch,
+    DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_VALUE_SIZE,
+};
+use crate::error::{Error, Result};
 
-use fs4::FileExt;
 use std::{
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    borrow::Cow,
+    io::{BufRead, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-struct Log {
-    path: PathBuf,
-    file: std::fs::File,
-}
+/// Magic bytes at the start of a versioned log file.
+const MAGIC: [u8; 4] = *b"YUUB";
 
-type KeyDir = std::collections::BTreeMap<Vec<u8>, (u64, u32)>;
+/// The log format version written by this implementation.
+const VERSION: u8 = 4;
 
-impl Log {
-    fn new(path: PathBuf) -> Result<Self> {
-        if let Some(dir) = path.parent() {
-            std::fs::create_dir_all(dir)?
-        }
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)?;
-        file.try_lock_exclusive()?;
-        Ok(Self { path, file })
-    }
+/// The length of the log file header, i.e. magic bytes and version.
+const HEADER_LENGTH: u64 = MAGIC.len() as u64 + 1;
 
-    fn build_key_dir(&mut self) -> Result<KeyDir> {
-        let mut length_buffer = [0u8; 4];
-        let mut key_dir = KeyDir::new();
-        let file_length = self.file.metadata()?.len();
-        let mut reader = std::io::BufReader::new(&mut self.file);
-        let mut offset = reader.seek(SeekFrom::Start(0))?;
+/// Magic bytes at the start of a hint file. Hint files written before value
+/// lengths were widened used `YUUH`, and are ignored.
+const HINT_MAGIC: [u8; 4] = *b"YUH2";
 
-        while offset < file_length {
-            let result = || -> std::result::Result<(Vec<u8>, u64, Option<u32>), std::io::Error> {
-                reader.read_exact(&mut length_buffer)?;
-                let key_length = u32::from_be_bytes(length_buffer);
+/// The default size of the read buffer used to scan data files on open.
+pub(super) const READ_BUFFER_SIZE: usize = 1 << 20;
 
-                reader.read_exact(&mut length_buffer)?;
-                let value_length = match i32::from_be_bytes(length_buffer) {
-                    length if !length.is_negative() => Some(length as u32),
-                    _ => None,
-                };
-                let value_offset = offset + 4 + 4 + key_length as u64;
+/// The number of bytes scanned between progress reports when opening, see
+/// [`BitCask::with_progress`].
+const PROGRESS_INTERVAL: u64 = 4 << 20;
 
-                let mut key = vec![0u8; key_length as usize];
-                reader.read_exact(&mut key)?;
+/// The largest write buffer kept for reuse by a log, see
+/// [`Log::reuse_write_buffer`]. Larger buffers are freed after the write.
+const MAX_WRITE_BUFFER_SIZE: usize = 64 * 1024;
 
-                if let Some(value_length) = value_length {
-                    if value_offset + value_length as u64 > file_length {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::UnexpectedEof,
-                            "Value length exceeds file length",
-                        ));
-                    }
+/// The minimum number of keys the bloom filter is sized for, to avoid
+/// rebuilding it repeatedly while a small database grows.
+const MIN_BLOOM_CAPACITY: usize = 1024;
 
-                    reader.seek_relative(value_length as i64)?;
-                }
+/// The value length of a tombstone entry.
+const TOMBSTONE: i64 = -1;
 
-                Ok((key, value_offset, value_length))
-            }();
+/// The value length of a batch marker entry.
+const BATCH_MARKER: i64 = -2;
 
-            match result {
-                Ok((key, value_offset, Some(value_length))) => {
-                    key_dir.insert(key, (value_offset, value_length));
-                    offset = value_offset + value_length as u64;
-                }
-                Ok((key, value_offset, None)) => {
-                    key_dir.remove(&key);
-                    offset = value_offset;
-                }
-                Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    log::error!("Found incomplete entry at offset {offset}, truncating file");
-                    self.file.set_len(offset)?;
-                    break;
-                }
-                Err(error) => return Err(error.into()),
-            }
-        }
+/// A source of wall clock time used to expire keys, in milliseconds since the
+/// UNIX epoch. It must never go backwards.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
 
-        Ok(key_dir)
+/// The system clock, clamped so that it never goes backwards.
+#[derive(Debug, Default)]
+pub struct SystemClock {
+    last: AtomicU64,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.last.fetch_max(now, Ordering::Relaxed).max(now)
     }
+}
 
-    fn read_value(&mut self, value_offset: u64, value_length: u32) -> Result<Vec<u8>> {
-        let mut value = vec![0u8; value_length as usize];
-        self.file.seek(SeekFrom::Start(value_offset))?;
-        self.file.read_exact(&mut value)?;
-        Ok(value)
+/// When BitCask fsyncs writes to disk. Writes are always handed to the OS
+/// immediately, so they survive a process crash, but writes that haven't been
+/// fsynced can be lost on an OS crash or power loss. Fsyncing is slow, so more
+/// frequent syncs trade write throughput for durability. Write batches and
+/// [`Engine::sync`] always fsync, regardless of the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncPolicy {
+    /// Never fsync writes, except on sync and when the database is closed.
+    #[default]
+    Never,
+    /// Fsync after every write. No acknowledged write is lost.
+    EveryWrite,
+    /// Fsync on the first write after the interval has elapsed since the last
+    /// fsync. There is no background timer, so the writes since the last
+    /// fsync remain unsynced until the next write, sync or close.
+    Interval(Duration),
+    /// Fsync once at least the given number of writes or bytes have been
+    /// written since the last fsync, whichever comes first. This bounds the
+    /// writes that can be lost to a known amount.
+    Threshold { writes: u64, bytes: u64 },
+}
+
+/// How BitCask handles an incomplete entry or write batch at the end of a data
+/// file when opening it, e.g. after a crash during a write. Read-only opens
+/// skip them regardless, since they may still be being written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RecoveryPolicy {
+    /// Truncates the data file before the incomplete entry, logging an error.
+    #[default]
+    Truncate,
+    /// Fails to open with [`Error::Internal`], leaving the data file alone.
+    Error,
+    /// Truncates the data file like `Truncate`, but first records the
+    /// discarded bytes and their offset in a sidecar file next to it,
+    /// `<path>.discarded`, such that they can be inspected later.
+    Repair,
+}
+
+/// The storage backing a log, a [`std::fs::File`] by default. Other stores,
+/// e.g. an in-memory buffer or a file backed by an object store, can be used
+/// with [`BitCask::with_file`]. Locking and fsyncing are no-ops by default,
+/// for stores without them.
+pub trait ReadWriteSeek: Read + Write + Seek + Send + Sync {
+    /// Returns the length of the store in bytes.
+    fn len(&self) -> std::io::Result<u64>;
+
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
     }
 
-    fn append_entry(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(u64, u32)> {
-        let offset = self.file.seek(SeekFrom::End(0))?;
-        let key_length = key.len() as u32;
-        let append_length = 4 + 4 + key_length + value.map_or(0, |v| v.len() as u32);
+    /// Truncates or extends the store to the given length, without moving
+    /// the cursor.
+    fn set_len(&mut self, length: u64) -> std::io::Result<()>;
 
-        let mut writer = std::io::BufWriter::with_capacity(append_length as usize, &mut self.file);
-        writer.write_all(&key_length.to_be_bytes())?;
-        writer.write_all(&value.map_or(-1, |v| v.len() as i32).to_be_bytes())?;
-        writer.write_all(key)?;
-        if let Some(value) = value {
-            writer.write_all(value)?;
-        }
-        writer.flush()?;
-        Ok((offset, append_length))
+    /// Reads exactly enough bytes to fill the buffer at the given offset.
+    /// This doesn't need the cursor, so it can be called concurrently with
+    /// other reads.
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<()>;
+
+    /// Opens another handle to the store, e.g. for reading while it's being
+    /// written to. The handle only has to see the bytes written so far, so it
+    /// may be a snapshot.
+    fn try_clone(&self) -> std::io::Result<Box<dyn ReadWriteSeek>>;
+
+    /// Flushes all writes to durable storage.
+    fn sync_all(&self) -> std::io::Result<()> {
+        Ok(())
     }
-}
 
-pub struct ScanIterator<'a> {
-    inner: std::collections::btree_map::Range<'a, Vec<u8>, (u64, u32)>,
-    log: &'a mut Log,
-}
+    /// Locks the store against other processes, failing if it's already
+    /// locked.
+    fn try_lock_exclusive(&self) -> std::io::Result<()> {
+        Ok(())
+    }
 
-impl<'a> ScanIterator<'a> {
-    fn map(&mut self, item: (&Vec<u8>, &(u64, u32))) -> <Self as Iterator>::Item {
-        let (key, (value_offset, value_length)) = item;
-        Ok((
-            key.clone(),
-            self.log.read_value(*value_offset, *value_length)?,
-        ))
+    /// Returns the underlying file, if any, e.g. to memory-map it.
+    fn as_file(&self) -> Option<&std::fs::File> {
+        None
     }
 }
 
-impl<'a> Iterator for ScanIterator<'a> {
-    type Item = Result<(Vec<u8>, Vec<u8>)>;
+impl ReadWriteSeek for std::fs::File {
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|item| self.map(item))
+    fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+        std::fs::File::set_len(self, length)
     }
-}
 
-impl<'a> DoubleEndedIterator for ScanIterator<'a> {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        self.inner.next_back().map(|item| self.map(item))
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+        read_exact_at(self, buffer, offset)
     }
-}
 
-pub struct BitCask {
-    log: Log,
-    key_dir: KeyDir,
+    fn try_clone(&self) -> std::io::Result<Box<dyn ReadWriteSeek>> {
+        Ok(Box::new(std::fs::File::try_clone(self)?))
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        std::fs::File::sync_all(self)
+    }
+
+    fn try_lock_exclusive(&self) -> std::io::Result<()> {
+        fs4::FileExt::try_lock_exclusive(self)
+    }
+
+    fn as_file(&self) -> Option<&std::fs::File> {
+        Some(self)
+    }
 }
 
-impl BitCask {
-    pub fn new(path: PathBuf) -> Result<Self> {
-        let mut log = Log::new(path)?;
-        let key_dir = log.build_key_dir()?;
-        Ok(Self { log, key_dir })
+/// An in-memory store, mostly for tests. Clones are snapshots.
+impl ReadWriteSeek for std::io::Cursor<Vec<u8>> {
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.get_ref().len() as u64)
     }
 
-    pub fn new_compact(path: PathBuf, garbage_ratio_threshold: f64) -> Result<Self> {
-        let mut bit_cask = Self::new(path)?;
-        let status = bit_cask.status()?;
-        let garbage_ratio = status.garbage_disk_size as f64 / status.total_disk_size as f64;
-        if status.garbage_disk_size > 0 && garbage_ratio >= garbage_ratio_threshold {
-            log::info!(
-                "Compacting {} to remove {:.3}MB garbage ({:.0}% of {:.3}MB)",
-                bit_cask.log.path.display(),
-                status.garbage_disk_size / 1048576,
-                garbage_ratio * 100.0,
-                status.total_disk_size / 1048576,
-            );
-            bit_cask.compact()?;
-            log::info!("Compacted");
-        }
-        Ok(bit_cask)
+    fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+        self.get_mut().resize(length as usize, 0);
+        Ok(())
     }
 
-    pub fn compact(&mut self) -> Result<()> {
-        let mut new_path = self.log.path.clone();
-        new_path.set_extension("new");
-        let (mut new_log, new_key_dir) = self.write_log(new_path)?;
-        std::fs::rename(&new_log.path, &self.log.path)?;
-        new_log.path = self.log.path.clone();
-        self.log = new_log;
-        self.key_dir = new_key_dir;
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+        let bytes = self.get_ref();
+        let start = (offset as usize).min(bytes.len());
+        let Some(bytes) = bytes[start..].get(..buffer.len()) else {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        };
+        buffer.copy_from_slice(bytes);
         Ok(())
     }
 
-    fn write_log(&mut self, path: PathBuf) -> Result<(Log, KeyDir)> {
-        let mut new_log = Log::new(path)?;
-        let mut new_key_dir = KeyDir::new();
+    fn try_clone(&self) -> std::io::Result<Box<dyn ReadWriteSeek>> {
+        Ok(Box::new(std::io::Cursor::new(self.get_ref().clone())))
+    }
+}
 
-        new_log.file.set_len(0)?;
-        for (key, (value_offset, value_length)) in &self.key_dir {
-            let value = self.log.read_value(*value_offset, *value_length)?;
-            let (offset, write_length) = new_log.append_entry(key, Some(&value))?;
-            new_key_dir.insert(
-                key.clone(),
-                (
-                    offset + write_length as u64 - *value_length as u64,
-                    *value_length,
-                ),
-            );
-        }
+/// A reader over a store, reading at its own offset with
+/// [`ReadWriteSeek::read_exact_at`], such that it doesn't move the store's
+/// cursor. Reads stop at the given length.
+struct LogReader<'a> {
+    file: &'a dyn ReadWriteSeek,
+    offset: u64,
+    length: u64,
+}
 
-        Ok((new_log, new_key_dir))
+impl Read for LogReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.length.saturating_sub(self.offset);
+        let length = (buffer.len() as u64).min(remaining) as usize;
+        self.file
+            .read_exact_at(&mut buffer[..length], self.offset)?;
+        self.offset += length as u64;
+        Ok(length)
     }
 }
 
-impl std::fmt::Display for BitCask {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "bitcask")
+impl Seek for LogReader<'_> {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let offset = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek offset")
+        })?;
+        Ok(self.offset)
     }
 }
 
-impl Engine for BitCask {
-    type ScanIterator<'a> = ScanIterator<'a>;
+/// A log file. Also used as the write-ahead log of [`super::durable::Durable`].
+pub(super) struct Log {
+    /// The path of the log file, or empty if the log is backed by a custom
+    /// store, see [`BitCask::with_file`].
+    pub(super) path: PathBuf,
+    pub(super) file: Box<dyn ReadWriteSeek>,
+    /// The data file id, referenced by key dir entries.
+    id: u32,
+    /// The format version of the log, 0 for legacy logs without a header.
+    version: u8,
+    /// The number of tombstone entries in the file. Only counted when the
+    /// file is scanned on open and for subsequent writes, so it's 0 for files
+    /// loaded from a hint file.
+    tombstone_count: u64,
+    /// The total size of the tombstone entries in the file, in bytes.
+    tombstone_bytes: u64,
+    /// A buffer to encode entries in, reused across writes such that they
+    /// don't allocate a new one.
+    write_buffer: Vec<u8>,
+    /// The size at which writes fail as if the disk were full.
+    #[cfg(test)]
+    disk_limit: Option<u64>,
+    /// A memory map of the file used for reads, if enabled. It's remapped
+    /// when reading past its end, since the file may have grown.
+    #[cfg(feature = "mmap")]
+    mmap: Option<std::sync::RwLock<memmap2::Mmap>>,
+}
 
-    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        let (offset, write_length) = self.log.append_entry(key, Some(&value))?;
-        let value_length = value.len() as u32;
-        self.key_dir.insert(
-            key.to_vec(),
-            (
-                offset + write_length as u64 - value_length as u64,
-                value_length,
-            ),
-        );
-        Ok(())
+/// The location of a live value in the data files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct KeyDirEntry {
+    file_id: u32,
+    value_offset: u64,
+    value_length: u64,
+    /// Expiry time in milliseconds since the UNIX epoch, if any.
+    expiry: Option<u64>,
+}
+
+impl KeyDirEntry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= now)
     }
+}
 
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        if let Some((offset, length)) = self.key_dir.get(key) {
-            Ok(Some(self.log.read_value(*offset, *length)?))
-        } else {
-            Ok(None)
+pub(super) type KeyDir = std::collections::BTreeMap<Vec<u8>, KeyDirEntry>;
+
+/// A key dir entry without its key, see [`HashedKeyDir`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HashedEntry {
+    key_length: u32,
+    entry: KeyDirEntry,
+}
+
+/// The entries of the keys with a given hash. Hash collisions are rare, so a
+/// single entry is stored inline.
+#[derive(Clone, Debug)]
+enum HashSlot {
+    One(HashedEntry),
+    Many(Vec<HashedEntry>),
+}
+
+impl HashSlot {
+    fn entries(&self) -> &[HashedEntry] {
+        match self {
+            Self::One(entry) => std::slice::from_ref(entry),
+            Self::Many(entries) => entries,
         }
     }
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.log.append_entry(key, None)?;
-        self.key_dir.remove(key);
-        Ok(())
+    fn entries_mut(&mut self) -> &mut [HashedEntry] {
+        match self {
+            Self::One(entry) => std::slice::from_mut(entry),
+            Self::Many(entries) => entries,
+        }
     }
 
-    fn flush(&mut self) -> Result<()> {
-        Ok(self.log.file.sync_all()?)
+    fn push(&mut self, entry: HashedEntry) {
+        match self {
+            Self::One(first) => *self = Self::Many(vec![*first, entry]),
+            Self::Many(entries) => entries.push(entry),
+        }
     }
 
-    fn status(&mut self) -> Result<Status> {
-        let name = self.to_string();
-        let key_count = self.key_dir.len() as u64;
-        let size = self
-            .key_dir
-            .iter()
-            .fold(0, |size, (key, (_, value_length))| {
-                size + key.len() as u64 + *value_length as u64
-            });
-        let total_disk_size = self.log.file.metadata()?.len();
-        let live_disk_size = size + 8 * key_count;
-        let garbage_disk_size = total_disk_size - live_disk_size;
-        Ok(Status {
-            name,
-            key_count,
-            size,
-            total_disk_size,
-            live_disk_size,
-            garbage_disk_size,
+    /// Removes the entry at the index, returning false if the slot is empty
+    /// afterwards and must be removed.
+    fn remove(&mut self, index: usize) -> bool {
+        match self {
+            Self::One(_) => false,
+            Self::Many(entries) => {
+                entries.remove(index);
+                if let [entry] = entries.as_slice() {
+                    *self = Self::One(*entry);
+                }
+                true
+            }
+        }
+    }
+}
+
+/// A key dir that stores a hash of each key instead of the key, see
+/// [`BitCaskConfig::hashed_key_dir`]. Keys are read from the data files,
+/// where they precede their values, to tell apart keys with colliding hashes
+/// and to iterate over keys. The read function passed to the methods reads
+/// the key of an entry.
+struct HashedKeyDir {
+    slots: std::collections::HashMap<u64, HashSlot>,
+    len: usize,
+    /// The key hash function, which tests replace to force collisions.
+    hash: fn(&[u8]) -> u64,
+}
+
+impl HashedKeyDir {
+    fn new() -> Self {
+        Self::with_hash(|key| {
+            use std::hash::{Hash as _, Hasher as _};
+            let mut hasher = std::hash::DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
         })
     }
 
-    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
-        ScanIterator {
-            inner: self.key_dir.range(range),
-            log: &mut self.log,
+    fn with_hash(hash: fn(&[u8]) -> u64) -> Self {
+        Self {
+            slots: std::collections::HashMap::new(),
+            len: 0,
+            hash,
         }
     }
-}
 
-impl Drop for BitCask {
-    fn drop(&mut self) {
-        if let Err(error) = self.flush() {
-            log::error!("Failed to flush database: {}", error);
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the index of the key's entry in the slot, if any, reading the
+    /// keys of the entries with the same key length.
+    fn find(
+        slot: &HashSlot,
+        key: &[u8],
+        read_key: impl Fn(&HashedEntry) -> Result<Vec<u8>>,
+    ) -> Result<Option<usize>> {
+        for (i, hashed) in slot.entries().iter().enumerate() {
+            if hashed.key_length as usize == key.len() && read_key(hashed)? == key {
+                return Ok(Some(i));
+            }
         }
+        Ok(None)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn get(
+        &self,
+        key: &[u8],
+        read_key: impl Fn(&HashedEntry) -> Result<Vec<u8>>,
+    ) -> Result<Option<KeyDirEntry>> {
+        let Some(slot) = self.slots.get(&(self.hash)(key)) else {
+            return Ok(None);
+        };
+        let index = Self::find(slot, key, read_key)?;
+        Ok(index.map(|i| slot.entries()[i].entry))
+    }
 
-    impl Log {
-        fn print(&mut self, writer: &mut impl Write) -> Result<()> {
-            let mut length_buffer = [0u8; 4];
-            let file_length = self.file.metadata()?.len();
-            let mut reader = std::io::BufReader::new(&mut self.file);
-            let mut offset = reader.seek(SeekFrom::Start(0))?;
-            let mut index = 0;
+    /// Inserts or replaces the key's entry, returning the replaced one.
+    fn insert(
+        &mut self,
+        key: &[u8],
+        entry: KeyDirEntry,
+        read_key: impl Fn(&HashedEntry) -> Result<Vec<u8>>,
+    ) -> Result<Option<KeyDirEntry>> {
+        let hashed = HashedEntry {
+            key_length: key.len() as u32,
+            entry,
+        };
+        let slot = match self.slots.entry((self.hash)(key)) {
+            std::collections::hash_map::Entry::Occupied(slot) => slot.into_mut(),
+            std::collections::hash_map::Entry::Vacant(slot) => {
+                slot.insert(HashSlot::One(hashed));
+                self.len += 1;
+                return Ok(None);
+            }
+        };
+        if let Some(i) = Self::find(slot, key, read_key)? {
+            let replaced = std::mem::replace(&mut slot.entries_mut()[i], hashed);
+            return Ok(Some(replaced.entry));
+        }
+        slot.push(hashed);
+        self.len += 1;
+        Ok(None)
+    }
 
-            while offset < file_length {
-                writeln!(writer, "index = {index}, offset = {offset}")?;
+    /// Inserts the entry of a key that isn't in the key dir, without reading
+    /// any keys.
+    fn insert_new(&mut self, key: &[u8], entry: KeyDirEntry) {
+        let hashed = HashedEntry {
+            key_length: key.len() as u32,
+            entry,
+        };
+        match self.slots.entry((self.hash)(key)) {
+            std::collections::hash_map::Entry::Occupied(mut slot) => slot.get_mut().push(hashed),
+            std::collections::hash_map::Entry::Vacant(slot) => {
+                slot.insert(HashSlot::One(hashed));
+            }
+        }
+        self.len += 1;
+    }
 
-                reader.read_exact(&mut length_buffer)?;
-                let key_length = u32::from_be_bytes(length_buffer);
-                writeln!(writer, "key_length = {key_length} {:x?}", length_buffer)?;
+    fn remove(
+        &mut self,
+        key: &[u8],
+        read_key: impl Fn(&HashedEntry) -> Result<Vec<u8>>,
+    ) -> Result<Option<KeyDirEntry>> {
+        let hash = (self.hash)(key);
+        let Some(slot) = self.slots.get_mut(&hash) else {
+            return Ok(None);
+        };
+        let Some(i) = Self::find(slot, key, read_key)? else {
+            return Ok(None);
+        };
+        let removed = slot.entries()[i].entry;
+        if !slot.remove(i) {
+            self.slots.remove(&hash);
+        }
+        self.len -= 1;
+        Ok(Some(removed))
+    }
 
-                reader.read_exact(&mut length_buffer)?;
-                let value_length_raw = i32::from_be_bytes(length_buffer);
-                let value_length = value_length_raw.max(0) as u32;
-                writeln!(
-                    writer,
-                    "value_length = {value_length_raw} {:x?}",
-                    length_buffer
-                )?;
+    /// Returns the index of the key's entry in its slot if it's the given
+    /// entry. Entries are identified by their location in the data files, so
+    /// this doesn't read any keys.
+    fn position(&self, key: &[u8], entry: &KeyDirEntry) -> Option<(u64, usize)> {
+        let hash = (self.hash)(key);
+        let slot = self.slots.get(&hash)?;
+        let index = slot
+            .entries()
+            .iter()
+            .position(|hashed| hashed.key_length as usize == key.len() && hashed.entry == *entry)?;
+        Some((hash, index))
+    }
 
-                let mut key = vec![0u8; key_length as usize];
-                reader.read_exact(&mut key)?;
-                write!(writer, "key = ")?;
-                if let Ok(key) = std::str::from_utf8(&key) {
-                    write!(writer, r#""{}" "#, key)?;
-                }
-                writeln!(writer, "{:x?}", key)?;
-
-                let mut value = vec![0u8; value_length as usize];
-                reader.read_exact(&mut value)?;
-                write!(writer, "value = ")?;
-                if value_length_raw.is_negative() {
-                    write!(writer, "tombstone ")?;
-                } else if let Ok(value) = std::str::from_utf8(&value) {
-                    if value.chars().all(|c| !c.is_control()) {
-                        write!(writer, r#""{}" "#, value)?;
-                    }
+    /// Replaces the key's entry with the given one, or removes it if None,
+    /// but only if it's still the given entry. Returns whether it was.
+    fn replace(&mut self, key: &[u8], entry: &KeyDirEntry, new: Option<KeyDirEntry>) -> bool {
+        let Some((hash, i)) = self.position(key, entry) else {
+            return false;
+        };
+        let slot = self.slots.get_mut(&hash).expect("no hash slot");
+        match new {
+            Some(new) => slot.entries_mut()[i].entry = new,
+            None => {
+                if !slot.remove(i) {
+                    self.slots.remove(&hash);
                 }
-                writeln!(writer, "{:x?}\n", value)?;
-
-                offset += 4 + 4 + key_length as u64 + value_length as u64;
-                index += 1;
+                self.len -= 1;
             }
-            Ok(())
         }
+        true
     }
 
-    const GOLDEN_DIR: &str = "tests/golden/bitcask";
+    /// Iterates over the entries, in arbitrary order.
+    fn iter(&self) -> impl Iterator<Item = &HashedEntry> {
+        self.slots.values().flat_map(HashSlot::entries)
+    }
 
-    /// Creates a new BitCask engine for testing.
-    fn setup() -> Result<BitCask> {
-        BitCask::new(tempdir::TempDir::new("yuudb")?.path().join("yuudb"))
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.len = 0;
     }
 
-    /// Writes various values primarily for testing log file handling.
-    ///
-    /// - '': empty key and value
-    /// - a: write
-    /// - b: write, write
-    /// - c: write, delete, write
-    /// - d: delete, write
-    /// - e: write, delete
-    /// - f: delete
-    fn setup_log(s: &mut BitCask) -> Result<()> {
-        s.set(b"b", vec![0x01])?;
-        s.set(b"b", vec![0x02])?;
+    /// Estimates the memory used by the hash table and collision lists.
+    fn memory_usage(&self) -> u64 {
+        // Hash tables use a control byte per bucket.
+        let bucket_size = std::mem::size_of::<(u64, HashSlot)>() + 1;
+        let collisions = (self.slots.values())
+            .map(|slot| match slot {
+                HashSlot::One(_) => 0,
+                HashSlot::Many(entries) => entries.capacity(),
+            })
+            .sum::<usize>();
+        (self.slots.capacity() * bucket_size + collisions * std::mem::size_of::<HashedEntry>())
+            as u64
+    }
+}
 
-        s.set(b"e", vec![0x05])?;
-        s.delete(b"e")?;
+/// Totals over key dir entries, maintained incrementally such that
+/// [`Engine::status`] doesn't have to scan the key dir.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct KeyDirStats {
+    key_count: u64,
+    /// The total length of the keys and values.
+    size: u64,
+    /// The total length of the entries in the data files.
+    disk_size: u64,
+}
 
-        s.set(b"c", vec![0x00])?;
-        s.delete(b"c")?;
-        s.set(b"c", vec![0x03])?;
+impl KeyDirStats {
+    fn add(&mut self, other: Self) {
+        self.key_count += other.key_count;
+        self.size += other.size;
+        self.disk_size += other.disk_size;
+    }
 
-        s.set(b"", vec![])?;
+    fn sub(&mut self, other: Self) {
+        self.key_count -= other.key_count;
+        self.size -= other.size;
+        self.disk_size -= other.disk_size;
+    }
+}
 
-        s.set(b"a", vec![0x01])?;
+/// The immutable data files, by file id.
+type OldLogs = std::collections::BTreeMap<u32, Log>;
 
-        s.delete(b"f")?;
+/// Returns the path of the immutable data file with the given id.
+fn old_log_path(path: &Path, id: u32) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{id:06}"));
+    path.into()
+}
 
-        s.delete(b"d")?;
-        s.set(b"d", vec![0x04])?;
+/// Returns the path of the temporary file written by a compaction, which is
+/// renamed over a data file when the compaction finishes.
+fn compaction_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".new");
+    path.into()
+}
 
-        // Make sure the scan yields the expected results.
-        assert_eq!(
-            vec![
-                (b"".to_vec(), vec![]),
-                (b"a".to_vec(), vec![0x01]),
-                (b"b".to_vec(), vec![0x02]),
-                (b"c".to_vec(), vec![0x03]),
-                (b"d".to_vec(), vec![0x04]),
-            ],
-            s.scan(..).collect::<Result<Vec<_>>>()?,
-        );
+/// Returns whether the status has garbage, and its ratio of garbage to total
+/// disk size has reached the threshold.
+fn garbage_reached(status: &Status, garbage_ratio_threshold: f64) -> bool {
+    let garbage_ratio = status.garbage_disk_size as f64 / status.total_disk_size as f64;
+    status.garbage_disk_size > 0 && garbage_ratio >= garbage_ratio_threshold
+}
 
-        Ok(())
+/// Returns the path of the sidecar file recording the bytes discarded from a
+/// data file on open, see [`RecoveryPolicy::Repair`].
+fn discarded_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".discarded");
+    path.into()
+}
+
+/// Returns the directory containing the path.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     }
+}
 
-    #[test]
-    /// Tests that logs are written correctly using a golden file.
-    fn log() -> Result<()> {
-        let mut s = setup()?;
-        setup_log(&mut s)?;
+/// Fsyncs the directory containing the path, such that files renamed, created
+/// or removed in it survive a crash. Directories can only be fsynced on Unix,
+/// elsewhere this is a no-op.
+fn sync_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    std::fs::File::open(parent_dir(path))?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
 
-        let mut mint = goldenfile::Mint::new(GOLDEN_DIR);
-        s.log.print(&mut mint.new_goldenfile("log")?)?;
-        Ok(())
+/// Removes a temporary file left behind by a crash, if any.
+fn remove_leftover(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => log::warn!("Removed leftover file {}", path.display()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
     }
+    Ok(())
+}
 
-    #[test]
-    /// Tests that writing and then reading a file yields the same results.
-    fn reopen() -> Result<()> {
-        // NB: Don't use setup(), because the tempdir will be removed when
-        // the path falls out of scope.
-        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
-        let mut s = BitCask::new(path.clone())?;
-        setup_log(&mut s)?;
-
+/// Returns the ids of the immutable data files next to the active data file,
+/// in ascending order.
+fn old_log_ids(path: &Path) -> Result<Vec<u32>> {
+    let Some(prefix) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let entries = match std::fs::read_dir(parent_dir(path)) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut ids = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix)?.strip_prefix('.'))
+            .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|id| id.parse::<u32>().ok());
+        ids.extend(id);
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// A log entry, as read when building the key dir.
+enum LogEntry {
+    Value(KeyDirEntry),
+    Tombstone,
+    /// Marks the start of a batch of the given number of entries.
+    Batch(u32),
+}
+
+/// Where a scan of log entries stopped.
+enum ScanEnd {
+    /// At the end of the file.
+    Complete,
+    /// At the incomplete trailing batch with the given offset.
+    IncompleteBatch(u64),
+    /// At the incomplete trailing entry with the given offset.
+    IncompleteEntry(u64),
+    /// At the corrupt entry with the given offset.
+    Corrupt(u64, Error),
+}
+
+impl Log {
+    pub(super) fn new(path: PathBuf, id: u32) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        Self::with_file(path, Box::new(file), id)
+    }
+
+    /// Opens a log over the given store, locking it, and writing a header if
+    /// it's empty.
+    fn with_file(path: PathBuf, file: Box<dyn ReadWriteSeek>, id: u32) -> Result<Self> {
+        file.try_lock_exclusive()?;
+        let mut log = Self {
+            path,
+            file,
+            id,
+            version: VERSION,
+            tombstone_count: 0,
+            tombstone_bytes: 0,
+            write_buffer: Vec::new(),
+            #[cfg(test)]
+            disk_limit: None,
+            #[cfg(feature = "mmap")]
+            mmap: None,
+        };
+        log.version = log.read_header()?;
+        Ok(log)
+    }
+
+    /// Opens an existing log file for reading only, without locking, repairing
+    /// or memory-mapping it. The version is not read, see
+    /// [`Log::parse_header`].
+    fn open_read_only(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Box::new(std::fs::File::open(path)?),
+            id: 0,
+            version: VERSION,
+            tombstone_count: 0,
+            tombstone_bytes: 0,
+            write_buffer: Vec::new(),
+            #[cfg(test)]
+            disk_limit: None,
+            #[cfg(feature = "mmap")]
+            mmap: None,
+        })
+    }
+
+    /// Opens an existing log file for reading only, without locking or
+    /// repairing it, such that it can be read while another process holds it
+    /// for writing. An empty file or incomplete header is read as an empty log.
+    fn open_unlocked(path: PathBuf, id: u32) -> Result<Self> {
+        let mut log = Self::open_read_only(&path)?;
+        log.id = id;
+        log.version = log.parse_header()?.unwrap_or(VERSION);
+        Ok(log)
+    }
+
+    /// Reads the file header and returns the log format version. Writes a new
+    /// header if the file is empty or only contains an incomplete header.
+    fn read_header(&mut self) -> Result<u8> {
+        if let Some(version) = self.parse_header()? {
+            return Ok(version);
+        }
+        if self.file.len()? > 0 {
+            log::error!("Found incomplete log header, rewriting it");
+        }
+        self.reset()?;
+        Ok(VERSION)
+    }
+
+    /// Reads the file header and returns the log format version, or None if
+    /// the file is empty or only contains an incomplete header.
+    fn parse_header(&mut self) -> Result<Option<u8>> {
+        let file_length = self.file.len()?;
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        let length = file_length.min(HEADER_LENGTH) as usize;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut header[..length])?;
+
+        let magic_length = length.min(MAGIC.len());
+        if header[..magic_length] != MAGIC[..magic_length] {
+            return Ok(Some(0));
+        }
+        if length == HEADER_LENGTH as usize {
+            return match header[MAGIC.len()] {
+                version @ 1..=VERSION => Ok(Some(version)),
+                version => Err(Error::Internal(format!(
+                    "Unsupported log format version {version}"
+                ))),
+            };
+        }
+        Ok(None)
+    }
+
+    /// Opens another handle to the log file, e.g. for reading while the log is
+    /// being written to.
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            path: self.path.clone(),
+            file: self.file.try_clone()?,
+            id: self.id,
+            version: self.version,
+            tombstone_count: self.tombstone_count,
+            tombstone_bytes: self.tombstone_bytes,
+            write_buffer: Vec::new(),
+            #[cfg(test)]
+            disk_limit: None,
+            #[cfg(feature = "mmap")]
+            mmap: None,
+        })
+    }
+
+    /// Truncates the log and writes a new header with the current version.
+    pub(super) fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&MAGIC)?;
+        self.file.write_all(&[VERSION])?;
+        self.version = VERSION;
+        self.tombstone_count = 0;
+        self.tombstone_bytes = 0;
+        Ok(())
+    }
+
+    /// Returns a reader over the log up to the given length, which doesn't
+    /// need a mutable log.
+    fn reader(&self, length: u64) -> LogReader<'_> {
+        LogReader {
+            file: self.file.as_ref(),
+            offset: 0,
+            length,
+        }
+    }
+
+    /// Whether the log is backed by a custom store rather than a file at its
+    /// path, see [`BitCask::with_file`]. Such logs have no hint file.
+    fn is_custom(&self) -> bool {
+        self.path.as_os_str().is_empty()
+    }
+
+    /// The length of the file header.
+    fn header_length(&self) -> u64 {
+        if self.version == 0 {
+            0
+        } else {
+            HEADER_LENGTH
+        }
+    }
+
+    /// The length of the fixed-size fields preceding the key of each entry.
+    fn entry_header_length(&self) -> u64 {
+        let expiry_length = if self.version >= 2 { 8 } else { 0 };
+        4 + self.value_length_length() + expiry_length
+    }
+
+    /// The length of the value length field of each entry.
+    fn value_length_length(&self) -> u64 {
+        if self.version >= 4 {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Encodes a value length field in this log format.
+    fn encode_value_length(&self, value_length: i64) -> Vec<u8> {
+        if self.version >= 4 {
+            value_length.to_be_bytes().to_vec()
+        } else {
+            (value_length as i32).to_be_bytes().to_vec()
+        }
+    }
+
+    /// Returns the value length field for a value of the given length, or
+    /// [`Error::Value`] if it's too large for this log format.
+    fn check_value_length(&self, length: usize) -> Result<i64> {
+        let max = if self.version >= 4 {
+            i64::MAX as u64
+        } else {
+            i32::MAX as u64
+        };
+        if length as u64 > max {
+            return Err(Error::Value(format!(
+                "Value of {length} bytes exceeds the maximum of {max} bytes for log format version {}",
+                self.version
+            )));
+        }
+        Ok(length as i64)
+    }
+
+    /// The length of the checksum trailing each entry.
+    fn checksum_length(&self) -> u32 {
+        if self.version == 0 {
+            0
+        } else {
+            4
+        }
+    }
+
+    /// Computes the checksum of an entry. The value length is -1 for
+    /// tombstones and -2 for batch markers, in which case the value is empty.
+    fn checksum(&self, key: &[u8], value_length: i64, expiry: Option<u64>, value: &[u8]) -> u32 {
+        let mut hasher = self.checksum_hasher(key, value_length, expiry);
+        hasher.update(value);
+        hasher.finalize()
+    }
+
+    /// Returns a checksum hasher that has hashed the entry up to its value,
+    /// such that the value can be hashed piece by piece.
+    fn checksum_hasher(
+        &self,
+        key: &[u8],
+        value_length: i64,
+        expiry: Option<u64>,
+    ) -> crc32fast::Hasher {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&(key.len() as u32).to_be_bytes());
+        hasher.update(&self.encode_value_length(value_length));
+        if self.version >= 2 {
+            hasher.update(&expiry.unwrap_or(0).to_be_bytes());
+        }
+        hasher.update(key);
+        hasher
+    }
+
+    /// Builds the key dir by scanning the log, applying its entries on top of
+    /// the given key dir of older data files. Entries that have expired by
+    /// `now` are treated as tombstones, and incomplete trailing entries or
+    /// batches are handled according to the recovery policy if given, and
+    /// otherwise skipped, e.g. when they are still being written by another
+    /// process. The file is read with a buffer of the given size.
+    pub(super) fn build_key_dir(
+        &mut self,
+        mut key_dir: KeyDir,
+        verify_checksums: bool,
+        now: u64,
+        buffer_size: usize,
+        recovery: Option<RecoveryPolicy>,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<KeyDir> {
+        let tombstone_overhead = self.entry_header_length() + self.checksum_length() as u64;
+        let (mut tombstone_count, mut tombstone_bytes) = (0, 0);
+        let apply = |key: Vec<u8>, entry| match entry {
+            LogEntry::Value(entry) if !entry.is_expired(now) => {
+                key_dir.insert(key, entry);
+            }
+            LogEntry::Tombstone => {
+                tombstone_count += 1;
+                tombstone_bytes += tombstone_overhead + key.len() as u64;
+                key_dir.remove(&key);
+            }
+            _ => {
+                key_dir.remove(&key);
+            }
+        };
+        let size = self.file.len()?;
+        log::debug!("Building key dir from {}: size={size}", self.path.display());
+        let start = Instant::now();
+        let end = self.scan_entries(verify_checksums, buffer_size, apply, progress)?;
+        self.tombstone_count = tombstone_count;
+        self.tombstone_bytes = tombstone_bytes;
+        log::debug!(
+            "Built key dir from {}: duration={:?} size={size} keys={}",
+            self.path.display(),
+            start.elapsed(),
+            key_dir.len(),
+        );
+        let (offset, incomplete) = match end {
+            ScanEnd::Complete => return Ok(key_dir),
+            ScanEnd::IncompleteBatch(offset) => (offset, "batch"),
+            ScanEnd::IncompleteEntry(offset) => (offset, "entry"),
+            ScanEnd::Corrupt(_, error) => return Err(error),
+        };
+        match recovery {
+            None => {
+                log::warn!("Found incomplete {incomplete} at offset {offset}, stopping scan");
+            }
+            Some(RecoveryPolicy::Error) => {
+                return Err(Error::Internal(format!(
+                    "Found incomplete {incomplete} at offset {offset} in {}",
+                    self.path.display()
+                )));
+            }
+            Some(policy) => {
+                if policy == RecoveryPolicy::Repair {
+                    self.save_discarded(offset, incomplete)?;
+                }
+                log::error!("Found incomplete {incomplete} at offset {offset}, truncating file");
+                self.file.set_len(offset)?;
+            }
+        }
+        Ok(key_dir)
+    }
+
+    /// Appends the bytes from the offset to the end of the file to the
+    /// sidecar file of discarded bytes, see [`RecoveryPolicy::Repair`].
+    fn save_discarded(&self, offset: u64, incomplete: &str) -> Result<()> {
+        let length = self.file.len()? - offset;
+        let mut bytes = vec![0; length as usize];
+        self.file.read_exact_at(&mut bytes, offset)?;
+        let path = discarded_path(&self.path);
+        log::warn!("Saving incomplete {incomplete} to {}", path.display());
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(
+            file,
+            "Discarded incomplete {incomplete} at offset {offset} ({length} bytes): {}",
+            hex::encode(bytes)
+        )?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes a human-readable rendering of the log's entries, including
+    /// overwritten entries, tombstones and batch markers, for debugging. Keys
+    /// and values are shown as strings where they're valid UTF-8, along with
+    /// their bytes in hex.
+    fn print(&self, writer: &mut impl Write) -> Result<()> {
+        let mut length_buffer = [0u8; 4];
+        let file_length = self.file.len()?;
+        let version = self.version;
+        let entry_header_length = self.entry_header_length();
+        let value_length_length = self.value_length_length();
+        let checksum_length = self.checksum_length();
+        let mut reader = std::io::BufReader::new(self.reader(file_length));
+        let mut offset = reader.seek(SeekFrom::Start(0))?;
+        let mut index = 0;
+
+        if version > 0 {
+            let mut header = [0u8; HEADER_LENGTH as usize];
+            reader.read_exact(&mut header)?;
+            writeln!(writer, "header = {:x?}, version = {}\n", header, version)?;
+            offset += HEADER_LENGTH;
+        }
+
+        while offset < file_length {
+            writeln!(writer, "index = {index}, offset = {offset}")?;
+
+            reader.read_exact(&mut length_buffer)?;
+            let key_length = u32::from_be_bytes(length_buffer);
+            writeln!(writer, "key_length = {key_length} {:x?}", length_buffer)?;
+
+            let mut value_length_buffer = vec![0u8; value_length_length as usize];
+            reader.read_exact(&mut value_length_buffer)?;
+            let value_length_raw = if value_length_length == 8 {
+                i64::from_be_bytes(value_length_buffer.as_slice().try_into().unwrap())
+            } else {
+                i32::from_be_bytes(value_length_buffer.as_slice().try_into().unwrap()) as i64
+            };
+            let value_length = value_length_raw.max(0) as u64;
+            writeln!(
+                writer,
+                "value_length = {value_length_raw} {:x?}",
+                value_length_buffer
+            )?;
+
+            if version >= 2 {
+                let mut expiry_buffer = [0u8; 8];
+                reader.read_exact(&mut expiry_buffer)?;
+                let expiry = u64::from_be_bytes(expiry_buffer);
+                writeln!(writer, "expiry = {expiry} {:x?}", expiry_buffer)?;
+            }
+
+            let mut key = vec![0u8; key_length as usize];
+            reader.read_exact(&mut key)?;
+            write!(writer, "key = ")?;
+            if let Ok(key) = std::str::from_utf8(&key) {
+                write!(writer, r#""{}" "#, key)?;
+            }
+            writeln!(writer, "{:x?}", key)?;
+
+            let mut value = vec![0u8; value_length as usize];
+            reader.read_exact(&mut value)?;
+            write!(writer, "value = ")?;
+            if value_length_raw == BATCH_MARKER {
+                write!(writer, "batch marker ")?;
+            } else if value_length_raw.is_negative() {
+                write!(writer, "tombstone ")?;
+            } else if let Ok(value) = std::str::from_utf8(&value) {
+                if value.chars().all(|c| !c.is_control()) {
+                    write!(writer, r#""{}" "#, value)?;
+                }
+            }
+            writeln!(writer, "{:x?}", value)?;
+
+            if checksum_length > 0 {
+                reader.read_exact(&mut length_buffer)?;
+                writeln!(writer, "checksum = {:x?}", length_buffer)?;
+            }
+            writeln!(writer)?;
+
+            offset += entry_header_length + key_length as u64 + value_length;
+            offset += checksum_length as u64;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Scans the entries of the log in order, passing each key and value or
+    /// tombstone entry to `apply`. The entries of a write batch are only
+    /// passed once the whole batch has been read. Returns where the scan
+    /// stopped, i.e. at the end of the file, or at the first incomplete or
+    /// corrupt entry.
+    ///
+    /// The file is read sequentially with a buffer of the given size. Values
+    /// are never copied out of the buffer: they're checksummed in place if
+    /// verifying checksums, and skipped within the buffer otherwise, such that
+    /// the file is only read again once the buffer is exhausted.
+    ///
+    /// The number of bytes scanned so far is passed to `progress` about once
+    /// per buffer of bytes read.
+    fn scan_entries(
+        &self,
+        verify_checksums: bool,
+        buffer_size: usize,
+        mut apply: impl FnMut(Vec<u8>, LogEntry),
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<ScanEnd> {
+        let mut length_buffer = [0u8; 4];
+        let mut entry_header = [0u8; 4 + 8 + 8];
+        let file_length = self.file.len()?;
+        let header_length = self.header_length();
+        let entry_header_length = self.entry_header_length();
+        let value_length_length = self.value_length_length() as usize;
+        let checksum_length = self.checksum_length();
+        let verify_checksums = verify_checksums && checksum_length > 0;
+        let reader = self.reader(file_length);
+        let mut reader = std::io::BufReader::with_capacity(buffer_size, reader);
+        let mut offset = reader.seek(SeekFrom::Start(header_length))?;
+        let mut next_progress = offset + buffer_size as u64;
+
+        // The batch being read, if any: its offset, the number of entries
+        // remaining, and the entries read so far.
+        let mut batch = None;
+
+        while offset < file_length {
+            let result = || -> std::io::Result<(Vec<u8>, LogEntry, u64)> {
+                // Read the fixed-size fields with a single read.
+                let entry_header = &mut entry_header[..entry_header_length as usize];
+                reader.read_exact(entry_header)?;
+                let (key_length, rest) = entry_header.split_at(4);
+                let (raw_value_length, expiry) = rest.split_at(value_length_length);
+                let key_length = u32::from_be_bytes(key_length.try_into().unwrap());
+                let raw_value_length = if value_length_length == 8 {
+                    i64::from_be_bytes(raw_value_length.try_into().unwrap())
+                } else {
+                    i32::from_be_bytes(raw_value_length.try_into().unwrap()) as i64
+                };
+                let value_length = raw_value_length.max(0) as u64;
+                let expiry = if expiry.is_empty() {
+                    None
+                } else {
+                    Some(u64::from_be_bytes(expiry.try_into().unwrap()))
+                        .filter(|expiry| *expiry > 0)
+                };
+                let value_offset = offset + entry_header_length + key_length as u64;
+
+                // Check the lengths before allocating buffers, such that a
+                // corrupt length can't cause a huge allocation.
+                let remaining_length = value_length.saturating_add(checksum_length as u64);
+                if value_offset.saturating_add(remaining_length) > file_length {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "Entry length exceeds file length",
+                    ));
+                }
+
+                let mut key = vec![0u8; key_length as usize];
+                reader.read_exact(&mut key)?;
+
+                if verify_checksums {
+                    let mut hasher = self.checksum_hasher(&key, raw_value_length, expiry);
+                    let mut remaining = value_length;
+                    while remaining > 0 {
+                        let buffer = reader.fill_buf()?;
+                        if buffer.is_empty() {
+                            return Err(std::io::ErrorKind::UnexpectedEof.into());
+                        }
+                        let length = (buffer.len() as u64).min(remaining) as usize;
+                        hasher.update(&buffer[..length]);
+                        reader.consume(length);
+                        remaining -= length as u64;
+                    }
+                    reader.read_exact(&mut length_buffer)?;
+                    if hasher.finalize() != u32::from_be_bytes(length_buffer) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Checksum mismatch for entry at offset {offset}"),
+                        ));
+                    }
+                } else {
+                    reader.seek_relative(remaining_length as i64)?;
+                }
+
+                let entry = match raw_value_length {
+                    TOMBSTONE => LogEntry::Tombstone,
+                    BATCH_MARKER => match <[u8; 4]>::try_from(key.as_slice()) {
+                        Ok(count) => LogEntry::Batch(u32::from_be_bytes(count)),
+                        Err(_) => {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                format!("Invalid batch marker at offset {offset}"),
+                            ))
+                        }
+                    },
+                    _ => LogEntry::Value(KeyDirEntry {
+                        file_id: self.id,
+                        value_offset,
+                        value_length,
+                        expiry,
+                    }),
+                };
+                Ok((key, entry, value_offset + remaining_length))
+            }();
+
+            match result {
+                Ok((_, LogEntry::Batch(_), _)) if batch.is_some() => {
+                    return Ok(ScanEnd::Corrupt(
+                        offset,
+                        Error::Internal(format!("Unexpected batch marker at offset {offset}")),
+                    ));
+                }
+                Ok((_, LogEntry::Batch(count), next_offset)) => {
+                    batch = Some((offset, count, Vec::new()));
+                    offset = next_offset;
+                }
+                Ok((key, entry, next_offset)) => {
+                    match batch.as_mut() {
+                        Some((_, remaining, entries)) => {
+                            entries.push((key, entry));
+                            *remaining -= 1;
+                        }
+                        None => apply(key, entry),
+                    }
+                    offset = next_offset;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(error) => return Ok(ScanEnd::Corrupt(offset, error.into())),
+            }
+
+            if let Some((_, 0, _)) = batch {
+                for (key, entry) in batch.take().unwrap().2 {
+                    apply(key, entry);
+                }
+            }
+            if offset >= next_progress {
+                progress(offset);
+                next_progress = offset + buffer_size as u64;
+            }
+        }
+
+        Ok(match batch {
+            Some((batch_offset, ..)) => ScanEnd::IncompleteBatch(batch_offset),
+            None if offset < file_length => ScanEnd::IncompleteEntry(offset),
+            None => ScanEnd::Complete,
+        })
+    }
+
+    /// The path of the hint file for this log.
+    fn hint_path(&self) -> PathBuf {
+        let mut path = self.path.clone();
+        path.set_extension("hint");
+        path
+    }
+
+    /// Writes a hint file containing the key dir, such that it can be loaded
+    /// on the next open instead of scanning the data files. This must be
+    /// called on the active log, with the older data files. The hint file is
+    /// written to a temporary file and then renamed into place.
+    fn write_hint(&self, older: &OldLogs, key_dir: &KeyDir) -> Result<()> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&HINT_MAGIC);
+        buffer.extend_from_slice(&(older.len() as u32 + 1).to_be_bytes());
+        for log in older.values().chain(std::iter::once(self)) {
+            buffer.extend_from_slice(&log.id.to_be_bytes());
+            buffer.push(log.version);
+            buffer.extend_from_slice(&log.file.len()?.to_be_bytes());
+        }
+        for (key, entry) in key_dir {
+            buffer.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(&entry.file_id.to_be_bytes());
+            buffer.extend_from_slice(&entry.value_offset.to_be_bytes());
+            buffer.extend_from_slice(&entry.value_length.to_be_bytes());
+            buffer.extend_from_slice(&entry.expiry.unwrap_or(0).to_be_bytes());
+            buffer.extend_from_slice(key);
+        }
+        buffer.extend_from_slice(&crc32fast::hash(&buffer).to_be_bytes());
+
+        let hint_path = self.hint_path();
+        let mut temp_path = hint_path.clone();
+        temp_path.set_extension("hint.new");
+        std::fs::write(&temp_path, buffer)?;
+        std::fs::rename(&temp_path, &hint_path)?;
+        Ok(())
+    }
+
+    /// Removes the hint file, if any.
+    fn remove_hint(&self) -> Result<()> {
+        if self.is_custom() {
+            return Ok(());
+        }
+        match std::fs::remove_file(self.hint_path()) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the key dir from the hint file, if it exists and is valid for the
+    /// current data files. This must be called on the active log, with the
+    /// older data files. Returns None if the hint file is missing, stale or
+    /// corrupt, in which case the data files must be scanned instead. Entries
+    /// that have expired by `now` are skipped.
+    fn read_hint(&self, older: &OldLogs, now: u64) -> Result<Option<KeyDir>> {
+        if self.is_custom() {
+            return Ok(None);
+        }
+        let hint_path = self.hint_path();
+        let buffer = match std::fs::read(&hint_path) {
+            Ok(buffer) => buffer,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        // Only use the hint file if it's newer than the data files, and was
+        // written for data files of the same lengths.
+        let hint_modified = std::fs::metadata(&hint_path)?.modified()?;
+        let mut file_lengths = Vec::new();
+        for log in older.values().chain(std::iter::once(self)) {
+            let metadata = std::fs::metadata(&log.path)?;
+            if hint_modified < metadata.modified()? {
+                log::warn!("Ignoring stale hint file {}", hint_path.display());
+                return Ok(None);
+            }
+            file_lengths.push((log, metadata.len()));
+        }
+        let key_dir = Self::parse_hint(&buffer, &file_lengths, now);
+        if key_dir.is_none() {
+            log::warn!("Ignoring invalid hint file {}", hint_path.display());
+        }
+        Ok(key_dir)
+    }
+
+    /// Parses and validates a hint file for the given data files and their
+    /// lengths, oldest first.
+    fn parse_hint(buffer: &[u8], file_lengths: &[(&Log, u64)], now: u64) -> Option<KeyDir> {
+        let (buffer, checksum) = buffer.split_at(buffer.len().checked_sub(4)?);
+        if crc32fast::hash(buffer).to_be_bytes() != checksum {
+            return None;
+        }
+
+        fn take<'a>(buffer: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+            let (taken, rest) = buffer.split_at_checked(length)?;
+            *buffer = rest;
+            Some(taken)
+        }
+        fn take_u32(buffer: &mut &[u8]) -> Option<u32> {
+            Some(u32::from_be_bytes(take(buffer, 4)?.try_into().ok()?))
+        }
+        fn take_u64(buffer: &mut &[u8]) -> Option<u64> {
+            Some(u64::from_be_bytes(take(buffer, 8)?.try_into().ok()?))
+        }
+
+        let mut buffer = buffer;
+        if take(&mut buffer, HINT_MAGIC.len())? != HINT_MAGIC
+            || take_u32(&mut buffer)? != file_lengths.len() as u32
+        {
+            return None;
+        }
+        for (log, file_length) in file_lengths {
+            if take_u32(&mut buffer)? != log.id
+                || take(&mut buffer, 1)? != [log.version]
+                || take_u64(&mut buffer)? != *file_length
+            {
+                return None;
+            }
+        }
+
+        let mut key_dir = KeyDir::new();
+        while !buffer.is_empty() {
+            let key_length = take_u32(&mut buffer)?;
+            let file_id = take_u32(&mut buffer)?;
+            let value_offset = take_u64(&mut buffer)?;
+            let value_length = take_u64(&mut buffer)?;
+            let expiry = take_u64(&mut buffer)?;
+            let key = take(&mut buffer, key_length as usize)?.to_vec();
+
+            let (log, file_length) = file_lengths.iter().find(|(log, _)| log.id == file_id)?;
+            let header_length = log.header_length() + log.entry_header_length();
+            let value_end = value_offset
+                .saturating_add(value_length)
+                .saturating_add(log.checksum_length() as u64);
+            if value_offset < header_length + key_length as u64 || value_end > *file_length {
+                return None;
+            }
+            let entry = KeyDirEntry {
+                file_id,
+                value_offset,
+                value_length,
+                expiry: Some(expiry).filter(|expiry| *expiry > 0),
+            };
+            if !entry.is_expired(now) {
+                key_dir.insert(key, entry);
+            }
+        }
+        Some(key_dir)
+    }
+
+    /// Reads the key of a hashed key dir entry, which precedes its value.
+    fn read_key(&self, hashed: &HashedEntry) -> Result<Vec<u8>> {
+        let mut key = vec![0; hashed.key_length as usize];
+        let offset = hashed.entry.value_offset - hashed.key_length as u64;
+        self.read_exact_at(&mut key, offset)?;
+        Ok(key)
+    }
+
+    /// Reads a value from the log, verifying its checksum if requested and
+    /// available in this log format.
+    pub(super) fn read_value(
+        &self,
+        key: &[u8],
+        entry: &KeyDirEntry,
+        verify_checksum: bool,
+    ) -> Result<Vec<u8>> {
+        let checksum_length = if verify_checksum {
+            self.checksum_length()
+        } else {
+            0
+        };
+        let mut value = vec![0u8; (entry.value_length + checksum_length as u64) as usize];
+        self.read_exact_at(&mut value, entry.value_offset)?;
+
+        if checksum_length > 0 {
+            let checksum = value.split_off(entry.value_length as usize);
+            self.verify_value(key, entry, &value, &checksum)?;
+        }
+        Ok(value)
+    }
+
+    /// Verifies a value read from the log against its checksum.
+    fn verify_value(
+        &self,
+        key: &[u8],
+        entry: &KeyDirEntry,
+        value: &[u8],
+        checksum: &[u8],
+    ) -> Result<()> {
+        let expected = self.checksum(key, entry.value_length as i64, entry.expiry, value);
+        if expected.to_be_bytes() != checksum {
+            return Err(Error::Internal(format!(
+                "Checksum mismatch for value at offset {}",
+                entry.value_offset
+            )));
+        }
+        Ok(())
+    }
+
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = &self.mmap {
+            let start = offset as usize;
+            let end = start.saturating_add(buffer.len());
+            {
+                let map = mmap.read().unwrap_or_else(|error| error.into_inner());
+                if end <= map.len() {
+                    buffer.copy_from_slice(&map[start..end]);
+                    return Ok(());
+                }
+            }
+            let mut map = mmap.write().unwrap_or_else(|error| error.into_inner());
+            if end > map.len() {
+                *map = Self::map_file(self.file.as_ref())?;
+            }
+            if end > map.len() {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            buffer.copy_from_slice(&map[start..end]);
+            return Ok(());
+        }
+        self.file.read_exact_at(buffer, offset)
+    }
+
+    /// Memory-maps the log file for reads.
+    #[cfg(feature = "mmap")]
+    fn map(&mut self) -> Result<()> {
+        self.mmap = Some(std::sync::RwLock::new(Self::map_file(self.file.as_ref())?));
+        Ok(())
+    }
+
+    #[cfg(feature = "mmap")]
+    fn map_file(file: &dyn ReadWriteSeek) -> std::io::Result<memmap2::Mmap> {
+        let Some(file) = file.as_file() else {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        };
+        // SAFETY: the file is locked against other processes, and is never
+        // modified while mapped, only appended to. It's only truncated while
+        // mapped when a write fails partway, and then only back to the offset
+        // the write started at, see Log::write_entries. The map never covers
+        // that partial write: it's only (re)mapped by reads between writes, and
+        // writes take &mut self, so it ends at or before the write's offset.
+        // Truncations below that, i.e. Log::reset, unmap the file first.
+        unsafe { memmap2::Mmap::map(file) }
+    }
+
+    /// Encodes an entry into the buffer. The value length is -1 for tombstones
+    /// and -2 for batch markers, in which case the value is empty.
+    fn encode_entry(
+        &self,
+        buffer: &mut Vec<u8>,
+        key: &[u8],
+        value_length: i64,
+        expiry: Option<u64>,
+        value: &[u8],
+    ) {
+        buffer.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(&self.encode_value_length(value_length));
+        if self.version >= 2 {
+            buffer.extend_from_slice(&expiry.unwrap_or(0).to_be_bytes());
+        }
+        buffer.extend_from_slice(key);
+        buffer.extend_from_slice(value);
+        if self.checksum_length() > 0 {
+            let checksum = self.checksum(key, value_length, expiry, value);
+            buffer.extend_from_slice(&checksum.to_be_bytes());
+        }
+    }
+
+    /// Appends an entry to the log, returning the entry's offset and length.
+    pub(super) fn append_entry(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expiry: Option<u64>,
+    ) -> Result<(u64, u64)> {
+        if expiry.is_some() && self.version < 2 {
+            return Err(Error::Value(format!(
+                "Log format version {} does not support expiry, compact the log to upgrade it",
+                self.version
+            )));
+        }
+
+        let value_length = match value {
+            Some(value) => self.check_value_length(value.len())?,
+            None => TOMBSTONE,
+        };
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        let mut buffer = std::mem::take(&mut self.write_buffer);
+        self.encode_entry(
+            &mut buffer,
+            key,
+            value_length,
+            expiry,
+            value.unwrap_or_default(),
+        );
+        let result = self.write_entries(offset, &buffer);
+        let length = buffer.len() as u64;
+        self.reuse_write_buffer(buffer);
+        result?;
+        if value.is_none() {
+            self.tombstone_count += 1;
+            self.tombstone_bytes += length;
+        }
+        Ok((offset, length))
+    }
+
+    /// Keeps an encoding buffer for the next write, unless it grew larger
+    /// than [`MAX_WRITE_BUFFER_SIZE`] for a large write, in which case it's
+    /// freed rather than holding on to the memory.
+    fn reuse_write_buffer(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() <= MAX_WRITE_BUFFER_SIZE {
+            buffer.clear();
+            self.write_buffer = buffer;
+        }
+    }
+
+    /// Writes encoded entries at the given offset, the end of the file. If
+    /// the write fails, e.g. because the disk is full, the partially written
+    /// bytes are truncated away, such that the file only contains complete
+    /// entries. Otherwise, the next open would discard them anyway.
+    fn write_entries(&mut self, offset: u64, buffer: &[u8]) -> Result<()> {
+        let Err(error) = self.write_all(buffer) else {
+            return Ok(());
+        };
+        Err(self.truncate_after_error(offset, error.into()))
+    }
+
+    /// Truncates a failed write away at the given offset, returning the
+    /// original error. A truncate failure is only logged, since the original
+    /// error is more useful to the caller.
+    fn truncate_after_error(&mut self, offset: u64, error: Error) -> Error {
+        if let Err(truncate_error) = self.file.set_len(offset) {
+            log::error!("Failed to truncate partial write at offset {offset}: {truncate_error}");
+        }
+        error
+    }
+
+    #[cfg(not(test))]
+    fn write_all(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buffer)
+    }
+
+    /// Writes up to the disk limit, failing like a full disk past it.
+    #[cfg(test)]
+    fn write_all(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        let Some(limit) = self.disk_limit else {
+            return self.file.write_all(buffer);
+        };
+        let available = limit.saturating_sub(self.file.stream_position()?) as usize;
+        let length = available.min(buffer.len());
+        self.file.write_all(&buffer[..length])?;
+        if available < buffer.len() {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        Ok(())
+    }
+
+    /// Appends a batch of entries preceded by a batch marker with a single
+    /// write, returning the offset of each entry.
+    pub(super) fn append_batch(&mut self, batch: &WriteBatch) -> Result<Vec<u64>> {
+        if self.version < 3 {
+            return Err(Error::Value(format!(
+                "Log format version {} does not support write batches, compact the log to upgrade it",
+                self.version
+            )));
+        }
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        let mut buffer = std::mem::take(&mut self.write_buffer);
+        let count = (batch.len() as u32).to_be_bytes();
+        self.encode_entry(&mut buffer, &count, BATCH_MARKER, None, &[]);
+
+        let mut offsets = Vec::with_capacity(batch.len());
+        let (mut tombstone_count, mut tombstone_bytes) = (0, 0);
+        for (key, value) in batch.iter() {
+            let entry_offset = buffer.len() as u64;
+            offsets.push(offset + entry_offset);
+            let value_length = match value {
+                Some(value) => self.check_value_length(value.len())?,
+                None => TOMBSTONE,
+            };
+            self.encode_entry(
+                &mut buffer,
+                key,
+                value_length,
+                None,
+                value.unwrap_or_default(),
+            );
+            if value.is_none() {
+                tombstone_count += 1;
+                tombstone_bytes += buffer.len() as u64 - entry_offset;
+            }
+        }
+        let result = self.write_entries(offset, &buffer);
+        self.reuse_write_buffer(buffer);
+        result?;
+        self.tombstone_count += tombstone_count;
+        self.tombstone_bytes += tombstone_bytes;
+        Ok(offsets)
+    }
+}
+
+/// Reads exactly enough bytes to fill the buffer at the given offset. This
+/// doesn't use the file cursor, so it can be called concurrently.
+#[cfg(unix)]
+pub(super) fn read_exact_at(
+    file: &std::fs::File,
+    buffer: &mut [u8],
+    offset: u64,
+) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+/// Reads exactly enough bytes to fill the buffer at the given offset. This
+/// moves the file cursor, so writers must seek before writing.
+#[cfg(windows)]
+pub(super) fn read_exact_at(
+    file: &std::fs::File,
+    mut buffer: &mut [u8],
+    mut offset: u64,
+) -> std::io::Result<()> {
+    while !buffer.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buffer, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buffer = &mut buffer[n..];
+                offset += n as u64;
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// A bounded LRU cache of values by key. The size is the total length of the
+/// cached keys and values.
+struct ValueCache {
+    capacity: usize,
+    size: usize,
+    /// The cached values and their last access tick, by key.
+    entries: std::collections::HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    /// The cached keys by last access tick, least recently used first.
+    recency: std::collections::BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl ValueCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            entries: std::collections::HashMap::new(),
+            recency: std::collections::BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns a cached value, marking it as recently used.
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let (value, tick) = self.entries.get_mut(key)?;
+        let key = self.recency.remove(tick)?;
+        self.tick += 1;
+        *tick = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    /// Caches a value, evicting the least recently used values if the cache
+    /// is full. Values larger than the capacity are not cached.
+    fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.remove(key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        while self.size + size > self.capacity {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = self.entries.remove(&key) {
+                self.size -= key.len() + value.len();
+            }
+        }
+        self.tick += 1;
+        self.size += size;
+        self.entries
+            .insert(key.to_vec(), (value.to_vec(), self.tick));
+        self.recency.insert(self.tick, key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((value, tick)) = self.entries.remove(key) {
+            self.recency.remove(&tick);
+            self.size -= key.len() + value.len();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+    }
+}
+
+/// Locks the value cache, ignoring poisoning.
+fn lock_cache(cache: &Mutex<ValueCache>) -> std::sync::MutexGuard<'_, ValueCache> {
+    cache.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// The maximum number of bytes read at once by a forward scan, when reading
+/// the values of several consecutive keys together.
+const SCAN_READ_AHEAD: u64 = 64 * 1024;
+
+/// The maximum gap between the values of consecutive keys for a forward scan
+/// to read them together. Values of consecutive keys are only separated by the
+/// next entry's header and key after compaction, which writes them in key
+/// order.
+const SCAN_MAX_GAP: u64 = 4 * 1024;
+
+/// Key dir entries in key order, see [`BitCask::key_dir_range`].
+enum KeyDirRange<'a> {
+    /// Entries borrowed from an ordered key dir.
+    Ordered(std::collections::btree_map::Range<'a, Vec<u8>, KeyDirEntry>),
+    /// Entries of a hashed key dir, with their keys read from the data files
+    /// and sorted. A failed read is returned as the only item.
+    Sorted(std::vec::IntoIter<Result<(Vec<u8>, KeyDirEntry)>>),
+}
+
+impl KeyDirRange<'_> {
+    /// Returns the remaining entries, without advancing.
+    fn peek_entries(&self) -> Box<dyn Iterator<Item = &KeyDirEntry> + '_> {
+        match self {
+            Self::Ordered(inner) => Box::new(inner.clone().map(|(_, entry)| entry)),
+            Self::Sorted(inner) => Box::new(
+                (inner.as_slice().iter())
+                    .map_while(|item| item.as_ref().ok())
+                    .map(|(_, entry)| entry),
+            ),
+        }
+    }
+}
+
+impl<'a> Iterator for KeyDirRange<'a> {
+    type Item = Result<(Cow<'a, [u8]>, KeyDirEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Ordered(inner) => inner.next().map(|(key, entry)| Ok((key.into(), *entry))),
+            Self::Sorted(inner) => inner.next().map(|item| item.map(|(k, e)| (k.into(), e))),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Self::Ordered(inner) => inner.size_hint(),
+            Self::Sorted(inner) => inner.size_hint(),
+        }
+    }
+}
+
+impl DoubleEndedIterator for KeyDirRange<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Ordered(inner) => inner
+                .next_back()
+                .map(|(key, entry)| Ok((key.into(), *entry))),
+            Self::Sorted(inner) => inner
+                .next_back()
+                .map(|item| item.map(|(k, e)| (k.into(), e))),
+        }
+    }
+}
+
+pub struct ScanIterator<'a> {
+    inner: KeyDirRange<'a>,
+    bitcask: &'a BitCask,
+    /// The time at which the scan started, used to skip expired entries.
+    now: u64,
+    /// The number of entries left to yield, if known. This is only the case
+    /// for full-range scans, where it's the number of unexpired keys.
+    remaining: Option<usize>,
+    /// Whether forward scans read the values of consecutive keys together
+    /// when they're close in the same data file. This is disabled with a
+    /// value cache, which is consulted per key, and with memory-mapped reads,
+    /// which don't need a syscall per value anyway.
+    read_ahead: bool,
+    /// A chunk of a data file read ahead by a forward scan, as the file ID,
+    /// the offset of the chunk, and its bytes.
+    buffer: Option<(u32, u64, Vec<u8>)>,
+    /// The number of data file reads made by the scan.
+    #[cfg(test)]
+    reads: usize,
+}
+
+impl<'a> ScanIterator<'a> {
+    fn map(&mut self, item: (Cow<'a, [u8]>, KeyDirEntry)) -> <Self as Iterator>::Item {
+        let (key, entry) = item;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        #[cfg(test)]
+        {
+            self.reads += 1;
+        }
+        let value = self.bitcask.read_value(&key, &entry)?;
+        Ok((key.into_owned(), value))
+    }
+
+    /// Like [`ScanIterator::map`], but reads the value from the read-ahead
+    /// buffer, filling it with the values of the following keys if needed.
+    fn map_ahead(&mut self, item: (Cow<'a, [u8]>, KeyDirEntry)) -> <Self as Iterator>::Item {
+        if !self.read_ahead {
+            return self.map(item);
+        }
+        let (key, entry) = item;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        let log = self.bitcask.log(entry.file_id);
+        let checksum_length = match self.bitcask.verify_checksums {
+            true => log.checksum_length() as u64,
+            false => 0,
+        };
+        let start = entry.value_offset;
+        let end = start + entry.value_length + checksum_length;
+        let buffered = matches!(&self.buffer, Some((file_id, offset, bytes))
+            if *file_id == entry.file_id
+                && start >= *offset
+                && end <= *offset + bytes.len() as u64);
+        if !buffered {
+            // Extend the read over the following keys' values while they're
+            // close and in the same file. Expired entries are still in the
+            // file, so they can be read past.
+            let mut chunk_end = end;
+            for next in self.inner.peek_entries() {
+                let next_end = next.value_offset + next.value_length + checksum_length;
+                if next.file_id != entry.file_id
+                    || next.value_offset < chunk_end
+                    || next.value_offset - chunk_end > SCAN_MAX_GAP
+                    || next_end - start > SCAN_READ_AHEAD
+                {
+                    break;
+                }
+                chunk_end = next_end;
+            }
+            let mut bytes = vec![0; (chunk_end - start) as usize];
+            if let Err(error) = log.read_exact_at(&mut bytes, start) {
+                // Only fail if this key's value can't be read, e.g. not if a
+                // later value is missing from a truncated file.
+                if chunk_end == end {
+                    return Err(error.into());
+                }
+                bytes.truncate((end - start) as usize);
+                log.read_exact_at(&mut bytes, start)?;
+            }
+            #[cfg(test)]
+            {
+                self.reads += 1;
+            }
+            self.buffer = Some((entry.file_id, start, bytes));
+        }
+
+        let (_, offset, bytes) = self.buffer.as_ref().expect("no read-ahead buffer");
+        let value_start = (start - offset) as usize;
+        let value_end = value_start + entry.value_length as usize;
+        let value = &bytes[value_start..value_end];
+        if checksum_length > 0 {
+            let checksum = &bytes[value_end..value_end + checksum_length as usize];
+            log.verify_value(&key, &entry, value, checksum)?;
+        }
+        Ok((key.into_owned(), value.to_vec()))
+    }
+}
+
+impl<'a> Iterator for ScanIterator<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = self.now;
+        match self
+            .inner
+            .find(|item| !matches!(item, Ok((_, e)) if e.is_expired(now)))?
+        {
+            Ok(item) => Some(self.map_ahead(item)),
+            Err(error) => Some(Err(error)),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.remaining {
+            Some(remaining) => (remaining, Some(remaining)),
+            None => (0, self.inner.size_hint().1),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for ScanIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let now = self.now;
+        match self
+            .inner
+            .rfind(|item| !matches!(item, Ok((_, e)) if e.is_expired(now)))?
+        {
+            Ok(item) => Some(self.map(item)),
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+pub struct BitCask {
+    /// The active data file, which writes are appended to.
+    log: Log,
+    /// The immutable data files, which are only read and compacted.
+    old_logs: OldLogs,
+    /// The key dir, which is empty if the hashed key dir is used instead.
+    key_dir: KeyDir,
+    /// The key dir by key hash, if enabled with
+    /// [`BitCaskConfig::hashed_key_dir`].
+    hashed_key_dir: Option<HashedKeyDir>,
+    /// Totals over the key dir, including expired entries.
+    stats: KeyDirStats,
+    /// The keys with an expiry, by expiry, used to exclude expired entries
+    /// from the stats.
+    expiring: std::collections::BTreeSet<(u64, Vec<u8>)>,
+    /// Whether to verify entry checksums when reading. Legacy logs without
+    /// checksums are read unverified regardless.
+    verify_checksums: bool,
+    clock: Box<dyn Clock>,
+    /// Whether to write a hint file on sync and compaction.
+    write_hint: bool,
+    sync_policy: SyncPolicy,
+    /// The clock time of the last fsync, in milliseconds.
+    last_sync: u64,
+    /// The number of writes since the last fsync.
+    writes_since_sync: u64,
+    /// The number of bytes written since the last fsync.
+    bytes_since_sync: u64,
+    /// The size at which the active data file is rolled over, if any.
+    max_file_size: Option<u64>,
+    /// Whether a compaction has been prepared but not yet finished.
+    compacting: bool,
+    /// Whether to keep the temporary file of a failed compaction.
+    keep_failed_compaction: bool,
+    /// Whether to memory-map the data files for reads.
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    /// A cache of recently read values, if enabled. Values are cached by key,
+    /// so entries remain valid when compaction moves values between files.
+    cache: Option<Mutex<ValueCache>>,
+    /// The maximum key size, in bytes.
+    max_key_size: usize,
+    /// The maximum value size, in bytes.
+    max_value_size: u64,
+    /// The garbage ratio threshold for compacting on drop, if enabled.
+    compact_on_drop: Option<f64>,
+    /// The garbage ratio threshold for recommending compaction, if any.
+    compaction_threshold: Option<f64>,
+    /// Whether the data files were opened read-only, rejecting writes.
+    read_only: bool,
+    /// A bloom filter of the keys in the key dir, if enabled, which lets
+    /// lookups of absent keys skip the key dir. Deleted keys remain in the
+    /// filter until it's rebuilt on compaction.
+    bloom: Option<BloomFilter>,
+    /// The number of values read from the data files or value cache.
+    #[cfg(test)]
+    value_reads: AtomicU64,
+}
+
+/// A report of a data file's contents and integrity, see [`BitCask::verify`].
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct VerifyReport {
+    /// The log format version.
+    pub version: u8,
+    /// The number of value and tombstone entries, excluding batch markers.
+    pub entries: u64,
+    /// The number of live, i.e. unexpired and not overwritten or deleted, keys.
+    pub live_keys: u64,
+    /// The number of tombstone entries.
+    pub tombstones: u64,
+    /// The number of bytes not used by live keys, including any bytes after
+    /// the first corrupt entry.
+    pub garbage_bytes: u64,
+    /// The offset of the first corrupt or incomplete entry, if any. Entries
+    /// after it are not scanned.
+    pub corrupt_offset: Option<u64>,
+    /// A description of the corruption at the corrupt offset, if any.
+    pub error: Option<String>,
+}
+
+/// BitCask configuration options, see [`BitCask::with_config`]. Configs are
+/// best constructed with struct update syntax from the default, such that new
+/// options don't break existing code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BitCaskConfig {
+    /// Whether to verify entry checksums when opening and reading. Legacy
+    /// logs without checksums are read unverified regardless.
+    pub verify_checksums: bool,
+    /// Whether to write a hint file with the key dir on sync and compaction,
+    /// which speeds up the next open.
+    pub hint_file: bool,
+    /// When to fsync writes.
+    pub sync_policy: SyncPolicy,
+    /// The size in bytes at which the active data file is rolled over to a
+    /// new one, such that compaction only has to rewrite the older, immutable
+    /// data files. None disables roll-over.
+    pub max_file_size: Option<u64>,
+    /// The size of an LRU cache of recently read values, in bytes of keys and
+    /// values. 0 disables the cache.
+    pub cache_size: usize,
+    /// Compacts the data files on open if the ratio of garbage to their total
+    /// size is at least the given threshold, and recommends compaction in
+    /// [`Status::compaction_recommended`] once it's reached. None disables
+    /// compaction on open.
+    pub compaction_threshold: Option<f64>,
+    /// Compacts the data files on open if they contain at least the given
+    /// number of tombstones, regardless of the garbage ratio. Tombstones must
+    /// be processed on every open, so this keeps delete-heavy databases quick
+    /// to open. None disables it.
+    pub compaction_tombstone_count: Option<u64>,
+    /// Like `compaction_tombstone_count`, but for the total size of the
+    /// tombstones in bytes.
+    pub compaction_tombstone_bytes: Option<u64>,
+    /// Compacts the data files when the engine is dropped if the ratio of
+    /// garbage to their total size is at least the given threshold, leaving
+    /// them tidy for the next open. None disables compaction on drop.
+    pub compact_on_drop: Option<f64>,
+    /// Maintains an in-memory bloom filter of the keys with the given target
+    /// false positive rate, between 0 and 1 exclusive, such that lookups of
+    /// absent keys can usually return without consulting the key dir or data
+    /// files. The filter takes about 10 bits per key at a rate of 0.01. None
+    /// disables the filter.
+    pub bloom_false_positive_rate: Option<f64>,
+    /// Whether the key dir only stores a hash of each key, reading the keys
+    /// from the data files when needed, instead of keeping them in memory.
+    /// This shrinks the key dir for long keys, at the cost of a key read per
+    /// lookup of a present key, and of reading and sorting all keys for each
+    /// scan. Keys are still read into memory while opening, compacting and
+    /// writing the hint file, and kept for entries with an expiry. The bloom
+    /// filter is not used, since lookups of absent keys don't read any keys.
+    pub hashed_key_dir: bool,
+    /// The size of the read buffer used to scan the data files on open, in
+    /// bytes. Larger buffers need fewer reads to scan large files.
+    pub read_buffer_size: usize,
+    /// The maximum key size in bytes. Larger keys are rejected with
+    /// [`Error::Value`] before anything is written.
+    pub max_key_size: usize,
+    /// The maximum value size in bytes. Larger values are rejected with
+    /// [`Error::Value`] before anything is written.
+    pub max_value_size: u64,
+    /// Whether to open the data files read-only, see
+    /// [`BitCask::open_read_only`]. Compaction thresholds and memory-mapping
+    /// are ignored.
+    pub read_only: bool,
+    /// Whether to keep the partially written temporary file of a failed
+    /// compaction for debugging, rather than removing it. It's still removed
+    /// on the next open, and overwritten by the next compaction.
+    pub keep_failed_compaction: bool,
+    /// How to handle an incomplete entry at the end of a data file on open.
+    pub recovery_policy: RecoveryPolicy,
+    /// Whether to memory-map the data files, such that reads are copied from
+    /// the mapping instead of requiring a syscall.
+    ///
+    /// The data files must not be modified by other processes while mapped,
+    /// which the file locks only guard against for cooperating processes. On
+    /// some platforms, e.g. Windows, mapped files can't be renamed or removed,
+    /// which breaks compaction.
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+}
+
+impl Default for BitCaskConfig {
+    fn default() -> Self {
+        Self {
+            verify_checksums: true,
+            hint_file: false,
+            sync_policy: SyncPolicy::default(),
+            max_file_size: None,
+            cache_size: 0,
+            compaction_threshold: None,
+            compaction_tombstone_count: None,
+            compaction_tombstone_bytes: None,
+            compact_on_drop: None,
+            bloom_false_positive_rate: None,
+            hashed_key_dir: false,
+            read_buffer_size: READ_BUFFER_SIZE,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            read_only: false,
+            keep_failed_compaction: false,
+            recovery_policy: RecoveryPolicy::default(),
+            #[cfg(feature = "mmap")]
+            mmap: false,
+        }
+    }
+}
+
+impl BitCask {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        Self::with_config(path, BitCaskConfig::default())
+    }
+
+    /// Opens a BitCask with the given configuration.
+    pub fn with_config(path: PathBuf, config: BitCaskConfig) -> Result<Self> {
+        Self::open(path, config, Box::<SystemClock>::default())
+    }
+
+    /// Verifies a data file without opening the database, i.e. without locking
+    /// or repairing the file, such that it can be used to diagnose a corrupt
+    /// file or one that is in use. Checksums are verified if the log format
+    /// has them. Only the given file is scanned, so keys overwritten or
+    /// deleted in other data files are counted as live.
+    pub fn verify(path: &Path) -> Result<VerifyReport> {
+        let mut log = Log::open_read_only(path)?;
+        let file_length = log.file.len()?;
+        let Some(version) = log.parse_header()? else {
+            let corrupt = file_length > 0;
+            return Ok(VerifyReport {
+                version: VERSION,
+                garbage_bytes: file_length,
+                corrupt_offset: corrupt.then_some(0),
+                error: corrupt.then(|| "Incomplete log header".to_string()),
+                ..Default::default()
+            });
+        };
+        log.version = version;
+
+        let now = SystemClock::default().now();
+        let mut report = VerifyReport {
+            version,
+            ..Default::default()
+        };
+        let mut key_dir = KeyDir::new();
+        let apply = |key, entry| {
+            report.entries += 1;
+            match entry {
+                LogEntry::Value(entry) if !entry.is_expired(now) => {
+                    key_dir.insert(key, entry);
+                }
+                LogEntry::Tombstone => {
+                    report.tombstones += 1;
+                    key_dir.remove(&key);
+                }
+                _ => {
+                    key_dir.remove(&key);
+                }
+            }
+        };
+        let end = log.scan_entries(true, READ_BUFFER_SIZE, apply, &mut |_| {})?;
+        (report.corrupt_offset, report.error) = match end {
+            ScanEnd::Complete => (None, None),
+            ScanEnd::IncompleteBatch(offset) => (Some(offset), Some("Incomplete batch".into())),
+            ScanEnd::IncompleteEntry(offset) => (Some(offset), Some("Incomplete entry".into())),
+            ScanEnd::Corrupt(offset, error) => (Some(offset), Some(error.to_string())),
+        };
+
+        report.live_keys = key_dir.len() as u64;
+        let entry_overhead = log.entry_header_length() + log.checksum_length() as u64;
+        let live_bytes = key_dir
+            .iter()
+            .map(|(key, entry)| entry_overhead + key.len() as u64 + entry.value_length)
+            .sum::<u64>();
+        report.garbage_bytes = file_length.saturating_sub(log.header_length() + live_bytes);
+        Ok(report)
+    }
+
+    /// Writes a human-readable rendering of the data files' entries to the
+    /// writer, for inspecting a live database. Each data file is rendered in
+    /// order, oldest first, including overwritten entries and tombstones. Keys
+    /// and values are shown as strings where they're valid UTF-8, along with
+    /// their bytes in hex.
+    pub fn dump(&mut self, mut writer: impl Write) -> Result<()> {
+        for log in self.old_logs.values().chain(std::iter::once(&self.log)) {
+            writeln!(writer, "file = {}\n", log.id)?;
+            log.print(&mut writer)?;
+        }
+        Ok(())
+    }
+
+    /// Opens a BitCask with the given configuration, calling
+    /// `progress(bytes_scanned, total_bytes)` every few MB while scanning the
+    /// data files to build the key dir, e.g. to show progress when opening a
+    /// large database. It's called a final time with all bytes scanned once
+    /// the key dir is built, also when it's loaded from a hint file.
+    pub fn with_progress(
+        path: PathBuf,
+        config: BitCaskConfig,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<Self> {
+        let clock = Box::<SystemClock>::default();
+        Self::open_with_progress(path, config, clock, &mut progress)
+    }
+
+    /// Opens an existing BitCask read-only, without locking, writing or
+    /// repairing the data files, such that it can be opened while another
+    /// process holds it for writing, e.g. for backups or live inspection.
+    /// Writes return [`Error::ReadOnly`]. The key dir is a snapshot of the
+    /// data files as of opening, skipping entries that are still being
+    /// written, and later writes by other processes are not visible.
+    /// Compaction or roll-over by the writer may remove data files from under
+    /// the handle, which makes reads fail.
+    pub fn open_read_only(path: PathBuf) -> Result<Self> {
+        let config = BitCaskConfig {
+            read_only: true,
+            ..Default::default()
+        };
+        Self::with_config(path, config)
+    }
+
+    /// Opens a BitCask that compacts the data files on open if the ratio of
+    /// garbage to their total size is at least the given threshold.
+    pub fn new_compact(path: PathBuf, garbage_ratio_threshold: f64) -> Result<Self> {
+        let config = BitCaskConfig {
+            compaction_threshold: Some(garbage_ratio_threshold),
+            ..Default::default()
+        };
+        Self::with_config(path, config)
+    }
+
+    /// Opens a BitCask over a custom store instead of a data file, e.g. an
+    /// in-memory buffer or a file backed by an object store, see
+    /// [`ReadWriteSeek`]. The store is the only data file, so options that
+    /// need further files next to it are rejected with [`Error::Config`]:
+    /// hint files, roll-over, compaction, memory-mapping and the
+    /// [`RecoveryPolicy::Repair`] sidecar file.
+    pub fn with_file(file: impl ReadWriteSeek + 'static, config: BitCaskConfig) -> Result<Self> {
+        let compaction = config.compaction_threshold.is_some()
+            || config.compaction_tombstone_count.is_some()
+            || config.compaction_tombstone_bytes.is_some()
+            || config.compact_on_drop.is_some();
+        #[cfg(feature = "mmap")]
+        let mmap = config.mmap;
+        #[cfg(not(feature = "mmap"))]
+        let mmap = false;
+        let unsupported = [
+            ("Hint files", config.hint_file),
+            ("Roll-over", config.max_file_size.is_some()),
+            ("Compaction", compaction),
+            ("Memory-mapping", mmap),
+            ("Repair", config.recovery_policy == RecoveryPolicy::Repair),
+        ];
+        if let Some((option, _)) = unsupported.iter().find(|(_, enabled)| *enabled) {
+            return Err(Error::Config(format!("{option} requires a data file path")));
+        }
+        let log = Log::with_file(PathBuf::new(), Box::new(file), 1)?;
+        let clock = Box::<SystemClock>::default();
+        Self::load(log, OldLogs::new(), config, clock, &mut |_, _| {})
+    }
+
+    /// Opens a BitCask with the given configuration and clock, which is used
+    /// to expire keys, e.g. a test clock. The key dir is loaded from the hint
+    /// file if it's valid and otherwise built by scanning the data files.
+    /// Checksums are not verified when loading from a hint file.
+    pub fn open(path: PathBuf, config: BitCaskConfig, clock: Box<dyn Clock>) -> Result<Self> {
+        Self::open_with_progress(path, config, clock, &mut |_, _| {})
+    }
+
+    /// Like [`BitCask::open`], reporting scan progress to `progress`, see
+    /// [`BitCask::with_progress`].
+    fn open_with_progress(
+        path: PathBuf,
+        config: BitCaskConfig,
+        clock: Box<dyn Clock>,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<Self> {
+        let read_only = config.read_only;
+        let open_log = match read_only {
+            true => Log::open_unlocked,
+            false => Log::new,
+        };
+        let mut old_logs = OldLogs::new();
+        for id in old_log_ids(&path)? {
+            old_logs.insert(id, open_log(old_log_path(&path, id), id)?);
+        }
+        let id = old_logs.keys().last().map_or(1, |id| id + 1);
+        let log = open_log(path, id)?;
+        // A crash during a compaction leaves the compacted file behind, but
+        // the data files are still intact, so just remove it.
+        if !read_only {
+            remove_leftover(&compaction_path(&log.path))?;
+        }
+        Self::load(log, old_logs, config, clock, progress)
+    }
+
+    /// Loads the key dir of opened data files, see
+    /// [`BitCask::open_with_progress`].
+    fn load(
+        mut log: Log,
+        mut old_logs: OldLogs,
+        config: BitCaskConfig,
+        clock: Box<dyn Clock>,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<Self> {
+        let verify_checksums = config.verify_checksums;
+        let read_only = config.read_only;
+        let mut total = 0;
+        for log in old_logs.values().chain(std::iter::once(&log)) {
+            total += log.file.len()?;
+        }
+        let key_dir = match log.read_hint(&old_logs, clock.now())? {
+            Some(key_dir) => key_dir,
+            None => {
+                let mut key_dir = KeyDir::new();
+                let (mut scanned, mut next_progress) = (0, PROGRESS_INTERVAL);
+                for log in old_logs.values_mut().chain(std::iter::once(&mut log)) {
+                    key_dir = log.build_key_dir(
+                        key_dir,
+                        verify_checksums,
+                        clock.now(),
+                        config.read_buffer_size,
+                        (!read_only).then_some(config.recovery_policy),
+                        &mut |offset| {
+                            if scanned + offset >= next_progress {
+                                progress(scanned + offset, total);
+                                next_progress = scanned + offset + PROGRESS_INTERVAL;
+                            }
+                        },
+                    )?;
+                    scanned += log.file.len()?;
+                }
+                key_dir
+            }
+        };
+        progress(total, total);
+        let last_sync = clock.now();
+        let cache_size = config.cache_size;
+        let bloom = match config.bloom_false_positive_rate {
+            Some(rate) if !config.hashed_key_dir => Some(BloomFilter::new(0, rate)?),
+            _ => None,
+        };
+        let mut bit_cask = Self {
+            log,
+            old_logs,
+            key_dir: KeyDir::new(),
+            hashed_key_dir: config.hashed_key_dir.then(HashedKeyDir::new),
+            stats: KeyDirStats::default(),
+            expiring: std::collections::BTreeSet::new(),
+            verify_checksums,
+            clock,
+            write_hint: config.hint_file,
+            sync_policy: config.sync_policy,
+            last_sync,
+            writes_since_sync: 0,
+            bytes_since_sync: 0,
+            max_file_size: config.max_file_size,
+            compacting: false,
+            keep_failed_compaction: config.keep_failed_compaction,
+            cache: (cache_size > 0).then(|| Mutex::new(ValueCache::new(cache_size))),
+            max_key_size: config.max_key_size,
+            max_value_size: config.max_value_size,
+            compact_on_drop: config.compact_on_drop.filter(|_| !read_only),
+            compaction_threshold: config.compaction_threshold.filter(|_| !read_only),
+            read_only,
+            bloom,
+            #[cfg(test)]
+            value_reads: AtomicU64::new(0),
+            // Another process may truncate the data files, which would crash
+            // reads from a mapping.
+            #[cfg(feature = "mmap")]
+            mmap: config.mmap && !read_only,
+        };
+        bit_cask.set_key_dir(key_dir)?;
+
+        #[cfg(feature = "mmap")]
+        if bit_cask.mmap {
+            bit_cask.log.map()?;
+            for log in bit_cask.old_logs.values_mut() {
+                log.map()?;
+            }
+        }
+        if let Some(threshold) = bit_cask.compaction_threshold {
+            bit_cask.compact_if_garbage(threshold)?;
+        }
+        if !read_only {
+            bit_cask.compact_if_tombstones(
+                config.compaction_tombstone_count,
+                config.compaction_tombstone_bytes,
+            )?;
+        }
+        Ok(bit_cask)
+    }
+
+    /// Compacts the data files if the ratio of garbage to their total size is
+    /// at least the given threshold.
+    fn compact_if_garbage(&mut self, garbage_ratio_threshold: f64) -> Result<()> {
+        let status = self.status()?;
+        let garbage_ratio = status.garbage_disk_size as f64 / status.total_disk_size as f64;
+        if garbage_reached(&status, garbage_ratio_threshold) {
+            log::info!(
+                "Compacting {} to remove {:.3}MB garbage ({:.0}% of {:.3}MB)",
+                self.log.path.display(),
+                status.garbage_disk_size / 1048576,
+                garbage_ratio * 100.0,
+                status.total_disk_size / 1048576,
+            );
+            self.compact()?;
+            log::info!("Compacted");
+        }
+        Ok(())
+    }
+
+    /// Compacts the data files if their tombstones reach the given count or
+    /// size threshold, if any.
+    fn compact_if_tombstones(&mut self, count: Option<u64>, bytes: Option<u64>) -> Result<()> {
+        let (tombstone_count, tombstone_bytes) = self.tombstones();
+        let exceeded = tombstone_count > 0
+            && (count.is_some_and(|count| tombstone_count >= count)
+                || bytes.is_some_and(|bytes| tombstone_bytes >= bytes));
+        if exceeded {
+            log::info!(
+                "Compacting {} to remove {tombstone_count} tombstones ({tombstone_bytes} bytes)",
+                self.log.path.display(),
+            );
+            self.compact()?;
+            log::info!("Compacted");
+        }
+        Ok(())
+    }
+
+    /// Sets a key that expires after the given time to live. Expired keys are
+    /// no longer visible, and are removed from the log on compaction.
+    pub fn set_with_ttl(&mut self, key: &[u8], value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expiry = self.clock.now().saturating_add(ttl.as_millis() as u64);
+        self.set_entry(key, value, Some(expiry))
+    }
+
+    /// Loads many key/value pairs, e.g. when restoring a backup, returning
+    /// the number of pairs loaded. The entries are appended to the active
+    /// data file in large buffered writes, and the key dir is only
+    /// updated once at the end, followed by a single fsync. This is much
+    /// faster than calling [`Engine::set`] for each pair. Later pairs win
+    /// over earlier pairs with the same key.
+    ///
+    /// The pairs should ideally be sorted by key, such that the values are
+    /// laid out in key order for scans and subsequent compactions. If loading
+    /// fails, the entries written so far are truncated away, but a crash
+    /// during loading may leave some of them behind, like individual sets.
+    pub fn bulk_load(&mut self, pairs: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<u64> {
+        self.check_writable()?;
+        let start = self.log.file.seek(SeekFrom::End(0))?;
+        let entries = match self.write_bulk(start, pairs) {
+            Ok(entries) => entries,
+            Err(error) => return Err(self.log.truncate_after_error(start, error)),
+        };
+
+        let count = entries.len() as u64;
+        for (key, entry) in entries {
+            self.invalidate(&key);
+            self.key_dir_insert(key, entry)?;
+        }
+        self.rebuild_stats()?;
+        self.sync()?;
+        self.maybe_roll_over()?;
+        Ok(count)
+    }
+
+    /// Writes the pairs of a bulk load to the active data file at the given
+    /// offset, returning their key dir entries.
+    fn write_bulk(
+        &mut self,
+        mut offset: u64,
+        pairs: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<Vec<(Vec<u8>, KeyDirEntry)>> {
+        let header_length = self.log.entry_header_length();
+        let mut entries = Vec::new();
+        let mut buffer = Vec::with_capacity(READ_BUFFER_SIZE);
+        for (key, value) in pairs {
+            check_size(&key, value.len(), self.max_key_size, self.max_value_size)?;
+            let value_length = self.log.check_value_length(value.len())?;
+            let length = buffer.len();
+            self.log
+                .encode_entry(&mut buffer, &key, value_length, None, &value);
+            let entry = KeyDirEntry {
+                file_id: self.log.id,
+                value_offset: offset + header_length + key.len() as u64,
+                value_length: value.len() as u64,
+                expiry: None,
+            };
+            offset += (buffer.len() - length) as u64;
+            entries.push((key, entry));
+            if buffer.len() >= READ_BUFFER_SIZE {
+                self.log
+                    .write_entries(offset - buffer.len() as u64, &buffer)?;
+                buffer.clear();
+            }
+        }
+        self.log
+            .write_entries(offset - buffer.len() as u64, &buffer)?;
+        Ok(entries)
+    }
+
+    /// Fsyncs the log after a write of the given number of bytes, if required
+    /// by the sync policy.
+    fn sync_after_write(&mut self, bytes: u64) -> Result<()> {
+        self.writes_since_sync += 1;
+        self.bytes_since_sync += bytes;
+        let due = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::Interval(interval) => {
+                self.clock.now().saturating_sub(self.last_sync) >= interval.as_millis() as u64
+            }
+            SyncPolicy::Threshold { writes, bytes } => {
+                self.writes_since_sync >= writes || self.bytes_since_sync >= bytes
+            }
+        };
+        if due {
+            self.sync_log()?;
+        }
+        Ok(())
+    }
+
+    /// Fsyncs the active data file, resetting the sync counters.
+    fn sync_log(&mut self) -> Result<()> {
+        self.log.file.sync_all()?;
+        self.last_sync = self.clock.now();
+        self.writes_since_sync = 0;
+        self.bytes_since_sync = 0;
+        Ok(())
+    }
+
+    /// Returns [`Error::ReadOnly`] if the data files were opened read-only.
+    fn check_writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    fn set_entry(&mut self, key: &[u8], value: Vec<u8>, expiry: Option<u64>) -> Result<()> {
+        self.check_writable()?;
+        check_size(key, value.len(), self.max_key_size, self.max_value_size)?;
+        let (offset, length) = self.log.append_entry(key, Some(&value), expiry)?;
+        self.invalidate(key);
+        self.insert_entry(
+            key.to_vec(),
+            KeyDirEntry {
+                file_id: self.log.id,
+                value_offset: offset + self.log.entry_header_length() + key.len() as u64,
+                value_length: value.len() as u64,
+                expiry,
+            },
+        )?;
+        self.sync_after_write(length)?;
+        self.maybe_roll_over()
+    }
+
+    /// Returns the stats of a key dir entry with a key of the given length.
+    fn entry_stats(&self, key_length: usize, entry: &KeyDirEntry) -> KeyDirStats {
+        let size = key_length as u64 + entry.value_length;
+        // Each entry has the fixed-size fields and checksum in addition to the
+        // key and value.
+        let log = self.log(entry.file_id);
+        KeyDirStats {
+            key_count: 1,
+            size,
+            disk_size: log.entry_header_length() + log.checksum_length() as u64 + size,
+        }
+    }
+
+    /// Inserts a key dir entry, updating the stats.
+    fn insert_entry(&mut self, key: Vec<u8>, entry: KeyDirEntry) -> Result<()> {
+        self.remove_entry(&key)?;
+        self.stats.add(self.entry_stats(key.len(), &entry));
+        if let Some(expiry) = entry.expiry {
+            self.expiring.insert((expiry, key.clone()));
+        }
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(&key);
+        }
+        match &mut self.hashed_key_dir {
+            Some(hashed) => hashed.insert_new(&key, entry),
+            None => {
+                self.key_dir.insert(key, entry);
+            }
+        }
+        if self.bloom.as_ref().is_some_and(|bloom| bloom.is_full()) {
+            self.rebuild_bloom();
+        }
+        Ok(())
+    }
+
+    /// Removes a key dir entry, updating the stats.
+    fn remove_entry(&mut self, key: &[u8]) -> Result<()> {
+        if let Some(entry) = self.key_dir_remove(key)? {
+            self.stats.sub(self.entry_stats(key.len(), &entry));
+            if let Some(expiry) = entry.expiry {
+                self.expiring.remove(&(expiry, key.to_vec()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Recomputes the stats and bloom filter from scratch, after the key dir
+    /// was replaced or its entries moved between data files, e.g. by
+    /// compaction.
+    fn rebuild_stats(&mut self) -> Result<()> {
+        let mut stats = KeyDirStats::default();
+        for (key_length, entry) in self.key_dir_entries() {
+            stats.add(self.entry_stats(key_length, entry));
+        }
+        self.stats = stats;
+        self.expiring = (self.key_dir.iter())
+            .filter_map(|(key, entry)| Some((entry.expiry?, key.clone())))
+            .collect();
+        if let Some(hashed) = &self.hashed_key_dir {
+            for hashed in hashed.iter() {
+                if let Some(expiry) = hashed.entry.expiry {
+                    let key = self.log(hashed.entry.file_id).read_key(hashed)?;
+                    self.expiring.insert((expiry, key));
+                }
+            }
+        }
+        self.rebuild_bloom();
+        Ok(())
+    }
+
+    /// Replaces the key dir, hashing it if the key dir is hashed, and
+    /// rebuilds the stats.
+    fn set_key_dir(&mut self, key_dir: KeyDir) -> Result<()> {
+        match &mut self.hashed_key_dir {
+            Some(hashed) => {
+                hashed.clear();
+                for (key, entry) in key_dir {
+                    hashed.insert_new(&key, entry);
+                }
+            }
+            None => self.key_dir = key_dir,
+        }
+        self.rebuild_stats()
+    }
+
+    /// Returns the number of entries in the key dir, including expired ones.
+    fn key_dir_len(&self) -> usize {
+        match &self.hashed_key_dir {
+            Some(hashed) => hashed.len(),
+            None => self.key_dir.len(),
+        }
+    }
+
+    /// Returns the key length and entry of each key dir entry, without
+    /// reading any keys. Hashed key dir entries are in arbitrary order.
+    fn key_dir_entries(&self) -> impl Iterator<Item = (usize, &KeyDirEntry)> {
+        let hashed = self.hashed_key_dir.iter().flat_map(HashedKeyDir::iter);
+        (self.key_dir.iter())
+            .map(|(key, entry)| (key.len(), entry))
+            .chain(hashed.map(|hashed| (hashed.key_length as usize, &hashed.entry)))
+    }
+
+    /// Looks up a key's key dir entry, including expired ones.
+    fn key_dir_get(&self, key: &[u8]) -> Result<Option<KeyDirEntry>> {
+        match &self.hashed_key_dir {
+            Some(hashed) => hashed.get(key, |hashed| {
+                self.log(hashed.entry.file_id).read_key(hashed)
+            }),
+            None => Ok(self.key_dir.get(key).copied()),
+        }
+    }
+
+    /// Inserts or replaces a key's key dir entry, without updating the stats.
+    fn key_dir_insert(&mut self, key: Vec<u8>, entry: KeyDirEntry) -> Result<()> {
+        let Self {
+            log,
+            old_logs,
+            key_dir,
+            hashed_key_dir,
+            ..
+        } = self;
+        match hashed_key_dir {
+            Some(hashed) => {
+                hashed.insert(&key, entry, |hashed| {
+                    let id = hashed.entry.file_id;
+                    old_logs.get(&id).unwrap_or(log).read_key(hashed)
+                })?;
+            }
+            None => {
+                key_dir.insert(key, entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a key's key dir entry, without updating the stats.
+    fn key_dir_remove(&mut self, key: &[u8]) -> Result<Option<KeyDirEntry>> {
+        let Self {
+            log,
+            old_logs,
+            key_dir,
+            hashed_key_dir,
+            ..
+        } = self;
+        match hashed_key_dir {
+            Some(hashed) => hashed.remove(key, |hashed| {
+                let id = hashed.entry.file_id;
+                old_logs.get(&id).unwrap_or(log).read_key(hashed)
+            }),
+            None => Ok(key_dir.remove(key)),
+        }
+    }
+
+    /// Returns whether the key's key dir entry is the given entry.
+    fn key_dir_contains(&self, key: &[u8], entry: &KeyDirEntry) -> bool {
+        match &self.hashed_key_dir {
+            Some(hashed) => hashed.position(key, entry).is_some(),
+            None => self.key_dir.get(key) == Some(entry),
+        }
+    }
+
+    /// Returns the key dir entries in the range, in key order, including
+    /// expired ones. With a hashed key dir, this reads all keys from the data
+    /// files and sorts the ones in the range.
+    fn key_dir_range<T>(&self, range: impl std::ops::RangeBounds<T>) -> KeyDirRange<'_>
+    where
+        T: Ord + ?Sized,
+        Vec<u8>: std::borrow::Borrow<T>,
+    {
+        let Some(hashed) = &self.hashed_key_dir else {
+            return KeyDirRange::Ordered(self.key_dir.range(range));
+        };
+        let mut entries = Vec::new();
+        for hashed in hashed.iter() {
+            match self.log(hashed.entry.file_id).read_key(hashed) {
+                Ok(key) if range.contains(std::borrow::Borrow::<T>::borrow(&key)) => {
+                    entries.push((key, hashed.entry))
+                }
+                Ok(_) => {}
+                Err(error) => return KeyDirRange::Sorted(vec![Err(error)].into_iter()),
+            }
+        }
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        KeyDirRange::Sorted(entries.into_iter().map(Ok).collect::<Vec<_>>().into_iter())
+    }
+
+    /// Returns the key dir with its keys, borrowed or, for a hashed key dir,
+    /// read from the data files.
+    fn sorted_key_dir(&self) -> Result<Cow<'_, KeyDir>> {
+        let Some(hashed) = &self.hashed_key_dir else {
+            return Ok(Cow::Borrowed(&self.key_dir));
+        };
+        let mut key_dir = KeyDir::new();
+        for hashed in hashed.iter() {
+            let key = self.log(hashed.entry.file_id).read_key(hashed)?;
+            key_dir.insert(key, hashed.entry);
+        }
+        Ok(Cow::Owned(key_dir))
+    }
+
+    /// Writes the hint file with the key dir, see [`Log::write_hint`].
+    fn write_hint(&self) -> Result<()> {
+        self.log
+            .write_hint(&self.old_logs, &*self.sorted_key_dir()?)
+    }
+
+    /// Rebuilds the bloom filter, if enabled, from the keys in the key dir,
+    /// sized for twice as many keys. This drops deleted keys from it.
+    fn rebuild_bloom(&mut self) {
+        let Some(bloom) = &self.bloom else {
+            return;
+        };
+        let mut bloom = bloom.with_capacity(MIN_BLOOM_CAPACITY.max(2 * self.key_dir.len()));
+        for key in self.key_dir.keys() {
+            bloom.insert(key);
+        }
+        self.bloom = Some(bloom);
+    }
+
+    /// Returns whether the key may be in the key dir. False if the bloom
+    /// filter is enabled and rules the key out.
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.bloom.as_ref().is_none_or(|bloom| bloom.contains(key))
+    }
+
+    /// Looks up the key dir entry of a live key, removing it if it has
+    /// expired.
+    fn live_entry(&mut self, key: &[u8]) -> Result<Option<KeyDirEntry>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        match self.key_dir_get(key)? {
+            Some(entry) if entry.is_expired(self.clock.now()) => {
+                self.remove_entry(key)?;
+                self.invalidate(key);
+                Ok(None)
+            }
+            entry => Ok(entry),
+        }
+    }
+
+    /// Returns the data file with the given id.
+    fn log(&self, file_id: u32) -> &Log {
+        match self.old_logs.get(&file_id) {
+            Some(log) => log,
+            None => &self.log,
+        }
+    }
+
+    /// Scans the key dir range, with owned or borrowed bounds.
+    fn scan_range<T>(&self, range: impl std::ops::RangeBounds<T>) -> ScanIterator<'_>
+    where
+        T: Ord + ?Sized,
+        Vec<u8>: std::borrow::Borrow<T>,
+    {
+        use std::ops::Bound::Unbounded;
+        let now = self.clock.now();
+        let remaining = match (range.start_bound(), range.end_bound()) {
+            (Unbounded, Unbounded) => {
+                let expired = self
+                    .expiring
+                    .iter()
+                    .take_while(|(expiry, _)| *expiry <= now)
+                    .count();
+                Some(self.key_dir_len() - expired)
+            }
+            _ => None,
+        };
+        #[cfg(feature = "mmap")]
+        let mmap = self.mmap;
+        #[cfg(not(feature = "mmap"))]
+        let mmap = false;
+        ScanIterator {
+            inner: self.key_dir_range(range),
+            bitcask: self,
+            now,
+            remaining,
+            read_ahead: self.cache.is_none() && !mmap,
+            buffer: None,
+            #[cfg(test)]
+            reads: 0,
+        }
+    }
+
+    /// Returns all data files, oldest first.
+    fn logs(&self) -> impl Iterator<Item = &Log> {
+        self.old_logs.values().chain(std::iter::once(&self.log))
+    }
+
+    /// Reads the value of a key dir entry from the cache, or otherwise from its
+    /// data file and caches it.
+    fn read_value(&self, key: &[u8], entry: &KeyDirEntry) -> Result<Vec<u8>> {
+        #[cfg(test)]
+        self.value_reads.fetch_add(1, Ordering::Relaxed);
+        let log = self.log(entry.file_id);
+        let Some(cache) = &self.cache else {
+            return log.read_value(key, entry, self.verify_checksums);
+        };
+        if let Some(value) = lock_cache(cache).get(key) {
+            return Ok(value);
+        }
+        let value = log.read_value(key, entry, self.verify_checksums)?;
+        lock_cache(cache).insert(key, &value);
+        Ok(value)
+    }
+
+    /// Removes a key from the value cache, if enabled. Must be called whenever
+    /// the key is written or removed.
+    fn invalidate(&self, key: &[u8]) {
+        if let Some(cache) = &self.cache {
+            lock_cache(cache).remove(key);
+        }
+    }
+
+    /// Rolls the active data file over to a new one if it has reached the
+    /// maximum file size.
+    fn maybe_roll_over(&mut self) -> Result<()> {
+        match self.max_file_size {
+            Some(max_file_size) if self.log.file.len()? >= max_file_size => self.roll_over(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rolls the active data file over to a new one. The active file is
+    /// fsynced and renamed to its immutable path, keeping its id.
+    fn roll_over(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.sync_log()?;
+
+        let id = self.log.id;
+        let old_path = old_log_path(&self.log.path, id);
+        std::fs::rename(&self.log.path, &old_path)?;
+        let log = match Log::new(self.log.path.clone(), id + 1) {
+            Ok(log) => log,
+            Err(error) => {
+                std::fs::rename(&old_path, &self.log.path)?;
+                return Err(error);
+            }
+        };
+        let mut old_log = std::mem::replace(&mut self.log, log);
+        log::info!(
+            "Rolled over {} to {}",
+            old_log.path.display(),
+            old_path.display()
+        );
+        old_log.path = old_path;
+        self.old_logs.insert(id, old_log);
+        #[cfg(feature = "mmap")]
+        if self.mmap {
+            self.log.map()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the data files, removing garbage and expired entries. If there
+    /// are immutable data files, only these are merged into a single file,
+    /// leaving the active data file alone. Otherwise, the active data file is
+    /// rewritten.
+    pub fn compact(&mut self) -> Result<()> {
+        let size = self.disk_size()?;
+        log::debug!("Compacting {}: size={size}", self.log.path.display());
+        let start = Instant::now();
+        let compaction = self.prepare_compaction()?;
+        let result = compaction.run();
+        self.finish_compaction(compaction, result)?;
+        let compacted_size = self.disk_size()?;
+        log::debug!(
+            "Compacted {}: duration={:?} size={size} compacted_size={compacted_size} reclaimed={}",
+            self.log.path.display(),
+            start.elapsed(),
+            size.saturating_sub(compacted_size),
+        );
+        Ok(())
+    }
+
+    /// Returns the number and total size in bytes of the tombstones in the
+    /// data files. Tombstones are only counted for data files that were
+    /// scanned on open or written since, not ones loaded from a hint file.
+    fn tombstones(&self) -> (u64, u64) {
+        (self.logs()).fold((0, 0), |(count, bytes), log| {
+            (count + log.tombstone_count, bytes + log.tombstone_bytes)
+        })
+    }
+
+    /// Returns the total size of the data files, in bytes.
+    fn disk_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for log in self.logs() {
+            size += log.file.len()?;
+        }
+        Ok(size)
+    }
+
+    /// Checks that the key dir matches the data files, by scanning them to
+    /// build a fresh key dir and comparing it with the in-memory one, and that
+    /// the incrementally maintained stats match the key dir. Returns
+    /// [`Error::Internal`] describing the first divergent key otherwise. This
+    /// scans all data files, so it's as expensive as opening the database,
+    /// and is intended for tests and diagnostics.
+    pub fn validate_key_dir(&self) -> Result<()> {
+        let now = self.clock.now();
+        let mut key_dir = KeyDir::new();
+        for log in self.logs() {
+            let apply = |key, entry| match entry {
+                LogEntry::Value(entry) if !entry.is_expired(now) => {
+                    key_dir.insert(key, entry);
+                }
+                _ => {
+                    key_dir.remove(&key);
+                }
+            };
+            match log.scan_entries(self.verify_checksums, READ_BUFFER_SIZE, apply, &mut |_| {})? {
+                ScanEnd::Complete => {}
+                ScanEnd::IncompleteBatch(offset) | ScanEnd::IncompleteEntry(offset) => {
+                    return Err(Error::Internal(format!(
+                        "Incomplete entry at offset {offset} in {}",
+                        log.path.display()
+                    )))
+                }
+                ScanEnd::Corrupt(_, error) => return Err(error),
+            }
+        }
+
+        let sorted_key_dir = self.sorted_key_dir()?;
+        let mut memory = (sorted_key_dir.iter()).filter(|(_, entry)| !entry.is_expired(now));
+        let mut disk = key_dir.iter();
+        let (mut next_memory, mut next_disk) = (memory.next(), disk.next());
+        loop {
+            let error = match (next_memory, next_disk) {
+                (None, None) => break,
+                (Some((key, memory_entry)), Some((disk_key, disk_entry))) if key == disk_key => {
+                    if memory_entry != disk_entry {
+                        format!(
+                            "Key {} is {memory_entry:?} in the key dir, but {disk_entry:?} in the data files",
+                            key.escape_ascii()
+                        )
+                    } else {
+                        (next_memory, next_disk) = (memory.next(), disk.next());
+                        continue;
+                    }
+                }
+                (Some((key, _)), Some((disk_key, _))) if key < disk_key => {
+                    format!("Key {} is missing from the data files", key.escape_ascii())
+                }
+                (Some((key, _)), None) => {
+                    format!("Key {} is missing from the data files", key.escape_ascii())
+                }
+                (_, Some((key, _))) => {
+                    format!("Key {} is missing from the key dir", key.escape_ascii())
+                }
+            };
+            return Err(Error::Internal(error));
+        }
+
+        let mut stats = KeyDirStats::default();
+        for (key_length, entry) in self.key_dir_entries() {
+            stats.add(self.entry_stats(key_length, entry));
+        }
+        if stats != self.stats {
+            return Err(Error::Internal(format!(
+                "Key dir stats are {:?}, but should be {stats:?}",
+                self.stats
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the on-disk size of each key's stale entries, i.e. overwritten
+    /// or expired values and tombstones, which compaction would remove. This
+    /// scans all data files, so it's as expensive as opening the database.
+    pub fn key_garbage(&self, keys: &[Vec<u8>]) -> Result<Vec<u64>> {
+        Ok(self
+            .stale_entries(keys)?
+            .into_iter()
+            .map(|(size, _)| size)
+            .collect())
+    }
+
+    /// Removes the stale entries of the given keys, see
+    /// [`BitCask::key_garbage`], while rewriting as few data files as
+    /// possible. Data files are append-only, so this compacts the oldest data
+    /// files through the newest one holding stale entries of the keys, which
+    /// also removes any other garbage in them. Newer data files are left
+    /// alone, and the active data file is rolled over first if it holds stale
+    /// entries. Nothing is rewritten if the keys have no stale entries.
+    pub fn compact_keys(&mut self, keys: &[Vec<u8>]) -> Result<()> {
+        let stale = self.stale_entries(keys)?;
+        let Some(last_id) = stale.into_iter().filter_map(|(_, id)| id).max() else {
+            return Ok(());
+        };
+        if last_id == self.log.id {
+            self.roll_over()?;
+        }
+        let compaction = self.prepare_compaction_through(last_id)?;
+        let result = compaction.run();
+        self.finish_compaction(compaction, result)
+    }
+
+    /// Deletes the keys in a single write batch, then compacts the data files
+    /// if the garbage ratio has reached the configured compaction threshold,
+    /// see [`BitCaskConfig::compaction_threshold`], or if there's any garbage
+    /// when no threshold is configured. This reclaims the space of bulk
+    /// deletes right away, e.g. for cleanup jobs that want to bound disk
+    /// usage.
+    pub fn delete_many_and_compact(&mut self, keys: &[Vec<u8>]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.delete(key);
+        }
+        self.write_batch(batch)?;
+
+        let threshold = self.compaction_threshold.unwrap_or(0.0);
+        if !garbage_reached(&self.status()?, threshold) {
+            return Ok(());
+        }
+        // Compaction leaves the active data file alone if there are immutable
+        // ones, so roll it over to compact the deletes and their garbage too.
+        if !self.old_logs.is_empty() && self.log.file.len()? > self.log.header_length() {
+            self.roll_over()?;
+        }
+        self.compact_if_garbage(threshold)
+    }
+
+    /// Replaces the database contents with a prepared data file, e.g. a
+    /// downloaded backup or a file written by [`BitCask::compact`]. The source
+    /// file must parse as a complete, valid log, otherwise an error is
+    /// returned and the database is left untouched. It's then fsynced and
+    /// renamed over the active data file, so it's moved rather than copied
+    /// and must be on the same filesystem. The source file is locked before
+    /// the swap and the previous active data file's lock only released after
+    /// it, so the data file path stays locked throughout.
+    ///
+    /// The swap of the active data file is atomic, but any immutable data
+    /// files are removed afterwards, so if interrupted by a crash some of
+    /// their entries may reappear alongside the restored ones.
+    pub fn replace_from(&mut self, source_path: &Path) -> Result<()> {
+        self.check_writable()?;
+        if self.log.is_custom() {
+            return Err(Error::Config(
+                "Replacing requires a data file path".to_string(),
+            ));
+        }
+        if self.compacting {
+            return Err(Error::Internal("Compaction in progress".to_string()));
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(source_path)?;
+        let mut new_log = Log::with_file(source_path.to_path_buf(), Box::new(file), self.log.id)?;
+        let key_dir = new_log.build_key_dir(
+            KeyDir::new(),
+            true,
+            self.clock.now(),
+            READ_BUFFER_SIZE,
+            Some(RecoveryPolicy::Error),
+            &mut |_| {},
+        )?;
+
+        new_log.file.sync_all()?;
+        self.log.remove_hint()?;
+        let path = self.log.path.clone();
+        sync_dir(source_path)?;
+        std::fs::rename(source_path, &path)?;
+        sync_dir(&path)?;
+        new_log.path = path;
+        self.log = new_log;
+        for (_, log) in std::mem::take(&mut self.old_logs) {
+            let path = log.path.clone();
+            drop(log);
+            std::fs::remove_file(path)?;
+        }
+
+        self.set_key_dir(key_dir)?;
+        if let Some(cache) = &self.cache {
+            lock_cache(cache).clear();
+        }
+        #[cfg(feature = "mmap")]
+        if self.mmap {
+            self.log.map()?;
+        }
+        Ok(())
+    }
+
+    /// Scans the data files for the stale entries of the given keys,
+    /// returning their total on-disk size and the newest data file holding
+    /// any of them for each key.
+    fn stale_entries(&self, keys: &[Vec<u8>]) -> Result<Vec<(u64, Option<u32>)>> {
+        let index = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.as_slice(), i))
+            .collect::<std::collections::HashMap<_, _>>();
+        let mut stale = vec![(0, None); keys.len()];
+        self.for_each_stale_entry(|key, size, id| {
+            if let Some(&i) = index.get(key) {
+                stale[i].0 += size;
+                stale[i].1 = Some(id);
+            }
+        })?;
+        Ok(stale)
+    }
+
+    /// Scans the data files for stale entries, i.e. overwritten or expired
+    /// values and tombstones, calling the function with the key, on-disk size
+    /// and data file id of each.
+    fn for_each_stale_entry(&self, mut f: impl FnMut(&[u8], u64, u32)) -> Result<()> {
+        let now = self.clock.now();
+        for log in self.logs() {
+            let overhead = log.entry_header_length() + log.checksum_length() as u64;
+            let apply = |key: Vec<u8>, entry| {
+                let size = match entry {
+                    LogEntry::Value(entry) => {
+                        if self.key_dir_contains(&key, &entry) && !entry.is_expired(now) {
+                            return;
+                        }
+                        overhead + key.len() as u64 + entry.value_length
+                    }
+                    LogEntry::Tombstone => overhead + key.len() as u64,
+                    LogEntry::Batch(_) => return,
+                };
+                f(&key, size, log.id);
+            };
+            let end = log.scan_entries(false, READ_BUFFER_SIZE, apply, &mut |_| {})?;
+            if let ScanEnd::Corrupt(_, error) = end {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawns a thread that compacts the database in the background every
+    /// interval, if the garbage ratio has reached the given threshold.
+    ///
+    /// The active data file is rolled over before each compaction, and the
+    /// immutable data files are then merged without holding the database
+    /// lock, such that writes can continue. The lock is only held while
+    /// preparing the compaction and while swapping in the compacted file.
+    ///
+    /// The thread exits when stopped via the returned handle, when the
+    /// database is dropped, or when a compaction fails.
+    pub fn spawn_compactor(
+        db: &Arc<Mutex<Self>>,
+        garbage_ratio_threshold: f64,
+        interval: Duration,
+    ) -> Compactor {
+        let db = Arc::downgrade(db);
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || loop {
+            if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(interval) {
+                return Ok(());
+            }
+            let Some(db) = db.upgrade() else {
+                return Ok(());
+            };
+            if let Err(error) = Self::compact_in_background(&db, garbage_ratio_threshold) {
+                log::error!("Background compaction failed: {error}");
+                return Err(error);
+            }
+        });
+        Compactor { stop, thread }
+    }
+
+    /// Compacts the immutable data files if the garbage ratio has reached the
+    /// threshold, only locking the database to prepare and finish.
+    fn compact_in_background(db: &Mutex<Self>, garbage_ratio_threshold: f64) -> Result<()> {
+        let lock = || db.lock().unwrap_or_else(|error| error.into_inner());
+        let compaction = {
+            let mut bit_cask = lock();
+            let status = bit_cask.status()?;
+            if !garbage_reached(&status, garbage_ratio_threshold) {
+                return Ok(());
+            }
+            let garbage_ratio = status.garbage_disk_size as f64 / status.total_disk_size as f64;
+            // Move all garbage into immutable data files, such that writes
+            // can continue in a new active data file while compacting.
+            if bit_cask.log.file.len()? > bit_cask.log.header_length() {
+                bit_cask.roll_over()?;
+            }
+            if bit_cask.old_logs.is_empty() {
+                return Ok(());
+            }
+            log::info!(
+                "Compacting {} in the background to remove {:.3}MB garbage ({:.0}% of {:.3}MB)",
+                bit_cask.log.path.display(),
+                status.garbage_disk_size / 1048576,
+                garbage_ratio * 100.0,
+                status.total_disk_size / 1048576,
+            );
+            bit_cask.prepare_compaction()?
+        };
+        let result = compaction.run();
+        lock().finish_compaction(compaction, result)?;
+        log::info!("Compacted");
+        Ok(())
+    }
+
+    /// Prepares a compaction of the immutable data files, or of the active
+    /// data file if there are none, by taking a snapshot of their key dir
+    /// entries and read handles.
+    fn prepare_compaction(&mut self) -> Result<Compaction> {
+        let last_id = self.old_logs.keys().last().copied();
+        self.prepare_compaction_through(last_id.unwrap_or(self.log.id))
+    }
+
+    /// Prepares a compaction of the oldest data files, through the one with
+    /// the given id. If there are no immutable data files, the active data
+    /// file is compacted. Since the compacted data files include the oldest
+    /// one, their tombstones can be dropped, and newer data files still
+    /// shadow the compacted file.
+    fn prepare_compaction_through(&mut self, last_id: u32) -> Result<Compaction> {
+        self.check_writable()?;
+        if self.log.is_custom() {
+            return Err(Error::Config(
+                "Compaction requires a data file path".to_string(),
+            ));
+        }
+        if self.compacting {
+            return Err(Error::Internal(
+                "Compaction already in progress".to_string(),
+            ));
+        }
+        let logs = if self.old_logs.is_empty() {
+            OldLogs::from([(self.log.id, self.log.try_clone()?)])
+        } else {
+            let mut logs = OldLogs::new();
+            for (id, log) in self.old_logs.range(..=last_id) {
+                logs.insert(*id, log.try_clone()?);
+            }
+            logs
+        };
+        let key_dir = self
+            .sorted_key_dir()?
+            .iter()
+            .filter(|(_, entry)| logs.contains_key(&entry.file_id))
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+        self.compacting = true;
+        Ok(Compaction {
+            path: compaction_path(&self.log.path),
+            logs,
+            key_dir,
+            verify_checksums: self.verify_checksums,
+            now: self.clock.now(),
+            #[cfg(test)]
+            write_limit: None,
+        })
+    }
+
+    /// Swaps in the compacted data file written by a compaction, replacing the
+    /// compacted data files. Key dir entries that have been written or
+    /// deleted since the compaction was prepared are left alone. If the
+    /// compaction failed, its temporary file is removed unless configured to
+    /// keep it, leaving the data files untouched.
+    fn finish_compaction(
+        &mut self,
+        compaction: Compaction,
+        result: Result<(Log, KeyDir)>,
+    ) -> Result<()> {
+        self.compacting = false;
+        let (mut new_log, new_key_dir) = match result {
+            Ok(result) => result,
+            Err(error) if self.keep_failed_compaction => {
+                log::error!(
+                    "Compaction failed, keeping {}: {error}",
+                    compaction.path.display()
+                );
+                return Err(error);
+            }
+            Err(error) => {
+                // Don't mask the compaction error with a removal error.
+                match std::fs::remove_file(&compaction.path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => log::error!("Failed to remove {}: {e}", compaction.path.display()),
+                }
+                return Err(error);
+            }
+        };
+        let Compaction { logs, key_dir, .. } = compaction;
+        let ids = logs.keys().copied().collect::<Vec<_>>();
+        drop(logs);
+
+        // If the database was cleared since the compaction was prepared, the
+        // compacted data files are gone and the result must be discarded.
+        let active_id = self.log.id;
+        let cleared = ids
+            .iter()
+            .any(|id| *id != active_id && !self.old_logs.contains_key(id));
+        if cleared {
+            let path = new_log.path.clone();
+            drop(new_log);
+            std::fs::remove_file(path)?;
+            return Ok(());
+        }
+
+        // The compacted file replaces the oldest data file, such that newer
+        // data files still shadow it if we crash before removing them. The
+        // compacted file was fsynced, and the directory is fsynced before and
+        // after the rename, so that the swap is atomic even on a crash.
+        let path = self.log(new_log.id).path.clone();
+        self.log.remove_hint()?;
+        sync_dir(&path)?;
+        std::fs::rename(&new_log.path, &path)?;
+        sync_dir(&path)?;
+        new_log.path = path;
+        let id = new_log.id;
+
+        if id == self.log.id {
+            self.log = new_log;
+        } else {
+            // Remove the merged data files oldest first, such that no
+            // remaining file has entries shadowed by a removed one.
+            self.old_logs.insert(new_log.id, new_log);
+            for id in &ids[1..] {
+                if let Some(log) = self.old_logs.remove(id) {
+                    let path = log.path.clone();
+                    drop(log);
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+
+        for (key, entry) in key_dir {
+            let new_entry = new_key_dir.get(&key).copied();
+            if let Some(hashed) = &mut self.hashed_key_dir {
+                hashed.replace(&key, &entry, new_entry);
+                continue;
+            }
+            if self.key_dir.get(&key) != Some(&entry) {
+                continue;
+            }
+            match new_entry {
+                Some(new_entry) => self.key_dir.insert(key, new_entry),
+                None => self.key_dir.remove(&key),
+            };
+        }
+        self.rebuild_stats()?;
+        #[cfg(feature = "mmap")]
+        if self.mmap {
+            match self.old_logs.get_mut(&id) {
+                Some(log) => log.map()?,
+                None => self.log.map()?,
+            }
+        }
+        if self.write_hint {
+            self.write_hint()?;
+        }
+        Ok(())
+    }
+}
+
+/// A compaction of a set of data files, prepared from a snapshot of the
+/// database such that it can run without access to the database.
+struct Compaction {
+    /// The path of the temporary file to write the compacted data file to.
+    path: PathBuf,
+    /// Read handles for the compacted data files.
+    logs: OldLogs,
+    /// The key dir entries in the compacted data files.
+    key_dir: KeyDir,
+    verify_checksums: bool,
+    /// The time of the snapshot, used to drop expired entries.
+    now: u64,
+    /// Fails writes to the compacted data file after the given number of
+    /// bytes, to test failed compactions.
+    #[cfg(test)]
+    write_limit: Option<u64>,
+}
+
+impl Compaction {
+    /// Writes the live entries to a new temporary data file with the id of the
+    /// oldest compacted data file, returning it and its key dir. The file is
+    /// fsynced, such that it can be safely renamed into place.
+    fn run(&self) -> Result<(Log, KeyDir)> {
+        let Some(&id) = self.logs.keys().next() else {
+            return Err(Error::Internal("No data files to compact".to_string()));
+        };
+        let mut new_log = Log::new(self.path.clone(), id)?;
+        let mut new_key_dir = KeyDir::new();
+        #[cfg(test)]
+        if let Some(limit) = self.write_limit {
+            let file = new_log.file.try_clone()?;
+            new_log.file = Box::new(tests::DiskFull { file, limit });
+        }
+
+        new_log.reset()?;
+        for (key, entry) in self.key_dir.iter().filter(|(_, e)| !e.is_expired(self.now)) {
+            let value = self.logs[&entry.file_id].read_value(key, entry, self.verify_checksums)?;
+            let (offset, _) = new_log.append_entry(key, Some(&value), entry.expiry)?;
+            new_key_dir.insert(
+                key.clone(),
+                KeyDirEntry {
+                    file_id: id,
+                    value_offset: offset + new_log.entry_header_length() + key.len() as u64,
+                    ..*entry
+                },
+            );
+        }
+        new_log.file.sync_all()?;
+
+        Ok((new_log, new_key_dir))
+    }
+}
+
+/// A handle to a background compaction thread, see
+/// [`BitCask::spawn_compactor`].
+pub struct Compactor {
+    stop: std::sync::mpsc::Sender<()>,
+    thread: std::thread::JoinHandle<Result<()>>,
+}
+
+impl Compactor {
+    /// Stops the thread, waiting for an ongoing compaction to finish, and
+    /// returns the error that made it exit early, if any.
+    pub fn stop(self) -> Result<()> {
+        drop(self.stop);
+        Self::join_thread(self.thread)
+    }
+
+    /// Waits for the thread to exit, i.e. until the database is dropped or a
+    /// compaction fails, and returns the error, if any.
+    pub fn join(self) -> Result<()> {
+        let result = Self::join_thread(self.thread);
+        drop(self.stop);
+        result
+    }
+
+    fn join_thread(thread: std::thread::JoinHandle<Result<()>>) -> Result<()> {
+        thread
+            .join()
+            .unwrap_or_else(|_| Err(Error::Internal("Compaction thread panicked".to_string())))
+    }
+}
+
+impl std::fmt::Display for BitCask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bitcask")
+    }
+}
+
+impl Engine for BitCask {
+    type ScanIterator<'a> = ScanIterator<'a>;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.set_entry(key, value, None)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.live_entry(key)? {
+            Some(entry) => Ok(Some(self.read_value(key, &entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reports the value's data file ID and offset.
+    fn get_with_metadata(&mut self, key: &[u8]) -> Result<Option<(Vec<u8>, EntryMeta)>> {
+        let Some(entry) = self.live_entry(key)? else {
+            return Ok(None);
+        };
+        let value = self.read_value(key, &entry)?;
+        let meta = EntryMeta {
+            value_length: entry.value_length,
+            location: Some((entry.file_id.into(), entry.value_offset)),
+        };
+        Ok(Some((value, meta)))
+    }
+
+    /// Reports the key dir entry without reading the value. Expired entries
+    /// that haven't been removed yet are reported as not live.
+    fn stat_key(&mut self, key: &[u8]) -> Result<Option<KeyStat>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        Ok(self.key_dir_get(key)?.map(|entry| KeyStat {
+            key_length: key.len() as u64,
+            value_length: entry.value_length,
+            live: !entry.is_expired(self.clock.now()),
+            location: Some((entry.file_id.into(), entry.value_offset)),
+        }))
+    }
+
+    /// Checks the key directory without reading the existing value.
+    fn set_if_absent(&mut self, key: &[u8], value: Vec<u8>) -> Result<bool> {
+        match self.key_dir_get(key)? {
+            Some(entry) if !entry.is_expired(self.clock.now()) => Ok(false),
+            _ => self.set(key, value).map(|_| true),
+        }
+    }
+
+    /// Reads the values in data file order rather than key order, to avoid
+    /// seeking back and forth.
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let now = self.clock.now();
+        let mut entries = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if !self.may_contain(key) {
+                continue;
+            }
+            match self.key_dir_get(key)? {
+                Some(entry) if !entry.is_expired(now) => entries.push((i, key, entry)),
+                _ => {}
+            }
+        }
+        entries.sort_by_key(|(_, _, entry)| (entry.file_id, entry.value_offset));
+
+        let mut values = vec![None; keys.len()];
+        for (i, key, entry) in entries {
+            values[i] = Some(self.read_value(key, &entry)?);
+        }
+        Ok(values)
+    }
+
+    /// Reads only the value of the first unexpired key.
+    fn first(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let now = self.clock.now();
+        let mut entries = self.key_dir_range::<Vec<u8>>(..);
+        match entries.find(|item| !matches!(item, Ok((_, e)) if e.is_expired(now))) {
+            Some(item) => {
+                let (key, entry) = item?;
+                let value = self.read_value(&key, &entry)?;
+                Ok(Some((key.into_owned(), value)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads only the value of the last unexpired key.
+    fn last(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let now = self.clock.now();
+        let mut entries = self.key_dir_range::<Vec<u8>>(..);
+        match entries.rfind(|item| !matches!(item, Ok((_, e)) if e.is_expired(now))) {
+            Some(item) => {
+                let (key, entry) = item?;
+                let value = self.read_value(&key, &entry)?;
+                Ok(Some((key.into_owned(), value)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let (_, length) = self.log.append_entry(key, None, None)?;
+        self.remove_entry(key)?;
+        self.invalidate(key);
+        self.sync_after_write(length)?;
+        self.maybe_roll_over()
+    }
+
+    /// Writes are handed to the OS as they are made, so this only flushes the
+    /// file handle, which is a no-op.
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.log.file.flush()?)
+    }
+
+    /// Fsyncs the active data file, and writes the hint file if enabled. A
+    /// no-op if the data files were opened read-only.
+    fn sync(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.sync_log()?;
+        if self.write_hint {
+            self.write_hint()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the immutable data files and truncates the active data file,
+    /// instead of writing a tombstone for every key. If interrupted by a
+    /// crash, some data files may remain.
+    fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.key_dir.clear();
+        if let Some(hashed) = &mut self.hashed_key_dir {
+            hashed.clear();
+        }
+        self.rebuild_stats()?;
+        if let Some(cache) = &self.cache {
+            lock_cache(cache).clear();
+        }
+        self.log.remove_hint()?;
+
+        // Unmap the active data file while truncating it, since reading a
+        // truncated part of the mapping would crash.
+        #[cfg(feature = "mmap")]
+        let mapped = self.log.mmap.take().is_some();
+        self.log.reset()?;
+        self.sync_log()?;
+        #[cfg(feature = "mmap")]
+        if mapped {
+            self.log.map()?;
+        }
+
+        for (_, log) in std::mem::take(&mut self.old_logs) {
+            let path = log.path.clone();
+            drop(log);
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for (key, value) in batch.iter() {
+            if let Some(value) = value {
+                check_size(key, value.len(), self.max_key_size, self.max_value_size)?;
+            }
+        }
+        let offsets = self.log.append_batch(&batch)?;
+        for ((key, value), offset) in batch.into_iter().zip(offsets) {
+            self.invalidate(&key);
+            match value {
+                Some(value) => {
+                    let entry = KeyDirEntry {
+                        file_id: self.log.id,
+                        value_offset: offset + self.log.entry_header_length() + key.len() as u64,
+                        value_length: value.len() as u64,
+                        expiry: None,
+                    };
+                    self.insert_entry(key, entry)?;
+                }
+                None => {
+                    self.remove_entry(&key)?;
+                }
+            }
+        }
+        // Only fsync the data file, the hint file is written on explicit
+        // syncs, compaction and drop, since rewriting it is O(live keys).
+        self.sync_log()?;
+        self.maybe_roll_over()
+    }
+
+    /// Uses the value lengths in the key dir, without reading any values.
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        let now = self.clock.now();
+        let mut histogram = SizeHistogram::default();
+        for (key_length, entry) in self.key_dir_entries() {
+            if !entry.is_expired(now) {
+                histogram.add(key_length as u64, entry.value_length);
+            }
+        }
+        Ok(histogram)
+    }
+
+    /// Scans all data files, since the key dir only holds the live entries.
+    /// Batch markers aren't counted, since they don't belong to a key.
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        let range = (
+            range.start_bound().map(Vec::as_slice),
+            range.end_bound().map(Vec::as_slice),
+        );
+        let mut garbage = 0;
+        self.for_each_stale_entry(|key, size, _| {
+            if std::ops::RangeBounds::<[u8]>::contains(&range, key) {
+                garbage += size;
+            }
+        })?;
+        Ok(garbage)
+    }
+
+    /// Counts the keys in the key dir with their entries, the expiring keys,
+    /// the bloom filter and the value cache. This walks the key dir.
+    fn approximate_memory_usage(&self) -> u64 {
+        let vec_size = std::mem::size_of::<Vec<u8>>() as u64;
+        let entry_overhead = vec_size + std::mem::size_of::<KeyDirEntry>() as u64;
+        let mut usage = (self.key_dir.keys())
+            .map(|key| key.len() as u64 + entry_overhead)
+            .sum::<u64>();
+        usage += (self.expiring.iter())
+            .map(|(_, key)| 8 + vec_size + key.len() as u64)
+            .sum::<u64>();
+        if let Some(hashed) = &self.hashed_key_dir {
+            usage += hashed.memory_usage();
+        }
+        if let Some(bloom) = &self.bloom {
+            usage += bloom.memory_usage();
+        }
+        if let Some(cache) = &self.cache {
+            usage += lock_cache(cache).size as u64;
+        }
+        usage
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        let name = self.to_string();
+        let now = self.clock.now();
+        // Expired entries linger in the key dir until they are read, written
+        // or compacted, so exclude them from the stats.
+        let mut stats = self.stats;
+        for (expiry, key) in &self.expiring {
+            if *expiry > now {
+                break;
+            }
+            if let Some(entry) = self.key_dir_get(key)? {
+                stats.sub(self.entry_stats(key.len(), &entry));
+            }
+        }
+        let mut live_disk_size = stats.disk_size;
+        let mut total_disk_size = 0;
+        for log in self.logs() {
+            live_disk_size += log.header_length();
+            total_disk_size += log.file.len()?;
+        }
+        // The live size should never exceed the file size, but don't panic if
+        // the file was modified behind our back.
+        let garbage_disk_size = total_disk_size.saturating_sub(live_disk_size);
+        let mut status = Status {
+            name,
+            key_count: stats.key_count,
+            size: stats.size,
+            total_disk_size,
+            live_disk_size,
+            garbage_disk_size,
+            tombstone_count: self.tombstones().0,
+            file_count: self.logs().count() as u64,
+            compaction_recommended: false,
+        };
+        status.compaction_recommended = self
+            .compaction_threshold
+            .is_some_and(|threshold| garbage_reached(&status, threshold));
+        Ok(status)
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.scan_shared(range)
+    }
+
+    fn scan_bytes(
+        &mut self,
+        start: std::ops::Bound<&[u8]>,
+        end: std::ops::Bound<&[u8]>,
+    ) -> Self::ScanIterator<'_> {
+        self.scan_range::<[u8]>((start, end))
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        let now = self.clock.now();
+        let mut count = 0;
+        for item in self.key_dir_range(range) {
+            if !item?.1.is_expired(now) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        let now = self.clock.now();
+        self.key_dir_range(range)
+            .filter(move |item| !matches!(item, Ok((_, e)) if e.is_expired(now)))
+            .map(|item| Ok(item?.0.into_owned()))
+    }
+}
+
+impl ReadEngine for BitCask {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        match self.key_dir_get(key)? {
+            Some(entry) if !entry.is_expired(self.clock.now()) => {
+                Ok(Some(self.read_value(key, &entry)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> ScanIterator<'_> {
+        self.scan_range(range)
+    }
+}
+
+impl Drop for BitCask {
+    fn drop(&mut self) {
+        if let Err(error) = self.sync() {
+            log::error!("Failed to sync database: {}", error);
+        }
+        if let Some(threshold) = self.compact_on_drop {
+            if let Err(error) = self.compact_if_garbage(threshold) {
+                log::error!("Failed to compact database: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::engine::engines_equal;
+    use super::*;
+
+    const GOLDEN_DIR: &str = "tests/golden/bitcask";
+
+    /// Creates a new BitCask engine for testing.
+    fn setup() -> Result<BitCask> {
+        BitCask::new(tempdir::TempDir::new("yuudb")?.path().join("yuudb"))
+    }
+
+    /// Writes various values primarily for testing log file handling.
+    ///
+    /// - '': empty key and value
+    /// - a: write
+    /// - b: write, write
+    /// - c: write, delete, write
+    /// - d: delete, write
+    /// - e: write, delete
+    /// - f: delete
+    fn setup_log(s: &mut BitCask) -> Result<()> {
+        s.set(b"b", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+
+        s.set(b"e", vec![0x05])?;
+        s.delete(b"e")?;
+
+        s.set(b"c", vec![0x00])?;
+        s.delete(b"c")?;
+        s.set(b"c", vec![0x03])?;
+
+        s.set(b"", vec![])?;
+
+        s.set(b"a", vec![0x01])?;
+
+        s.delete(b"f")?;
+
+        s.delete(b"d")?;
+        s.set(b"d", vec![0x04])?;
+
+        // Make sure the scan yields the expected results.
+        assert_eq!(
+            vec![
+                (b"".to_vec(), vec![]),
+                (b"a".to_vec(), vec![0x01]),
+                (b"b".to_vec(), vec![0x02]),
+                (b"c".to_vec(), vec![0x03]),
+                (b"d".to_vec(), vec![0x04]),
+            ],
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that logs are written correctly using a golden file.
+    fn log() -> Result<()> {
+        let mut s = setup()?;
+        setup_log(&mut s)?;
+
+        let mut mint = goldenfile::Mint::new(GOLDEN_DIR);
+        s.log.print(&mut mint.new_goldenfile("log")?)?;
+        Ok(())
+    }
+
+    #[test]
+    /// Tests the dump of all data files using a golden file.
+    fn dump() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                max_file_size: Some(100),
+                ..Default::default()
+            },
+        )?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", b"text".to_vec())?;
+        s.set(b"b", vec![0xff, 0x00])?;
+        s.delete(b"a")?;
+        s.set(&[0xff], vec![])?;
+        assert_eq!(s.old_logs.len(), 1);
+
+        let mut mint = goldenfile::Mint::new(GOLDEN_DIR);
+        s.dump(&mut mint.new_goldenfile("dump")?)?;
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that writing and then reading a file yields the same results.
+    fn reopen() -> Result<()> {
+        // NB: Don't use setup(), because the tempdir will be removed when
+        // the path falls out of scope.
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        setup_log(&mut s)?;
+
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        drop(s);
+        let mut s = BitCask::new(path)?;
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?,);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests log compaction, by writing golden files of the before/after state,
+    /// and checking that the database contains the same results, even after
+    /// reopening the file.
+    fn compact() -> Result<()> {
+        // NB: Don't use setup(), because the tempdir will be removed when
+        // the path falls out of scope.
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        setup_log(&mut s)?;
+
+        // Dump the initial log file.
+        let mut mint = goldenfile::Mint::new(GOLDEN_DIR);
+        s.log.print(&mut mint.new_goldenfile("compact-before")?)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+
+        // Compact the log file and assert the new log file contents.
+        s.compact()?;
+        assert_eq!(path, s.log.path);
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?,);
+        s.log.print(&mut mint.new_goldenfile("compact-after")?)?;
+
+        // Reopen the log file and assert that the contents are the same.
+        drop(s);
+        let mut s = BitCask::new(path)?;
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?,);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that new_compact() will automatically compact the file when appropriate.
+    fn new_compact() -> Result<()> {
+        // Create an initial log file with a few entries.
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("orig");
+        let compactpath = dir.path().join("compact");
+
+        let mut s = BitCask::new_compact(path.clone(), 0.2)?;
+        setup_log(&mut s)?;
+        let status = s.status()?;
+        let garbage_ratio = status.garbage_disk_size as f64 / status.total_disk_size as f64;
+        drop(s);
+
+        // Test a few threshold value and assert whether it should trigger compaction.
+        let cases = vec![
+            (-1.0, true),
+            (0.0, true),
+            (garbage_ratio - 0.001, true),
+            (garbage_ratio, true),
+            (garbage_ratio + 0.001, false),
+            (1.0, false),
+            (2.0, false),
+        ];
+        for (threshold, expect_compact) in cases.into_iter() {
+            std::fs::copy(&path, &compactpath)?;
+            let mut s = BitCask::new_compact(compactpath.clone(), threshold)?;
+            let new_status = s.status()?;
+            assert_eq!(new_status.live_disk_size, status.live_disk_size);
+            if expect_compact {
+                assert_eq!(new_status.total_disk_size, status.live_disk_size);
+                assert_eq!(new_status.garbage_disk_size, 0);
+            } else {
+                // Below the threshold, compaction isn't recommended either.
+                let expect = Status {
+                    compaction_recommended: false,
+                    ..status.clone()
+                };
+                assert_eq!(new_status, expect);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a BitCask can be opened read-only while a writer holds it,
+    /// rejecting writes and skipping an incomplete trailing entry without
+    /// truncating it.
+    fn open_read_only() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                max_file_size: Some(64),
+                ..Default::default()
+            },
+        )?;
+        setup_log(&mut s)?;
+        assert!(!s.old_logs.is_empty());
+        assert!(BitCask::new(path.clone()).is_err());
+
+        let mut r = BitCask::open_read_only(path.clone())?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(r.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert!(matches!(r.set(b"a", vec![1]), Err(Error::ReadOnly)));
+        assert!(matches!(r.delete(b"a"), Err(Error::ReadOnly)));
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", vec![1]);
+        assert!(matches!(r.write_batch(batch), Err(Error::ReadOnly)));
+        assert!(matches!(r.clear(), Err(Error::ReadOnly)));
+        assert!(matches!(r.compact(), Err(Error::ReadOnly)));
+        r.sync()?;
+        assert_eq!(r.scan(..).collect::<Result<Vec<_>>>()?, expect);
+
+        // Later writes aren't visible to the read-only handle.
+        s.set(b"x", vec![0x10])?;
+        assert_eq!(r.get(b"x")?, None);
+        drop(r);
+
+        // An entry that is still being written is skipped, not truncated.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[0, 0, 0])?;
+        let length = file.metadata()?.len();
+        let mut r = BitCask::open_read_only(path.clone())?;
+        assert_eq!(r.get(b"x")?, Some(vec![0x10]));
+        assert_eq!(r.scan(..).count(), expect.len() + 1);
+        drop(r);
+        assert_eq!(std::fs::metadata(&path)?.len(), length);
+        Ok(())
+    }
+
+    thread_local! {
+        /// The log records captured on the current thread.
+        static CAPTURED: std::cell::RefCell<Vec<(log::Level, String)>> = Default::default();
+    }
+
+    /// A logger that captures log records per thread, such that tests running
+    /// in parallel only see their own records.
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let message = record.args().to_string();
+            CAPTURED.with(|captured| captured.borrow_mut().push((record.level(), message)));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Runs the closure, returning the debug records it logged.
+    fn capture_debug_logs(f: impl FnOnce() -> Result<()>) -> Result<Vec<String>> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).expect("logger already set");
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+        CAPTURED.with(|captured| captured.borrow_mut().clear());
+        f()?;
+        let captured = CAPTURED.with(|captured| captured.take());
+        Ok((captured.into_iter())
+            .filter(|(level, _)| *level == log::Level::Debug)
+            .map(|(_, message)| message)
+            .collect())
+    }
+
+    /// Returns the first log record with the given prefix.
+    fn find_record<'a>(records: &'a [String], prefix: &str) -> &'a str {
+        (records.iter())
+            .find(|record| record.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no {prefix} record in {records:?}"))
+    }
+
+    #[test]
+    /// Tests that compaction and opening log their durations and sizes.
+    fn logging() -> Result<()> {
+        let mut s = setup()?;
+        setup_log(&mut s)?;
+        let status = s.status()?;
+        let (size, live_size) = (status.total_disk_size, status.live_disk_size);
+
+        let records = capture_debug_logs(|| s.compact())?;
+        assert!(find_record(&records, "Compacting").ends_with(&format!(": size={size}")));
+        let compacted = find_record(&records, "Compacted");
+        assert!(compacted.contains(": duration="), "{compacted}");
+        assert!(compacted.ends_with(&format!(
+            " size={size} compacted_size={live_size} reclaimed={}",
+            size - live_size
+        )));
+
+        let path = s.log.path.clone();
+        drop(s);
+        let records = capture_debug_logs(|| BitCask::new(path).map(drop))?;
+        let building = find_record(&records, "Building key dir");
+        assert!(building.ends_with(&format!(": size={live_size}")));
+        let built = find_record(&records, "Built key dir");
+        assert!(built.contains(": duration="), "{built}");
+        assert!(built.ends_with(&format!(" size={live_size} keys=5")));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that writes failing partway when the disk is full leave neither
+    /// partial entries in the file nor changes in the key dir, and that the
+    /// database remains usable and reopenable.
+    fn disk_full() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2])?;
+        let size = s.log.file.len()?;
+        let status = s.status()?;
+
+        s.log.disk_limit = Some(size + 30);
+        let assert_disk_full = |result: Result<()>| {
+            assert!(matches!(
+                result,
+                Err(Error::Io {
+                    kind: std::io::ErrorKind::WriteZero,
+                    ..
+                })
+            ));
+        };
+        assert_disk_full(s.set(b"a", vec![3; 100]));
+        assert_disk_full(s.set_with_ttl(b"c", vec![3; 100], Duration::from_secs(1)));
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", vec![3]);
+        batch.delete(b"a");
+        batch.set(b"d", vec![4; 10]);
+        assert_disk_full(s.write_batch(batch));
+        assert_eq!(s.log.file.len()?, size);
+        assert_eq!(s.status()?, status);
+        assert_eq!(s.get(b"a")?, Some(vec![1]));
+        assert_eq!(s.get(b"c")?, None);
+        s.validate_key_dir()?;
+
+        // Small writes still fit, and the database is usable once space is
+        // freed up.
+        s.delete(b"b")?;
+        s.log.disk_limit = None;
+        s.set(b"c", vec![3; 100])?;
+        drop(s);
+
+        let mut s = BitCask::new(path)?;
+        assert_eq!(
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![1]), (b"c".to_vec(), vec![3; 100])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that writes reuse the log's encoding buffer instead of
+    /// allocating one each, except after large writes.
+    fn write_buffer_reuse() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path)?;
+        s.set(b"a", vec![1; 100])?;
+        let buffer = s.log.write_buffer.as_ptr();
+        assert!(s.log.write_buffer.capacity() > 0);
+
+        for i in 0..100u8 {
+            s.set(&[i], vec![i; 100])?;
+            s.delete(&[i])?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(b"b", vec![2]);
+        batch.delete(b"a");
+        s.write_batch(batch)?;
+        assert_eq!(s.log.write_buffer.as_ptr(), buffer);
+        assert!(s.log.write_buffer.is_empty());
+
+        s.set(b"c", vec![3; MAX_WRITE_BUFFER_SIZE])?;
+        assert_eq!(s.log.write_buffer.capacity(), 0);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    /// Times small writes, which are dominated by the write syscall and the
+    /// encoding buffer. Run with
+    /// `cargo test --release -- --ignored --nocapture append_time`.
+    fn append_time() -> Result<()> {
+        const KEYS: u64 = 500_000;
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path)?;
+        let start = std::time::Instant::now();
+        for i in 0..KEYS {
+            s.set(&i.to_be_bytes(), vec![0x01; 16])?;
+        }
+        let elapsed = start.elapsed();
+        let per_write = elapsed / KEYS as u32;
+        println!("{KEYS} writes: {elapsed:?}, {per_write:?} per write");
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that tombstones are counted in the status, and that a
+    /// delete-heavy database is compacted on open once its tombstones reach
+    /// the count or size threshold, regardless of the garbage ratio.
+    fn compact_tombstones() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        for i in 0..100u8 {
+            s.set(&[i], vec![i; 100])?;
+        }
+        for i in 0..50u8 {
+            s.delete(&[i])?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete(&[50]);
+        batch.delete(&[51]);
+        s.write_batch(batch)?;
+        let status = s.status()?;
+        assert_eq!(status.tombstone_count, 52);
+        let (_, tombstone_bytes) = s.tombstones();
+        assert_eq!(tombstone_bytes, 52 * (1 + s.log.entry_header_length() + 4));
+        drop(s);
+
+        // The tombstones are counted when scanning on open. Neither threshold
+        // is reached, nor is the garbage ratio.
+        let open = |count, bytes| {
+            BitCask::with_config(
+                path.clone(),
+                BitCaskConfig {
+                    compaction_threshold: Some(0.9),
+                    compaction_tombstone_count: count,
+                    compaction_tombstone_bytes: bytes,
+                    ..Default::default()
+                },
+            )
+        };
+        let mut s = open(Some(53), None)?;
+        assert_eq!(s.status()?, status);
+        drop(s);
+        let mut s = open(None, Some(tombstone_bytes + 1))?;
+        assert_eq!(s.status()?, status);
+        drop(s);
+
+        // Reaching either threshold compacts the database.
+        let mut s = open(None, Some(tombstone_bytes))?;
+        let compacted = s.status()?;
+        assert_eq!(compacted.tombstone_count, 0);
+        assert_eq!(compacted.garbage_disk_size, 0);
+        assert_eq!(compacted.key_count, 48);
+        for i in 52..60u8 {
+            s.delete(&[i])?;
+        }
+        assert_eq!(s.status()?.tombstone_count, 8);
+        drop(s);
+        let mut s = open(Some(8), None)?;
+        assert_eq!(s.status()?.tombstone_count, 0);
+        assert_eq!(s.status()?.key_count, 40);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that compact_on_drop compacts the file on drop when the garbage
+    /// ratio exceeds the threshold, and leaves it alone otherwise.
+    fn compact_on_drop() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        for (threshold, expect_compact) in [(0.2, true), (1.0, false)] {
+            let path = dir.path().join(format!("{threshold}"));
+            let config = BitCaskConfig {
+                compact_on_drop: Some(threshold),
+                ..Default::default()
+            };
+            let mut s = BitCask::with_config(path.clone(), config)?;
+            setup_log(&mut s)?;
+            let status = s.status()?;
+            assert!(status.garbage_disk_size > 0);
+            drop(s);
+
+            let size = std::fs::metadata(&path)?.len();
+            if expect_compact {
+                assert_eq!(size, status.live_disk_size);
+            } else {
+                assert_eq!(size, status.total_disk_size);
+            }
+            let mut s = BitCask::new(path)?;
+            assert_eq!(s.status()?.live_disk_size, status.live_disk_size);
+            assert_eq!(s.scan(..).count(), 5);
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that delete_many_and_compact reclaims the space of the deleted
+    /// keys once the garbage ratio reaches the threshold, including with
+    /// rolled over data files.
+    fn delete_many_and_compact() -> Result<()> {
+        for max_file_size in [None, Some(1024)] {
+            let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+            let config = BitCaskConfig {
+                compaction_threshold: Some(0.5),
+                max_file_size,
+                ..Default::default()
+            };
+            let mut s = BitCask::with_config(path, config)?;
+            let keys = (0..100u8).map(|i| vec![i]).collect::<Vec<_>>();
+            for key in &keys {
+                s.set(key, vec![0; 100])?;
+            }
+            let size = s.status()?.total_disk_size;
+
+            // Deleting a few keys stays below the threshold.
+            s.delete_many_and_compact(&keys[..10])?;
+            let status = s.status()?;
+            assert!(status.total_disk_size > size);
+            assert!(status.garbage_disk_size > 0);
+
+            // Deleting most keys compacts.
+            s.delete_many_and_compact(&keys[10..90])?;
+            let status = s.status()?;
+            assert!(status.total_disk_size < size / 5);
+            assert_eq!(status.garbage_disk_size, 0);
+            assert_eq!(status.key_count, 10);
+            assert_eq!(s.scan_keys(..).collect::<Result<Vec<_>>>()?, keys[90..]);
+            s.validate_key_dir()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Tests restoring a database from a data file written by compaction,
+    /// and that invalid source files are rejected without touching the
+    /// database.
+    fn replace_from() -> Result<()> {
+        use std::collections::BTreeMap;
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let backup_path = dir.path().join("backup");
+        let mut expect = BTreeMap::new();
+        {
+            let path = dir.path().join("source");
+            let mut s = BitCask::new(path.clone())?;
+            for i in 0..10u8 {
+                s.set(&[i], vec![i; 10])?;
+                expect.insert(vec![i], vec![i; 10]);
+            }
+            s.delete(&[3])?;
+            expect.remove([3].as_slice());
+            s.compact()?;
+            std::fs::copy(&path, &backup_path)?;
+        }
+
+        let path = dir.path().join("yuudb");
+        let config = BitCaskConfig {
+            max_file_size: Some(64),
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path.clone(), config.clone())?;
+        for i in 5..20u8 {
+            s.set(&[i], vec![0xff])?;
+        }
+        assert!(!s.old_logs.is_empty());
+
+        // Invalid source files are rejected.
+        let invalid_path = dir.path().join("invalid");
+        let backup = std::fs::read(&backup_path)?;
+        std::fs::write(&invalid_path, &backup[..backup.len() - 1])?;
+        assert!(s.replace_from(&invalid_path).is_err());
+        assert!(s.replace_from(&dir.path().join("missing")).is_err());
+        assert!(invalid_path.exists());
+        assert_eq!(s.count(..)?, 15);
+
+        s.replace_from(&backup_path)?;
+        assert!(!backup_path.exists());
+        assert!(s.old_logs.is_empty());
+        assert_eq!(s.scan(..).collect::<Result<BTreeMap<_, _>>>()?, expect);
+        assert_eq!(s.status()?.garbage_disk_size, 0);
+        s.validate_key_dir()?;
+
+        // The data file is still locked.
+        assert!(BitCask::new(path.clone()).is_err());
+        s.set(&[20], vec![20])?;
+        expect.insert(vec![20], vec![20]);
+        drop(s);
+
+        let mut s = BitCask::with_config(path, config)?;
+        assert_eq!(s.scan(..).collect::<Result<BTreeMap<_, _>>>()?, expect);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a non-default config takes effect.
+    fn config() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let config = BitCaskConfig {
+            verify_checksums: false,
+            hint_file: true,
+            sync_policy: SyncPolicy::EveryWrite,
+            max_file_size: Some(64),
+            cache_size: 1024,
+            compaction_threshold: Some(0.0),
+            compaction_tombstone_count: Some(100),
+            compaction_tombstone_bytes: Some(4096),
+            compact_on_drop: Some(0.5),
+            bloom_false_positive_rate: Some(0.01),
+            hashed_key_dir: false,
+            read_buffer_size: 16,
+            max_key_size: 1024,
+            max_value_size: 1024,
+            read_only: false,
+            keep_failed_compaction: true,
+            recovery_policy: RecoveryPolicy::Error,
+            #[cfg(feature = "mmap")]
+            mmap: true,
+        };
+        let mut s = BitCask::with_config(path.clone(), config.clone())?;
+        assert!(!s.verify_checksums);
+        assert!(s.keep_failed_compaction);
+        assert_eq!(s.sync_policy, SyncPolicy::EveryWrite);
+        #[cfg(feature = "mmap")]
+        assert!(s.log.mmap.is_some());
+
+        // Writes roll over, and sync writes a hint file.
+        for i in 0..8u8 {
+            s.set(&[i], vec![i; 16])?;
+            s.set(&[i], vec![i; 16])?;
+        }
+        assert!(s.old_logs.len() > 1);
+        s.get(&[0])?;
+        assert!(lock_cache(s.cache.as_ref().unwrap()).get(&[0]).is_some());
+        s.sync()?;
+        assert!(s.log.hint_path().exists());
+        drop(s);
+
+        // Reopening compacts the immutable data files into one.
+        let mut s = BitCask::with_config(path, config)?;
+        assert_eq!(s.old_logs.len(), 1);
+        for i in 0..8u8 {
+            assert_eq!(s.get(&[i])?, Some(vec![i; 16]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that get_with_metadata reports where the value is in the log.
+    fn get_with_metadata() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        s.set(b"a", b"first".to_vec())?;
+        s.set(b"bb", b"second".to_vec())?;
+        s.set(b"a", b"third".to_vec())?;
+
+        let file = std::fs::read(&path)?;
+        for (key, expect) in [(b"a".as_slice(), b"third".as_slice()), (b"bb", b"second")] {
+            let (value, meta) = s.get_with_metadata(key)?.unwrap();
+            assert_eq!(value, expect);
+            assert_eq!(meta.value_length, expect.len() as u64);
+            let (file_id, offset) = meta.location.unwrap();
+            assert_eq!(file_id, s.log.id as u64);
+            let offset = offset as usize;
+            assert_eq!(&file[offset..offset + expect.len()], expect);
+        }
+        assert_eq!(s.get_with_metadata(b"c")?, None);
+        drop(s);
+
+        // The hashed key dir yields the same entries.
+        let config = BitCaskConfig {
+            hashed_key_dir: true,
+            ..Default::default()
+        };
+        let mut h = BitCask::with_config(path, config)?;
+        let (value, meta) = h.get_with_metadata(b"a")?.unwrap();
+        assert_eq!(value, b"third");
+        assert_eq!(meta.value_length, 5);
+        assert_eq!(h.get_with_metadata(b"c")?, None);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that stat_key reports a key's footprint without reading its
+    /// value, and reports expired entries as not live.
+    fn stat_key() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let mut s = BitCask::open(path, BitCaskConfig::default(), Box::new(clock.clone()))?;
+        s.set(b"a", vec![0; 1000])?;
+        s.set_with_ttl(b"bb", vec![1, 2], Duration::from_secs(10))?;
+        let location = s.get_with_metadata(b"a")?.unwrap().1.location;
+        let reads = s.value_reads.load(Ordering::Relaxed);
+
+        let expect = KeyStat {
+            key_length: 1,
+            value_length: 1000,
+            live: true,
+            location,
+        };
+        assert_eq!(s.stat_key(b"a")?, Some(expect));
+        assert_eq!(s.stat_key(b"c")?, None);
+
+        clock.advance(Duration::from_secs(10));
+        let stat = s.stat_key(b"bb")?.unwrap();
+        assert_eq!((stat.key_length, stat.value_length), (2, 2));
+        assert!(!stat.live);
+        assert_eq!(s.value_reads.load(Ordering::Relaxed), reads);
+
+        assert_eq!(s.get(b"a")?, Some(vec![0; 1000]));
+        assert_eq!(s.value_reads.load(Ordering::Relaxed), reads + 1);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that keys and values over the size limits are rejected without
+    /// writing anything, while those at the limits are accepted.
+    fn size_limits() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let config = BitCaskConfig {
+            max_key_size: 4,
+            max_value_size: 8,
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path, config)?;
+
+        s.set(&[1; 4], vec![1; 8])?;
+        assert_eq!(s.get(&[1; 4])?, Some(vec![1; 8]));
+        let length = s.log.file.len()?;
+
+        assert!(matches!(s.set(&[2; 5], vec![2]), Err(Error::Value(_))));
+        assert!(matches!(s.set(&[2], vec![2; 9]), Err(Error::Value(_))));
+        assert!(matches!(
+            s.set_with_ttl(&[2], vec![2; 9], Duration::from_secs(1)),
+            Err(Error::Value(_))
+        ));
+        let mut batch = WriteBatch::new();
+        batch.set(&[3], vec![3]);
+        batch.set(&[4], vec![4; 9]);
+        assert!(matches!(s.write_batch(batch), Err(Error::Value(_))));
+
+        assert_eq!(s.log.file.len()?, length);
+        assert_eq!(s.get(&[2])?, None);
+        assert_eq!(s.get(&[3])?, None);
+
+        // Deletes only have a key, and aren't limited.
+        s.delete(&[5; 5])?;
+        assert_eq!(s.status()?.key_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that exclusive locks are taken out on log files, released when the
+    /// database is closed, and that an error is returned if a lock is already
+    /// held.
+    fn log_lock() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let s = BitCask::new(path.clone())?;
+
+        assert!(BitCask::new(path.clone()).is_err());
+        drop(s);
+        assert!(BitCask::new(path.clone()).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that an incomplete write at the end of the log file can be
+    /// recovered by discarding the last entry.
+    fn recovery() -> Result<()> {
+        // Create an initial log file with a few entries.
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("complete");
+        let truncpath = dir.path().join("truncated");
+
+        let mut log = Log::new(path.clone(), 1)?;
+        let mut ends = vec![];
+
+        let (pos, len) = log.append_entry("deleted".as_bytes(), Some(&[1, 2, 3]), None)?;
+        ends.push(pos + len);
+
+        let (pos, len) = log.append_entry("deleted".as_bytes(), None, None)?;
+        ends.push(pos + len);
+
+        let (pos, len) = log.append_entry(&[], Some(&[]), None)?;
+        ends.push(pos + len);
+
+        let (pos, len) = log.append_entry("key".as_bytes(), Some(&[1, 2, 3, 4, 5]), None)?;
+        ends.push(pos + len);
+
+        drop(log);
+
+        // Copy the file, and truncate it at each byte, then try to open it
+        // and assert that we always retain a prefix of entries.
+        let size = std::fs::metadata(&path)?.len();
+        for pos in 0..=size {
+            std::fs::copy(&path, &truncpath)?;
+            let f = std::fs::OpenOptions::new().write(true).open(&truncpath)?;
+            f.set_len(pos)?;
+            drop(f);
+
+            let mut expect = vec![];
+            if pos >= ends[0] {
+                expect.push((b"deleted".to_vec(), vec![1, 2, 3]))
+            }
+            if pos >= ends[1] {
+                expect.pop(); // "deleted" key removed
+            }
+            if pos >= ends[2] {
+                expect.push((b"".to_vec(), vec![]))
+            }
+            if pos >= ends[3] {
+                expect.push((b"key".to_vec(), vec![1, 2, 3, 4, 5]))
+            }
+
+            let mut s = BitCask::new(truncpath.clone())?;
+            assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests each recovery policy on a data file with an incomplete trailing
+    /// entry.
+    fn recovery_policy() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        setup_log(&mut s)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let size = std::fs::metadata(&path)?.len();
+        s.set(b"g", vec![0x07; 8])?;
+        drop(s);
+        let file = std::fs::read(&path)?;
+        let truncated = &file[..file.len() - 4];
+        let discarded = &truncated[size as usize..];
+
+        // Error fails to open, leaving the file alone.
+        std::fs::write(&path, truncated)?;
+        assert!(matches!(
+            BitCask::with_config(
+                path.clone(),
+                BitCaskConfig {
+                    recovery_policy: RecoveryPolicy::Error,
+                    ..Default::default()
+                }
+            ),
+            Err(Error::Internal(_))
+        ));
+        assert_eq!(std::fs::read(&path)?, truncated);
+
+        // Read-only opens skip the entry regardless of the policy.
+        let config = BitCaskConfig {
+            read_only: true,
+            recovery_policy: RecoveryPolicy::Error,
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path.clone(), config)?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        drop(s);
+
+        // Truncate discards the entry.
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                recovery_policy: RecoveryPolicy::Truncate,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert_eq!(std::fs::metadata(&path)?.len(), size);
+        assert!(!discarded_path(&path).exists());
+        drop(s);
+
+        // Repair discards the entry too, but records it in the sidecar file,
+        // appending to it on later repairs.
+        for repairs in 1..=2 {
+            std::fs::write(&path, truncated)?;
+            let mut s = BitCask::with_config(
+                path.clone(),
+                BitCaskConfig {
+                    recovery_policy: RecoveryPolicy::Repair,
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+            assert_eq!(std::fs::metadata(&path)?.len(), size);
+
+            let line = format!(
+                "Discarded incomplete entry at offset {size} ({} bytes): {}\n",
+                discarded.len(),
+                hex::encode(discarded)
+            );
+            let sidecar = std::fs::read_to_string(discarded_path(&path))?;
+            assert_eq!(sidecar, line.repeat(repairs));
+        }
+
+        // Repair needs a path for the sidecar file.
+        let config = BitCaskConfig {
+            recovery_policy: RecoveryPolicy::Repair,
+            ..Default::default()
+        };
+        let result = BitCask::with_file(std::io::Cursor::new(Vec::new()), config);
+        assert!(matches!(result, Err(Error::Config(_))));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that an entry with a huge key or value length is treated as an
+    /// incomplete entry and truncated, rather than allocated.
+    fn recovery_huge_length() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        setup_log(&mut s)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let size = s.status()?.total_disk_size;
+        drop(s);
+
+        let cases: Vec<[u8; 8]> = vec![
+            [0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01],
+            [0x00, 0x00, 0x00, 0x01, 0x7f, 0xff, 0xff, 0xff],
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ];
+        for case in cases {
+            let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+            file.write_all(&case)?;
+            file.write_all(&[0; 16])?;
+            drop(file);
+
+            let mut s = BitCask::new(path.clone())?;
+            assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+            assert_eq!(s.status()?.total_disk_size, size);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that an incomplete write batch at the end of the log file is
+    /// discarded as a whole, by truncating it at every byte of the batch.
+    fn batch_recovery() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("complete");
+        let truncpath = dir.path().join("truncated");
+
+        let mut s = BitCask::new(path.clone())?;
+        s.set(b"a", vec![1])?;
+        let start = std::fs::metadata(&path)?.len();
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"b", vec![2]);
+        batch.delete(b"a");
+        batch.set(b"c", vec![3]);
+        s.write_batch(batch)?;
+        let end = std::fs::metadata(&path)?.len();
+        drop(s);
+
+        for pos in start..=end {
+            std::fs::copy(&path, &truncpath)?;
+            let f = std::fs::OpenOptions::new().write(true).open(&truncpath)?;
+            f.set_len(pos)?;
+            drop(f);
+
+            let expect = if pos == end {
+                vec![(b"b".to_vec(), vec![2]), (b"c".to_vec(), vec![3])]
+            } else {
+                vec![(b"a".to_vec(), vec![1])]
+            };
+            let mut s = BitCask::new(truncpath.clone())?;
+            assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+            drop(s);
+            if pos < end {
+                assert_eq!(std::fs::metadata(&truncpath)?.len(), start);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Fuzzes crash recovery: applies random sets, deletes and batches, then
+    /// damages the log by truncating it, flipping a byte, or appending garbage
+    /// at random offsets, and asserts that reopening it either fails with an
+    /// error or recovers the state after a prefix of the operations, never
+    /// including the damaged one. The seed and number of iterations can be
+    /// set with the YUUDB_FUZZ_SEED and YUUDB_FUZZ_ITERATIONS environment
+    /// variables, and each iteration uses the seed plus its index.
+    fn crash_fuzz() -> Result<()> {
+        use rand::{Rng, RngCore, SeedableRng};
+        use std::collections::BTreeMap;
+
+        let env = |name: &str, default: u64| -> u64 {
+            std::env::var(name).map_or(default, |v| v.parse().expect("invalid number"))
+        };
+        let seed = env("YUUDB_FUZZ_SEED", 0x5eed);
+        let iterations = env("YUUDB_FUZZ_ITERATIONS", 50);
+
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("yuudb");
+
+        for iteration in 0..iterations {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed + iteration);
+            let random_key = |rng: &mut rand::rngs::StdRng| vec![rng.gen_range(b'a'..=b'h')];
+            let random_value = |rng: &mut rand::rngs::StdRng| {
+                let mut value = vec![0; rng.gen_range(0..=16)];
+                rng.fill_bytes(&mut value);
+                value
+            };
+
+            // Apply random operations, recording the expected state and the
+            // end offset of the log after each one.
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            let mut s = BitCask::new(path.clone())?;
+            let mut model = BTreeMap::new();
+            let mut states = vec![(std::fs::metadata(&path)?.len(), model.clone())];
+            for _ in 0..rng.gen_range(1..=30) {
+                match rng.gen_range(0..4) {
+                    0 | 1 => {
+                        let (key, value) = (random_key(&mut rng), random_value(&mut rng));
+                        s.set(&key, value.clone())?;
+                        model.insert(key, value);
+                    }
+                    2 => {
+                        let key = random_key(&mut rng);
+                        s.delete(&key)?;
+                        model.remove(&key);
+                    }
+                    _ => {
+                        let mut batch = WriteBatch::new();
+                        for _ in 0..rng.gen_range(1..=4) {
+                            let key = random_key(&mut rng);
+                            if rng.gen_bool(0.7) {
+                                let value = random_value(&mut rng);
+                                batch.set(&key, value.clone());
+                                model.insert(key, value);
+                            } else {
+                                batch.delete(&key);
+                                model.remove(&key);
+                            }
+                        }
+                        s.write_batch(batch)?;
+                    }
+                }
+                states.push((std::fs::metadata(&path)?.len(), model.clone()));
+            }
+            drop(s);
+
+            // Damage the log after its header. The recovered state must be
+            // the one after the last operation that ends before the damage.
+            let start = states[0].0;
+            let end = states.last().unwrap().0;
+            let offset = rng.gen_range(start..end);
+            let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+            let truncated = match rng.gen_range(0..3) {
+                0 => {
+                    file.set_len(offset)?;
+                    true
+                }
+                1 => {
+                    let byte = std::fs::read(&path)?[offset as usize];
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.write_all(&[byte ^ (1 << rng.gen_range(0..8))])?;
+                    false
+                }
+                _ => {
+                    file.set_len(offset)?;
+                    file.seek(SeekFrom::End(0))?;
+                    file.write_all(&random_value(&mut rng))?;
+                    false
+                }
+            };
+            drop(file);
+            let (_, expect) = states.iter().rev().find(|(end, _)| *end <= offset).unwrap();
+
+            let mut s = match BitCask::new(path.clone()) {
+                Ok(s) => s,
+                // Corruption, unlike truncation, may be detected as such.
+                Err(Error::Io {
+                    kind: std::io::ErrorKind::InvalidData,
+                    ..
+                }) if !truncated => continue,
+                Err(error) => panic!("iteration {iteration}: {error}"),
+            };
+            let actual: BTreeMap<_, _> = s.scan(..).collect::<Result<_>>()?;
+            assert_eq!(&actual, expect, "iteration {iteration}");
+            s.validate_key_dir()?;
+
+            // The repaired log must accept further writes, and recover them.
+            s.set(b"z", vec![iteration as u8])?;
+            drop(s);
+            let mut s = BitCask::new(path.clone())?;
+            let mut expect = expect.clone();
+            expect.insert(b"z".to_vec(), vec![iteration as u8]);
+            assert_eq!(s.scan(..).collect::<Result<BTreeMap<_, _>>>()?, expect);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that many threads can read concurrently through a shared
+    /// reference, while seeing consistent values.
+    fn concurrent_reads() -> Result<()> {
+        let mut s = setup()?;
+        setup_log(&mut s)?;
+        let s = std::sync::Arc::new(s);
+
+        let threads = (0..8)
+            .map(|_| {
+                let s = s.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..1000 {
+                        assert_eq!(s.get_shared(b"c")?, Some(vec![0x03]));
+                        assert_eq!(s.get_shared(b"e")?, None);
+                    }
+                    assert_eq!(s.scan_shared(..).count(), 5);
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests status(), both for a log file with known garbage, and
+    /// after compacting it when the live size must equal the file size.
+    fn status_full() -> Result<()> {
+        let mut s = setup()?;
+        setup_log(&mut s)?;
+
+        // Before compaction.
+        assert_eq!(
+            s.status()?,
+            Status {
+                name: "bitcask".to_string(),
+                key_count: 5,
+                size: 8,
+                total_disk_size: 311,
+                live_disk_size: 133,
+                garbage_disk_size: 178,
+                tombstone_count: 4,
+                file_count: 1,
+                compaction_recommended: false,
+            }
+        );
+
+        // After compaction.
+        s.compact()?;
+        assert_eq!(
+            s.status()?,
+            Status {
+                name: "bitcask".to_string(),
+                key_count: 5,
+                size: 8,
+                total_disk_size: 133,
+                live_disk_size: 133,
+                garbage_disk_size: 0,
+                tombstone_count: 0,
+                file_count: 1,
+                compaction_recommended: false,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that compaction is recommended once the garbage ratio reaches
+    /// the configured threshold, and that the data files are counted.
+    fn status_compaction_recommended() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let config = BitCaskConfig {
+            compaction_threshold: Some(0.5),
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path.clone(), config)?;
+        let garbage_ratio =
+            |status: &Status| status.garbage_disk_size as f64 / status.total_disk_size as f64;
+
+        // The first overwrite leaves just under half the file as garbage, and
+        // the second one over half.
+        s.set(b"a", vec![0; 100])?;
+        assert!(!s.status()?.compaction_recommended);
+        s.set(b"a", vec![1; 100])?;
+        let status = s.status()?;
+        assert!(garbage_ratio(&status) < 0.5);
+        assert!(!status.compaction_recommended);
+        s.set(b"a", vec![2; 100])?;
+        let status = s.status()?;
+        assert!(garbage_ratio(&status) >= 0.5);
+        assert!(status.compaction_recommended);
+        assert_eq!(status.file_count, 1);
+
+        s.compact()?;
+        assert!(!s.status()?.compaction_recommended);
+        drop(s);
+
+        // Without a threshold, compaction is never recommended. Rolled over
+        // data files are counted.
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                max_file_size: Some(64),
+                ..Default::default()
+            },
+        )?;
+        for i in 0..4 {
+            s.set(b"a", vec![i; 100])?;
+        }
+        let status = s.status()?;
+        assert!(garbage_ratio(&status) >= 0.5);
+        assert!(!status.compaction_recommended);
+        assert_eq!(status.file_count, 5);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that corrupted entries are detected by their checksums, both
+    /// when reading values and when rebuilding the key dir on open.
+    fn checksum() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        setup_log(&mut s)?;
+
+        // Flip a byte in the value of key "a".
+        let value_offset = s.key_dir[b"a".as_slice()].value_offset;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(value_offset))?;
+        file.write_all(&[0xff])?;
+        drop(file);
+
+        assert!(matches!(s.get(b"a"), Err(Error::Internal(_))));
+        assert_eq!(s.get(b"b")?, Some(vec![0x02]));
+        drop(s);
+
+        assert!(matches!(
+            BitCask::new(path.clone()),
+            Err(Error::Io {
+                kind: std::io::ErrorKind::InvalidData,
+                ..
+            })
+        ));
+
+        // The log can still be read with verification disabled.
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                verify_checksums: false,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(s.get(b"a")?, Some(vec![0xff]));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that verify reports the contents of a data file, and the offset
+    /// of the first corrupt or incomplete entry, without modifying the file.
+    fn verify() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        setup_log(&mut s)?;
+
+        // The file can be verified while it's open.
+        let report = BitCask::verify(&path)?;
+        assert_eq!(
+            report,
+            VerifyReport {
+                version: VERSION,
+                entries: 12,
+                live_keys: 5,
+                tombstones: 4,
+                garbage_bytes: s.status()?.garbage_disk_size,
+                corrupt_offset: None,
+                error: None,
+            }
+        );
+        let json = serde_json::to_string(&report).map_err(std::io::Error::from)?;
+        assert!(json.contains(r#""corrupt_offset":null"#), "{json}");
+
+        // Flip a byte in the value of key "a".
+        let value_offset = s.key_dir[b"a".as_slice()].value_offset;
+        let entry_offset = value_offset - s.log.entry_header_length() - 1;
+        drop(s);
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(value_offset))?;
+        file.write_all(&[0xff])?;
+        drop(file);
+
+        let report = BitCask::verify(&path)?;
+        assert_eq!(report.corrupt_offset, Some(entry_offset));
+        assert!(report.error.unwrap().contains("Checksum mismatch"));
+        assert_eq!(
+            (report.entries, report.live_keys, report.tombstones),
+            (8, 3, 2)
+        );
+
+        // Incomplete entries are reported, but not truncated.
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(entry_offset + 3)?;
+        let report = BitCask::verify(&path)?;
+        assert_eq!(report.corrupt_offset, Some(entry_offset));
+        assert_eq!(report.error.as_deref(), Some("Incomplete entry"));
+        assert_eq!(std::fs::metadata(&path)?.len(), entry_offset + 3);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that opening reports increasing progress across all data files,
+    /// every few MB and finally with all bytes scanned.
+    fn open_progress() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                max_file_size: Some(1 << 20),
+                ..Default::default()
+            },
+        )?;
+        for i in 0..2500u32 {
+            s.set(&i.to_be_bytes(), vec![0x01; 4096])?;
+        }
+        let total = s.status()?.total_disk_size;
+        assert!(s.old_logs.len() > 5);
+        drop(s);
+
+        let mut calls = Vec::new();
+        let progress = |scanned, total| calls.push((scanned, total));
+        let s = BitCask::with_progress(path, BitCaskConfig::default(), progress)?;
+        assert_eq!(s.key_dir.len(), 2500);
+        assert!(calls.len() > 2, "{calls:?}");
+        assert!(calls.len() as u64 <= total / PROGRESS_INTERVAL + 1);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0), "{calls:?}");
+        assert!(calls.iter().all(|(_, t)| *t == total));
+        assert_eq!(calls.last(), Some(&(total, total)));
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    /// Times opening a large log with an 8KB read buffer, the default of
+    /// std::io::BufReader, and with the default read buffer size. The
+    /// difference is largest when the file isn't in the page cache. Run with
+    /// `cargo test --release -- --ignored --nocapture open_time`.
+    fn open_time() -> Result<()> {
+        const KEYS: usize = 500_000;
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        for i in 0..KEYS {
+            s.set(&(i as u64).to_be_bytes(), vec![0x01; 256])?;
+        }
+        drop(s);
+
+        for read_buffer_size in [8 << 10, READ_BUFFER_SIZE, 8 << 10, READ_BUFFER_SIZE] {
+            let start = std::time::Instant::now();
+            let config = BitCaskConfig {
+                read_buffer_size,
+                ..Default::default()
+            };
+            let s = BitCask::with_config(path.clone(), config)?;
+            let elapsed = start.elapsed();
+            assert_eq!(s.key_dir.len(), KEYS);
+            println!("{read_buffer_size} byte buffer: {elapsed:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that forward scans read the values of consecutive keys together
+    /// after compaction, and yield the same results as gets on a fragmented
+    /// file, including across data files and with expired entries.
+    fn scan_read_ahead() -> Result<()> {
+        use rand::{seq::SliceRandom as _, SeedableRng as _};
+
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let mut s = BitCask::open(path, BitCaskConfig::default(), Box::new(clock.clone()))?;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut keys = (0..1000u32).collect::<Vec<_>>();
+        keys.shuffle(&mut rng);
+        for (i, key) in keys.iter().enumerate() {
+            let value = vec![*key as u8; i % 300];
+            match i % 10 {
+                0 => s.delete(&key.to_be_bytes())?,
+                1 => s.set_with_ttl(&key.to_be_bytes(), value, Duration::from_secs(1))?,
+                2 => s.roll_over()?,
+                _ => s.set(&key.to_be_bytes(), value)?,
+            }
+        }
+        keys.shuffle(&mut rng);
+        for key in &keys[..300] {
+            s.set(&key.to_be_bytes(), key.to_be_bytes().to_vec())?;
+        }
+        clock.advance(Duration::from_secs(1));
+
+        let mut expect = Vec::new();
+        for key in 0..1000u32 {
+            if let Some(value) = s.get(&key.to_be_bytes())? {
+                expect.push((key.to_be_bytes().to_vec(), value));
+            }
+        }
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        let mut reverse = s.scan(..).rev().collect::<Result<Vec<_>>>()?;
+        reverse.reverse();
+        assert_eq!(reverse, expect);
+        let range = 200u32.to_be_bytes().to_vec()..700u32.to_be_bytes().to_vec();
+        let mut scan = s.scan(range.clone());
+        let mut mixed = Vec::new();
+        while let Some(item) = scan.next() {
+            mixed.push(item?);
+            if let Some(item) = scan.next_back() {
+                mixed.push(item?);
+            }
+        }
+        mixed.sort();
+        let expect_range = expect.iter().filter(|(key, _)| range.contains(key));
+        assert!(mixed.iter().eq(expect_range));
+
+        // After compaction of all data files, the values are read in few large
+        // chunks.
+        s.roll_over()?;
+        s.compact()?;
+        assert_eq!(s.old_logs.len(), 1);
+        let size = s.old_logs.values().next().unwrap().file.len()?;
+        let mut scan = s.scan(..);
+        assert_eq!(scan.by_ref().collect::<Result<Vec<_>>>()?, expect);
+        let reads = scan.reads as u64;
+        assert!(reads <= size / SCAN_READ_AHEAD + 2, "{reads} reads");
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    /// Times full scans of a compacted file with and without read-ahead. Run
+    /// with `cargo test --release -- --ignored --nocapture scan_time`.
+    fn scan_time() -> Result<()> {
+        const KEYS: usize = 500_000;
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path)?;
+        for i in 0..KEYS {
+            s.set(&(i as u64).to_be_bytes(), vec![0x01; 256])?;
+        }
+        s.compact()?;
+
+        for read_ahead in [false, true, false, true] {
+            let start = std::time::Instant::now();
+            let mut scan = s.scan(..);
+            scan.read_ahead = read_ahead;
+            assert_eq!(scan.by_ref().count(), KEYS);
+            let (elapsed, reads) = (start.elapsed(), scan.reads);
+            println!("read-ahead {read_ahead}: {elapsed:?}, {reads} reads");
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that legacy logs without a header or checksums can be read and
+    /// written, and are upgraded to the current format by compaction.
+    fn legacy() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, [0, 0, 0, 1, 0, 0, 0, 1, b'a', 0x01])?;
+
+        let mut s = BitCask::new(path.clone())?;
+        assert_eq!(s.log.version, 0);
+        assert_eq!(s.get(b"a")?, Some(vec![0x01]));
+        s.set(b"b", vec![0x02])?;
+        drop(s);
+
+        let mut s = BitCask::new(path.clone())?;
+        assert_eq!(s.log.version, 0);
+        let expect = vec![(b"a".to_vec(), vec![0x01]), (b"b".to_vec(), vec![0x02])];
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+
+        s.compact()?;
+        assert_eq!(s.log.version, VERSION);
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+        drop(s);
+
+        let mut s = BitCask::new(path)?;
+        assert_eq!(s.log.version, VERSION);
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that version 3 logs with i32 value lengths can be read and
+    /// written, limit values to 2GB, and are upgraded by compaction.
+    fn version_3() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, [MAGIC.as_slice(), &[3]].concat())?;
+        let mut log = Log::new(path.clone(), 1)?;
+        assert_eq!(log.version, 3);
+        log.append_entry(b"a", Some(&[0x01]), None)?;
+        drop(log);
+
+        let mut s = BitCask::new(path.clone())?;
+        assert_eq!(s.log.version, 3);
+        assert_eq!(s.log.entry_header_length(), 16);
+        s.set(b"b", vec![0x02])?;
+        assert_eq!(
+            s.log.check_value_length(i32::MAX as usize)?,
+            i32::MAX as i64
+        );
+        assert!(matches!(
+            s.log.check_value_length(i32::MAX as usize + 1),
+            Err(Error::Value(_))
+        ));
+        drop(s);
+
+        let mut s = BitCask::new(path.clone())?;
+        let expect = vec![(b"a".to_vec(), vec![0x01]), (b"b".to_vec(), vec![0x02])];
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+
+        s.compact()?;
+        assert_eq!(s.log.version, VERSION);
+        assert_eq!(s.log.entry_header_length(), 20);
+        assert_eq!(
+            s.log.check_value_length(i32::MAX as usize + 1)?,
+            i32::MAX as i64 + 1
+        );
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that values larger than 2GB are indexed correctly, using a sparse
+    /// file with a hand-written entry header rather than an actual value.
+    fn large_value() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let value_length = (1u64 << 31) + 10;
+
+        let mut file = std::fs::File::create(&path)?;
+        file.write_all(&MAGIC)?;
+        file.write_all(&[VERSION])?;
+        file.write_all(&1u32.to_be_bytes())?;
+        file.write_all(&(value_length as i64).to_be_bytes())?;
+        file.write_all(&0u64.to_be_bytes())?;
+        file.write_all(b"k")?;
+        let value_offset = file.stream_position()?;
+        file.set_len(value_offset + value_length + 4)?;
+        drop(file);
+
+        // The checksum is bogus, so verification must be disabled.
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                verify_checksums: false,
+                ..Default::default()
+            },
+        )?;
+        let entry = s.key_dir[b"k".as_slice()];
+        assert_eq!(entry.value_offset, value_offset);
+        assert_eq!(entry.value_length, value_length);
+        assert_eq!(s.status()?.size, 1 + value_length);
+
+        // Entries after the large value are readable, also via the hint file.
+        s.set(b"a", vec![0x01])?;
+        assert!(s.key_dir[b"a".as_slice()].value_offset > value_length);
+        drop(s);
+
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                verify_checksums: false,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(s.key_dir[b"k".as_slice()], entry);
+        assert_eq!(s.get(b"a")?, Some(vec![0x01]));
+        assert_eq!(s.status()?.size, 2 + 1 + value_length);
+
+        Ok(())
+    }
+
+    /// A manually advanced clock for testing expiry.
+    #[derive(Clone, Default)]
+    struct TestClock(std::sync::Arc<AtomicU64>);
+
+    impl TestClock {
+        fn advance(&self, duration: Duration) {
+            self.0
+                .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    /// Tests that keys set with a TTL expire, both in memory and when
+    /// reopening the log, and that compaction removes them from disk.
+    fn ttl() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        clock.advance(Duration::from_secs(1000));
+        let mut s = BitCask::open(
+            path.clone(),
+            BitCaskConfig::default(),
+            Box::new(clock.clone()),
+        )?;
+
+        s.set(b"a", vec![0x01])?;
+        s.set_with_ttl(b"b", vec![0x02], Duration::from_secs(10))?;
+        s.set_with_ttl(b"c", vec![0x03], Duration::from_secs(20))?;
+        s.set_with_ttl(b"d", vec![0x04], Duration::from_secs(10))?;
+        s.set(b"d", vec![0x04])?;
+
+        let expect = vec![
+            (b"a".to_vec(), vec![0x01]),
+            (b"b".to_vec(), vec![0x02]),
+            (b"c".to_vec(), vec![0x03]),
+            (b"d".to_vec(), vec![0x04]),
+        ];
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+
+        // Expiry is inclusive of the expiry time.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(s.get(b"b")?, None);
+        assert_eq!(s.get(b"c")?, Some(vec![0x03]));
+        let expect = vec![
+            (b"a".to_vec(), vec![0x01]),
+            (b"c".to_vec(), vec![0x03]),
+            (b"d".to_vec(), vec![0x04]),
+        ];
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+        assert_eq!(expect, s.scan(..).rev().rev().collect::<Result<Vec<_>>>()?);
+        assert_eq!(s.status()?.key_count, 3);
+
+        // Reopening the log treats expired entries as tombstones.
+        drop(s);
+        let mut s = BitCask::open(
+            path.clone(),
+            BitCaskConfig::default(),
+            Box::new(clock.clone()),
+        )?;
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+
+        // Compaction removes expired entries from disk, so they don't
+        // reappear even with a clock that is behind.
+        clock.advance(Duration::from_secs(10));
+        s.compact()?;
+        let status = s.status()?;
+        assert_eq!(status.key_count, 2);
+        assert_eq!(status.garbage_disk_size, 0);
+        drop(s);
+
+        let mut s = BitCask::open(path, BitCaskConfig::default(), Box::<TestClock>::default())?;
+        let expect = vec![(b"a".to_vec(), vec![0x01]), (b"d".to_vec(), vec![0x04])];
+        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that first and last skip expired keys.
+    fn first_last_ttl() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let mut s = BitCask::open(path, BitCaskConfig::default(), Box::new(clock.clone()))?;
+
+        s.set_with_ttl(b"a", vec![0x01], Duration::from_secs(10))?;
+        s.set(b"b", vec![0x02])?;
+        s.set_with_ttl(b"c", vec![0x03], Duration::from_secs(10))?;
+        assert_eq!(s.first()?, Some((b"a".to_vec(), vec![0x01])));
+        assert_eq!(s.last()?, Some((b"c".to_vec(), vec![0x03])));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(s.first()?, Some((b"b".to_vec(), vec![0x02])));
+        assert_eq!(s.last()?, Some((b"b".to_vec(), vec![0x02])));
+
+        s.delete(b"b")?;
+        assert_eq!(s.first()?, None);
+        assert_eq!(s.last()?, None);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that full-range scans report their exact length, excluding
+    /// expired keys, and that other scans only report an upper bound.
+    fn scan_size_hint() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let mut s = BitCask::open(path, BitCaskConfig::default(), Box::new(clock.clone()))?;
+
+        s.set_with_ttl(b"a", vec![0x01], Duration::from_secs(10))?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"c", vec![0x03])?;
+        s.set_with_ttl(b"d", vec![0x04], Duration::from_secs(20))?;
+        assert_eq!(s.scan(..).size_hint(), (4, Some(4)));
+
+        clock.advance(Duration::from_secs(10));
+        let key_count = s.status()?.key_count as usize;
+        let mut scan = s.scan(..);
+        assert_eq!(scan.size_hint(), (key_count, Some(key_count)));
+        scan.next().transpose()?;
+        scan.next_back().transpose()?;
+        assert_eq!(scan.size_hint(), (1, Some(1)));
+        assert_eq!(scan.collect::<Result<Vec<_>>>()?.len(), 1);
+
+        assert_eq!(s.scan(b"b".to_vec()..).size_hint().0, 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that legacy logs reject TTLs until compacted.
+    fn ttl_legacy() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, [0, 0, 0, 1, 0, 0, 0, 1, b'a', 0x01])?;
+
+        let mut s = BitCask::new(path)?;
+        assert!(matches!(
+            s.set_with_ttl(b"b", vec![0x02], Duration::from_secs(10)),
+            Err(Error::Value(_))
+        ));
+        s.compact()?;
+        s.set_with_ttl(b"b", vec![0x02], Duration::from_secs(10))?;
+        assert_eq!(s.get(b"b")?, Some(vec![0x02]));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that status() doesn't underflow if the live size exceeds the
+    /// file size, e.g. if the file was truncated externally.
+    fn status_truncated() -> Result<()> {
+        let mut s = setup()?;
+        setup_log(&mut s)?;
+        s.compact()?;
+        s.log.file.set_len(HEADER_LENGTH)?;
+
+        let status = s.status()?;
+        assert_eq!(status.total_disk_size, HEADER_LENGTH);
+        assert!(status.live_disk_size > status.total_disk_size);
+        assert_eq!(status.garbage_disk_size, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that hint files are written on sync and used when opening, and
+    /// that stale or corrupt hint files are ignored.
+    fn hint_file() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                hint_file: true,
+                ..Default::default()
+            },
+        )?;
+        setup_log(&mut s)?;
+        s.set_with_ttl(b"ttl", vec![0x07], Duration::from_secs(3600))?;
+        s.sync()?;
+
+        let hint_path = s.log.hint_path();
+        let stale_hint = std::fs::read(&hint_path)?;
+
+        // Write batches don't rewrite the hint file.
+        let mut batch = WriteBatch::new();
+        batch.set(b"g", vec![0x08]);
+        batch.delete(b"g");
+        s.write_batch(batch)?;
+        assert_eq!(std::fs::read(&hint_path)?, stale_hint);
+        s.set(b"f", vec![0x06])?;
+        drop(s);
+
+        // The key dir loaded from the hint file matches a full scan.
+        let mut s = BitCask::new(path.clone())?;
+        let key_dir = s
+            .log
+            .read_hint(&s.old_logs, 0)?
+            .expect("hint file should be valid");
+        assert_eq!(
+            key_dir,
+            s.log.build_key_dir(
+                KeyDir::new(),
+                true,
+                0,
+                READ_BUFFER_SIZE,
+                Some(RecoveryPolicy::Truncate),
+                &mut |_| {}
+            )?
+        );
+        assert_eq!(key_dir, s.key_dir);
+        assert_eq!(s.get(b"f")?, Some(vec![0x06]));
+        assert_eq!(s.get(b"ttl")?, Some(vec![0x07]));
+        drop(s);
+
+        // A stale hint file written for an earlier log is ignored.
+        std::fs::write(&hint_path, &stale_hint)?;
+        let mut s = BitCask::new(path.clone())?;
+        assert_eq!(s.log.read_hint(&s.old_logs, 0)?, None);
+        assert_eq!(s.get(b"f")?, Some(vec![0x06]));
+        drop(s);
+
+        // A corrupt hint file is ignored.
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                hint_file: true,
+                ..Default::default()
+            },
+        )?;
+        s.sync()?;
+        let mut hint = std::fs::read(&hint_path)?;
+        hint[20] ^= 0xff;
+        std::fs::write(&hint_path, &hint)?;
+        assert_eq!(s.log.read_hint(&s.old_logs, 0)?, None);
+        drop(s);
+
+        // Compaction rewrites the hint file.
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                hint_file: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(s.get(b"f")?, Some(vec![0x06]));
+        s.compact()?;
+        let key_dir = s.log.build_key_dir(
+            KeyDir::new(),
+            true,
+            0,
+            READ_BUFFER_SIZE,
+            Some(RecoveryPolicy::Truncate),
+            &mut |_| {},
+        )?;
+        assert_eq!(s.log.read_hint(&s.old_logs, 0)?, Some(key_dir));
+
+        Ok(())
+    }
+
+    /// Returns the sorted file names in the directory of the given path.
+    fn file_names(path: &Path) -> Result<Vec<String>> {
+        let mut names = std::fs::read_dir(path.parent().unwrap())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    /// Tests that validate_key_dir accepts a consistent key dir across data
+    /// files, and detects deliberately corrupted key dir entries and stats.
+    fn validate_key_dir() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let config = BitCaskConfig {
+            max_file_size: Some(64),
+            ..Default::default()
+        };
+        let mut s = BitCask::open(path, config, Box::new(clock.clone()))?;
+        setup_log(&mut s)?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", vec![0x0a]);
+        batch.delete(b"b");
+        s.write_batch(batch)?;
+        s.set_with_ttl(b"t", vec![0x01], Duration::from_secs(1))?;
+        assert!(s.old_logs.len() > 1);
+        s.validate_key_dir()?;
+
+        // Expired entries may linger in the key dir.
+        clock.advance(Duration::from_secs(1));
+        s.validate_key_dir()?;
+
+        // Tampers with the key dir and restores it after checking the error.
+        let assert_invalid = |s: &mut BitCask, tamper: &dyn Fn(&mut BitCask), expect: &str| {
+            let (key_dir, stats) = (s.key_dir.clone(), s.stats);
+            tamper(s);
+            match s.validate_key_dir() {
+                Err(Error::Internal(message)) => assert!(message.contains(expect), "{message}"),
+                result => panic!("expected error, got {result:?}"),
+            }
+            (s.key_dir, s.stats) = (key_dir, stats);
+        };
+
+        assert_invalid(
+            &mut s,
+            &|s| s.key_dir.get_mut(b"c".as_slice()).unwrap().value_offset += 1,
+            "Key c is KeyDirEntry",
+        );
+        assert_invalid(
+            &mut s,
+            &|s| {
+                s.key_dir.remove(b"c".as_slice());
+            },
+            "Key c is missing from the key dir",
+        );
+        assert_invalid(
+            &mut s,
+            &|s| {
+                let entry = s.key_dir[b"c".as_slice()];
+                s.key_dir.insert(b"x".to_vec(), entry);
+            },
+            "Key x is missing from the data files",
+        );
+        assert_invalid(&mut s, &|s| s.stats.size += 1, "Key dir stats");
+        s.validate_key_dir()?;
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that stats_histogram uses the key dir without reading values,
+    /// and excludes expired keys.
+    fn stats_histogram() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let mut s = BitCask::open(path, BitCaskConfig::default(), Box::new(clock.clone()))?;
+        s.set(b"a", vec![1; 5])?;
+        s.set(b"bb", vec![1; 64])?;
+        s.set(b"cc", vec![1; 100])?;
+        s.set_with_ttl(b"d", vec![1; 2], Duration::from_secs(10))?;
+        clock.advance(Duration::from_secs(10));
+
+        // Truncate the data file, such that any value read fails.
+        s.log.file.set_len(0)?;
+        let histogram = s.stats_histogram()?;
+        assert_eq!(histogram.value_buckets, vec![0, 0, 0, 1, 0, 0, 0, 2]);
+        assert_eq!(histogram.key_sizes.total, 5);
+        assert_eq!(histogram.value_sizes.min, 5);
+        assert_eq!(histogram.value_sizes.max, 100);
+        assert!(s.get(b"a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a BitCask over an in-memory store writes the same log as
+    /// over a file, and can be reopened from it, but rejects options that
+    /// need further files.
+    fn custom_file() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut file = BitCask::new(path.clone())?;
+        setup_log(&mut file)?;
+        let mut s = BitCask::with_file(std::io::Cursor::new(Vec::new()), Default::default())?;
+        setup_log(&mut s)?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"e", vec![5]);
+        batch.delete(b"a");
+        s.write_batch(batch.clone())?;
+        file.write_batch(batch)?;
+        assert!(engines_equal(&mut s, &mut file)?);
+        assert_eq!(s.status()?, file.status()?);
+
+        let mut bytes = vec![0; s.log.file.len()? as usize];
+        s.log.file.read_exact_at(&mut bytes, 0)?;
+        assert_eq!(bytes, std::fs::read(&path)?);
+
+        let mut s = BitCask::with_file(std::io::Cursor::new(bytes), Default::default())?;
+        assert!(engines_equal(&mut s, &mut file)?);
+        assert!(matches!(s.compact(), Err(Error::Config(_))));
+        s.clear()?;
+        assert_eq!(s.count(..)?, 0);
+
+        let config = BitCaskConfig {
+            hint_file: true,
+            ..Default::default()
+        };
+        let result = BitCask::with_file(std::io::Cursor::new(Vec::new()), config);
+        assert!(matches!(result, Err(Error::Config(_))));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a bulk-loaded database reads back identically to one
+    /// written with individual sets, also after reopening, and that a failed
+    /// load leaves the database untouched.
+    fn bulk_load() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let mut s = BitCask::new(dir.path().join("bulk"))?;
+        let mut expect = BitCask::new(dir.path().join("expect"))?;
+        for engine in [&mut s, &mut expect] {
+            engine.set(b"a", vec![1])?;
+            engine.set(b"key00010", vec![2])?;
+        }
+
+        // Overwrite an existing key, and a key within the load.
+        let pairs = (0..1000u32)
+            .map(|i| (format!("key{i:05}").into_bytes(), i.to_be_bytes().to_vec()))
+            .chain([(b"key00020".to_vec(), vec![3])])
+            .collect::<Vec<_>>();
+        assert_eq!(s.bulk_load(pairs.clone().into_iter())?, 1001);
+        for (key, value) in pairs {
+            expect.set(&key, value)?;
+        }
+        assert!(engines_equal(&mut s, &mut expect)?);
+        let (status, expect_status) = (s.status()?, expect.status()?);
+        assert_eq!(status.key_count, expect_status.key_count);
+        assert_eq!(status.size, expect_status.size);
+        s.validate_key_dir()?;
+
+        drop(s);
+        let mut s = BitCask::new(dir.path().join("bulk"))?;
+        assert!(engines_equal(&mut s, &mut expect)?);
+
+        // A failed load is truncated away.
+        let size = s.log.file.len()?;
+        let pairs = [
+            (b"b".to_vec(), vec![1]),
+            (vec![0; DEFAULT_MAX_KEY_SIZE + 1], vec![1]),
+        ];
+        assert!(matches!(
+            s.bulk_load(pairs.into_iter()),
+            Err(Error::Value(_))
+        ));
+        assert_eq!(s.log.file.len()?, size);
+        assert_eq!(s.get(b"b")?, None);
+
+        // As is a load that runs out of disk space partway, with the write
+        // error returned.
+        s.log.disk_limit = Some(size + READ_BUFFER_SIZE as u64 + 100);
+        let pairs = (0..100_000u32).map(|i| (format!("new{i:06}").into_bytes(), vec![1; 10]));
+        assert!(matches!(
+            s.bulk_load(pairs),
+            Err(Error::Io {
+                kind: std::io::ErrorKind::WriteZero,
+                ..
+            })
+        ));
+        assert_eq!(s.log.file.len()?, size);
+        assert_eq!(s.get(b"new000000")?, None);
+        s.log.disk_limit = None;
+        drop(s);
+        let mut s = BitCask::new(dir.path().join("bulk"))?;
+        assert!(engines_equal(&mut s, &mut expect)?);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the memory usage estimate grows proportionally with the
+    /// keys, and excludes the values, which are only kept on disk.
+    fn approximate_memory_usage() -> Result<()> {
+        let mut s = setup()?;
+        assert_eq!(s.approximate_memory_usage(), 0);
+        for i in 0..1000u32 {
+            s.set(format!("key{i:05}").as_bytes(), vec![0; 100])?;
+        }
+        let usage = s.approximate_memory_usage();
+        assert!(usage > 8 * 1000);
+        assert!(usage < s.status()?.size);
+        for i in 1000..2000u32 {
+            s.set(format!("key{i:05}").as_bytes(), vec![0; 100])?;
+        }
+        assert_eq!(s.approximate_memory_usage(), 2 * usage);
+
+        // Overwrites and reads don't add to the key dir.
+        s.set(b"key00000", vec![1; 100])?;
+        s.get(b"key00001")?;
+        assert_eq!(s.approximate_memory_usage(), 2 * usage);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the hashed key dir reads keys from the data files to tell
+    /// apart keys with colliding hashes, across overwrites, deletes,
+    /// compaction and reopening with a hint file.
+    fn hashed_key_dir_collisions() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let config = BitCaskConfig {
+            hashed_key_dir: true,
+            max_file_size: Some(64),
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path.clone(), config.clone())?;
+        s.hashed_key_dir = Some(HashedKeyDir::with_hash(|_| 0));
+
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"c", vec![0x03])?;
+        s.set(b"long", vec![0x04])?;
+        s.set(b"b", vec![0x12])?;
+        s.delete(b"c")?;
+        s.delete(b"d")?;
+        s.set_with_ttl(b"e", vec![0x05], Duration::from_secs(3600))?;
+
+        let assert_contents = |s: &mut BitCask| -> Result<()> {
+            assert_eq!(s.get(b"a")?, Some(vec![0x01]));
+            assert_eq!(s.get(b"b")?, Some(vec![0x12]));
+            assert_eq!(s.get(b"c")?, None);
+            assert_eq!(s.get(b"d")?, None);
+            assert_eq!(s.get(b"l")?, None);
+            assert_eq!(s.get(b"long")?, Some(vec![0x04]));
+            assert_eq!(
+                s.scan(..).collect::<Result<Vec<_>>>()?,
+                vec![
+                    (b"a".to_vec(), vec![0x01]),
+                    (b"b".to_vec(), vec![0x12]),
+                    (b"e".to_vec(), vec![0x05]),
+                    (b"long".to_vec(), vec![0x04]),
+                ]
+            );
+            assert_eq!(
+                s.scan(b"b".to_vec()..b"f".to_vec())
+                    .rev()
+                    .map(|r| r.map(|(key, _)| key))
+                    .collect::<Result<Vec<_>>>()?,
+                vec![b"e".to_vec(), b"b".to_vec()]
+            );
+            assert_eq!(s.count(..)?, 4);
+            assert_eq!(s.first()?, Some((b"a".to_vec(), vec![0x01])));
+            assert_eq!(s.last()?, Some((b"long".to_vec(), vec![0x04])));
+            assert!(s.stat_key(b"long")?.is_some());
+            assert!(s.stat_key(b"c")?.is_none());
+            s.validate_key_dir()
+        };
+        assert_contents(&mut s)?;
+        let hashed = s.hashed_key_dir.as_ref().unwrap();
+        assert_eq!((hashed.slots.len(), hashed.len()), (1, 4));
+        assert!(s.key_dir.is_empty());
+
+        s.compact()?;
+        assert_contents(&mut s)?;
+        let hashed = s.hashed_key_dir.as_ref().unwrap();
+        assert_eq!((hashed.slots.len(), hashed.len()), (1, 4));
+
+        s.delete(b"a")?;
+        s.set(b"a", vec![0x11])?;
+        s.delete(b"a")?;
+        assert_eq!(s.get(b"a")?, None);
+        s.set(b"a", vec![0x01])?;
+        drop(s);
+
+        let mut s = BitCask::with_config(path, config)?;
+        assert_contents(&mut s)?;
+        assert_eq!(s.hashed_key_dir.as_ref().unwrap().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the hashed key dir uses less memory than the ordered key
+    /// dir for long keys, and yields the same results.
+    fn hashed_key_dir_memory_usage() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        for i in 0..1000u32 {
+            s.set(format!("{i:0256}").as_bytes(), vec![0; 10])?;
+        }
+        let ordered = s.approximate_memory_usage();
+        let scan = s.scan(..).collect::<Result<Vec<_>>>()?;
+        drop(s);
+
+        let config = BitCaskConfig {
+            hashed_key_dir: true,
+            ..Default::default()
+        };
+        let mut h = BitCask::with_config(path, config)?;
+        let hashed = h.approximate_memory_usage();
+        assert!(hashed > 0);
+        assert!(
+            hashed * 2 < ordered,
+            "{hashed} not much less than {ordered}"
+        );
+
+        assert_eq!(h.scan(..).collect::<Result<Vec<_>>>()?, scan);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    /// Compares the key dir memory usage and read times of the ordered and
+    /// hashed key dirs with long keys. Run with
+    /// `cargo test --release -- --ignored --nocapture hashed_key_dir_memory`.
+    fn hashed_key_dir_memory() -> Result<()> {
+        const KEYS: usize = 200_000;
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        for i in 0..KEYS {
+            s.set(format!("{i:0100}").as_bytes(), vec![0x01; 100])?;
+        }
+        drop(s);
+
+        for hashed_key_dir in [false, true] {
+            let config = BitCaskConfig {
+                hashed_key_dir,
+                ..Default::default()
+            };
+            let start = std::time::Instant::now();
+            let mut s = BitCask::with_config(path.clone(), config)?;
+            let open = start.elapsed();
+            let start = std::time::Instant::now();
+            for i in (0..KEYS).step_by(7) {
+                s.get(format!("{i:0100}").as_bytes())?.unwrap();
+            }
+            let get = start.elapsed();
+            println!(
+                "hashed={hashed_key_dir}: {} bytes, open {open:?}, gets {get:?}",
+                s.approximate_memory_usage()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the bloom filter rules out absent keys without touching the
+    /// key dir or data files, that it grows with the key dir, and that it
+    /// drops deleted keys on compaction.
+    fn bloom_filter() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let config = BitCaskConfig {
+            bloom_false_positive_rate: Some(0.01),
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path.clone(), config.clone())?;
+        let key = |i: u32| format!("key{i}").into_bytes();
+        for i in 0..3000 {
+            s.set(&key(i), i.to_be_bytes().to_vec())?;
+        }
+        for i in 0..3000 {
+            assert_eq!(s.get(&key(i))?, Some(i.to_be_bytes().to_vec()));
+        }
+
+        // Point the absent keys at a bogus offset past the end of the file,
+        // such that any read fails. Keys ruled out by the filter are still
+        // absent, since neither the key dir nor the file are consulted.
+        let bogus = KeyDirEntry {
+            value_offset: 1 << 40,
+            ..s.key_dir[key(0).as_slice()]
+        };
+        let absent = (3000..13000)
+            .map(key)
+            .filter(|key| !s.bloom.as_ref().unwrap().contains(key))
+            .collect::<Vec<_>>();
+        assert!(absent.len() > 9800);
+        for key in &absent {
+            s.key_dir.insert(key.clone(), bogus);
+        }
+        for key in &absent {
+            assert_eq!(s.get(key)?, None);
+            assert_eq!(s.get_shared(key)?, None);
+        }
+        assert_eq!(s.get_many(&absent)?, vec![None; absent.len()]);
+        for key in &absent {
+            s.key_dir.remove(key.as_slice());
+        }
+
+        // Deleted keys remain in the filter until compaction rebuilds it.
+        for i in 0..1500 {
+            s.delete(&key(i))?;
+            assert_eq!(s.get(&key(i))?, None);
+        }
+        let bloom = s.bloom.as_ref().unwrap();
+        assert!((0..1500).all(|i| bloom.contains(&key(i))));
+        s.compact()?;
+        let bloom = s.bloom.as_ref().unwrap();
+        assert!((0..1500).filter(|i| bloom.contains(&key(*i))).count() < 50);
+        assert!((1500..3000).all(|i| bloom.contains(&key(i))));
+
+        // The filter is rebuilt on open, and can be disabled.
+        drop(s);
+        let mut s = BitCask::with_config(path.clone(), config)?;
+        assert_eq!(s.bloom.as_ref().unwrap().len(), 1500);
+        assert_eq!(s.get(&key(2999))?, Some(2999u32.to_be_bytes().to_vec()));
+        drop(s);
+        let s = BitCask::new(path.clone())?;
+        assert!(s.bloom.is_none());
+        drop(s);
+
+        let config = BitCaskConfig {
+            bloom_false_positive_rate: Some(1.0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            BitCask::with_config(path, config),
+            Err(Error::Config(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that compact_keys removes the stale entries of the given keys by
+    /// only compacting the oldest data files holding them, and that reads are
+    /// unaffected.
+    fn compact_keys() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                max_file_size: Some(256),
+                ..Default::default()
+            },
+        )?;
+        for i in 0..50u8 {
+            s.set(b"hot", vec![i; 16])?;
+        }
+        s.set(b"deleted", vec![0x01])?;
+        s.delete(b"deleted")?;
+        for i in 0..50u8 {
+            s.set(&[b'c', i], vec![i; 16])?;
+        }
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let keys = vec![b"hot".to_vec(), b"deleted".to_vec(), b"c\x00".to_vec()];
+        let hot_size = 49 * (20 + 3 + 16 + 4);
+        let deleted_size = 2 * (20 + 7 + 4) + 1;
+        assert_eq!(s.key_garbage(&keys)?, vec![hot_size, deleted_size, 0]);
+
+        // Only the files through the last stale entry are compacted.
+        let files = s.old_logs.len();
+        let garbage = s.status()?.garbage_disk_size;
+        s.compact_keys(&keys)?;
+        assert_eq!(s.key_garbage(&keys)?, vec![0, 0, 0]);
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert!(s.old_logs.len() > 1 && s.old_logs.len() < files);
+        assert!(s.status()?.garbage_disk_size < garbage);
+
+        // Compacting keys without stale entries is a noop, and stale entries
+        // in the active data file roll it over.
+        let files = s.old_logs.len();
+        s.compact_keys(&keys)?;
+        assert_eq!(s.old_logs.len(), files);
+        s.set(b"c\x00", vec![0x01])?;
+        s.set(b"c\x00", vec![0x02])?;
+        s.compact_keys(&keys)?;
+        assert_eq!(s.key_garbage(&keys)?, vec![0, 0, 0]);
+        assert_eq!(s.get(b"c\x00")?, Some(vec![0x02]));
+        drop(s);
+
+        let mut s = BitCask::new(path)?;
+        assert_eq!(s.get(b"hot")?, Some(vec![49; 16]));
+        assert_eq!(s.get(b"deleted")?, None);
+        assert_eq!(s.get(b"c\x00")?, Some(vec![0x02]));
+        assert_eq!(s.status()?.key_count, 51);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that estimate_garbage counts the overwritten and expired values
+    /// and tombstones of the keys in the range, matching hand-computed sizes.
+    fn estimate_garbage() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let mut s = BitCask::open(path, BitCaskConfig::default(), Box::new(clock.clone()))?;
+        // Entries take 20 header bytes and 4 checksum bytes besides the key
+        // and value.
+        s.set(b"a", vec![1, 2, 3])?; // overwritten: 24 + 1 + 3
+        s.set(b"a", vec![4])?;
+        s.set(b"bb", vec![5; 10])?; // deleted: 24 + 2 + 10
+        s.delete(b"bb")?; // tombstone: 24 + 2
+        s.set_with_ttl(b"c", vec![6; 5], Duration::from_secs(1))?; // expired: 24 + 1 + 5
+        s.delete(b"d")?; // tombstone: 24 + 1
+        let mut batch = WriteBatch::new();
+        batch.set(b"e", vec![7]);
+        s.write_batch(batch)?; // batch marker: not counted
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(s.estimate_garbage(..)?, 28 + 36 + 26 + 30 + 25);
+        assert_eq!(s.estimate_garbage(..=b"bb".to_vec())?, 28 + 36 + 26);
+        let range = b"b".to_vec()..b"d".to_vec();
+        assert_eq!(s.estimate_garbage(range)?, 36 + 26 + 30);
+        assert_eq!(s.estimate_garbage(b"e".to_vec()..)?, 0);
+
+        s.compact()?;
+        assert_eq!(s.estimate_garbage(..)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the active data file is rolled over once it reaches the
+    /// maximum file size, and that writes across data files are visible after
+    /// reopening, including with a hint file.
+    fn roll_over() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                max_file_size: Some(64),
+                ..Default::default()
+            },
+        )?;
+        setup_log(&mut s)?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", vec![0x0a]);
+        batch.delete(b"b");
+        s.write_batch(batch)?;
+        s.set(b"g", vec![0x07; 32])?;
+
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        assert!(s.old_logs.len() > 1);
+        for log in s.old_logs.values() {
+            assert!(log.file.len()? >= 64);
+        }
+        assert!(s.log.file.len()? < 64);
+        let status = s.status()?;
+        assert_eq!(
+            status.total_disk_size,
+            s.logs().map(|l| l.file.len().unwrap()).sum::<u64>()
+        );
+        assert_eq!(status.key_count, 5);
+
+        let mut names = vec!["yuudb".to_string()];
+        names.extend(s.old_logs.keys().map(|id| format!("yuudb.{id:06}")));
+        names.sort();
+        assert_eq!(file_names(&path)?, names);
+        drop(s);
+
+        // Reopening scans all data files in order, with or without rollover.
+        let mut s = BitCask::new(path.clone())?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert_eq!(s.get(b"b")?, None);
+        assert_eq!(s.get(b"e")?, None);
+        assert_eq!(s.status()?, status);
+        drop(s);
+
+        // A hint file covers all data files.
+        let config = BitCaskConfig {
+            hint_file: true,
+            max_file_size: Some(64),
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path.clone(), config)?;
+        s.set(b"h", vec![0x08; 64])?;
+        s.sync()?;
+        let key_dir = s.key_dir.clone();
+        drop(s);
+        let s = BitCask::new(path)?;
+        assert_eq!(s.log.read_hint(&s.old_logs, 0)?, Some(key_dir));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that compaction merges the immutable data files into a single
+    /// file, leaving the active data file alone.
+    fn compact_old_files() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                max_file_size: Some(64),
+                ..Default::default()
+            },
+        )?;
+        setup_log(&mut s)?;
+        s.set(b"b", vec![0x0b])?;
+        s.delete(b"c")?;
+
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let active_length = s.log.file.len()?;
+        let first_id = *s.old_logs.keys().next().unwrap();
+        assert!(s.old_logs.len() > 1);
+
+        s.compact()?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert_eq!(s.old_logs.len(), 1);
+        assert!(s.old_logs.contains_key(&first_id));
+        assert_eq!(s.log.file.len()?, active_length);
+        assert_eq!(
+            file_names(&path)?,
+            vec!["yuudb".to_string(), format!("yuudb.{first_id:06}")]
+        );
+
+        // Only garbage in the active data file remains.
+        let active_garbage = s.status()?.garbage_disk_size;
+        s.compact()?;
+        assert_eq!(s.status()?.garbage_disk_size, active_garbage);
+        drop(s);
+
+        let mut s = BitCask::new(path)?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a crash after writing the compacted file, but before renaming
+    /// it into place, leaves the data intact, and that the leftover file is
+    /// removed on the next open.
+    fn compact_crash() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                max_file_size: Some(64),
+                ..Default::default()
+            },
+        )?;
+        setup_log(&mut s)?;
+        s.set(b"b", vec![0x0b])?;
+        s.delete(b"c")?;
         let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let names = file_names(&path)?;
+
+        // Crash before finishing the compaction.
+        let compaction = s.prepare_compaction()?;
+        let (new_log, _) = compaction.run()?;
+        drop(new_log);
+        drop(compaction);
+        drop(s);
+        assert!(file_names(&path)?.contains(&"yuudb.new".to_string()));
+
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                max_file_size: Some(64),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(file_names(&path)?, names);
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+
+        // Compaction still works.
+        s.compact()?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
         drop(s);
         let mut s = BitCask::new(path)?;
-        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?,);
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        Ok(())
+    }
+
+    /// A store that fails writes once the given number of bytes have been
+    /// written, like a full disk.
+    pub(super) struct DiskFull {
+        pub(super) file: Box<dyn ReadWriteSeek>,
+        pub(super) limit: u64,
+    }
+
+    impl Read for DiskFull {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl Write for DiskFull {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() as u64 > self.limit {
+                return Err(std::io::ErrorKind::StorageFull.into());
+            }
+            let n = self.file.write(buf)?;
+            self.limit -= n as u64;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for DiskFull {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl ReadWriteSeek for DiskFull {
+        fn len(&self) -> std::io::Result<u64> {
+            self.file.len()
+        }
+
+        fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+            self.file.set_len(length)
+        }
+
+        fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+            self.file.read_exact_at(buffer, offset)
+        }
+
+        fn try_clone(&self) -> std::io::Result<Box<dyn ReadWriteSeek>> {
+            self.file.try_clone()
+        }
+    }
+
+    #[test]
+    /// Tests that a compaction failing to write its temporary file leaves the
+    /// data intact and removes the temporary file, unless configured to keep
+    /// it.
+    fn compact_write_error() -> Result<()> {
+        for keep in [false, true] {
+            let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+            let config = BitCaskConfig {
+                keep_failed_compaction: keep,
+                ..Default::default()
+            };
+            let mut s = BitCask::with_config(path.clone(), config)?;
+            setup_log(&mut s)?;
+            s.set(b"b", vec![0x0b; 100])?;
+            let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+            let names = file_names(&path)?;
+            let file = std::fs::read(&path)?;
 
+            let mut compaction = s.prepare_compaction()?;
+            compaction.write_limit = Some(32);
+            let result = compaction.run();
+            assert!(matches!(
+                result,
+                Err(Error::Io {
+                    kind: std::io::ErrorKind::StorageFull,
+                    ..
+                })
+            ));
+            assert!(s.finish_compaction(compaction, result).is_err());
+
+            assert_eq!(std::fs::read(&path)?, file);
+            assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+            s.validate_key_dir()?;
+            if keep {
+                assert!(file_names(&path)?.contains(&"yuudb.new".to_string()));
+            } else {
+                assert_eq!(file_names(&path)?, names);
+            }
+
+            // The next compaction succeeds.
+            s.compact()?;
+            assert_eq!(file_names(&path)?, names);
+            assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        }
         Ok(())
     }
 
     #[test]
-    /// Tests log compaction, by writing golden files of the before/after state,
-    /// and checking that the database contains the same results, even after
-    /// reopening the file.
-    fn compact() -> Result<()> {
-        // NB: Don't use setup(), because the tempdir will be removed when
-        // the path falls out of scope.
-        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
-        let mut s = BitCask::new(path.clone())?;
-        setup_log(&mut s)?;
+    /// Tests that background compaction runs while writes continue, without
+    /// losing writes made during compaction.
+    fn background_compaction() -> Result<()> {
+        use rand::{Rng, SeedableRng};
 
-        // Dump the initial log file.
-        let mut mint = goldenfile::Mint::new(GOLDEN_DIR);
-        s.log.print(&mut mint.new_goldenfile("compact-before")?)?;
-        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let db = Arc::new(Mutex::new(BitCask::new(path.clone())?));
+        let compactor = BitCask::spawn_compactor(&db, 0.0, Duration::from_millis(1));
 
-        // Compact the log file and assert the new log file contents.
-        s.compact()?;
-        assert_eq!(path, s.log.path);
-        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?,);
-        s.log.print(&mut mint.new_goldenfile("compact-after")?)?;
+        // Write continuously until the active data file has been rolled over
+        // by several compactions.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(19);
+        let mut expect = std::collections::BTreeMap::new();
+        let deadline = std::time::Instant::now() + Duration::from_secs(30);
+        while db.lock().unwrap().log.id < 5 {
+            assert!(std::time::Instant::now() < deadline, "not compacted");
+            for _ in 0..100 {
+                let key = vec![rng.gen_range(0..100u8)];
+                let mut s = db.lock().unwrap();
+                if rng.gen_bool(0.2) {
+                    s.delete(&key)?;
+                    expect.remove(&key);
+                } else {
+                    let value = rng.gen::<[u8; 16]>().to_vec();
+                    s.set(&key, value.clone())?;
+                    expect.insert(key.clone(), value);
+                }
+                assert_eq!(s.get(&key)?, expect.get(&key).cloned());
+            }
+        }
+        compactor.stop()?;
 
-        // Reopen the log file and assert that the contents are the same.
+        let expect = expect.into_iter().collect::<Vec<_>>();
+        let mut s = Arc::into_inner(db).unwrap().into_inner().unwrap();
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert!(s.old_logs.len() <= 1);
         drop(s);
+
         let mut s = BitCask::new(path)?;
-        assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?,);
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the compactor thread exits when the database is dropped,
+    /// and that compactions can't overlap.
+    fn compactor_exits() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path)?;
+        setup_log(&mut s)?;
+        let compaction = s.prepare_compaction()?;
+        assert!(matches!(s.compact(), Err(Error::Internal(_))));
+        let result = compaction.run();
+        s.finish_compaction(compaction, result)?;
+        s.compact()?;
+
+        let db = Arc::new(Mutex::new(s));
+        let compactor = BitCask::spawn_compactor(&db, 0.0, Duration::from_millis(1));
+        drop(db);
+        compactor.join()
+    }
+
+    #[test]
+    /// Tests that cached values are read without hitting the data file, by
+    /// truncating it such that any value read would fail, and that writes
+    /// invalidate the cache.
+    fn cache() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                cache_size: 1024,
+                ..Default::default()
+            },
+        )?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"c", vec![0x03])?;
+        assert_eq!(s.get(b"a")?, Some(vec![0x01]));
+        assert_eq!(s.get_shared(b"b")?, Some(vec![0x02]));
+
+        s.log.file.set_len(HEADER_LENGTH)?;
+        assert_eq!(s.get(b"a")?, Some(vec![0x01]));
+        assert_eq!(s.get(b"b")?, Some(vec![0x02]));
+        assert!(s.get(b"c").is_err());
+
+        s.set(b"a", vec![0x0a])?;
+        s.delete(b"b")?;
+        s.log.file.set_len(HEADER_LENGTH)?;
+        assert!(s.get(b"a").is_err());
+        assert_eq!(s.get(b"b")?, None);
 
         Ok(())
     }
 
     #[test]
-    /// Tests that new_compact() will automatically compact the file when appropriate.
-    fn new_compact() -> Result<()> {
-        // Create an initial log file with a few entries.
+    /// Tests that the value cache evicts the least recently used values.
+    fn cache_eviction() {
+        let mut cache = ValueCache::new(10);
+        cache.insert(b"a", b"1234");
+        cache.insert(b"b", b"1234");
+        assert_eq!(cache.get(b"a"), Some(b"1234".to_vec()));
+
+        // Inserting c evicts b, which was used less recently than a.
+        cache.insert(b"c", b"12");
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.get(b"a"), Some(b"1234".to_vec()));
+        assert_eq!(cache.get(b"c"), Some(b"12".to_vec()));
+        assert_eq!(cache.size, 8);
+
+        // Replacing a value updates the size, and oversized values are not
+        // cached nor evict other values.
+        cache.insert(b"c", b"1");
+        assert_eq!(cache.size, 7);
+        cache.insert(b"d", b"1234567890");
+        assert_eq!(cache.get(b"d"), None);
+        assert_eq!(cache.size, 7);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    /// Tests that reads through the map still work after a failed write
+    /// truncated the file back, and that later writes are mapped.
+    fn mmap_disk_full() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                mmap: true,
+                ..Default::default()
+            },
+        )?;
+        s.set(b"a", vec![1; 10])?;
+        s.set(b"b", vec![2; 10])?;
+        assert_eq!(s.get(b"b")?, Some(vec![2; 10]));
+        let size = s.log.file.len()?;
+
+        s.log.disk_limit = Some(size + 30);
+        assert!(s.set(b"c", vec![3; 100]).is_err());
+        assert_eq!(s.log.file.len()?, size);
+        assert!(s.log.mmap.is_some());
+        assert_eq!(s.get(b"a")?, Some(vec![1; 10]));
+        assert_eq!(s.get(b"b")?, Some(vec![2; 10]));
+
+        s.log.disk_limit = None;
+        s.set(b"c", vec![3; 100])?;
+        assert_eq!(s.get(b"c")?, Some(vec![3; 100]));
+        assert_eq!(s.scan(..).count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    /// Tests that memory-mapped reads yield the same results as regular reads,
+    /// including after the file grows past the mapping, compaction, rollover
+    /// and reopening.
+    fn mmap() -> Result<()> {
         let dir = tempdir::TempDir::new("yuudb")?;
-        let path = dir.path().join("orig");
-        let compactpath = dir.path().join("compact");
+        let path = dir.path().join("mmap");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                mmap: true,
+                ..Default::default()
+            },
+        )?;
+        let mut r = BitCask::new(dir.path().join("read"))?;
+
+        fn assert_same(s: &mut BitCask, r: &mut BitCask) -> Result<()> {
+            let expect = r.scan(..).collect::<Result<Vec<_>>>()?;
+            assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+            assert_eq!(
+                s.scan(..).rev().collect::<Result<Vec<_>>>()?,
+                r.scan(..).rev().collect::<Result<Vec<_>>>()?
+            );
+            for (key, value) in expect {
+                assert_eq!(s.get(&key)?, Some(value));
+            }
+            Ok(())
+        }
 
-        let mut s = BitCask::new_compact(path.clone(), 0.2)?;
         setup_log(&mut s)?;
-        let status = s.status()?;
-        let garbage_ratio = status.garbage_disk_size as f64 / status.total_disk_size as f64;
-        drop(s);
+        setup_log(&mut r)?;
+        assert!(s.log.mmap.is_some());
+        assert_same(&mut s, &mut r)?;
 
-        // Test a few threshold value and assert whether it should trigger compaction.
-        let cases = vec![
-            (-1.0, true),
-            (0.0, true),
-            (garbage_ratio - 0.001, true),
-            (garbage_ratio, true),
-            (garbage_ratio + 0.001, false),
-            (1.0, false),
-            (2.0, false),
-        ];
-        for (threshold, expect_compact) in cases.into_iter() {
-            std::fs::copy(&path, &compactpath)?;
-            let mut s = BitCask::new_compact(compactpath.clone(), threshold)?;
-            let new_status = s.status()?;
-            assert_eq!(new_status.live_disk_size, status.live_disk_size);
-            if expect_compact {
-                assert_eq!(new_status.total_disk_size, status.live_disk_size);
-                assert_eq!(new_status.garbage_disk_size, 0);
-            } else {
-                assert_eq!(new_status, status);
-            }
+        for i in 0..100u8 {
+            s.set(&[i], vec![i; i as usize * 10])?;
+            r.set(&[i], vec![i; i as usize * 10])?;
+            assert_eq!(s.get(&[i])?, Some(vec![i; i as usize * 10]));
         }
+        assert_same(&mut s, &mut r)?;
+
+        s.compact()?;
+        assert!(s.log.mmap.is_some());
+        assert_same(&mut s, &mut r)?;
 
+        s.roll_over()?;
+        s.set(b"new", vec![0x01])?;
+        r.set(b"new", vec![0x01])?;
+        assert!(s.log.mmap.is_some());
+        assert_same(&mut s, &mut r)?;
+
+        s.compact()?;
+        assert!(s.old_logs.values().all(|log| log.mmap.is_some()));
+        assert_same(&mut s, &mut r)?;
+
+        drop(s);
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                mmap: true,
+                ..Default::default()
+            },
+        )?;
+        assert_same(&mut s, &mut r)?;
         Ok(())
     }
 
     #[test]
-    /// Tests that exclusive locks are taken out on log files, released when the
-    /// database is closed, and that an error is returned if a lock is already
-    /// held.
-    fn log_lock() -> Result<()> {
+    /// Tests that clear resets the data files on disk, such that the database
+    /// is empty when reopened.
+    fn clear() -> Result<()> {
         let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
-        let s = BitCask::new(path.clone())?;
+        let config = BitCaskConfig {
+            hint_file: true,
+            max_file_size: Some(64),
+            cache_size: 1024,
+            ..Default::default()
+        };
+        let mut s = BitCask::with_config(path.clone(), config)?;
+        setup_log(&mut s)?;
+        s.sync()?;
+        assert!(!s.old_logs.is_empty());
 
-        assert!(BitCask::new(path.clone()).is_err());
+        s.clear()?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, vec![]);
+        assert_eq!(s.get(b"a")?, None);
+        assert!(s.old_logs.is_empty());
+        assert_eq!(s.log.file.len()?, HEADER_LENGTH);
+        assert_eq!(file_names(&path)?, vec!["yuudb"]);
         drop(s);
-        assert!(BitCask::new(path.clone()).is_ok());
 
+        let mut s = BitCask::new(path)?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, vec![]);
+        let status = s.status()?;
+        assert_eq!(status.key_count, 0);
+        assert_eq!(status.total_disk_size, HEADER_LENGTH);
         Ok(())
     }
 
     #[test]
-    /// Tests that an incomplete write at the end of the log file can be
-    /// recovered by discarding the last entry.
-    fn recovery() -> Result<()> {
-        // Create an initial log file with a few entries.
-        let dir = tempdir::TempDir::new("yuudb")?;
-        let path = dir.path().join("complete");
-        let truncpath = dir.path().join("truncated");
+    /// Tests that limited scans don't read values past the limit, by
+    /// truncating the log such that reading the next value would fail.
+    fn scan_limit() -> Result<()> {
+        let mut s = setup()?;
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"c", vec![0x03])?;
+        let offset = s.key_dir[b"c".as_slice()].value_offset;
+        s.log.file.set_len(offset)?;
 
-        let mut log = Log::new(path.clone())?;
-        let mut ends = vec![];
+        assert_eq!(
+            s.scan_limit(.., 2).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![0x01]), (b"b".to_vec(), vec![0x02])]
+        );
+        assert!(s.scan_limit(.., 3).collect::<Result<Vec<_>>>().is_err());
+        Ok(())
+    }
 
-        let (pos, len) = log.append_entry("deleted".as_bytes(), Some(&[1, 2, 3]))?;
-        ends.push(pos + len as u64);
+    #[test]
+    /// Tests that key scans don't read values from the log, by truncating it
+    /// such that any value read would fail.
+    fn scan_keys() -> Result<()> {
+        let mut s = setup()?;
+        setup_log(&mut s)?;
+        s.log.file.set_len(HEADER_LENGTH)?;
 
-        let (pos, len) = log.append_entry("deleted".as_bytes(), None)?;
-        ends.push(pos + len as u64);
+        assert!(s.scan(..).collect::<Result<Vec<_>>>().is_err());
+        assert_eq!(
+            s.scan_keys(..).collect::<Result<Vec<_>>>()?,
+            vec![
+                b"".to_vec(),
+                b"a".to_vec(),
+                b"b".to_vec(),
+                b"c".to_vec(),
+                b"d".to_vec()
+            ]
+        );
 
-        let (pos, len) = log.append_entry(&[], Some(&[]))?;
-        ends.push(pos + len as u64);
+        Ok(())
+    }
 
-        let (pos, len) = log.append_entry("key".as_bytes(), Some(&[1, 2, 3, 4, 5]))?;
-        ends.push(pos + len as u64);
+    #[test]
+    /// Tests that writes with the EveryWrite sync policy survive a simulated
+    /// process restart, where the database is not synced or closed.
+    fn sync_every_write() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::with_config(
+            path.clone(),
+            BitCaskConfig {
+                sync_policy: SyncPolicy::EveryWrite,
+                ..Default::default()
+            },
+        )?;
+        setup_log(&mut s)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
 
-        drop(log);
+        // Skip the sync on drop, but release the lock so it can be reopened.
+        fs4::FileExt::unlock(s.log.file.as_file().unwrap())?;
+        std::mem::forget(s);
 
-        // Copy the file, and truncate it at each byte, then try to open it
-        // and assert that we always retain a prefix of entries.
-        let size = std::fs::metadata(&path)?.len();
-        for pos in 0..=size {
-            std::fs::copy(&path, &truncpath)?;
-            let f = std::fs::OpenOptions::new().write(true).open(&truncpath)?;
-            f.set_len(pos)?;
-            drop(f);
+        let mut s = BitCask::new(path)?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        Ok(())
+    }
 
-            let mut expect = vec![];
-            if pos >= ends[0] {
-                expect.push((b"deleted".to_vec(), vec![1, 2, 3]))
-            }
-            if pos >= ends[1] {
-                expect.pop(); // "deleted" key removed
-            }
-            if pos >= ends[2] {
-                expect.push((b"".to_vec(), vec![]))
-            }
-            if pos >= ends[3] {
-                expect.push((b"key".to_vec(), vec![1, 2, 3, 4, 5]))
+    #[test]
+    /// Tests that the Interval sync policy syncs on the first write after
+    /// the interval has elapsed.
+    fn sync_interval() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        clock.advance(Duration::from_secs(1));
+        let config = BitCaskConfig {
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut s = BitCask::open(path, config, Box::new(clock.clone()))?;
+        assert_eq!(s.last_sync, 1000);
+
+        clock.advance(Duration::from_millis(99));
+        s.set(b"a", vec![1])?;
+        assert_eq!(s.last_sync, 1000);
+
+        clock.advance(Duration::from_millis(1));
+        s.set(b"b", vec![2])?;
+        assert_eq!(s.last_sync, 1100);
+
+        clock.advance(Duration::from_millis(150));
+        s.delete(b"a")?;
+        assert_eq!(s.last_sync, 1250);
+
+        // Syncing always syncs, flushing doesn't.
+        clock.advance(Duration::from_millis(10));
+        s.flush()?;
+        assert_eq!(s.last_sync, 1250);
+        s.sync()?;
+        assert_eq!(s.last_sync, 1260);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the Threshold sync policy syncs once either the write count
+    /// or the written bytes since the last sync reach their threshold.
+    fn sync_threshold() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        let config = BitCaskConfig {
+            sync_policy: SyncPolicy::Threshold {
+                writes: 3,
+                bytes: 1024,
+            },
+            ..Default::default()
+        };
+        let mut s = BitCask::open(path.clone(), config, Box::new(clock.clone()))?;
+        let size = s.log.file.len()?;
+
+        // Each write advances the clock, to tell when the last sync happened.
+        let write = |s: &mut BitCask, key: &[u8], value: Option<Vec<u8>>| {
+            clock.advance(Duration::from_millis(1));
+            match value {
+                Some(value) => s.set(key, value),
+                None => s.delete(key),
             }
+        };
+        write(&mut s, b"a", Some(vec![1]))?;
+        write(&mut s, b"b", Some(vec![2]))?;
+        assert_eq!(s.last_sync, 0);
+        assert_eq!(s.writes_since_sync, 2);
+        assert_eq!(s.bytes_since_sync, s.log.file.len()? - size);
+        write(&mut s, b"a", None)?;
+        assert_eq!(s.last_sync, 3);
+        assert_eq!((s.writes_since_sync, s.bytes_since_sync), (0, 0));
 
-            let mut s = BitCask::new(truncpath.clone())?;
-            assert_eq!(expect, s.scan(..).collect::<Result<Vec<_>>>()?);
-        }
+        // A large write reaches the byte threshold first.
+        write(&mut s, b"c", Some(vec![3; 900]))?;
+        assert_eq!(s.last_sync, 3);
+        write(&mut s, b"d", Some(vec![4; 200]))?;
+        assert_eq!(s.last_sync, 5);
+        write(&mut s, b"e", Some(vec![5]))?;
+        assert_eq!(s.writes_since_sync, 1);
 
+        // Syncing resets the counters.
+        s.sync()?;
+        assert_eq!((s.writes_since_sync, s.bytes_since_sync), (0, 0));
+        drop(s);
+        let mut s = BitCask::new(path)?;
+        assert_eq!(s.get(b"d")?, Some(vec![4; 200]));
+        assert_eq!(s.status()?.key_count, 4);
         Ok(())
     }
 
     #[test]
-    /// Tests status(), both for a log file with known garbage, and
-    /// after compacting it when the live size must equal the file size.
-    fn status_full() -> Result<()> {
-        let mut s = setup()?;
+    /// Tests that flushed writes survive a simulated process restart, but that
+    /// only syncing fsyncs them and writes the hint file. An OS crash can't be
+    /// simulated here, so this can't show that unsynced writes may be lost.
+    fn flush_and_sync() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        clock.advance(Duration::from_secs(1));
+        let config = BitCaskConfig {
+            hint_file: true,
+            sync_policy: SyncPolicy::Never,
+            ..Default::default()
+        };
+        let mut s = BitCask::open(path.clone(), config, Box::new(clock.clone()))?;
         setup_log(&mut s)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let hint_path = s.log.hint_path();
 
-        // Before compaction.
-        assert_eq!(
-            s.status()?,
-            Status {
-                name: "bitcask".to_string(),
-                key_count: 5,
-                size: 8,
-                total_disk_size: 114,
-                live_disk_size: 48,
-                garbage_disk_size: 66
-            }
-        );
+        clock.advance(Duration::from_millis(10));
+        s.flush()?;
+        assert_eq!(s.last_sync, 1000);
+        assert!(!hint_path.exists());
 
-        // After compaction.
-        s.compact()?;
-        assert_eq!(
-            s.status()?,
-            Status {
-                name: "bitcask".to_string(),
-                key_count: 5,
-                size: 8,
-                total_disk_size: 48,
-                live_disk_size: 48,
-                garbage_disk_size: 0,
+        // Skip the sync on drop, but release the lock so it can be reopened.
+        fs4::FileExt::unlock(s.log.file.as_file().unwrap())?;
+        std::mem::forget(s);
+        let mut s = BitCask::with_config(
+            path,
+            BitCaskConfig {
+                hint_file: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+
+        s.sync()?;
+        assert!(hint_path.exists());
+        Ok(())
+    }
+
+    /// Computes the status by folding over the key dir, for comparison with
+    /// the incrementally maintained status.
+    fn folded_status(s: &BitCask) -> Result<Status> {
+        let now = s.clock.now();
+        let (mut key_count, mut size, mut live_disk_size, mut total_disk_size) = (0, 0, 0, 0);
+        for log in s.logs() {
+            live_disk_size += log.header_length();
+            total_disk_size += log.file.len()?;
+        }
+        for (key, entry) in s.key_dir.iter().filter(|(_, e)| !e.is_expired(now)) {
+            let log = s.log(entry.file_id);
+            key_count += 1;
+            size += key.len() as u64 + entry.value_length;
+            live_disk_size += log.entry_header_length() + log.checksum_length() as u64;
+        }
+        live_disk_size += size;
+        let mut status = Status {
+            name: s.to_string(),
+            key_count,
+            size,
+            total_disk_size,
+            live_disk_size,
+            garbage_disk_size: total_disk_size - live_disk_size,
+            tombstone_count: s.tombstones().0,
+            file_count: s.logs().count() as u64,
+            compaction_recommended: false,
+        };
+        status.compaction_recommended = s
+            .compaction_threshold
+            .is_some_and(|threshold| garbage_reached(&status, threshold));
+        Ok(status)
+    }
+
+    #[test]
+    /// Tests that the incrementally maintained status matches a fold over the
+    /// key dir after random operations, including expiries, roll-overs and
+    /// compactions.
+    fn status_incremental() -> Result<()> {
+        use rand::{Rng, SeedableRng};
+
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let clock = TestClock::default();
+        clock.advance(Duration::from_secs(1));
+        let config = BitCaskConfig {
+            max_file_size: Some(512),
+            ..Default::default()
+        };
+        let mut s = BitCask::open(path.clone(), config.clone(), Box::new(clock.clone()))?;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(34);
+        for i in 0..2000 {
+            let key = vec![rng.gen_range(0..50u8)];
+            let value = vec![0; rng.gen_range(0..20)];
+            let ttl = Duration::from_millis(rng.gen_range(1..50));
+            match rng.gen_range(0..100) {
+                0..=39 => s.set(&key, value)?,
+                40..=59 => s.set_with_ttl(&key, value, ttl)?,
+                60..=74 => s.delete(&key)?,
+                75..=84 => {
+                    s.get(&key)?;
+                }
+                85..=94 => {
+                    let mut batch = WriteBatch::new();
+                    batch.set(&key, value);
+                    batch.delete(&[rng.gen_range(0..50u8)]);
+                    s.write_batch(batch)?;
+                }
+                95..=97 => clock.advance(Duration::from_millis(rng.gen_range(1..20))),
+                98 => s.compact()?,
+                _ => s.clear()?,
             }
-        );
+            assert_eq!(s.status()?, folded_status(&s)?, "operation {i}");
+        }
+        assert!(s.log.id > 1);
 
+        let key_count = s.status()?.key_count;
+        drop(s);
+        let mut s = BitCask::open(path, config, Box::new(clock))?;
+        assert_eq!(s.status()?, folded_status(&s)?);
+        assert_eq!(s.status()?.key_count, key_count);
         Ok(())
     }
 }
@@ -0,0 +1,142 @@
+use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
+
+use crate::error::{Error, Result};
+
+/// A bloom filter over byte keys, which answers whether a key may have been
+/// inserted, with no false negatives and a configurable false positive rate.
+/// Keys can't be removed, so the filter must be rebuilt to forget them.
+///
+/// The filter is sized for an expected number of keys, and its false
+/// positive rate rises above the target once more keys are inserted, which
+/// callers can check with [`BloomFilter::is_full`] to rebuild it larger.
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    /// The number of bits, i.e. the number of usable bits in `bits`.
+    bit_count: u64,
+    /// The number of bits set per key.
+    hash_count: u32,
+    /// The number of keys the filter is sized for.
+    capacity: usize,
+    /// The number of keys inserted.
+    len: usize,
+    /// The target false positive rate at capacity.
+    false_positive_rate: f64,
+}
+
+impl BloomFilter {
+    /// Creates a filter for the given number of keys, with the given target
+    /// false positive rate, which must be between 0 and 1 exclusive.
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Result<Self> {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(Error::Config(format!(
+                "Invalid bloom filter false positive rate {false_positive_rate}"
+            )));
+        }
+        Ok(Self::build(capacity, false_positive_rate))
+    }
+
+    /// Returns an empty filter for the given number of keys, with the same
+    /// target false positive rate, e.g. to rebuild a full filter larger.
+    pub fn with_capacity(&self, capacity: usize) -> Self {
+        Self::build(capacity, self.false_positive_rate)
+    }
+
+    /// Builds a filter with a valid false positive rate.
+    fn build(capacity: usize, false_positive_rate: f64) -> Self {
+        // The optimal number of bits is -n ln(p) / ln(2)^2, with ln(2) * m/n
+        // hash functions.
+        let capacity = capacity.max(1);
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let bit_count = (bit_count as u64).max(64);
+        let hash_count = ((bit_count as f64 / capacity as f64) * ln2)
+            .round()
+            .max(1.0) as u32;
+        Self {
+            bits: vec![0; bit_count.div_ceil(64) as usize],
+            bit_count,
+            hash_count,
+            capacity,
+            len: 0,
+            false_positive_rate,
+        }
+    }
+
+    /// Inserts a key.
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_indexes(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Returns whether the key may have been inserted. False means the key
+    /// was definitely not inserted.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.bit_indexes(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns the number of keys inserted, including duplicates.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the size of the filter's bits, in bytes.
+    pub fn memory_usage(&self) -> u64 {
+        (self.bits.len() * std::mem::size_of::<u64>()) as u64
+    }
+
+    /// Returns whether the filter holds as many keys as it's sized for, such
+    /// that further inserts raise the false positive rate above the target.
+    pub fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    /// Returns the bits to set for a key, using double hashing of a 64-bit
+    /// hash, i.e. `h1 + i * h2` for each hash function `i`.
+    fn bit_indexes(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        let hash = BuildHasherDefault::<DefaultHasher>::default().hash_one(key);
+        let (h1, h2) = (hash & 0xffffffff, (hash >> 32) | 1);
+        let bit_count = self.bit_count;
+        (0..self.hash_count as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Tests that inserted keys are always found, and that the false positive
+    /// rate of absent keys is close to the target.
+    fn false_positives() -> Result<()> {
+        for rate in [0.1, 0.01, 0.001] {
+            let mut filter = BloomFilter::new(10000, rate)?;
+            for i in 0..10000u32 {
+                filter.insert(&i.to_be_bytes());
+            }
+            assert!(filter.is_full());
+            assert!((0..10000u32).all(|i| filter.contains(&i.to_be_bytes())));
+
+            let positives = (10000..110000u32)
+                .filter(|i| filter.contains(&i.to_be_bytes()))
+                .count();
+            let actual = positives as f64 / 100000.0;
+            assert!(actual < rate * 1.5, "rate {actual} for target {rate}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_rate() {
+        for rate in [0.0, 1.0, -0.5, f64::NAN] {
+            assert!(matches!(BloomFilter::new(10, rate), Err(Error::Config(_))));
+        }
+    }
+}
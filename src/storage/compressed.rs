@@ -0,0 +1,388 @@
+/*!
+An engine wrapper that transparently compresses values.
+
+Each stored value is prefixed with a 1-byte codec tag, followed by the
+compressed value. Values that do not shrink when compressed are stored raw
+with the [`Codec::None`] tag, and every value is decompressed according to its
+own tag, so a store can be reopened with a different codec. Keys are stored
+as-is, which preserves their ordering for scans.
+*/
+
+use super::engine::{Engine, ReadEngine, Status, WriteBatch};
+use crate::error::{Error, Result};
+
+/// A compression codec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    /// The zstd compression level.
+    const ZSTD_LEVEL: i32 = 3;
+
+    /// The maximum ratio of decompressed to compressed size for lz4, which
+    /// encodes at most 255 bytes of a match per byte.
+    const LZ4_MAX_RATIO: usize = 255;
+
+    fn tag(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Lz4),
+            2 => Ok(Self::Zstd),
+            tag => Err(Error::Internal(format!("Invalid codec tag {tag}"))),
+        }
+    }
+
+    fn compress(self, value: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(value.to_vec()),
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(value)),
+            Self::Zstd => Ok(zstd::bulk::compress(value, Self::ZSTD_LEVEL)?),
+        }
+    }
+
+    fn decompress(self, value: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(value.to_vec()),
+            Self::Lz4 => Self::decompress_lz4(value),
+            Self::Zstd => Ok(zstd::stream::decode_all(value)?),
+        }
+    }
+
+    /// Decompresses an lz4 value with a prepended size. The size is checked
+    /// against the maximum compression ratio before allocating the output,
+    /// such that a corrupt size can't cause a huge allocation.
+    fn decompress_lz4(value: &[u8]) -> Result<Vec<u8>> {
+        let Some((size, compressed)) = value.split_first_chunk::<4>() else {
+            return Err(Error::Parse("Missing lz4 size prefix".to_string()));
+        };
+        let size = u32::from_le_bytes(*size) as usize;
+        let limit = compressed.len().saturating_mul(Self::LZ4_MAX_RATIO);
+        if size > limit {
+            return Err(Error::Parse(format!(
+                "lz4 size {size} exceeds the maximum of {limit} for {} compressed bytes",
+                compressed.len()
+            )));
+        }
+        lz4_flex::decompress(compressed, size)
+            .map_err(|error| Error::Internal(format!("lz4 decompression failed: {error}")))
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Lz4 => write!(f, "lz4"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Encodes a value with the codec tag, storing it raw if compression does not
+/// shrink it.
+fn encode(codec: Codec, value: &[u8]) -> Result<Vec<u8>> {
+    let compressed = codec.compress(value)?;
+    let (codec, payload) = if compressed.len() < value.len() {
+        (codec, compressed.as_slice())
+    } else {
+        (Codec::None, value)
+    };
+    let mut encoded = Vec::with_capacity(1 + payload.len());
+    encoded.push(codec.tag());
+    encoded.extend_from_slice(payload);
+    Ok(encoded)
+}
+
+/// Decodes a value encoded by [`encode`].
+fn decode(encoded: &[u8]) -> Result<Vec<u8>> {
+    match encoded.split_first() {
+        Some((tag, payload)) => Codec::from_tag(*tag)?.decompress(payload),
+        None => Err(Error::Internal("Missing codec tag".to_string())),
+    }
+}
+
+/// An engine that compresses values with the given codec before passing them
+/// to the wrapped engine.
+pub struct CompressedEngine<E: Engine> {
+    inner: E,
+    codec: Codec,
+}
+
+impl<E: Engine> CompressedEngine<E> {
+    pub fn new(inner: E, codec: Codec) -> Self {
+        Self { inner, codec }
+    }
+
+    /// Returns the codec used for new writes.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Engine> std::fmt::Display for CompressedEngine<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.inner, self.codec)
+    }
+}
+
+pub struct ScanIterator<'a, E: Engine + 'a> {
+    inner: E::ScanIterator<'a>,
+}
+
+impl<'a, E: Engine + 'a> ScanIterator<'a, E> {
+    fn map(item: <Self as Iterator>::Item) -> <Self as Iterator>::Item {
+        let (key, value) = item?;
+        Ok((key, decode(&value)?))
+    }
+}
+
+impl<'a, E: Engine + 'a> Iterator for ScanIterator<'a, E> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(Self::map)
+    }
+}
+
+impl<'a, E: Engine + 'a> DoubleEndedIterator for ScanIterator<'a, E> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(Self::map)
+    }
+}
+
+impl<E: Engine> Engine for CompressedEngine<E> {
+    type ScanIterator<'a>
+        = ScanIterator<'a, E>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.inner.set(key, encode(self.codec, &value)?)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)?.map(|value| decode(&value)).transpose()
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner
+            .get_many(keys)?
+            .into_iter()
+            .map(|value| value.map(|value| decode(&value)).transpose())
+            .collect()
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<u64> {
+        self.inner.delete_prefix(prefix)
+    }
+
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.delete_range(range)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut encoded = WriteBatch::new();
+        for (key, value) in batch {
+            match value {
+                Some(value) => encoded.set(&key, encode(self.codec, &value)?),
+                None => encoded.delete(&key),
+            }
+        }
+        self.inner.write_batch(encoded)
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.estimate_garbage(range)
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    /// Returns the status of the inner engine. The size reflects the stored,
+    /// possibly compressed, values excluding the codec tags.
+    fn status(&mut self) -> Result<Status> {
+        let status = self.inner.status()?;
+        Ok(Status {
+            name: self.to_string(),
+            size: status.size.saturating_sub(status.key_count),
+            ..status
+        })
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        ScanIterator {
+            inner: self.inner.scan(range),
+        }
+    }
+
+    fn scan_bytes(
+        &mut self,
+        start: std::ops::Bound<&[u8]>,
+        end: std::ops::Bound<&[u8]>,
+    ) -> Self::ScanIterator<'_> {
+        ScanIterator {
+            inner: self.inner.scan_bytes(start, end),
+        }
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.inner.scan_keys(range)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.inner.count(range)
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for CompressedEngine<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .get_shared(key)?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        ScanIterator {
+            inner: self.inner.scan_shared(range),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    /// A compressible JSON-like value.
+    fn json_value() -> Vec<u8> {
+        let mut value = b"[".to_vec();
+        for i in 0..100 {
+            value.extend_from_slice(format!("{{\"id\":{i},\"name\":\"item\"}},").as_bytes());
+        }
+        value.push(b']');
+        value
+    }
+
+    #[test]
+    /// Tests that values round-trip through each codec, and that compressible
+    /// values are stored compressed while others are stored raw.
+    fn round_trip() -> Result<()> {
+        let json = json_value();
+        for codec in [Codec::None, Codec::Lz4, Codec::Zstd] {
+            let mut s = CompressedEngine::new(Memory::new(), codec);
+            s.set(b"json", json.clone())?;
+            s.set(b"short", vec![1, 2, 3])?;
+            s.set(b"empty", vec![])?;
+            s.set(b"zeros", vec![0; 1 << 20])?;
+
+            assert_eq!(s.get(b"json")?, Some(json.clone()));
+            assert_eq!(s.get(b"zeros")?, Some(vec![0; 1 << 20]));
+            assert_eq!(s.get(b"short")?, Some(vec![1, 2, 3]));
+            assert_eq!(s.get(b"empty")?, Some(vec![]));
+            assert_eq!(s.get(b"missing")?, None);
+            assert_eq!(
+                s.scan(..).collect::<Result<Vec<_>>>()?,
+                vec![
+                    (b"empty".to_vec(), vec![]),
+                    (b"json".to_vec(), json.clone()),
+                    (b"short".to_vec(), vec![1, 2, 3]),
+                    (b"zeros".to_vec(), vec![0; 1 << 20]),
+                ]
+            );
+
+            let mut inner = s.into_inner();
+            let stored = inner.get(b"json")?.unwrap();
+            assert_eq!(stored[0], codec.tag());
+            if codec != Codec::None {
+                assert!(stored.len() < json.len() / 2);
+            }
+            assert_eq!(inner.get(b"short")?, Some(vec![0, 1, 2, 3]));
+            assert_eq!(inner.get(b"empty")?, Some(vec![0]));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests that compressed values read back through a fresh wrapper, even
+    /// with a different codec.
+    fn reopen() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let json = json_value();
+
+        let mut s = CompressedEngine::new(BitCask::new(path.clone())?, Codec::Zstd);
+        s.set(b"a", json.clone())?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"b", json.clone());
+        batch.delete(b"c");
+        s.write_batch(batch)?;
+        drop(s);
+
+        let mut s = CompressedEngine::new(BitCask::new(path)?, Codec::Lz4);
+        assert_eq!(s.get(b"a")?, Some(json.clone()));
+        assert_eq!(
+            s.scan(..).rev().collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), json.clone()), (b"a".to_vec(), json)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that corrupt stored values are rejected.
+    fn corrupt() -> Result<()> {
+        let mut inner = Memory::new();
+        inner.set(b"empty", vec![])?;
+        inner.set(b"tag", vec![9, 1])?;
+        inner.set(b"lz4", vec![1, 0xff, 0xff, 0xff, 0xff])?;
+        inner.set(b"lz4 short", vec![1, 0xff])?;
+        inner.set(b"lz4 data", vec![1, 0x10, 0, 0, 0, 0xff, 0xff])?;
+        let mut s = CompressedEngine::new(inner, Codec::Lz4);
+
+        assert!(matches!(s.get(b"empty"), Err(Error::Internal(_))));
+        assert!(matches!(s.get(b"tag"), Err(Error::Internal(_))));
+        // Sizes beyond the maximum ratio are rejected without allocating.
+        assert!(matches!(s.get(b"lz4"), Err(Error::Parse(_))));
+        assert!(matches!(s.get(b"lz4 short"), Err(Error::Parse(_))));
+        assert!(matches!(s.get(b"lz4 data"), Err(Error::Internal(_))));
+        Ok(())
+    }
+}
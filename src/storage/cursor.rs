@@ -0,0 +1,156 @@
+use std::ops::Bound;
+
+use super::engine::Engine;
+use crate::error::Result;
+
+/// A resumable forward scan position. Scan iterators borrow the engine, so
+/// they can't be held across engine borrows, e.g. between requests or across
+/// a reopen. A cursor instead remembers the last key it yielded, and re-seeks
+/// past it on each use, such that it can be checkpointed and resumed with
+/// [`Cursor::after`], even on a different engine instance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cursor {
+    /// The start of the remaining keys: inclusive for a new cursor, and
+    /// exclusive of the last yielded key once advanced.
+    start: Bound<Vec<u8>>,
+    /// The end of the scan.
+    end: Bound<Vec<u8>>,
+}
+
+impl Cursor {
+    /// Creates a cursor starting at the given key, inclusive.
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            start: Bound::Included(key.to_vec()),
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// Resumes a cursor after the given key, exclusive, e.g. a checkpoint.
+    pub fn after(key: &[u8]) -> Self {
+        Self {
+            start: Bound::Excluded(key.to_vec()),
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// Stops the cursor at the given end bound instead of the last key.
+    pub fn until(mut self, end: Bound<Vec<u8>>) -> Self {
+        self.end = end;
+        self
+    }
+
+    /// Returns the last yielded key, if any, from which the cursor can be
+    /// resumed with [`Cursor::after`].
+    pub fn checkpoint(&self) -> Option<&[u8]> {
+        match &self.start {
+            Bound::Excluded(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Returns the next key/value pair, advancing the cursor past it.
+    pub fn next(&mut self, engine: &mut impl Engine) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.next_batch(engine, 1)?.pop())
+    }
+
+    /// Returns up to the given number of key/value pairs, advancing the
+    /// cursor past them. Fewer pairs are returned at the end of the scan.
+    pub fn next_batch(
+        &mut self,
+        engine: &mut impl Engine,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.iter(engine).take(limit).collect()
+    }
+
+    /// Returns an iterator over the remaining key/value pairs, which advances
+    /// the cursor past each pair as it's yielded. The cursor can be resumed
+    /// after the iterator is dropped.
+    pub fn iter<'a, E: Engine>(
+        &'a mut self,
+        engine: &'a mut E,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        let range = (self.start.clone(), self.end.clone());
+        engine.scan(range).inspect(|item| {
+            if let Ok((key, _)) = item {
+                self.start = Bound::Excluded(key.clone());
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    #[test]
+    /// Tests that a cursor yields the keys from its start in order, in
+    /// batches and one at a time.
+    fn cursor() -> Result<()> {
+        let mut s = Memory::new();
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            s.set(key, key.to_vec())?;
+        }
+
+        let mut cursor = Cursor::new(b"b");
+        assert_eq!(cursor.checkpoint(), None);
+        assert_eq!(
+            cursor.next_batch(&mut s, 2)?,
+            vec![
+                (b"b".to_vec(), b"b".to_vec()),
+                (b"c".to_vec(), b"c".to_vec())
+            ]
+        );
+        assert_eq!(cursor.checkpoint(), Some(b"c".as_slice()));
+
+        // Writes between uses are seen by the cursor.
+        s.set(b"cc", vec![])?;
+        s.delete(b"d")?;
+        assert_eq!(cursor.next(&mut s)?, Some((b"cc".to_vec(), vec![])));
+        assert_eq!(cursor.next(&mut s)?, Some((b"e".to_vec(), b"e".to_vec())));
+        assert_eq!(cursor.next(&mut s)?, None);
+        assert_eq!(cursor.checkpoint(), Some(b"e".as_slice()));
+
+        // The end bound is respected.
+        let mut cursor = Cursor::new(b"").until(Bound::Excluded(b"c".to_vec()));
+        assert_eq!(cursor.next_batch(&mut s, 10)?.len(), 2);
+        assert_eq!(cursor.next(&mut s)?, None);
+        assert_eq!(
+            s.iter_from(b"cc").collect::<Result<Vec<_>>>()?,
+            vec![(b"cc".to_vec(), vec![]), (b"e".to_vec(), b"e".to_vec())]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests resuming a cursor from a checkpoint after reopening the engine.
+    fn cursor_resume() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path.clone())?;
+        for i in 0..10u8 {
+            s.set(&[i], vec![i])?;
+        }
+
+        let mut cursor = Cursor::new(&[]);
+        let mut values = Vec::new();
+        for item in cursor.iter(&mut s).take(4) {
+            values.push(item?.1);
+        }
+        let checkpoint = cursor.checkpoint().unwrap().to_vec();
+        assert_eq!(checkpoint, vec![3]);
+        drop(s);
+
+        let mut s = BitCask::new(path)?;
+        let mut cursor = Cursor::after(&checkpoint);
+        for item in cursor.iter(&mut s) {
+            values.push(item?.1);
+        }
+        assert_eq!(values, (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+        assert_eq!(cursor.next(&mut s)?, None);
+        Ok(())
+    }
+}
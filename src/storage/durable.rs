@@ -0,0 +1,279 @@
+/*!
+An engine wrapper that makes a volatile engine, e.g. [`super::memory::Memory`],
+durable with a write-ahead log (WAL).
+
+Every write is appended to the WAL before it is applied to the wrapped engine,
+and the WAL is replayed into the wrapped engine when the wrapper is opened. The
+WAL uses the BitCask log format, see [`super::bitcask`], so write batches are
+logged atomically and incomplete trailing entries or batches left by a crash
+are truncated on replay.
+
+The WAL grows with every write, and is shrunk by [`Durable::checkpoint`], which
+rewrites it with only the current contents of the engine.
+*/
+
+use std::path::PathBuf;
+
+use super::bitcask::{KeyDir, Log, RecoveryPolicy, READ_BUFFER_SIZE};
+use super::engine::{Engine, ReadEngine, SizeHistogram, Status, WriteBatch};
+use crate::error::Result;
+
+/// An engine that logs writes to a WAL before passing them to the wrapped
+/// engine. Writes are handed to the OS immediately, so they survive a process
+/// crash, but are only fsynced by write batches and [`Engine::sync`].
+pub struct Durable<E: Engine> {
+    inner: E,
+    wal: Log,
+}
+
+impl<E: Engine> Durable<E> {
+    /// Opens or creates the WAL at the given path, and replays it into the
+    /// inner engine, which should be empty. The inner engine should not
+    /// reject writes, since a write that was logged but rejected is replayed
+    /// again on the next open.
+    pub fn new(mut inner: E, path: PathBuf) -> Result<Self> {
+        let mut wal = Log::new(path, 0)?;
+        for (key, entry) in wal.build_key_dir(
+            KeyDir::new(),
+            true,
+            0,
+            READ_BUFFER_SIZE,
+            Some(RecoveryPolicy::Truncate),
+            &mut |_| {},
+        )? {
+            let value = wal.read_value(&key, &entry, true)?;
+            inner.set(&key, value)?;
+        }
+        Ok(Self { inner, wal })
+    }
+
+    /// Rewrites the WAL with the current contents of the engine, discarding
+    /// overwritten and deleted entries. The new WAL is written to a temporary
+    /// file and renamed over the old one, so a crash during the checkpoint
+    /// leaves the old WAL intact. Call it periodically and on clean shutdown
+    /// to keep the WAL small and replays fast.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let mut new_path = self.wal.path.clone();
+        new_path.set_extension("new");
+        let mut new_wal = Log::new(new_path, 0)?;
+        new_wal.reset()?;
+        for item in self.inner.scan(..) {
+            let (key, value) = item?;
+            new_wal.append_entry(&key, Some(&value), None)?;
+        }
+        new_wal.file.sync_all()?;
+
+        std::fs::rename(&new_wal.path, &self.wal.path)?;
+        new_wal.path = self.wal.path.clone();
+        self.wal = new_wal;
+        Ok(())
+    }
+
+    /// Unwraps the inner engine, closing the WAL.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Engine> std::fmt::Display for Durable<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (durable)", self.inner)
+    }
+}
+
+impl<E: Engine> Engine for Durable<E> {
+    type ScanIterator<'a>
+        = E::ScanIterator<'a>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.wal.append_entry(key, Some(&value), None)?;
+        self.inner.set(key, value)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.get_many(keys)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.wal.append_entry(key, None, None)?;
+        self.inner.delete(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.wal.file.sync_all()?;
+        self.inner.sync()
+    }
+
+    /// Fsyncs the WAL, which can't be synced partially, but skips syncing
+    /// the inner engine, since the range is replayed from the WAL anyway.
+    fn sync_range(&mut self, _range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        Ok(self.wal.file.sync_all()?)
+    }
+
+    /// Truncates the WAL, rather than logging a tombstone for every key.
+    fn clear(&mut self) -> Result<()> {
+        self.wal.reset()?;
+        self.wal.file.sync_all()?;
+        self.inner.clear()
+    }
+
+    /// Logs the batch atomically, and fsyncs it like BitCask does.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.wal.append_batch(&batch)?;
+        self.wal.file.sync_all()?;
+        self.inner.write_batch(batch)
+    }
+
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        self.inner.stats_histogram()
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.estimate_garbage(range)
+    }
+
+    /// The write-ahead log is only kept on disk.
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        Ok(Status {
+            name: self.to_string(),
+            ..self.inner.status()?
+        })
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan(range)
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.inner.scan_keys(range)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.inner.count(range)
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for Durable<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_shared(key)
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan_shared(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::memory::Memory, *};
+    use std::io::Write as _;
+
+    /// Writes some data, including overwrites, deletes and a batch.
+    fn populate(s: &mut impl Engine) -> Result<()> {
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2])?;
+        s.set(b"c", vec![3])?;
+        s.set(b"a", vec![1, 1])?;
+        s.delete(b"b")?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"d", vec![4]);
+        batch.delete(b"c");
+        batch.set(b"", vec![]);
+        s.write_batch(batch)
+    }
+
+    #[test]
+    /// Tests that replaying the WAL after a crash restores the engine.
+    fn recovery() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("wal");
+
+        let mut s = Durable::new(Memory::new(), path.clone())?;
+        populate(&mut s)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(expect.len(), 3);
+        drop(s);
+
+        // A torn trailing write is discarded on replay.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[0, 0, 0, 1, 0, 0])?;
+        drop(file);
+
+        let mut s = Durable::new(Memory::new(), path.clone())?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+
+        // Writes after recovery are replayed too.
+        s.set(b"e", vec![5])?;
+        drop(s);
+        let mut s = Durable::new(Memory::new(), path)?;
+        assert_eq!(s.get(b"e")?, Some(vec![5]));
+        assert_eq!(s.count(..)?, 4);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that sync_range makes the range durable via the WAL alone.
+    fn sync_range() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("wal");
+
+        let mut s = Durable::new(Memory::new(), path.clone())?;
+        populate(&mut s)?;
+        s.sync_range(b"a".to_vec()..b"b".to_vec())?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        drop(s);
+
+        let mut s = Durable::new(Memory::new(), path)?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that checkpoints shrink the WAL without losing data, and that
+    /// clearing the engine truncates the WAL.
+    fn checkpoint() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("wal");
+
+        let mut s = Durable::new(Memory::new(), path.clone())?;
+        populate(&mut s)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        let size = std::fs::metadata(&path)?.len();
+        s.checkpoint()?;
+        assert!(std::fs::metadata(&path)?.len() < size);
+        assert!(!dir.path().join("wal.new").exists());
+
+        s.set(b"f", vec![6])?;
+        drop(s);
+        let mut s = Durable::new(Memory::new(), path.clone())?;
+        s.delete(b"f")?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+
+        s.clear()?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 5);
+        drop(s);
+        let mut s = Durable::new(Memory::new(), path)?;
+        assert_eq!(s.count(..)?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+/*!
+An engine wrapper that encrypts values, and optionally keys, at rest.
+
+Values are encrypted with AES-256-GCM using a random 96-bit nonce, and stored
+as the nonce followed by the ciphertext and the 128-bit authentication tag.
+The plaintext key is used as associated data, so a value can't be moved to a
+different key without detection. Decryption failures, i.e. a wrong key or
+tampered data, return [`Error::Decryption`].
+
+Keys are stored in plaintext by default, which preserves their ordering for
+scans. If key encryption is enabled, keys are encrypted deterministically with
+AES-256-GCM-SIV and a fixed nonce, under a separate key derived from the
+encryption key with HKDF-SHA256, such that point lookups still work but only
+reveal whether two keys are equal. Encrypted keys don't preserve ordering, so
+range scans must read and decrypt all keys in the store, and then sort them.
+*/
+
+use super::engine::{clone_bounds, Engine, ReadEngine, Status, WriteBatch};
+use crate::error::{Error, Result};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use aes_gcm_siv::Aes256GcmSiv;
+
+/// The length of an encryption key.
+pub const KEY_LENGTH: usize = 32;
+
+/// The length of the nonce prepended to encrypted values.
+const NONCE_LENGTH: usize = 12;
+
+/// The length of the authentication tag appended to ciphertexts.
+const TAG_LENGTH: usize = 16;
+
+/// The HKDF info string used to derive the key cipher's key from the
+/// encryption key.
+const KEY_CIPHER_INFO: &[u8] = b"yuudb-keys";
+
+/// The ciphers for values and keys.
+struct Cipher {
+    values: Aes256Gcm,
+    keys: Option<Aes256GcmSiv>,
+}
+
+impl Cipher {
+    fn new(key: &[u8; KEY_LENGTH], encrypt_keys: bool) -> Self {
+        let values = Aes256Gcm::new(key.into());
+        // Derive a separate key for the key cipher with HKDF-SHA256.
+        let keys = encrypt_keys.then(|| {
+            let mut derived = [0; KEY_LENGTH];
+            hkdf::Hkdf::<sha2::Sha256>::new(None, key)
+                .expand(KEY_CIPHER_INFO, &mut derived)
+                .expect("key length is valid for HKDF");
+            Aes256GcmSiv::new(&derived.into())
+        });
+        Self { values, keys }
+    }
+
+    fn encrypt_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+        match &self.keys {
+            Some(cipher) => cipher
+                .encrypt(aes_gcm_siv::Nonce::from_slice(&[0; NONCE_LENGTH]), key)
+                .map_err(|_| Error::Internal("Key encryption failed".to_string())),
+            None => Ok(key.to_vec()),
+        }
+    }
+
+    fn decrypt_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+        match &self.keys {
+            Some(cipher) => cipher
+                .decrypt(aes_gcm_siv::Nonce::from_slice(&[0; NONCE_LENGTH]), key)
+                .map_err(|_| Error::Decryption),
+            None => Ok(key.to_vec()),
+        }
+    }
+
+    fn encrypt_value(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .values
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value,
+                    aad: key,
+                },
+            )
+            .map_err(|_| Error::Internal("Value encryption failed".to_string()))?;
+        let mut encrypted = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    fn decrypt_value(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        if value.len() < NONCE_LENGTH + TAG_LENGTH {
+            return Err(Error::Decryption);
+        }
+        let (nonce, ciphertext) = value.split_at(NONCE_LENGTH);
+        self.values
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key,
+                },
+            )
+            .map_err(|_| Error::Decryption)
+    }
+
+    /// The number of bytes added to each stored key and value.
+    fn overhead(&self) -> u64 {
+        let key_overhead = if self.keys.is_some() { TAG_LENGTH } else { 0 };
+        (key_overhead + NONCE_LENGTH + TAG_LENGTH) as u64
+    }
+}
+
+/// An engine that encrypts values, and optionally keys, before passing them to
+/// the wrapped engine.
+pub struct EncryptedEngine<E: Engine> {
+    inner: E,
+    cipher: Cipher,
+}
+
+impl<E: Engine> EncryptedEngine<E> {
+    /// Wraps an engine, encrypting values with the given key.
+    pub fn new(inner: E, key: &[u8; KEY_LENGTH]) -> Self {
+        Self {
+            inner,
+            cipher: Cipher::new(key, false),
+        }
+    }
+
+    /// Wraps an engine, encrypting both keys and values with the given key.
+    pub fn new_with_key_encryption(inner: E, key: &[u8; KEY_LENGTH]) -> Self {
+        Self {
+            inner,
+            cipher: Cipher::new(key, true),
+        }
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Engine> std::fmt::Display for EncryptedEngine<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (encrypted)", self.inner)
+    }
+}
+
+enum Entries<'a, E: Engine + 'a> {
+    /// Plaintext keys, in order from the inner scan.
+    Ordered(E::ScanIterator<'a>),
+    /// Decrypted keys with their encrypted values, sorted by key.
+    Sorted(std::vec::IntoIter<Result<(Vec<u8>, Vec<u8>)>>),
+}
+
+pub struct ScanIterator<'a, E: Engine + 'a> {
+    entries: Entries<'a, E>,
+    cipher: &'a Cipher,
+}
+
+impl<'a, E: Engine + 'a> ScanIterator<'a, E> {
+    fn new(
+        inner: E::ScanIterator<'a>,
+        cipher: &'a Cipher,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> Self {
+        if cipher.keys.is_none() {
+            return Self {
+                entries: Entries::Ordered(inner),
+                cipher,
+            };
+        }
+        // The inner iterator scans all keys, which are decrypted, filtered
+        // and sorted. Values are decrypted lazily.
+        let entries = inner
+            .map(|result| {
+                let (key, value) = result?;
+                Ok((cipher.decrypt_key(&key)?, value))
+            })
+            .filter(|result| match result {
+                Ok((key, _)) => range.contains(key),
+                Err(_) => true,
+            })
+            .collect::<Result<Vec<_>>>();
+        let entries = match entries {
+            Ok(mut entries) => {
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                entries.into_iter().map(Ok).collect::<Vec<_>>()
+            }
+            Err(error) => vec![Err(error)],
+        };
+        Self {
+            entries: Entries::Sorted(entries.into_iter()),
+            cipher,
+        }
+    }
+
+    fn decrypt(&self, item: <Self as Iterator>::Item) -> <Self as Iterator>::Item {
+        let (key, value) = item?;
+        let value = self.cipher.decrypt_value(&key, &value)?;
+        Ok((key, value))
+    }
+}
+
+impl<'a, E: Engine + 'a> Iterator for ScanIterator<'a, E> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = match &mut self.entries {
+            Entries::Ordered(inner) => inner.next(),
+            Entries::Sorted(inner) => inner.next(),
+        };
+        item.map(|item| self.decrypt(item))
+    }
+}
+
+impl<'a, E: Engine + 'a> DoubleEndedIterator for ScanIterator<'a, E> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let item = match &mut self.entries {
+            Entries::Ordered(inner) => inner.next_back(),
+            Entries::Sorted(inner) => inner.next_back(),
+        };
+        item.map(|item| self.decrypt(item))
+    }
+}
+
+impl<E: Engine> Engine for EncryptedEngine<E> {
+    type ScanIterator<'a>
+        = ScanIterator<'a, E>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let value = self.cipher.encrypt_value(key, &value)?;
+        self.inner.set(&self.cipher.encrypt_key(key)?, value)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .get(&self.cipher.encrypt_key(key)?)?
+            .map(|value| self.cipher.decrypt_value(key, &value))
+            .transpose()
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let encrypted_keys = keys
+            .iter()
+            .map(|key| self.cipher.encrypt_key(key))
+            .collect::<Result<Vec<_>>>()?;
+        self.inner
+            .get_many(&encrypted_keys)?
+            .into_iter()
+            .zip(keys)
+            .map(|(value, key)| {
+                value
+                    .map(|value| self.cipher.decrypt_value(key, &value))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.inner.delete(&self.cipher.encrypt_key(key)?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut encrypted = WriteBatch::new();
+        for (key, value) in batch {
+            let encrypted_key = self.cipher.encrypt_key(&key)?;
+            match value {
+                Some(value) => {
+                    encrypted.set(&encrypted_key, self.cipher.encrypt_value(&key, &value)?)
+                }
+                None => encrypted.delete(&encrypted_key),
+            }
+        }
+        self.inner.write_batch(encrypted)
+    }
+
+    /// With key encryption, ranges of plaintext keys don't map to ranges of
+    /// encrypted keys, so this returns the garbage of the whole inner engine.
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        match self.cipher.keys {
+            Some(_) => self.inner.estimate_garbage(..),
+            None => self.inner.estimate_garbage(range),
+        }
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    /// Returns the status of the inner engine, with the size excluding the
+    /// encryption overhead.
+    fn status(&mut self) -> Result<Status> {
+        let status = self.inner.status()?;
+        Ok(Status {
+            name: self.to_string(),
+            size: status
+                .size
+                .saturating_sub(status.key_count * self.cipher.overhead()),
+            ..status
+        })
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        let inner = match self.cipher.keys {
+            Some(_) => self.inner.scan(..),
+            None => self.inner.scan(clone_bounds(&range)),
+        };
+        ScanIterator::new(inner, &self.cipher, range)
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for EncryptedEngine<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .get_shared(&self.cipher.encrypt_key(key)?)?
+            .map(|value| self.cipher.decrypt_value(key, &value))
+            .transpose()
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        let inner = match self.cipher.keys {
+            Some(_) => self.inner.scan_shared(..),
+            None => self.inner.scan_shared(clone_bounds(&range)),
+        };
+        ScanIterator::new(inner, &self.cipher, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    const KEY: &[u8; KEY_LENGTH] = &[7; KEY_LENGTH];
+    const WRONG_KEY: &[u8; KEY_LENGTH] = &[8; KEY_LENGTH];
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests that values round-trip, and are not stored in plaintext.
+    fn round_trip() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = EncryptedEngine::new(BitCask::new(path.clone())?, KEY);
+        s.set(b"a", b"secret".to_vec())?;
+        s.set(b"b", vec![])?;
+        assert_eq!(s.get(b"a")?, Some(b"secret".to_vec()));
+        drop(s);
+
+        let contents = std::fs::read(&path)?;
+        assert!(!contents.windows(6).any(|w| w == b"secret"));
+
+        let mut s = EncryptedEngine::new(BitCask::new(path)?, KEY);
+        assert_eq!(
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), b"secret".to_vec()), (b"b".to_vec(), vec![])]
+        );
+
+        // Writing the same value twice uses different nonces.
+        s.set(b"c", b"secret".to_vec())?;
+        let mut inner = s.into_inner();
+        assert_ne!(inner.get(b"a")?, inner.get(b"c")?);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests that estimate_garbage maps the range to the inner engine, except
+    /// with key encryption where it covers the whole inner engine.
+    fn estimate_garbage() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let mut s = EncryptedEngine::new(BitCask::new(dir.path().join("plain"))?, KEY);
+        s.set(b"a", vec![1])?;
+        s.set(b"a", vec![2])?;
+        s.set(b"b", vec![1])?;
+        s.set(b"b", vec![2])?;
+        let garbage = s.estimate_garbage(..)?;
+        assert!(garbage > 0);
+        assert_eq!(s.estimate_garbage(..=b"a".to_vec())?, garbage / 2);
+
+        let inner = BitCask::new(dir.path().join("keys"))?;
+        let mut s = EncryptedEngine::new_with_key_encryption(inner, KEY);
+        s.set(b"a", vec![1])?;
+        s.set(b"a", vec![2])?;
+        let garbage = s.estimate_garbage(..)?;
+        assert!(garbage > 0);
+        assert_eq!(s.estimate_garbage(b"b".to_vec()..)?, garbage);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a wrong key is rejected rather than returning garbage.
+    fn wrong_key() -> Result<()> {
+        let mut s = EncryptedEngine::new(Memory::new(), KEY);
+        s.set(b"a", vec![1, 2, 3])?;
+
+        let mut s = EncryptedEngine::new(s.into_inner(), WRONG_KEY);
+        assert_eq!(s.get(b"a"), Err(Error::Decryption));
+        assert_eq!(s.scan(..).next(), Some(Err(Error::Decryption)));
+
+        // With key encryption, keys encrypted under the wrong key don't match
+        // any stored key, and scans fail to decrypt them.
+        let mut s = EncryptedEngine::new_with_key_encryption(Memory::new(), KEY);
+        s.set(b"a", vec![1, 2, 3])?;
+
+        let mut s = EncryptedEngine::new_with_key_encryption(s.into_inner(), WRONG_KEY);
+        assert_eq!(s.get(b"a")?, None);
+        assert_eq!(s.scan(..).next(), Some(Err(Error::Decryption)));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that tampered values and values moved between keys are rejected.
+    fn tampering() -> Result<()> {
+        let mut s = EncryptedEngine::new(Memory::new(), KEY);
+        s.set(b"a", vec![1, 2, 3])?;
+        s.set(b"b", vec![4, 5, 6])?;
+
+        let mut inner = s.into_inner();
+        let mut value = inner.get(b"a")?.unwrap();
+        *value.last_mut().unwrap() ^= 0x01;
+        inner.set(b"a", value)?;
+        let value = inner.get(b"b")?.unwrap();
+        inner.set(b"c", value)?;
+        inner.set(b"d", vec![0; NONCE_LENGTH])?;
+
+        let mut s = EncryptedEngine::new(inner, KEY);
+        assert_eq!(s.get(b"a"), Err(Error::Decryption));
+        assert_eq!(s.get(b"b")?, Some(vec![4, 5, 6]));
+        assert_eq!(s.get(b"c"), Err(Error::Decryption));
+        assert_eq!(s.get(b"d"), Err(Error::Decryption));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that encrypted keys are not stored in plaintext, and that scans
+    /// still return keys in order.
+    fn key_encryption() -> Result<()> {
+        let mut s = EncryptedEngine::new_with_key_encryption(Memory::new(), KEY);
+        for key in [b"c", b"a", b"d", b"b"] {
+            s.set(key, key.to_vec())?;
+        }
+        s.delete(b"d")?;
+
+        assert_eq!(s.get(b"a")?, Some(b"a".to_vec()));
+        assert_eq!(
+            s.get_many(&[b"d".to_vec(), b"b".to_vec(), b"a".to_vec()])?,
+            vec![None, Some(b"b".to_vec()), Some(b"a".to_vec())]
+        );
+        assert_eq!(
+            s.scan(b"b".to_vec()..).collect::<Result<Vec<_>>>()?,
+            vec![
+                (b"b".to_vec(), b"b".to_vec()),
+                (b"c".to_vec(), b"c".to_vec())
+            ]
+        );
+        assert_eq!(
+            s.scan(..=b"b".to_vec()).rev().collect::<Result<Vec<_>>>()?,
+            vec![
+                (b"b".to_vec(), b"b".to_vec()),
+                (b"a".to_vec(), b"a".to_vec())
+            ]
+        );
+
+        let mut inner = s.into_inner();
+        assert_eq!(inner.count(..)?, 3);
+        assert_eq!(inner.get(b"a")?, None);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that scans over various ranges match an unencrypted engine, with
+    /// and without key encryption.
+    fn scan_ranges() -> Result<()> {
+        use std::ops::Bound;
+
+        let mut expect = Memory::new();
+        let mut s = EncryptedEngine::new(Memory::new(), KEY);
+        let mut k = EncryptedEngine::new_with_key_encryption(Memory::new(), KEY);
+        for key in [&b""[..], b"a", b"b", b"ba", b"bb", b"b\xff", b"c"] {
+            expect.set(key, key.to_vec())?;
+            s.set(key, key.to_vec())?;
+            k.set(key, key.to_vec())?;
+        }
+
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (
+                Bound::Included(b"b".to_vec()),
+                Bound::Excluded(b"bb".to_vec()),
+            ),
+            (
+                Bound::Excluded(b"b".to_vec()),
+                Bound::Included(b"c".to_vec()),
+            ),
+            (Bound::Unbounded, Bound::Excluded(b"a".to_vec())),
+        ];
+        for range in ranges {
+            let expect = expect.scan(range.clone()).collect::<Result<Vec<_>>>()?;
+            assert_eq!(s.scan(range.clone()).collect::<Result<Vec<_>>>()?, expect);
+            assert_eq!(k.scan(range.clone()).collect::<Result<Vec<_>>>()?, expect);
+
+            let mut expect = expect;
+            expect.reverse();
+            assert_eq!(k.scan(range).rev().collect::<Result<Vec<_>>>()?, expect);
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
+use std::io::{Read as _, Write as _};
 use std::ops::Bound;
 
-use crate::error::Result;
+use super::scan_builder::ScanBuilder;
+use crate::error::{Error, Result};
 
 /// The status of a key-value store engine.
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -15,6 +17,152 @@ pub struct Status {
     pub total_disk_size: u64,
     pub live_disk_size: u64,
     pub garbage_disk_size: u64,
+
+    /// The number of tombstones on disk, for engines that persist deletes as
+    /// tombstones until compaction.
+    #[serde(default)]
+    pub tombstone_count: u64,
+
+    /// The number of data files, for engines that store data in files.
+    #[serde(default)]
+    pub file_count: u64,
+
+    /// Whether the engine should be compacted, e.g. because its garbage ratio
+    /// has reached the configured threshold.
+    #[serde(default)]
+    pub compaction_recommended: bool,
+}
+
+/// Metadata about a key's value, see [`Engine::get_with_metadata`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntryMeta {
+    /// The value length in bytes.
+    pub value_length: u64,
+    /// Where the value is stored, as a data file ID and the byte offset of
+    /// the value in the file, for engines that store values in files.
+    pub location: Option<(u64, u64)>,
+}
+
+/// The footprint of a single key, see [`Engine::stat_key`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyStat {
+    /// The key length in bytes.
+    pub key_length: u64,
+    /// The value length in bytes.
+    pub value_length: u64,
+    /// Whether the key's latest entry is live, i.e. not expired, for engines
+    /// that keep expired entries until they're read or compacted.
+    pub live: bool,
+    /// Where the value is stored, as a data file ID and the byte offset of
+    /// the value in the file, for engines that store values in files.
+    pub location: Option<(u64, u64)>,
+}
+
+/// The distribution of key and value sizes, see [`Engine::stats_histogram`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SizeHistogram {
+    /// The number of values per power-of-two length bucket. Bucket 0 counts
+    /// empty values, and bucket `i` counts values of `2^(i-1)` to `2^i - 1`
+    /// bytes. Trailing empty buckets are omitted.
+    pub value_buckets: Vec<u64>,
+    /// Key sizes in bytes.
+    pub key_sizes: SizeStats,
+    /// Value sizes in bytes.
+    pub value_sizes: SizeStats,
+}
+
+impl SizeHistogram {
+    /// Records a key/value pair with the given sizes.
+    pub fn add(&mut self, key_size: u64, value_size: u64) {
+        let bucket = (u64::BITS - value_size.leading_zeros()) as usize;
+        if self.value_buckets.len() <= bucket {
+            self.value_buckets.resize(bucket + 1, 0);
+        }
+        self.value_buckets[bucket] += 1;
+        self.key_sizes.add(key_size);
+        self.value_sizes.add(value_size);
+    }
+
+    /// Returns the number of key/value pairs recorded.
+    pub fn count(&self) -> u64 {
+        self.key_sizes.count
+    }
+}
+
+/// Summary statistics of sizes, see [`SizeHistogram`]. All zero if empty.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SizeStats {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,
+}
+
+impl SizeStats {
+    fn add(&mut self, size: u64) {
+        self.min = match self.count {
+            0 => size,
+            _ => self.min.min(size),
+        };
+        self.max = self.max.max(size);
+        self.total += size;
+        self.count += 1;
+    }
+
+    /// Returns the mean size, or 0 if empty.
+    pub fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.total as f64 / count as f64,
+        }
+    }
+}
+
+/// A batch of writes, applied atomically by [`Engine::write_batch`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteBatch {
+    operations: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a write of the key to the batch.
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) {
+        self.operations.push((key.to_vec(), Some(value)));
+    }
+
+    /// Adds a deletion of the key to the batch.
+    pub fn delete(&mut self, key: &[u8]) {
+        self.operations.push((key.to_vec(), None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Iterates over the operations in the order they were added, with `None`
+    /// values for deletions.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.operations
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = (Vec<u8>, Option<Vec<u8>>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.operations.into_iter()
+    }
 }
 
 /// A single-thread key-value store engine.
@@ -27,36 +175,617 @@ pub trait Engine: std::fmt::Display + Send + Sync {
 
     fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Gets a value for a key, returning [`Error::NotFound`] if it does not
+    /// exist.
+    fn get_required(&mut self, key: &[u8]) -> Result<Vec<u8>> {
+        self.get(key)?
+            .ok_or_else(|| Error::NotFound(format!("key {}", key.escape_ascii())))
+    }
+
+    /// Gets the values for several keys, in the same order as the keys. The
+    /// default implementation gets the keys one by one, engines should
+    /// override it where they can read the values more efficiently together.
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Gets a value along with its metadata. The default implementation only
+    /// reports the value length, engines should override it where they know
+    /// the value's location.
+    fn get_with_metadata(&mut self, key: &[u8]) -> Result<Option<(Vec<u8>, EntryMeta)>> {
+        Ok(self.get(key)?.map(|value| {
+            let meta = EntryMeta {
+                value_length: value.len() as u64,
+                location: None,
+            };
+            (value, meta)
+        }))
+    }
+
+    /// Returns the footprint of a key, or None if it doesn't exist, e.g. to
+    /// find oversized values. The default implementation reads the value,
+    /// engines should override it where they know its length without reading
+    /// it.
+    fn stat_key(&mut self, key: &[u8]) -> Result<Option<KeyStat>> {
+        Ok(self.get(key)?.map(|value| KeyStat {
+            key_length: key.len() as u64,
+            value_length: value.len() as u64,
+            live: true,
+            location: None,
+        }))
+    }
+
     fn delete(&mut self, key: &[u8]) -> Result<()>;
 
+    /// Sets the key to the new value, or deletes it if the new value is
+    /// `None`, but only if its current value equals the expected value, where
+    /// `None` expects the key to be absent. Returns whether the key was
+    /// written. The read and write happen under the same exclusive borrow, so
+    /// no other write can interleave.
+    fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None => self.delete(key)?,
+        }
+        Ok(true)
+    }
+
+    /// Sets the key to the value only if it doesn't already exist. Returns
+    /// whether the key was inserted. Like [`Engine::compare_and_swap`], the
+    /// check and write happen under the same exclusive borrow.
+    fn set_if_absent(&mut self, key: &[u8], value: Vec<u8>) -> Result<bool> {
+        if self.get(key)?.is_some() {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Merges the operand into the key's current value, if any, by writing the
+    /// result of `f(current, operand)`, e.g. to increment a counter. The read
+    /// and write happen under the same exclusive borrow, so no other write can
+    /// interleave. This is a read followed by a regular write, so log-based
+    /// engines like BitCask still append the full merged value.
+    fn merge(
+        &mut self,
+        key: &[u8],
+        operand: Vec<u8>,
+        f: impl Fn(Option<Vec<u8>>, Vec<u8>) -> Vec<u8>,
+    ) -> Result<()> {
+        let value = f(self.get(key)?, operand);
+        self.set(key, value)
+    }
+
+    /// Updates the key with the result of `f(current)`, where `Some` sets the
+    /// key and `None` deletes it. Unlike [`Engine::merge`], the closure fully
+    /// decides the outcome, including deletion. The read and write happen
+    /// under the same exclusive borrow, so no other write can interleave.
+    fn update(
+        &mut self,
+        key: &[u8],
+        f: impl FnOnce(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Result<()> {
+        match f(self.get(key)?) {
+            Some(value) => self.set(key, value),
+            None => self.delete(key),
+        }
+    }
+
+    /// Moves the value of a key to another key, overwriting its value if any,
+    /// and deletes the source key. Returns whether the source key existed,
+    /// and does nothing otherwise. Renaming a key to itself only checks that
+    /// it exists. The read and write happen under the same exclusive borrow,
+    /// and both writes are applied as one [`WriteBatch`], so engines with
+    /// atomic batches never expose a half-done rename, even after a crash.
+    fn rename_key(&mut self, from: &[u8], to: &[u8]) -> Result<bool> {
+        let Some(value) = self.get(from)? else {
+            return Ok(false);
+        };
+        if from != to {
+            let mut batch = WriteBatch::new();
+            batch.set(to, value);
+            batch.delete(from);
+            self.write_batch(batch)?;
+        }
+        Ok(true)
+    }
+
+    /// Ensures that all writes have left the process, e.g. by flushing
+    /// buffers to the OS, such that they survive a process crash. This is
+    /// cheap, but doesn't protect against an OS crash or power loss, see
+    /// [`Engine::sync`].
     fn flush(&mut self) -> Result<()>;
 
+    /// Makes all writes durable, e.g. by fsyncing them to disk, such that they
+    /// survive an OS crash or power loss. This is expensive, so callers that
+    /// need throughput can make several writes between syncs. The default
+    /// implementation flushes, which suffices for engines that don't persist
+    /// their data.
+    fn sync(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Makes all writes of keys in the range durable, like [`Engine::sync`].
+    /// Writes of other keys may or may not be made durable too. This lets
+    /// layered engines persist a single namespace without a full sync, e.g.
+    /// [`Tiered`](super::tiered::Tiered) only writes back the range's keys.
+    /// Engines that can only sync everything, like BitCask, do a full sync,
+    /// which is the default implementation.
+    fn sync_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        let _ = range;
+        self.sync()
+    }
+
+    /// Applies a batch of writes in order. Engines should apply the batch
+    /// atomically, such that a partially applied batch is never visible, even
+    /// after a crash. The default implementation applies the writes one by one.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        for (key, value) in batch {
+            match value {
+                Some(value) => self.set(&key, value)?,
+                None => self.delete(&key)?,
+            }
+        }
+        Ok(())
+    }
+
     fn status(&mut self) -> Result<Status>;
 
+    /// Returns the distribution of key and value sizes, e.g. to inform
+    /// compression and cache sizing. The default implementation scans all
+    /// keys and values, engines should override it where they know the sizes
+    /// without reading the values.
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        let mut histogram = SizeHistogram::default();
+        for item in self.scan(..) {
+            let (key, value) = item?;
+            histogram.add(key.len() as u64, value.len() as u64);
+        }
+        Ok(histogram)
+    }
+
+    /// Estimates the on-disk size of the stale entries of the keys in the
+    /// range, i.e. overwritten or expired values and tombstones, which
+    /// compaction would remove, e.g. to decide what to compact. This may be
+    /// expensive, e.g. scanning all data files. The default implementation
+    /// returns 0, for engines that don't keep stale entries.
+    fn estimate_garbage(&mut self, _range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Estimates the memory used by the engine's in-memory structures, in
+    /// bytes, e.g. for capacity planning. Unlike [`Status::size`], which is
+    /// the logical size of the keys and values, this includes per-entry
+    /// overhead and excludes data only kept on disk. The default
+    /// implementation returns 0, for engines that keep nothing in memory.
+    fn approximate_memory_usage(&self) -> u64 {
+        0
+    }
+
+    /// Removes all keys. The default implementation deletes every key in a
+    /// single write batch, engines should override it where they can discard
+    /// their data in one go.
+    fn clear(&mut self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for key in self.scan_keys(..).collect::<Result<Vec<_>>>()? {
+            batch.delete(&key);
+        }
+        self.write_batch(batch)
+    }
+
     fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_>;
 
+    /// Scans the range in reverse order, from the largest key to the smallest,
+    /// e.g. to fetch the latest entries with `scan_rev(range).take(n)`. This
+    /// is the same as `scan(range).rev()`, and equally lazy.
+    fn scan_rev(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> std::iter::Rev<Self::ScanIterator<'_>> {
+        self.scan(range).rev()
+    }
+
+    /// Returns the smallest key and its value, or `None` if the engine is
+    /// empty.
+    fn first(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.scan(..).next().transpose()
+    }
+
+    /// Returns the largest key and its value, or `None` if the engine is
+    /// empty.
+    fn last(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.scan(..).next_back().transpose()
+    }
+
+    /// Scans the range, yielding at most `limit` items. Combined with a start
+    /// bound after the last key of the previous page, this allows paginating
+    /// through a range. Scans are lazy, so engines that read values on demand,
+    /// e.g. BitCask, don't read values past the limit, although engines that
+    /// merge several sources may read ahead.
+    fn scan_limit(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.scan(range).take(limit)
+    }
+
+    /// Scans the keys in the range, without reading their values. The default
+    /// implementation scans the values too, engines should override it where
+    /// keys can be read on their own.
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.scan(range).map(|result| result.map(|(key, _)| key))
+    }
+
+    /// Returns the keys in the range, in order. This is shorthand for
+    /// [`Engine::scan_keys`], the counterpart of [`Engine::values`].
+    fn keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.scan_keys(range)
+    }
+
+    /// Returns the values of the keys in the range, in key order.
+    fn values(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.scan(range)
+            .map(|result| result.map(|(_, value)| value))
+    }
+
+    /// Counts the keys in the range.
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.scan_keys(range)
+            .try_fold(0, |count, key| key.map(|_| count + 1))
+    }
+
+    /// Returns whether the range contains no keys.
+    fn is_empty_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<bool> {
+        Ok(self.scan_keys(range).next().transpose()?.is_none())
+    }
+
+    /// Like [`Engine::scan`], but with borrowed bounds, such that callers
+    /// with key slices don't have to allocate vectors for them. The default
+    /// implementation copies the bounds, engines should override it where
+    /// they can scan with borrowed bounds.
+    fn scan_bytes(&mut self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self::ScanIterator<'_> {
+        self.scan((start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec)))
+    }
+
+    /// Returns a builder for scans with composable options, e.g. a reversed
+    /// and limited prefix scan of keys only.
+    fn scan_builder(&mut self) -> ScanBuilder<'_, Self>
+    where
+        Self: Sized,
+    {
+        ScanBuilder::new(self)
+    }
+
+    /// Scans forward from the given key, inclusive, to the end. See
+    /// [`super::cursor::Cursor`] for scans that can be paused and resumed
+    /// later.
+    fn iter_from(&mut self, key: &[u8]) -> Self::ScanIterator<'_> {
+        self.scan_bytes(Bound::Included(key), Bound::Unbounded)
+    }
+
+    /// Scans the keys with the given prefix. An empty prefix scans all keys.
     fn scan_prefix(&mut self, prefix: &[u8]) -> Self::ScanIterator<'_> {
-        let start = Bound::Included(prefix.to_vec());
-        let end = match prefix.iter().rposition(|b| *b != 0xff) {
-            Some(i) => Bound::Excluded(
-                prefix
-                    .iter()
-                    .take(i)
-                    .copied()
-                    .chain(std::iter::once(prefix[i] + 1))
-                    .collect(),
-            ),
-            None => Bound::Unbounded,
+        self.scan(prefix_range(prefix))
+    }
+
+    /// Counts the keys with the given prefix. An empty prefix counts all keys.
+    fn count_prefix(&mut self, prefix: &[u8]) -> Result<usize> {
+        self.count(prefix_range(prefix))
+    }
+
+    /// Deletes all keys with the given prefix, returning the number of keys
+    /// deleted. An empty prefix deletes all keys.
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<u64> {
+        self.delete_range(prefix_range(prefix))
+    }
+
+    /// Deletes all keys in the range, with the same bounds as [`Engine::scan`],
+    /// returning the number of keys deleted. The default implementation
+    /// deletes them in a single write batch, e.g. appending a tombstone per
+    /// key for BitCask.
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        let keys = self.scan_keys(range).collect::<Result<Vec<_>>>()?;
+        let mut batch = WriteBatch::new();
+        for key in &keys {
+            batch.delete(key);
+        }
+        self.write_batch(batch)?;
+        Ok(keys.len() as u64)
+    }
+
+    /// Exports all key/value pairs to the writer, in the format read by
+    /// [`import`].
+    fn export(&mut self, writer: impl std::io::Write) -> Result<()> {
+        let mut writer = ExportWriter {
+            inner: std::io::BufWriter::new(writer),
+            hasher: crc32fast::Hasher::new(),
         };
-        self.scan((start, end))
+        writer.write(EXPORT_MAGIC)?;
+        writer.write(&[EXPORT_VERSION])?;
+        let mut count: u64 = 0;
+        for item in self.scan(..) {
+            let (key, value) = item?;
+            writer.write(&[EXPORT_ENTRY])?;
+            writer.write(&export_length(&key)?.to_be_bytes())?;
+            writer.write(&export_length(&value)?.to_be_bytes())?;
+            writer.write(&key)?;
+            writer.write(&value)?;
+            count += 1;
+        }
+        writer.write(&[EXPORT_END])?;
+        writer.write(&count.to_be_bytes())?;
+        let checksum = writer.hasher.clone().finalize();
+        writer.write(&checksum.to_be_bytes())?;
+        writer.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// The magic bytes at the start of an export.
+const EXPORT_MAGIC: &[u8; 4] = b"YUUX";
+/// The export format version.
+const EXPORT_VERSION: u8 = 1;
+/// Tags an exported key/value pair.
+const EXPORT_ENTRY: u8 = 1;
+/// Tags the end of an export.
+const EXPORT_END: u8 = 0;
+
+/// Returns the length of an exported key or value.
+fn export_length(bytes: &[u8]) -> Result<u32> {
+    u32::try_from(bytes.len())
+        .map_err(|_| Error::Value(format!("Can't export {} bytes", bytes.len())))
+}
+
+/// A writer that checksums everything written to it.
+struct ExportWriter<W: std::io::Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: std::io::Write> ExportWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        Ok(self.inner.write_all(bytes)?)
+    }
+}
+
+/// Imports key/value pairs exported by [`Engine::export`] into the engine,
+/// overwriting existing keys. The export is read and validated in full before
+/// it is applied as a single write batch, so a truncated or corrupt export is
+/// rejected with [`Error::Parse`] without modifying the engine.
+///
+/// An export consists of the magic bytes `YUUX` and a 1-byte format version,
+/// followed by a 1-byte tag per record. An entry (tag 1) is followed by the
+/// u32 key length, u32 value length, key and value. The end (tag 0) is
+/// followed by the u64 entry count and a CRC32 checksum of all preceding
+/// bytes. Integers are big-endian.
+pub fn import(engine: &mut impl Engine, reader: impl std::io::Read) -> Result<()> {
+    let mut reader = ImportReader {
+        inner: std::io::BufReader::new(reader),
+        hasher: crc32fast::Hasher::new(),
+    };
+    if reader.read(EXPORT_MAGIC.len())? != EXPORT_MAGIC {
+        return Err(Error::Parse("Invalid export magic".to_string()));
+    }
+    match reader.read_u8()? {
+        EXPORT_VERSION => {}
+        version => {
+            return Err(Error::Parse(format!(
+                "Unsupported export version {version}"
+            )))
+        }
+    }
+    let mut batch = WriteBatch::new();
+    let count = loop {
+        match reader.read_u8()? {
+            EXPORT_ENTRY => {
+                let key_length = reader.read_u32()? as usize;
+                let value_length = reader.read_u32()? as usize;
+                let key = reader.read(key_length)?;
+                let value = reader.read(value_length)?;
+                batch.set(&key, value);
+            }
+            EXPORT_END => break reader.read_u64()?,
+            tag => return Err(Error::Parse(format!("Invalid export tag {tag}"))),
+        }
+    };
+    let checksum = reader.hasher.clone().finalize();
+    if reader.read_u32()? != checksum {
+        return Err(Error::Parse("Export checksum mismatch".to_string()));
+    }
+    if count != batch.len() as u64 {
+        return Err(Error::Parse(format!(
+            "Export has {} entries, expected {count}",
+            batch.len()
+        )));
+    }
+    engine.write_batch(batch)
+}
+
+/// A reader that checksums everything read from it, and reports a premature
+/// end of input as a truncated export.
+struct ImportReader<R: std::io::Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: std::io::Read> ImportReader<R> {
+    fn read(&mut self, length: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        (&mut self.inner)
+            .take(length as u64)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() != length {
+            return Err(Error::Parse("Truncated export".to_string()));
+        }
+        self.hasher.update(&bytes);
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.read(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.read(8)?.try_into().unwrap()))
     }
 }
 
+/// The first difference between the contents of two engines, as found by
+/// [`compare_engines`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    /// The key only exists in the first engine.
+    OnlyInFirst(Vec<u8>),
+    /// The key only exists in the second engine.
+    OnlyInSecond(Vec<u8>),
+    /// The key has different values in the engines.
+    Value(Vec<u8>),
+}
+
+/// Compares the contents of two engines, returning the first difference in
+/// key order, or None if they're equal. Both engines are scanned in lockstep,
+/// stopping at the first difference, so their contents are never held in
+/// memory. This can e.g. verify that an export and import or a migration
+/// preserved all data.
+pub fn compare_engines(a: &mut impl Engine, b: &mut impl Engine) -> Result<Option<Difference>> {
+    let (mut a, mut b) = (a.scan(..), b.scan(..));
+    loop {
+        match (a.next().transpose()?, b.next().transpose()?) {
+            (None, None) => return Ok(None),
+            (Some((key, _)), None) => return Ok(Some(Difference::OnlyInFirst(key))),
+            (None, Some((key, _))) => return Ok(Some(Difference::OnlyInSecond(key))),
+            (Some((a_key, _)), Some((b_key, _))) if a_key < b_key => {
+                return Ok(Some(Difference::OnlyInFirst(a_key)))
+            }
+            (Some((a_key, _)), Some((b_key, _))) if a_key > b_key => {
+                return Ok(Some(Difference::OnlyInSecond(b_key)))
+            }
+            (Some((key, a_value)), Some((_, b_value))) if a_value != b_value => {
+                return Ok(Some(Difference::Value(key)))
+            }
+            (Some(_), Some(_)) => {}
+        }
+    }
+}
+
+/// Returns whether two engines have the same contents, see
+/// [`compare_engines`].
+pub fn engines_equal(a: &mut impl Engine, b: &mut impl Engine) -> Result<bool> {
+    Ok(compare_engines(a, b)?.is_none())
+}
+
+/// Returns the range of keys with the given prefix. The end bound is the
+/// prefix with its last non-0xff byte incremented and any trailing 0xff bytes
+/// dropped, or unbounded if the prefix only consists of 0xff bytes. An empty
+/// prefix thus yields the full range, starting at the empty key.
+///
+/// The range can be passed to any range-based engine method, e.g.
+/// [`Engine::delete_range`] or [`Engine::count`].
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = Bound::Included(prefix.to_vec());
+    let end = match prefix.iter().rposition(|b| *b != 0xff) {
+        Some(i) => Bound::Excluded(
+            prefix
+                .iter()
+                .take(i)
+                .copied()
+                .chain(std::iter::once(prefix[i] + 1))
+                .collect(),
+        ),
+        None => Bound::Unbounded,
+    };
+    (start, end)
+}
+
+/// Clones the bounds of a range, e.g. to scan the same range more than once.
+pub(crate) fn clone_bounds(
+    range: &impl std::ops::RangeBounds<Vec<u8>>,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
+
+/// The default maximum key size, in bytes. Keys are held in memory by most
+/// engines, so this is far lower than the value limit.
+pub const DEFAULT_MAX_KEY_SIZE: usize = 1 << 24;
+
+/// The default maximum value size, in bytes.
+pub const DEFAULT_MAX_VALUE_SIZE: u64 = 1 << 32;
+
+/// Returns [`Error::Value`] if the key or value length exceeds the given
+/// limits, for engines and wrappers that enforce size limits.
+pub(crate) fn check_size(
+    key: &[u8],
+    value_length: usize,
+    max_key_size: usize,
+    max_value_size: u64,
+) -> Result<()> {
+    if key.len() > max_key_size {
+        return Err(Error::Value(format!(
+            "Key of {} bytes exceeds the maximum of {max_key_size} bytes",
+            key.len()
+        )));
+    }
+    if value_length as u64 > max_value_size {
+        return Err(Error::Value(format!(
+            "Value of {value_length} bytes exceeds the maximum of {max_value_size} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// An engine that can also serve reads through a shared reference, allowing
+/// concurrent readers, e.g. behind an `Arc`. Writes still require `&mut self`.
+pub trait ReadEngine: Engine {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_>;
+}
+
 // Original tests from toyDB
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "std")]
+    use super::super::{
+        bitcask::{BitCask, BitCaskConfig},
+        durable::Durable,
+        lsm::Lsm,
+        tiered::Tiered,
+    };
     use super::{
-        super::{bitcask::BitCask, memory::Memory},
+        super::{
+            compressed::{Codec, CompressedEngine},
+            encrypted::EncryptedEngine,
+            indexed::Indexed,
+            limited::Limited,
+            memory::Memory,
+            metered::Metered,
+            prefixed::Prefixed,
+            retry::Retry,
+            sharded::Sharded,
+        },
         *,
     };
 
@@ -117,6 +846,55 @@ mod tests {
                 Ok(())
             }
 
+            #[test]
+            /// Tests that write batches apply all operations in order.
+            fn write_batch() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+
+                let mut batch = WriteBatch::new();
+                batch.set(b"c", vec![3]);
+                batch.delete(b"a");
+                batch.set(b"b", vec![0]);
+                batch.set(b"b", vec![2, 2]);
+                batch.set(b"d", vec![4]);
+                batch.delete(b"d");
+                assert_eq!(batch.len(), 6);
+                s.write_batch(batch)?;
+
+                assert_scan(s.scan(..), vec![(b"b", vec![2, 2]), (b"c", vec![3])])?;
+
+                // Empty batches are no-ops.
+                s.write_batch(WriteBatch::new())?;
+                assert_scan(s.scan(..), vec![(b"b", vec![2, 2]), (b"c", vec![3])])?;
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that ReadEngine reads match the Engine reads.
+            fn shared_reads() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+                s.set(b"c", vec![3])?;
+                s.delete(b"b")?;
+
+                assert_eq!(s.get_shared(b"a")?, Some(vec![1]));
+                assert_eq!(s.get_shared(b"b")?, None);
+                assert_scan(
+                    s.scan_shared(b"a".to_vec()..),
+                    vec![(b"a", vec![1]), (b"c", vec![3])],
+                )?;
+                assert_scan(
+                    s.scan_shared(..).rev(),
+                    vec![(b"c", vec![3]), (b"a", vec![1])],
+                )?;
+
+                Ok(())
+            }
+
             #[test]
             /// Tests Engine point operations on empty keys and values. These
             /// are as valid as any other key/value.
@@ -208,6 +986,626 @@ mod tests {
                 Ok(())
             }
 
+            #[test]
+            /// Tests that key scans yield the same keys as scans.
+            fn scan_keys() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+                s.set(b"ba", vec![2, 1])?;
+                s.set(b"bb", vec![2, 2])?;
+                s.set(b"c", vec![3])?;
+                s.delete(b"c")?;
+
+                let ranges: Vec<(Bound<Vec<u8>>, Bound<Vec<u8>>)> = vec![
+                    (Bound::Unbounded, Bound::Unbounded),
+                    (
+                        Bound::Included(b"b".to_vec()),
+                        Bound::Excluded(b"bb".to_vec()),
+                    ),
+                    (
+                        Bound::Excluded(b"b".to_vec()),
+                        Bound::Included(b"bb".to_vec()),
+                    ),
+                    (Bound::Included(b"bz".to_vec()), Bound::Unbounded),
+                ];
+                for range in ranges {
+                    let expect = s
+                        .scan(range.clone())
+                        .map(|r| r.map(|(k, _)| k))
+                        .collect::<Result<Vec<_>>>()?;
+                    assert_eq!(
+                        s.scan_keys(range.clone()).collect::<Result<Vec<_>>>()?,
+                        expect
+                    );
+
+                    let mut expect = expect;
+                    expect.reverse();
+                    assert_eq!(
+                        s.scan_keys(range).rev().collect::<Result<Vec<_>>>()?,
+                        expect
+                    );
+                }
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that keys and values yield the projections of a scan.
+            fn keys_values() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+                s.set(b"c", vec![])?;
+                s.set(b"d", vec![4])?;
+                s.delete(b"d")?;
+
+                let range = b"b".to_vec()..;
+                assert_eq!(
+                    s.keys(range.clone()).collect::<Result<Vec<_>>>()?,
+                    vec![b"b".to_vec(), b"c".to_vec()]
+                );
+                assert_eq!(
+                    s.values(range).collect::<Result<Vec<_>>>()?,
+                    vec![vec![2], vec![]]
+                );
+                assert_eq!(
+                    s.values(..).rev().collect::<Result<Vec<_>>>()?,
+                    vec![vec![], vec![2], vec![1]]
+                );
+                assert_eq!(s.keys(..).count(), 3);
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that get_required errors on missing keys.
+            fn get_required() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                assert_eq!(s.get_required(b"a")?, vec![1]);
+
+                let error = s.get_required(b"b\x00").unwrap_err();
+                assert_eq!(error, Error::NotFound("key b\\x00".to_string()));
+                assert_eq!(error.to_string(), "Not found: key b\\x00");
+
+                s.delete(b"a")?;
+                assert!(matches!(s.get_required(b"a"), Err(Error::NotFound(_))));
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that compare_and_swap only writes when the current value
+            /// matches, including absent keys.
+            fn compare_and_swap() -> Result<()> {
+                let mut s = $setup;
+
+                // Absent keys.
+                assert!(!s.compare_and_swap(b"a", Some(&[]), Some(vec![1]))?);
+                assert_eq!(s.get(b"a")?, None);
+                assert!(s.compare_and_swap(b"a", None, Some(vec![1]))?);
+                assert_eq!(s.get(b"a")?, Some(vec![1]));
+                assert!(!s.compare_and_swap(b"a", None, Some(vec![2]))?);
+                assert!(s.compare_and_swap(b"b", None, None)?);
+                assert_eq!(s.get(b"b")?, None);
+
+                // Matches and mismatches.
+                assert!(!s.compare_and_swap(b"a", Some(&[2]), Some(vec![3]))?);
+                assert_eq!(s.get(b"a")?, Some(vec![1]));
+                assert!(s.compare_and_swap(b"a", Some(&[1]), Some(vec![2]))?);
+                assert_eq!(s.get(b"a")?, Some(vec![2]));
+                assert!(!s.compare_and_swap(b"a", Some(&[1]), None)?);
+                assert!(s.compare_and_swap(b"a", Some(&[2]), None)?);
+                assert_eq!(s.get(b"a")?, None);
+
+                // Empty values are distinct from absent keys.
+                s.set(b"c", vec![])?;
+                assert!(!s.compare_and_swap(b"c", None, Some(vec![1]))?);
+                assert!(s.compare_and_swap(b"c", Some(&[]), Some(vec![1]))?);
+                assert_eq!(s.get(b"c")?, Some(vec![1]));
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that get_with_metadata returns the value and its length.
+            fn get_with_metadata() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1, 2, 3])?;
+                s.set(b"b", vec![])?;
+
+                let (value, meta) = s.get_with_metadata(b"a")?.unwrap();
+                assert_eq!(value, vec![1, 2, 3]);
+                assert_eq!(meta.value_length, 3);
+                let (value, meta) = s.get_with_metadata(b"b")?.unwrap();
+                assert!(value.is_empty());
+                assert_eq!(meta.value_length, 0);
+                assert_eq!(s.get_with_metadata(b"c")?, None);
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that stat_key returns the key and value lengths.
+            fn stat_key() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"ab", vec![1, 2, 3])?;
+                s.set(b"", vec![])?;
+
+                let stat = s.stat_key(b"ab")?.unwrap();
+                assert_eq!((stat.key_length, stat.value_length), (2, 3));
+                assert!(stat.live);
+                let stat = s.stat_key(b"")?.unwrap();
+                assert_eq!((stat.key_length, stat.value_length), (0, 0));
+                assert_eq!(s.stat_key(b"c")?, None);
+                s.delete(b"ab")?;
+                assert_eq!(s.stat_key(b"ab")?, None);
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that scans with borrowed bounds match scans with owned
+            /// bounds.
+            fn scan_bytes() -> Result<()> {
+                use std::ops::Bound::{Excluded, Included, Unbounded};
+
+                let mut s = $setup;
+                for key in [&b""[..], b"a", b"ab", b"b", b"ba", b"c", b"\xff"] {
+                    s.set(key, key.to_vec())?;
+                }
+                let starts = [
+                    Unbounded,
+                    Included(&b""[..]),
+                    Included(b"a"),
+                    Excluded(b"a"),
+                    Included(b"b"),
+                ];
+                let ends = [
+                    Unbounded,
+                    Excluded(&b"bb"[..]),
+                    Included(b"c"),
+                    Excluded(b"\xff"),
+                    Included(b"\xff"),
+                ];
+                for start in starts {
+                    for end in ends {
+                        let owned = (start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+                        let expect = s.scan(owned).collect::<Result<Vec<_>>>()?;
+                        assert_eq!(
+                            s.scan_bytes(start, end).collect::<Result<Vec<_>>>()?,
+                            expect,
+                            "{start:?}..{end:?}"
+                        );
+                        assert_eq!(s.scan_bytes(start, end).rev().count(), expect.len());
+                    }
+                }
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that set_if_absent only inserts absent keys.
+            fn set_if_absent() -> Result<()> {
+                let mut s = $setup;
+
+                assert!(s.set_if_absent(b"a", vec![1])?);
+                assert_eq!(s.get(b"a")?, Some(vec![1]));
+                assert!(!s.set_if_absent(b"a", vec![2])?);
+                assert_eq!(s.get(b"a")?, Some(vec![1]));
+
+                // Empty values exist, while deleted keys can be inserted again.
+                s.set(b"b", vec![])?;
+                assert!(!s.set_if_absent(b"b", vec![2])?);
+                assert_eq!(s.get(b"b")?, Some(vec![]));
+                s.delete(b"a")?;
+                assert!(s.set_if_absent(b"a", vec![3])?);
+                assert_eq!(s.get(b"a")?, Some(vec![3]));
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that merges accumulate, using a counter merge.
+            fn merge() -> Result<()> {
+                fn add(value: Option<Vec<u8>>, operand: Vec<u8>) -> Vec<u8> {
+                    let decode = |bytes: &[u8]| u64::from_be_bytes(bytes.try_into().unwrap());
+                    let sum = value.map_or(0, |value| decode(&value)) + decode(&operand);
+                    sum.to_be_bytes().to_vec()
+                }
+
+                let mut s = $setup;
+                for i in 1..=100u64 {
+                    s.merge(b"counter", i.to_be_bytes().to_vec(), add)?;
+                    s.merge(b"ones", 1u64.to_be_bytes().to_vec(), add)?;
+                }
+                assert_eq!(s.get(b"counter")?, Some(5050u64.to_be_bytes().to_vec()));
+                assert_eq!(s.get(b"ones")?, Some(100u64.to_be_bytes().to_vec()));
+
+                // Merging into a deleted key starts over.
+                s.delete(b"ones")?;
+                s.merge(b"ones", 1u64.to_be_bytes().to_vec(), add)?;
+                assert_eq!(s.get(b"ones")?, Some(1u64.to_be_bytes().to_vec()));
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that update modifies, deletes and inserts keys.
+            fn update() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+
+                s.update(b"a", |value| {
+                    let mut value = value?;
+                    value.push(2);
+                    Some(value)
+                })?;
+                assert_eq!(s.get(b"a")?, Some(vec![1, 2]));
+
+                s.update(b"b", |value| {
+                    assert_eq!(value, Some(vec![2]));
+                    None
+                })?;
+                assert_eq!(s.get(b"b")?, None);
+
+                s.update(b"c", |value| Some(value.unwrap_or(vec![3])))?;
+                assert_eq!(s.get(b"c")?, Some(vec![3]));
+                s.update(b"d", |_| None)?;
+                assert_eq!(s.get(b"d")?, None);
+                assert_eq!(s.count(..)?, 2);
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that rename_key moves present keys, overwriting the
+            /// target, and ignores absent keys.
+            fn rename_key() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+
+                assert!(s.rename_key(b"a", b"c")?);
+                assert_eq!(s.get(b"a")?, None);
+                assert_eq!(s.get(b"c")?, Some(vec![1]));
+
+                assert!(s.rename_key(b"c", b"b")?);
+                assert_eq!(s.get(b"b")?, Some(vec![1]));
+                assert_eq!(s.get(b"c")?, None);
+
+                assert!(!s.rename_key(b"a", b"b")?);
+                assert_eq!(s.get(b"b")?, Some(vec![1]));
+                assert!(s.rename_key(b"b", b"b")?);
+                assert_eq!(s.get(b"b")?, Some(vec![1]));
+                assert_eq!(s.count(..)?, 1);
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that sync_range keeps all writes, in and out of the range.
+            fn sync_range() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a/1", vec![1])?;
+                s.set(b"b/1", vec![2])?;
+                s.sync_range(b"a/".to_vec()..b"b/".to_vec())?;
+                s.set(b"a/2", vec![3])?;
+                s.sync_range(..)?;
+                s.sync_range(b"c".to_vec()..)?;
+                assert_eq!(
+                    s.scan(..).collect::<Result<Vec<_>>>()?,
+                    vec![
+                        (b"a/1".to_vec(), vec![1]),
+                        (b"a/2".to_vec(), vec![3]),
+                        (b"b/1".to_vec(), vec![2]),
+                    ]
+                );
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that stats_histogram buckets value sizes by power of two,
+            /// and summarizes the live keys and values.
+            fn stats_histogram() -> Result<()> {
+                let mut s = $setup;
+                assert_eq!(s.stats_histogram()?, SizeHistogram::default());
+
+                s.set(b"a", vec![])?;
+                s.set(b"bb", vec![1])?;
+                s.set(b"c", vec![1; 3])?;
+                s.set(b"dddd", vec![1; 4])?;
+                s.set(b"e", vec![1; 100])?;
+                s.set(b"f", vec![1; 1000])?;
+                s.set(b"f", vec![1; 100])?;
+                s.set(b"g", vec![1; 1000])?;
+                s.delete(b"g")?;
+
+                let histogram = s.stats_histogram()?;
+                assert_eq!(histogram.value_buckets, vec![1, 1, 1, 1, 0, 0, 0, 2]);
+                assert_eq!(histogram.count(), 6);
+                assert_eq!(
+                    histogram.key_sizes,
+                    SizeStats {
+                        count: 6,
+                        min: 1,
+                        max: 4,
+                        total: 10
+                    }
+                );
+                assert_eq!(
+                    histogram.value_sizes,
+                    SizeStats {
+                        count: 6,
+                        min: 0,
+                        max: 100,
+                        total: 208
+                    }
+                );
+                assert_eq!(histogram.value_sizes.mean(), 208.0 / 6.0);
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that clear removes all keys, and that the engine remains
+            /// usable afterwards.
+            fn clear() -> Result<()> {
+                let mut s = $setup;
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+                s.delete(b"b")?;
+
+                s.clear()?;
+                assert_scan(s.scan(..), vec![])?;
+                assert_eq!(s.get(b"a")?, None);
+                assert_eq!(s.status()?.key_count, 0);
+
+                // Clearing an empty engine is a no-op.
+                s.clear()?;
+                s.set(b"c", vec![3])?;
+                assert_scan(s.scan(..), vec![(b"c", vec![3])])?;
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that get_many returns values in the order of the keys,
+            /// including missing and duplicate keys.
+            fn get_many() -> Result<()> {
+                let mut s = $setup;
+                assert_eq!(s.get_many(&[])?, vec![]);
+
+                s.set(b"c", vec![3])?;
+                s.set(b"a", vec![1])?;
+                s.set(b"d", vec![4])?;
+                s.set(b"b", vec![2])?;
+                s.delete(b"d")?;
+                s.set(b"a", vec![1, 1])?;
+
+                let keys = [b"b", b"x", b"a", b"d", b"c", b"b"].map(|key| key.to_vec());
+                assert_eq!(
+                    s.get_many(&keys)?,
+                    vec![
+                        Some(vec![2]),
+                        None,
+                        Some(vec![1, 1]),
+                        None,
+                        Some(vec![3]),
+                        Some(vec![2])
+                    ]
+                );
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that delete_prefix deletes exactly the keys with the
+            /// prefix, including keys straddling the prefix boundaries.
+            fn delete_prefix() -> Result<()> {
+                let mut s = $setup;
+                for key in [
+                    vec![0x00, 0xff],
+                    vec![0x01],
+                    vec![0x01, 0x00],
+                    vec![0x01, 0xff],
+                    vec![0x01, 0xff, 0xff],
+                    vec![0x02],
+                    vec![0xff],
+                    vec![0xff, 0xff],
+                    vec![0xff, 0xff, 0x00],
+                ] {
+                    s.set(&key, key.clone())?;
+                }
+
+                assert_eq!(s.delete_prefix(&[0x01])?, 4);
+                assert_eq!(s.delete_prefix(&[0x01])?, 0);
+                assert_eq!(s.delete_prefix(&[0x03])?, 0);
+
+                // An all-0xff prefix has an unbounded end.
+                assert_eq!(s.delete_prefix(&[0xff, 0xff])?, 2);
+                assert_scan(
+                    s.scan(..),
+                    vec![
+                        (&[0x00, 0xff], vec![0x00, 0xff]),
+                        (&[0x02], vec![0x02]),
+                        (&[0xff], vec![0xff]),
+                    ],
+                )?;
+
+                // An empty prefix deletes everything.
+                assert_eq!(s.delete_prefix(&[])?, 3);
+                assert_scan(s.scan(..), vec![])?;
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests first and last on an empty and a populated engine.
+            fn first_last() -> Result<()> {
+                let mut s = $setup;
+                assert_eq!(s.first()?, None);
+                assert_eq!(s.last()?, None);
+
+                s.set(b"b", vec![2])?;
+                assert_eq!(s.first()?, Some((b"b".to_vec(), vec![2])));
+                assert_eq!(s.last()?, Some((b"b".to_vec(), vec![2])));
+
+                s.set(b"", vec![0])?;
+                s.set(b"\xff\xff", vec![0xff])?;
+                s.set(b"c", vec![3])?;
+                assert_eq!(s.first()?, Some((b"".to_vec(), vec![0])));
+                assert_eq!(s.last()?, Some((b"\xff\xff".to_vec(), vec![0xff])));
+
+                s.delete(b"")?;
+                s.delete(b"\xff\xff")?;
+                assert_eq!(s.first()?, Some((b"b".to_vec(), vec![2])));
+                assert_eq!(s.last()?, Some((b"c".to_vec(), vec![3])));
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests range deletion with inclusive, exclusive and unbounded
+            /// ends, matching the bounds of scans.
+            fn delete_range() -> Result<()> {
+                let mut s = $setup;
+                for key in [b"a", b"b", b"c", b"d", b"e", b"f"] {
+                    s.set(key, key.to_vec())?;
+                }
+
+                // Exclusive end.
+                assert_eq!(s.delete_range(b"b".to_vec()..b"d".to_vec())?, 2);
+                assert_eq!(s.get(b"b")?, None);
+                assert_eq!(s.get(b"d")?, Some(b"d".to_vec()));
+
+                // Inclusive end.
+                assert_eq!(s.delete_range(b"d".to_vec()..=b"e".to_vec())?, 2);
+                assert_eq!(s.get(b"e")?, None);
+                assert_scan(
+                    s.scan(..),
+                    vec![(b"a", b"a".to_vec()), (b"f", b"f".to_vec())],
+                )?;
+
+                // Empty ranges delete nothing.
+                assert_eq!(s.delete_range(b"b".to_vec()..b"f".to_vec())?, 0);
+                assert_eq!(s.delete_range(b"f".to_vec()..b"f".to_vec())?, 0);
+                assert_eq!(s.count(..)?, 2);
+
+                // Unbounded ranges.
+                assert_eq!(s.delete_range(b"b".to_vec()..)?, 1);
+                s.set(b"g", vec![])?;
+                assert_eq!(s.delete_range(..)?, 2);
+                assert_scan(s.scan(..), vec![])?;
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests that reverse scans match reversed forward scans.
+            fn scan_rev() -> Result<()> {
+                let mut s = $setup;
+                assert_scan(s.scan_rev(..), vec![])?;
+                for key in [&b""[..], b"a", b"b", b"ba", b"c", b"\xff"] {
+                    s.set(key, key.to_vec())?;
+                }
+                s.delete(b"c")?;
+
+                let ranges: Vec<(Bound<Vec<u8>>, Bound<Vec<u8>>)> = vec![
+                    (Bound::Unbounded, Bound::Unbounded),
+                    (
+                        Bound::Included(b"a".to_vec()),
+                        Bound::Excluded(b"c".to_vec()),
+                    ),
+                    (
+                        Bound::Excluded(b"a".to_vec()),
+                        Bound::Included(b"\xff".to_vec()),
+                    ),
+                    (Bound::Included(b"d".to_vec()), Bound::Unbounded),
+                ];
+                for range in ranges {
+                    let expect = s.scan(range.clone()).rev().collect::<Result<Vec<_>>>()?;
+                    assert_eq!(s.scan_rev(range).collect::<Result<Vec<_>>>()?, expect);
+                }
+                assert_scan(
+                    s.scan_rev(..).take(2),
+                    vec![(b"\xff", b"\xff".to_vec()), (b"ba", b"ba".to_vec())],
+                )?;
+                Ok(())
+            }
+
+            #[test]
+            /// Tests limited scans, and paginating through a range with them.
+            fn scan_limit() -> Result<()> {
+                let mut s = $setup;
+                assert_scan(s.scan_limit(.., 2), vec![])?;
+                for key in [b"a", b"b", b"c", b"d", b"e"] {
+                    s.set(key, key.to_vec())?;
+                }
+
+                assert_scan(s.scan_limit(.., 0), vec![])?;
+                assert_scan(
+                    s.scan_limit(.., 2),
+                    vec![(b"a", b"a".to_vec()), (b"b", b"b".to_vec())],
+                )?;
+                assert_scan(
+                    s.scan_limit(b"d".to_vec().., 3),
+                    vec![(b"d", b"d".to_vec()), (b"e", b"e".to_vec())],
+                )?;
+
+                let mut pages = Vec::new();
+                let mut start = Bound::Unbounded;
+                loop {
+                    let page = s
+                        .scan_limit((start, Bound::Unbounded), 2)
+                        .map(|result| result.map(|(key, _)| key))
+                        .collect::<Result<Vec<_>>>()?;
+                    match page.last() {
+                        Some(last) => start = Bound::Excluded(last.clone()),
+                        None => break,
+                    }
+                    pages.push(page);
+                }
+                assert_eq!(
+                    pages,
+                    vec![
+                        vec![b"a".to_vec(), b"b".to_vec()],
+                        vec![b"c".to_vec(), b"d".to_vec()],
+                        vec![b"e".to_vec()],
+                    ]
+                );
+
+                Ok(())
+            }
+
+            #[test]
+            /// Tests counting keys in ranges.
+            fn count() -> Result<()> {
+                let mut s = $setup;
+                assert_eq!(s.count(..)?, 0);
+                assert!(s.is_empty_range(..)?);
+
+                s.set(b"a", vec![1])?;
+                s.set(b"b", vec![2])?;
+                s.set(b"ba", vec![2, 1])?;
+                s.set(b"bb", vec![2, 2])?;
+                s.set(b"c", vec![3])?;
+                s.set(b"d", vec![4])?;
+                s.delete(b"d")?;
+
+                // Unbounded ranges.
+                assert_eq!(s.count(..)?, 5);
+                assert_eq!(s.count(b"b".to_vec()..)?, 4);
+                assert_eq!(s.count(..b"b".to_vec())?, 1);
+                assert!(!s.is_empty_range(..)?);
+
+                // Bounded ranges.
+                assert_eq!(s.count(b"b".to_vec()..b"bb".to_vec())?, 2);
+                assert_eq!(s.count(b"b".to_vec()..=b"bb".to_vec())?, 3);
+                assert_eq!(s.count(b"d".to_vec()..b"e".to_vec())?, 0);
+                assert!(s.is_empty_range(b"d".to_vec()..b"e".to_vec())?);
+                assert!(!s.is_empty_range(b"a".to_vec()..=b"a".to_vec())?);
+
+                // Prefix ranges.
+                assert_eq!(s.count(b"b".to_vec()..b"c".to_vec())?, 3);
+                assert_eq!(s.count(b"ba".to_vec()..b"bb".to_vec())?, 1);
+                assert!(s.is_empty_range(b"bc".to_vec()..b"bd".to_vec())?);
+
+                Ok(())
+            }
+
             #[test]
             /// Tests prefix scans.
             fn scan_prefix() -> Result<()> {
@@ -245,6 +1643,12 @@ mod tests {
                     ],
                 )?;
 
+                assert_eq!(s.count_prefix(b"")?, 13);
+                assert_eq!(s.count_prefix(b"b")?, 7);
+                assert_eq!(s.count_prefix(b"b\xff")?, 4);
+                assert_eq!(s.count_prefix(b"bq")?, 0);
+                assert_eq!(s.count_prefix(b"\xff\xff")?, 3);
+
                 assert_scan(
                     s.scan_prefix(b"b"),
                     vec![
@@ -456,6 +1860,12 @@ mod tests {
         test_engine!(Memory::new());
     }
 
+    mod test_memory_capacity {
+        use super::*;
+        test_engine!(Memory::with_capacity(u64::MAX));
+    }
+
+    #[cfg(feature = "std")]
     mod test_bitcask {
         use super::*;
 
@@ -464,4 +1874,253 @@ mod tests {
             BitCask::new(path)?
         });
     }
+
+    #[cfg(feature = "std")]
+    mod test_bitcask_hashed {
+        use super::*;
+
+        test_engine!({
+            let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+            let config = BitCaskConfig {
+                hashed_key_dir: true,
+                ..Default::default()
+            };
+            BitCask::with_config(path, config)?
+        });
+    }
+
+    #[cfg(feature = "std")]
+    mod test_bitcask_memory {
+        use super::*;
+        test_engine!(BitCask::with_file(
+            std::io::Cursor::new(Vec::new()),
+            Default::default()
+        )?);
+    }
+
+    mod test_compressed {
+        use super::*;
+        test_engine!(CompressedEngine::new(Memory::new(), Codec::Zstd));
+    }
+
+    mod test_encrypted {
+        use super::*;
+        test_engine!(EncryptedEngine::new(Memory::new(), &[0; 32]));
+    }
+
+    mod test_encrypted_keys {
+        use super::*;
+        test_engine!(EncryptedEngine::new_with_key_encryption(
+            Memory::new(),
+            &[0; 32]
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    mod test_durable {
+        use super::*;
+
+        test_engine!({
+            let path = tempdir::TempDir::new("yuudb")?.path().join("wal");
+            Durable::new(Memory::new(), path)?
+        });
+    }
+
+    mod test_indexed {
+        use super::*;
+        test_engine!(Indexed::new(
+            Memory::new(),
+            Memory::new(),
+            |value: &[u8]| Some(value.to_vec())
+        ));
+    }
+
+    mod test_limited {
+        use super::*;
+        test_engine!(Limited::new(
+            Memory::new(),
+            DEFAULT_MAX_KEY_SIZE,
+            DEFAULT_MAX_VALUE_SIZE
+        ));
+    }
+
+    mod test_metered {
+        use super::*;
+        test_engine!(Metered::new(Memory::new()));
+    }
+
+    mod test_prefixed {
+        use super::*;
+        test_engine!(Prefixed::new(Memory::new(), b"ns/"));
+    }
+
+    mod test_retry {
+        use super::*;
+        test_engine!(Retry::new(Memory::new(), 3, std::time::Duration::ZERO));
+    }
+
+    mod test_sharded {
+        use super::*;
+        test_engine!(Sharded::new(3, |_| Ok(Memory::new()))?);
+    }
+
+    #[cfg(feature = "std")]
+    mod test_tiered {
+        use super::*;
+
+        test_engine!({
+            let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+            Tiered::new(BitCask::new(path)?, 16)
+        });
+    }
+
+    #[cfg(feature = "std")]
+    mod test_lsm {
+        use super::*;
+
+        test_engine!({
+            let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+            Lsm::new_with_memtable_size(path, 64)?
+        });
+    }
+
+    #[test]
+    /// Tests the prefix range bounds, in particular the carry over trailing
+    /// 0xff bytes.
+    fn prefix_range() {
+        use Bound::{Excluded, Included, Unbounded};
+
+        let cases: Vec<(&[u8], Bound<Vec<u8>>)> = vec![
+            (&[], Unbounded),
+            (&[0x00], Excluded(vec![0x01])),
+            (&[0x01, 0x02], Excluded(vec![0x01, 0x03])),
+            (&[0xff], Unbounded),
+            (&[0x01, 0xff], Excluded(vec![0x02])),
+            (&[0x01, 0xff, 0xff], Excluded(vec![0x02])),
+            (&[0x10, 0xff, 0xff], Excluded(vec![0x11])),
+            (&[0x01, 0xfe, 0xff], Excluded(vec![0x01, 0xff])),
+            (&[0xff, 0x00, 0xff], Excluded(vec![0xff, 0x01])),
+            (&[0xff, 0xff], Unbounded),
+        ];
+        for (prefix, end) in cases {
+            assert_eq!(
+                super::prefix_range(prefix),
+                (Included(prefix.to_vec()), end),
+                "prefix {prefix:x?}"
+            );
+        }
+    }
+
+    #[test]
+    /// Tests that an empty prefix scans all keys, including the empty key.
+    fn scan_prefix_empty() -> Result<()> {
+        let mut s = Memory::new();
+        for key in [&[][..], &[0x00], &[0x01, 0xff], &[0xff, 0xff]] {
+            s.set(key, vec![])?;
+        }
+        assert_eq!(s.scan_prefix(&[]).count(), 4);
+        assert_eq!(s.scan_prefix(&[0xff]).count(), 1);
+        assert_eq!(s.scan_prefix(&[0x01, 0xff]).count(), 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests comparing identical and divergent engines.
+    fn compare_engines() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut a = BitCask::new(path)?;
+        let mut b = Memory::new();
+        assert!(engines_equal(&mut a, &mut b)?);
+        for i in 0..100u8 {
+            a.set(&[i], vec![i])?;
+            b.set(&[i], vec![i])?;
+        }
+        a.delete(&[7])?;
+        b.delete(&[7])?;
+        assert!(engines_equal(&mut a, &mut b)?);
+        a.compact()?;
+        assert_eq!(super::compare_engines(&mut a, &mut b)?, None);
+
+        b.set(&[50], vec![0])?;
+        assert_eq!(
+            super::compare_engines(&mut a, &mut b)?,
+            Some(Difference::Value(vec![50]))
+        );
+        b.set(&[50], vec![50])?;
+        b.set(&[7], vec![7])?;
+        assert_eq!(
+            super::compare_engines(&mut a, &mut b)?,
+            Some(Difference::OnlyInSecond(vec![7]))
+        );
+        assert_eq!(
+            super::compare_engines(&mut b, &mut a)?,
+            Some(Difference::OnlyInFirst(vec![7]))
+        );
+        b.delete(&[7])?;
+        a.set(&[200], vec![])?;
+        assert_eq!(
+            super::compare_engines(&mut a, &mut b)?,
+            Some(Difference::OnlyInFirst(vec![200]))
+        );
+        assert!(!engines_equal(&mut a, &mut b)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests that an export from BitCask imports into Memory.
+    fn export_import() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path)?;
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2; 1000])?;
+        s.set(b"", vec![])?;
+        s.set(b"c", vec![3])?;
+        s.delete(b"c")?;
+
+        let mut buffer = Vec::new();
+        s.export(&mut buffer)?;
+
+        let mut m = Memory::new();
+        m.set(b"a", vec![0])?;
+        import(&mut m, buffer.as_slice())?;
+        assert_eq!(
+            m.scan(..).collect::<Result<Vec<_>>>()?,
+            s.scan(..).collect::<Result<Vec<_>>>()?
+        );
+
+        // An empty engine exports a valid, empty export.
+        let mut buffer = Vec::new();
+        Memory::new().export(&mut buffer)?;
+        import(&mut m, buffer.as_slice())?;
+        assert_eq!(m.count(..)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that truncated or corrupt exports are rejected without modifying
+    /// the engine.
+    fn import_invalid() -> Result<()> {
+        let mut s = Memory::new();
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2, 3])?;
+        let mut buffer = Vec::new();
+        s.export(&mut buffer)?;
+
+        let mut m = Memory::new();
+        for length in 0..buffer.len() {
+            assert!(
+                matches!(import(&mut m, &buffer[..length]), Err(Error::Parse(_))),
+                "length {length}"
+            );
+        }
+        for i in 0..buffer.len() {
+            let mut corrupt = buffer.clone();
+            corrupt[i] ^= 0x01;
+            assert!(import(&mut m, corrupt.as_slice()).is_err(), "byte {i}");
+        }
+        assert_eq!(m.count(..)?, 0);
+        Ok(())
+    }
 }
@@ -0,0 +1,407 @@
+/*!
+An engine that maintains a secondary index over values in a second engine.
+
+An [`Extractor`] derives an optional index key from each value, and the index
+engine maps each index key back to the primary keys whose values have it,
+such that [`Indexed::scan_index`] yields primary keys in index key order.
+Several primary keys can share an index key, in which case they're yielded in
+primary key order.
+
+Writes and deletes read the old value first, to remove its index entry. The
+index entry of a new value is written before the value, and the old one
+removed after it, so the index is updated in three separate writes. If
+interrupted by a crash, the index may contain stale entries, which
+[`Indexed::scan_index_values`] skips and [`Indexed::rebuild_index`] removes.
+*/
+
+use std::ops::Bound;
+
+use super::engine::{Engine, ReadEngine, Status};
+use super::mvcc::encode_bytes;
+use crate::error::Result;
+
+/// Derives the index key of a value. This must be deterministic, since the
+/// index key of an old value is derived again to remove its index entry.
+/// It's implemented for closures taking the value.
+pub trait Extractor: Send + Sync + 'static {
+    /// Returns the index key of the value, or None if it isn't indexed.
+    fn index_key(&self, value: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl<F> Extractor for F
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    fn index_key(&self, value: &[u8]) -> Option<Vec<u8>> {
+        self(value)
+    }
+}
+
+/// An engine with a secondary index over values.
+pub struct Indexed<E: Engine, X: Extractor> {
+    primary: E,
+    index: E,
+    extractor: X,
+}
+
+impl<E: Engine, X: Extractor> Indexed<E, X> {
+    /// Creates an indexed engine over the primary engine, storing the index
+    /// in the index engine. The index must match the primary engine, e.g. be
+    /// empty along with it, otherwise see [`Indexed::rebuild_index`].
+    pub fn new(primary: E, index: E, extractor: X) -> Self {
+        Self {
+            primary,
+            index,
+            extractor,
+        }
+    }
+
+    /// Returns the primary engine.
+    pub fn primary(&self) -> &E {
+        &self.primary
+    }
+
+    /// Returns the index engine.
+    pub fn index(&self) -> &E {
+        &self.index
+    }
+
+    /// Unwraps the primary and index engines.
+    pub fn into_inner(self) -> (E, E) {
+        (self.primary, self.index)
+    }
+
+    /// Returns the primary keys whose index keys are in the range, in index
+    /// key order.
+    pub fn scan_index(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        (self.index.scan(Self::index_range(range))).map(|item| item.map(|(_, key)| key))
+    }
+
+    /// Returns the primary key/value pairs whose index keys are in the range,
+    /// in index key order. Stale index entries, whose value is gone or has a
+    /// different index key, are skipped.
+    pub fn scan_index_values(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        let (primary, extractor) = (&mut self.primary, &self.extractor);
+        let items = self.index.scan(Self::index_range(range));
+        items.filter_map(move |item| {
+            let (entry_key, key) = match item {
+                Ok(item) => item,
+                Err(error) => return Some(Err(error)),
+            };
+            match primary.get(&key) {
+                Ok(Some(value)) => {
+                    let index_key = extractor.index_key(&value)?;
+                    (Self::entry_key(&index_key, &key) == entry_key).then_some(Ok((key, value)))
+                }
+                Ok(None) => None,
+                Err(error) => Some(Err(error)),
+            }
+        })
+    }
+
+    /// Rebuilds the index from the primary engine, e.g. to index existing
+    /// data or remove stale entries after a crash.
+    pub fn rebuild_index(&mut self) -> Result<()> {
+        self.index.clear()?;
+        for item in self.primary.scan(..) {
+            let (key, value) = item?;
+            if let Some(index_key) = self.extractor.index_key(&value) {
+                self.index.set(&Self::entry_key(&index_key, &key), key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the index engine key of a primary key's index entry. The index
+    /// key is encoded such that entries sort by index key, then primary key.
+    fn entry_key(index_key: &[u8], key: &[u8]) -> Vec<u8> {
+        let mut entry_key = Vec::with_capacity(index_key.len() + key.len() + 2);
+        encode_bytes(&mut entry_key, index_key);
+        entry_key.extend_from_slice(key);
+        entry_key
+    }
+
+    /// Maps a range of index keys to the range of their index entries.
+    fn index_range(range: impl std::ops::RangeBounds<Vec<u8>>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        // The entries of an index key are prefixed by its encoding, which is
+        // terminated by 0x00 0x00. Ending it with 0x00 0x01 instead sorts it
+        // after the entries of the index key, but before any greater one.
+        let before = |index_key: &[u8]| Self::entry_key(index_key, &[]);
+        let after = |index_key: &[u8]| {
+            let mut entry_key = before(index_key);
+            *entry_key.last_mut().unwrap() = 0x01;
+            entry_key
+        };
+        let start = match range.start_bound() {
+            Bound::Included(index_key) => Bound::Included(before(index_key)),
+            Bound::Excluded(index_key) => Bound::Included(after(index_key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(index_key) => Bound::Excluded(after(index_key)),
+            Bound::Excluded(index_key) => Bound::Excluded(before(index_key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        (start, end)
+    }
+}
+
+impl<E: Engine, X: Extractor> std::fmt::Display for Indexed<E, X> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (indexed)", self.primary)
+    }
+}
+
+impl<E: Engine, X: Extractor> Engine for Indexed<E, X> {
+    type ScanIterator<'a>
+        = E::ScanIterator<'a>
+    where
+        E: 'a,
+        X: 'a;
+
+    /// Moves the key's index entry if the new value has a different index
+    /// key than the old one.
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let old_value = self.primary.get(key)?;
+        let old_index_key = old_value.and_then(|value| self.extractor.index_key(&value));
+        let index_key = self.extractor.index_key(&value);
+        if index_key == old_index_key {
+            return self.primary.set(key, value);
+        }
+        if let Some(index_key) = &index_key {
+            self.index
+                .set(&Self::entry_key(index_key, key), key.to_vec())?;
+        }
+        self.primary.set(key, value)?;
+        if let Some(old_index_key) = &old_index_key {
+            self.index.delete(&Self::entry_key(old_index_key, key))?;
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.primary.get(key)
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.primary.get_many(keys)
+    }
+
+    /// Removes the key's index entry too.
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let Some(value) = self.primary.get(key)? else {
+            return Ok(());
+        };
+        self.primary.delete(key)?;
+        if let Some(index_key) = self.extractor.index_key(&value) {
+            self.index.delete(&Self::entry_key(&index_key, key))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.primary.flush()?;
+        self.index.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.primary.sync()?;
+        self.index.sync()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.primary.clear()?;
+        self.index.clear()
+    }
+
+    /// Includes all of the index's garbage, since index entries are ordered
+    /// by index key rather than primary key.
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        Ok(self.primary.estimate_garbage(range)? + self.index.estimate_garbage(..)?)
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.primary.approximate_memory_usage() + self.index.approximate_memory_usage()
+    }
+
+    /// Returns the key count and size of the primary engine, and the disk
+    /// sizes of both engines.
+    fn status(&mut self) -> Result<Status> {
+        let index = self.index.status()?;
+        let primary = self.primary.status()?;
+        Ok(Status {
+            name: self.to_string(),
+            total_disk_size: primary.total_disk_size + index.total_disk_size,
+            live_disk_size: primary.live_disk_size + index.live_disk_size,
+            garbage_disk_size: primary.garbage_disk_size + index.garbage_disk_size,
+            tombstone_count: primary.tombstone_count + index.tombstone_count,
+            file_count: primary.file_count + index.file_count,
+            compaction_recommended: primary.compaction_recommended || index.compaction_recommended,
+            ..primary
+        })
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.primary.scan(range)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.primary.count(range)
+    }
+}
+
+impl<E: ReadEngine, X: Extractor> ReadEngine for Indexed<E, X> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.primary.get_shared(key)
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.primary.scan_shared(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    /// Indexes values by their first byte.
+    fn first_byte(value: &[u8]) -> Option<Vec<u8>> {
+        value.first().map(|byte| vec![*byte])
+    }
+
+    #[test]
+    /// Tests that index scans yield primary keys in index key order, and
+    /// that updating a value moves its index entry.
+    fn scan_index() -> Result<()> {
+        let mut s = Indexed::new(Memory::new(), Memory::new(), first_byte);
+        s.set(b"a", vec![3, 1])?;
+        s.set(b"b", vec![1])?;
+        s.set(b"c", vec![2])?;
+        s.set(b"d", vec![])?;
+        s.set(b"e", vec![1, 2])?;
+
+        let scan = |s: &mut Indexed<_, _>, range| s.scan_index(range).collect::<Result<Vec<_>>>();
+        assert_eq!(
+            scan(&mut s, ..)?,
+            vec![b"b".to_vec(), b"e".to_vec(), b"c".to_vec(), b"a".to_vec()]
+        );
+        assert_eq!(
+            s.scan_index(..).rev().collect::<Result<Vec<_>>>()?,
+            vec![b"a".to_vec(), b"c".to_vec(), b"e".to_vec(), b"b".to_vec()]
+        );
+
+        // Updating a value moves its index entry, unless the index key is
+        // unchanged.
+        s.set(b"b", vec![3])?;
+        s.set(b"a", vec![3, 2])?;
+        assert_eq!(
+            scan(&mut s, ..)?,
+            vec![b"e".to_vec(), b"c".to_vec(), b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(s.index.count(..)?, 4);
+
+        // Unindexed values and deletes remove the index entry.
+        s.set(b"c", vec![])?;
+        s.delete(b"e")?;
+        s.delete(b"missing")?;
+        assert_eq!(scan(&mut s, ..)?, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(
+            s.scan_index_values(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![3, 2]), (b"b".to_vec(), vec![3])]
+        );
+        assert_eq!(s.count(..)?, 4);
+
+        s.clear()?;
+        assert_eq!(scan(&mut s, ..)?, Vec::<Vec<u8>>::new());
+        Ok(())
+    }
+
+    #[test]
+    /// Tests index scan bounds, including index keys that are prefixes of
+    /// other index keys or contain 0x00 bytes.
+    fn scan_index_range() -> Result<()> {
+        let mut s = Indexed::new(Memory::new(), Memory::new(), |value: &[u8]| {
+            Some(value.to_vec())
+        });
+        let values: Vec<&[u8]> = vec![b"", b"\x00", b"\x00\x00", b"a", b"a\x00", b"ab", b"b"];
+        for (i, value) in values.iter().enumerate() {
+            s.set(&[i as u8], value.to_vec())?;
+            s.set(&[i as u8, 0], value.to_vec())?;
+        }
+
+        let scan = |s: &mut Indexed<_, _>, range: (Bound<&[u8]>, Bound<&[u8]>)| {
+            let range = (range.0.map(|k| k.to_vec()), range.1.map(|k| k.to_vec()));
+            (s.scan_index_values(range))
+                .map(|item| item.map(|(_, value)| value))
+                .collect::<Result<Vec<_>>>()
+        };
+        let expect = |range: std::ops::Range<usize>| {
+            (values[range].iter())
+                .flat_map(|value| [value.to_vec(), value.to_vec()])
+                .collect::<Vec<_>>()
+        };
+        use Bound::{Excluded, Included, Unbounded};
+        assert_eq!(scan(&mut s, (Unbounded, Unbounded))?, expect(0..7));
+        assert_eq!(scan(&mut s, (Included(b"\x00"), Unbounded))?, expect(1..7));
+        assert_eq!(scan(&mut s, (Excluded(b"\x00"), Unbounded))?, expect(2..7));
+        assert_eq!(
+            scan(&mut s, (Included(b"a"), Included(b"ab")))?,
+            expect(3..6)
+        );
+        assert_eq!(
+            scan(&mut s, (Excluded(b"a"), Excluded(b"ab")))?,
+            expect(4..5)
+        );
+        assert_eq!(scan(&mut s, (Unbounded, Excluded(b"a")))?, expect(0..3));
+        assert_eq!(scan(&mut s, (Unbounded, Included(b"")))?, expect(0..1));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that stale index entries are skipped, and removed by rebuilding
+    /// the index.
+    fn rebuild_index() -> Result<()> {
+        let mut primary = Memory::new();
+        primary.set(b"a", vec![2])?;
+        primary.set(b"b", vec![1])?;
+        let mut index = Memory::new();
+        index.set(b"stale", b"b".to_vec())?;
+        let mut s = Indexed::new(primary, index, first_byte);
+        assert_eq!(s.scan_index_values(..).count(), 0);
+
+        s.rebuild_index()?;
+        assert_eq!(
+            s.scan_index_values(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), vec![1]), (b"a".to_vec(), vec![2])]
+        );
+        assert_eq!(s.index.count(..)?, 2);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests that estimate_garbage counts the primary garbage in the range,
+    /// and all of the index garbage.
+    fn estimate_garbage() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let primary = BitCask::new(dir.path().join("primary"))?;
+        let index = BitCask::new(dir.path().join("index"))?;
+        let mut s = Indexed::new(primary, index, first_byte);
+        s.set(b"a", vec![1])?;
+        s.set(b"a", vec![2])?;
+        let primary = s.primary.estimate_garbage(..)?;
+        let index = s.index.estimate_garbage(..)?;
+        assert!(primary > 0 && index > 0);
+        assert_eq!(s.estimate_garbage(..)?, primary + index);
+        assert_eq!(s.estimate_garbage(b"b".to_vec()..)?, index);
+        Ok(())
+    }
+}
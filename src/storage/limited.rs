@@ -0,0 +1,181 @@
+use super::engine::{check_size, Engine, ReadEngine, SizeHistogram, Status, WriteBatch};
+use crate::error::Result;
+
+/// An engine wrapper that rejects keys and values over the given sizes with
+/// [`Error::Value`](crate::error::Error::Value), before passing writes on to
+/// the wrapped engine. This enforces limits on engines that don't have their
+/// own, and applies them to the original values when wrapping e.g. a
+/// compressed engine.
+pub struct Limited<E: Engine> {
+    inner: E,
+    max_key_size: usize,
+    max_value_size: u64,
+}
+
+impl<E: Engine> Limited<E> {
+    pub fn new(inner: E, max_key_size: usize, max_value_size: u64) -> Self {
+        Self {
+            inner,
+            max_key_size,
+            max_value_size,
+        }
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Engine> std::fmt::Display for Limited<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (limited)", self.inner)
+    }
+}
+
+impl<E: Engine> Engine for Limited<E> {
+    type ScanIterator<'a>
+        = E::ScanIterator<'a>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        check_size(key, value.len(), self.max_key_size, self.max_value_size)?;
+        self.inner.set(key, value)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.get_many(keys)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn sync_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        self.inner.sync_range(range)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<u64> {
+        self.inner.delete_prefix(prefix)
+    }
+
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.delete_range(range)
+    }
+
+    /// Checks the whole batch before writing it, so that it's either written
+    /// atomically by the inner engine or not at all.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        for (key, value) in batch.iter() {
+            if let Some(value) = value {
+                check_size(key, value.len(), self.max_key_size, self.max_value_size)?;
+            }
+        }
+        self.inner.write_batch(batch)
+    }
+
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        self.inner.stats_histogram()
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.estimate_garbage(range)
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        self.inner.status()
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan(range)
+    }
+
+    fn scan_bytes(
+        &mut self,
+        start: std::ops::Bound<&[u8]>,
+        end: std::ops::Bound<&[u8]>,
+    ) -> Self::ScanIterator<'_> {
+        self.inner.scan_bytes(start, end)
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.inner.scan_keys(range)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.inner.count(range)
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for Limited<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_shared(key)
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan_shared(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::memory::Memory, *};
+    use crate::error::Error;
+
+    #[test]
+    /// Tests that writes at the limits pass through, while writes over them
+    /// are rejected without reaching the inner engine.
+    fn limited() -> Result<()> {
+        let mut s = Limited::new(Memory::new(), 2, 4);
+
+        s.set(b"ab", vec![1; 4])?;
+        assert!(matches!(s.set(b"abc", vec![1]), Err(Error::Value(_))));
+        assert!(matches!(s.set(b"b", vec![1; 5]), Err(Error::Value(_))));
+        assert!(matches!(
+            s.set_if_absent(b"c", vec![1; 5]),
+            Err(Error::Value(_))
+        ));
+        assert!(matches!(
+            s.compare_and_swap(b"ab", Some(&[1; 4]), Some(vec![2; 5])),
+            Err(Error::Value(_))
+        ));
+        let append =
+            |value: Option<Vec<u8>>, operand| [value.unwrap_or_default(), operand].concat();
+        assert!(matches!(
+            s.merge(b"ab", vec![2], append),
+            Err(Error::Value(_))
+        ));
+        let mut batch = WriteBatch::new();
+        batch.set(b"d", vec![1]);
+        batch.set(b"dddd", vec![1]);
+        assert!(matches!(s.write_batch(batch), Err(Error::Value(_))));
+
+        let inner = s.into_inner();
+        assert_eq!(inner.size(), 6);
+        Ok(())
+    }
+}
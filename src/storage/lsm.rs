@@ -0,0 +1,1233 @@
+/*!
+A log-structured merge-tree (LSM) engine.
+
+Writes go to an in-memory memtable, and are made durable by appending them to a
+write-ahead log (WAL) first. When the memtable exceeds a size threshold, it is
+written out as an immutable sorted string table (SSTable) file, and the WAL is
+reset. Unlike BitCask, only a sparse block index of each SSTable is kept in
+memory, so the key count isn't limited by memory.
+
+Reads check the memtable first, then the SSTables from newest to oldest. Scans
+merge the memtable and all SSTables into one sorted stream, where newer entries
+shadow older ones and tombstones hide deleted keys. Deleted and overwritten
+entries are only removed from disk by [`Lsm::compact`], which merges all
+SSTables into one.
+
+All files live in a directory:
+- `wal`: the write-ahead log.
+- `{id}.sst`: SSTables, where higher ids are newer.
+
+Entry format, used both in the WAL and SSTables:
+- Key length: big-endian u32
+- Value length: big-endian i32, -1 for tombstones
+- Key: raw bytes
+- Value: raw bytes
+
+WAL record format, where each record is a write batch that is applied
+atomically:
+- Entry count: big-endian u32
+- Entries
+- Checksum: big-endian u32, CRC32 of all the preceding bytes of the record
+
+SSTable format:
+- Header: magic bytes `YUUS` followed by a u8 format version
+- Data blocks of sorted entries, each roughly 4 KB
+- Block index: big-endian u32 block count, then for each block the big-endian
+  u32 length of its first key, the first key, and the big-endian u64 offset,
+  u32 length and u32 CRC32 checksum of the block
+- Footer: big-endian u64 offset of the block index, and u32 CRC32 of the index
+*/
+
+use super::bitcask::read_exact_at;
+use super::engine::{
+    check_size, clone_bounds, Engine, ReadEngine, Status, WriteBatch, DEFAULT_MAX_KEY_SIZE,
+};
+use crate::error::{Error, Result};
+
+use fs4::FileExt;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+};
+
+/// Magic bytes at the start of an SSTable file.
+const TABLE_MAGIC: [u8; 4] = *b"YUUS";
+
+/// The SSTable format version written by this implementation.
+const TABLE_VERSION: u8 = 1;
+
+/// The length of the SSTable header, i.e. magic bytes and version.
+const TABLE_HEADER_LENGTH: u64 = TABLE_MAGIC.len() as u64 + 1;
+
+/// The length of the SSTable footer, i.e. index offset and checksum.
+const TABLE_FOOTER_LENGTH: u64 = 12;
+
+/// The value length of a tombstone entry.
+const TOMBSTONE: i32 = -1;
+
+/// The maximum value length, since value lengths are encoded as i32. Longer
+/// values would otherwise wrap around, e.g. to [`TOMBSTONE`].
+const MAX_VALUE_SIZE: u64 = i32::MAX as u64;
+
+/// The approximate size of SSTable data blocks.
+const BLOCK_SIZE: usize = 4096;
+
+/// The default memtable size threshold, in bytes.
+const DEFAULT_MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// An entry, where a None value is a tombstone.
+type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// The memtable, where None values are tombstones.
+type Memtable = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Encodes an entry into the buffer.
+fn encode_entry(buffer: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) {
+    buffer.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&value.map_or(TOMBSTONE, |v| v.len() as i32).to_be_bytes());
+    buffer.extend_from_slice(key);
+    buffer.extend_from_slice(value.unwrap_or_default());
+}
+
+/// The encoded length of an entry.
+fn entry_length(key: &[u8], value: Option<&[u8]>) -> u64 {
+    8 + key.len() as u64 + value.map_or(0, |v| v.len() as u64)
+}
+
+/// Takes the given number of bytes from the front of the buffer, or None if
+/// the buffer is too short.
+fn take<'a>(buffer: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if buffer.len() < length {
+        return None;
+    }
+    let (taken, rest) = buffer.split_at(length);
+    *buffer = rest;
+    Some(taken)
+}
+
+fn take_u32(buffer: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(take(buffer, 4)?.try_into().unwrap()))
+}
+
+fn take_u64(buffer: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(take(buffer, 8)?.try_into().unwrap()))
+}
+
+/// Decodes an entry from the front of the buffer, or None if it's incomplete
+/// or invalid.
+fn decode_entry(buffer: &mut &[u8]) -> Option<Entry> {
+    let key_length = take_u32(buffer)? as usize;
+    let value_length = take_u32(buffer)? as i32;
+    let key = take(buffer, key_length)?.to_vec();
+    let value = match value_length {
+        TOMBSTONE => None,
+        length if length >= 0 => Some(take(buffer, length as usize)?.to_vec()),
+        _ => return None,
+    };
+    Some((key, value))
+}
+
+/// The write-ahead log, which makes memtable writes durable until the
+/// memtable is written to an SSTable.
+struct Wal {
+    path: PathBuf,
+    file: File,
+    /// The size at which writes fail as if the disk were full.
+    #[cfg(test)]
+    disk_limit: Option<u64>,
+}
+
+impl Wal {
+    fn new(path: PathBuf) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        file.try_lock_exclusive()?;
+        Ok(Self {
+            path,
+            file,
+            #[cfg(test)]
+            disk_limit: None,
+        })
+    }
+
+    /// Replays the WAL into a memtable. An incomplete trailing record, e.g.
+    /// after a crash during a write, is discarded.
+    fn replay(&mut self) -> Result<Memtable> {
+        let mut buffer = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buffer)?;
+
+        let mut memtable = Memtable::new();
+        let mut remaining = buffer.as_slice();
+        while !remaining.is_empty() {
+            let start = remaining;
+            let Some(entries) = Self::decode_record(&mut remaining)? else {
+                let offset = buffer.len() - start.len();
+                log::error!(
+                    "Found incomplete record at offset {offset} in {}, truncating file",
+                    self.path.display()
+                );
+                self.file.set_len(offset as u64)?;
+                break;
+            };
+            memtable.extend(entries);
+        }
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(memtable)
+    }
+
+    /// Decodes a record from the front of the buffer. Returns None if it's
+    /// incomplete, and an error if its checksum doesn't match.
+    fn decode_record(buffer: &mut &[u8]) -> Result<Option<Vec<Entry>>> {
+        let start = *buffer;
+        let Some(count) = take_u32(buffer) else {
+            return Ok(None);
+        };
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            match decode_entry(buffer) {
+                Some(entry) => entries.push(entry),
+                None => return Ok(None),
+            }
+        }
+        let length = start.len() - buffer.len();
+        let Some(checksum) = take_u32(buffer) else {
+            return Ok(None);
+        };
+        if crc32fast::hash(&start[..length]) != checksum {
+            return Err(Error::Internal("WAL record checksum mismatch".to_string()));
+        }
+        Ok(Some(entries))
+    }
+
+    /// Appends a batch of writes as a single record. If the write fails
+    /// partway, the partial record is truncated away, such that later
+    /// records don't follow a torn one and the WAL can still be replayed.
+    fn append(&mut self, batch: &WriteBatch) -> Result<()> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&(batch.len() as u32).to_be_bytes());
+        for (key, value) in batch.iter() {
+            encode_entry(&mut buffer, key, value);
+        }
+        buffer.extend_from_slice(&crc32fast::hash(&buffer).to_be_bytes());
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        let Err(error) = self.write_all(&buffer) else {
+            return Ok(());
+        };
+        if let Err(truncate_error) = self.file.set_len(offset) {
+            log::error!(
+                "Failed to truncate partial WAL write at offset {offset}: {truncate_error}"
+            );
+        }
+        Err(error.into())
+    }
+
+    #[cfg(not(test))]
+    fn write_all(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buffer)
+    }
+
+    /// Writes up to the disk limit, failing like a full disk past it.
+    #[cfg(test)]
+    fn write_all(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        let Some(limit) = self.disk_limit else {
+            return self.file.write_all(buffer);
+        };
+        let available = limit.saturating_sub(self.file.stream_position()?) as usize;
+        let length = available.min(buffer.len());
+        self.file.write_all(&buffer[..length])?;
+        if available < buffer.len() {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        Ok(())
+    }
+
+    /// Truncates the WAL, once its writes have been written to an SSTable.
+    fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// The location of an SSTable data block.
+struct BlockHandle {
+    first_key: Vec<u8>,
+    offset: u64,
+    length: u32,
+    checksum: u32,
+}
+
+/// An immutable sorted string table file.
+struct SsTable {
+    id: u64,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    blocks: Vec<BlockHandle>,
+}
+
+impl SsTable {
+    fn path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{id:016}.sst"))
+    }
+
+    /// Writes the sorted entries to a new SSTable. The file is written to a
+    /// temporary path first, and renamed when complete.
+    fn write(dir: &Path, id: u64, entries: impl Iterator<Item = Result<Entry>>) -> Result<Self> {
+        let path = Self::path(dir, id);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(&TABLE_MAGIC)?;
+        file.write_all(&[TABLE_VERSION])?;
+
+        let mut offset = TABLE_HEADER_LENGTH;
+        let mut blocks = Vec::new();
+        let mut block = Vec::new();
+        let mut first_key = Vec::new();
+        let mut write_block = |file: &mut BufWriter<File>, block: &mut Vec<u8>, first_key| {
+            file.write_all(block)?;
+            blocks.push(BlockHandle {
+                first_key,
+                offset,
+                length: block.len() as u32,
+                checksum: crc32fast::hash(block),
+            });
+            offset += block.len() as u64;
+            block.clear();
+            Ok::<_, Error>(())
+        };
+        for entry in entries {
+            let (key, value) = entry?;
+            if block.is_empty() {
+                first_key = key.clone();
+            }
+            encode_entry(&mut block, &key, value.as_deref());
+            if block.len() >= BLOCK_SIZE {
+                write_block(&mut file, &mut block, std::mem::take(&mut first_key))?;
+            }
+        }
+        if !block.is_empty() {
+            write_block(&mut file, &mut block, first_key)?;
+        }
+
+        let mut index = Vec::new();
+        index.extend_from_slice(&(blocks.len() as u32).to_be_bytes());
+        for block in &blocks {
+            index.extend_from_slice(&(block.first_key.len() as u32).to_be_bytes());
+            index.extend_from_slice(&block.first_key);
+            index.extend_from_slice(&block.offset.to_be_bytes());
+            index.extend_from_slice(&block.length.to_be_bytes());
+            index.extend_from_slice(&block.checksum.to_be_bytes());
+        }
+        file.write_all(&index)?;
+        file.write_all(&offset.to_be_bytes())?;
+        file.write_all(&crc32fast::hash(&index).to_be_bytes())?;
+
+        let file = file.into_inner().map_err(|error| error.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp_path, &path)?;
+        Self::open(path, id)
+    }
+
+    /// Opens an SSTable, reading its block index.
+    fn open(path: PathBuf, id: u64) -> Result<Self> {
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let invalid = || Error::Internal(format!("Invalid SSTable {}", path.display()));
+        if size < TABLE_HEADER_LENGTH + TABLE_FOOTER_LENGTH {
+            return Err(invalid());
+        }
+
+        let mut header = [0; TABLE_HEADER_LENGTH as usize];
+        read_exact_at(&file, &mut header, 0)?;
+        if header[..4] != TABLE_MAGIC || header[4] != TABLE_VERSION {
+            return Err(invalid());
+        }
+
+        let mut footer = [0; TABLE_FOOTER_LENGTH as usize];
+        read_exact_at(&file, &mut footer, size - TABLE_FOOTER_LENGTH)?;
+        let mut footer = footer.as_slice();
+        let index_offset = take_u64(&mut footer).ok_or_else(invalid)?;
+        let checksum = take_u32(&mut footer).ok_or_else(invalid)?;
+        if index_offset < TABLE_HEADER_LENGTH || index_offset > size - TABLE_FOOTER_LENGTH {
+            return Err(invalid());
+        }
+
+        let mut index = vec![0; (size - TABLE_FOOTER_LENGTH - index_offset) as usize];
+        read_exact_at(&file, &mut index, index_offset)?;
+        if crc32fast::hash(&index) != checksum {
+            return Err(Error::Internal(format!(
+                "SSTable {} index checksum mismatch",
+                path.display()
+            )));
+        }
+        let blocks = Self::parse_index(&index).ok_or_else(invalid)?;
+        Ok(Self {
+            id,
+            path,
+            file,
+            size,
+            blocks,
+        })
+    }
+
+    fn parse_index(mut index: &[u8]) -> Option<Vec<BlockHandle>> {
+        let index = &mut index;
+        let count = take_u32(index)?;
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            let key_length = take_u32(index)? as usize;
+            blocks.push(BlockHandle {
+                first_key: take(index, key_length)?.to_vec(),
+                offset: take_u64(index)?,
+                length: take_u32(index)?,
+                checksum: take_u32(index)?,
+            });
+        }
+        index.is_empty().then_some(blocks)
+    }
+
+    /// Reads and decodes a data block, verifying its checksum.
+    fn read_block(&self, index: usize) -> Result<Vec<Entry>> {
+        let handle = &self.blocks[index];
+        let mut buffer = vec![0; handle.length as usize];
+        read_exact_at(&self.file, &mut buffer, handle.offset)?;
+        if crc32fast::hash(&buffer) != handle.checksum {
+            return Err(Error::Internal(format!(
+                "SSTable {} block checksum mismatch at offset {}",
+                self.path.display(),
+                handle.offset
+            )));
+        }
+        let mut entries = Vec::new();
+        let mut remaining = buffer.as_slice();
+        while !remaining.is_empty() {
+            entries.push(decode_entry(&mut remaining).ok_or_else(|| {
+                Error::Internal(format!(
+                    "Invalid entry in SSTable {} block at offset {}",
+                    self.path.display(),
+                    handle.offset
+                ))
+            })?);
+        }
+        Ok(entries)
+    }
+
+    /// Returns the index of the block that may contain the key, if any.
+    fn block_for(&self, key: &[u8]) -> Option<usize> {
+        self.blocks
+            .partition_point(|block| block.first_key.as_slice() <= key)
+            .checked_sub(1)
+    }
+
+    /// Looks up a key, returning Some(None) for a tombstone.
+    fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        let Some(index) = self.block_for(key) else {
+            return Ok(None);
+        };
+        let mut entries = self.read_block(index)?;
+        Ok(entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|i| entries.swap_remove(i).1))
+    }
+
+    /// Scans the entries in the range, including tombstones.
+    fn scan(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> TableIterator<'_> {
+        let next_block = match &range.0 {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_for(key).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        let end_block = match &range.1 {
+            Bound::Included(key) => self.blocks.partition_point(|block| &block.first_key <= key),
+            Bound::Excluded(key) => self.blocks.partition_point(|block| &block.first_key < key),
+            Bound::Unbounded => self.blocks.len(),
+        };
+        TableIterator {
+            table: self,
+            range,
+            next_block,
+            end_block: end_block.max(next_block),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+}
+
+/// An iterator over an SSTable range, which reads blocks lazily from either
+/// end.
+struct TableIterator<'a> {
+    table: &'a SsTable,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    /// The blocks in next_block..end_block haven't been read yet.
+    next_block: usize,
+    end_block: usize,
+    front: VecDeque<Entry>,
+    back: VecDeque<Entry>,
+}
+
+impl<'a> TableIterator<'a> {
+    /// Reads a block, keeping the entries in the range. On errors, the
+    /// iterator is exhausted.
+    fn read_block(&mut self, index: usize) -> Result<VecDeque<Entry>> {
+        match self.table.read_block(index) {
+            Ok(entries) => Ok(entries
+                .into_iter()
+                .filter(|(key, _)| self.range.contains(key))
+                .collect()),
+            Err(error) => {
+                self.next_block = self.end_block;
+                self.front.clear();
+                self.back.clear();
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for TableIterator<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.front.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.next_block == self.end_block {
+                return self.back.pop_front().map(Ok);
+            }
+            match self.read_block(self.next_block) {
+                Ok(entries) => self.front = entries,
+                Err(error) => return Some(Err(error)),
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for TableIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.back.pop_back() {
+                return Some(Ok(entry));
+            }
+            if self.next_block == self.end_block {
+                return self.front.pop_back().map(Ok);
+            }
+            match self.read_block(self.end_block - 1) {
+                Ok(entries) => self.back = entries,
+                Err(error) => return Some(Err(error)),
+            }
+            self.end_block -= 1;
+        }
+    }
+}
+
+/// A source of sorted entries for a merge, with the next entry buffered at
+/// either end.
+struct Source<'a> {
+    iter: Box<dyn DoubleEndedIterator<Item = Result<Entry>> + 'a>,
+    front: Option<Result<Entry>>,
+    back: Option<Result<Entry>>,
+}
+
+/// Merges sorted sources, ordered from newest to oldest. For keys that exist
+/// in several sources, only the newest entry is returned, which may be a
+/// tombstone.
+struct MergeIterator<'a> {
+    sources: Vec<Source<'a>>,
+}
+
+impl<'a> MergeIterator<'a> {
+    fn new(iters: Vec<Box<dyn DoubleEndedIterator<Item = Result<Entry>> + 'a>>) -> Self {
+        Self {
+            sources: iters
+                .into_iter()
+                .map(|iter| Source {
+                    iter,
+                    front: None,
+                    back: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Takes the next entry from the buffers selected by `buffer`, where
+    /// `precedes(a, b)` returns whether key a comes before key b. Errors are
+    /// returned first.
+    fn take(
+        &mut self,
+        buffer: for<'s> fn(&'s mut Source<'a>) -> &'s mut Option<Result<Entry>>,
+        precedes: fn(&[u8], &[u8]) -> bool,
+    ) -> Option<Result<Entry>> {
+        let mut next: Option<(usize, &[u8])> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            let buffer = buffer(source);
+            match buffer {
+                Some(Err(_)) => return buffer.take(),
+                Some(Ok((key, _))) if next.is_none_or(|(_, next)| precedes(key, next)) => {
+                    next = Some((i, key))
+                }
+                Some(Ok(_)) | None => {}
+            }
+        }
+        let (index, key) = next?;
+        let key = key.to_vec();
+        let entry = buffer(&mut self.sources[index]).take();
+        // Drop shadowed entries for the same key in older sources.
+        for source in self.sources[index + 1..].iter_mut() {
+            let buffer = buffer(source);
+            if matches!(buffer, Some(Ok((k, _))) if *k == key) {
+                *buffer = None;
+            }
+        }
+        entry
+    }
+}
+
+impl<'a> Iterator for MergeIterator<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for source in self.sources.iter_mut() {
+            if source.front.is_none() {
+                source.front = source.iter.next().or_else(|| source.back.take());
+            }
+        }
+        self.take(|source| &mut source.front, |a, b| a < b)
+    }
+}
+
+impl<'a> DoubleEndedIterator for MergeIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        for source in self.sources.iter_mut() {
+            if source.back.is_none() {
+                source.back = source.iter.next_back().or_else(|| source.front.take());
+            }
+        }
+        self.take(|source| &mut source.back, |a, b| a > b)
+    }
+}
+
+/// An LSM-tree key-value engine.
+pub struct Lsm {
+    dir: PathBuf,
+    wal: Wal,
+    memtable: Memtable,
+    /// The approximate size of the memtable, in encoded bytes.
+    memtable_size: u64,
+    /// The memtable size at which it is written to an SSTable.
+    memtable_threshold: u64,
+    /// SSTables, from oldest to newest.
+    tables: Vec<SsTable>,
+}
+
+impl Lsm {
+    /// Opens or creates an LSM engine in the given directory.
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        Self::new_with_memtable_size(dir, DEFAULT_MEMTABLE_SIZE)
+    }
+
+    /// Opens or creates an LSM engine in the given directory, which writes the
+    /// memtable to an SSTable once it exceeds the given size in bytes.
+    pub fn new_with_memtable_size(dir: PathBuf, memtable_threshold: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut wal = Wal::new(dir.join("wal"))?;
+
+        let mut tables = Vec::new();
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                // Remove SSTables that were not completely written.
+                Some("tmp") => std::fs::remove_file(&path)?,
+                Some("sst") => {
+                    let id = path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse().ok())
+                        .ok_or_else(|| {
+                            Error::Internal(format!("Invalid SSTable name {}", path.display()))
+                        })?;
+                    tables.push(SsTable::open(path, id)?);
+                }
+                _ => {}
+            }
+        }
+        tables.sort_by_key(|table| table.id);
+
+        let memtable = wal.replay()?;
+        let memtable_size = memtable
+            .iter()
+            .map(|(key, value)| entry_length(key, value.as_deref()))
+            .sum();
+        Ok(Self {
+            dir,
+            wal,
+            memtable,
+            memtable_size,
+            memtable_threshold,
+            tables,
+        })
+    }
+
+    /// Returns the number of SSTables.
+    pub fn table_count(&self) -> usize {
+        self.tables.len()
+    }
+
+    fn next_table_id(&self) -> u64 {
+        self.tables.last().map_or(1, |table| table.id + 1)
+    }
+
+    /// Writes the memtable to a new SSTable and resets the WAL.
+    pub fn flush_memtable(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let entries = self
+            .memtable
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), v.clone())));
+        let table = SsTable::write(&self.dir, self.next_table_id(), entries)?;
+        self.tables.push(table);
+        self.memtable.clear();
+        self.memtable_size = 0;
+        self.wal.reset()
+    }
+
+    /// Merges all SSTables, and the memtable, into a single SSTable. This
+    /// removes overwritten entries and tombstones.
+    pub fn compact(&mut self) -> Result<()> {
+        self.flush_memtable()?;
+        if self.tables.len() <= 1 && self.tables.iter().all(|table| table.blocks.is_empty()) {
+            return Ok(());
+        }
+        let id = self.next_table_id();
+        let table = SsTable::write(
+            &self.dir,
+            id,
+            self.merge_range((Bound::Unbounded, Bound::Unbounded), false)
+                .filter(|result| !matches!(result, Ok((_, None)))),
+        )?;
+        for old in std::mem::replace(&mut self.tables, vec![table]) {
+            drop(old.file);
+            std::fs::remove_file(&old.path)?;
+        }
+        Ok(())
+    }
+
+    /// Merges the SSTables, and optionally the memtable, in the range.
+    fn merge_range(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        memtable: bool,
+    ) -> MergeIterator<'_> {
+        let mut iters: Vec<Box<dyn DoubleEndedIterator<Item = Result<Entry>>>> = Vec::new();
+        if memtable {
+            iters.push(Box::new(
+                self.memtable
+                    .range(range.clone())
+                    .map(|(key, value)| Ok((key.clone(), value.clone()))),
+            ));
+        }
+        for table in self.tables.iter().rev() {
+            iters.push(Box::new(table.scan(range.clone())));
+        }
+        MergeIterator::new(iters)
+    }
+
+    /// Applies a batch of writes, logging it to the WAL first.
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        for (key, value) in batch.iter() {
+            if let Some(value) = value {
+                check_size(key, value.len(), DEFAULT_MAX_KEY_SIZE, MAX_VALUE_SIZE)?;
+            }
+        }
+        self.wal.append(&batch)?;
+        for (key, value) in batch {
+            let value_length =
+                |value: &Option<Vec<u8>>| value.as_ref().map_or(0, |v| v.len() as u64);
+            self.memtable_size += value_length(&value);
+            let key_length = key.len() as u64;
+            match self.memtable.insert(key, value) {
+                Some(old) => self.memtable_size -= value_length(&old),
+                None => self.memtable_size += entry_length(&[], None) + key_length,
+            }
+        }
+        if self.memtable_size > self.memtable_threshold {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Lsm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lsm")
+    }
+}
+
+pub struct ScanIterator<'a> {
+    inner: MergeIterator<'a>,
+}
+
+impl<'a> Iterator for ScanIterator<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next()? {
+                Ok((key, Some(value))) => return Some(Ok((key, value))),
+                Ok((_, None)) => {}
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for ScanIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next_back()? {
+                Ok((key, Some(value))) => return Some(Ok((key, value))),
+                Ok((_, None)) => {}
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+impl Engine for Lsm {
+    type ScanIterator<'a> = ScanIterator<'a>;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, value);
+        self.write(batch)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_shared(key)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    /// Writes are appended to the WAL file as they are made, so there is
+    /// nothing to flush.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(self.wal.file.sync_all()?)
+    }
+
+    /// Resets the memtable and WAL, and removes all SSTables. If interrupted
+    /// by a crash, some SSTables may remain.
+    fn clear(&mut self) -> Result<()> {
+        self.memtable.clear();
+        self.memtable_size = 0;
+        self.wal.reset()?;
+        for table in std::mem::take(&mut self.tables) {
+            drop(table.file);
+            std::fs::remove_file(&table.path)?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write(batch)
+    }
+
+    /// Counts the SSTable entries in the range that compaction would remove,
+    /// i.e. tombstones and entries shadowed by newer ones in the memtable or
+    /// a newer SSTable. The WAL isn't counted, since memtable flushes rather
+    /// than compaction reclaim it. This keeps the range's keys in memory.
+    fn estimate_garbage(&mut self, range: impl RangeBounds<Vec<u8>>) -> Result<u64> {
+        let range = clone_bounds(&range);
+        let mut seen = (self.memtable.range(range.clone()))
+            .map(|(key, _)| key.clone())
+            .collect::<HashSet<_>>();
+        let mut garbage = 0;
+        for table in self.tables.iter().rev() {
+            for result in table.scan(range.clone()) {
+                let (key, value) = result?;
+                let length = entry_length(&key, value.as_deref());
+                if !seen.insert(key) || value.is_none() {
+                    garbage += length;
+                }
+            }
+        }
+        Ok(garbage)
+    }
+
+    /// Counts the memtable and the block indexes of the SSTables.
+    fn approximate_memory_usage(&self) -> u64 {
+        let handle_size = std::mem::size_of::<BlockHandle>() as u64;
+        let index_size = (self.tables.iter())
+            .flat_map(|table| &table.blocks)
+            .map(|block| handle_size + block.first_key.len() as u64)
+            .sum::<u64>();
+        self.memtable_size + index_size
+    }
+
+    /// Returns the engine status. This scans all live entries. The live disk
+    /// size is the encoded size of the live entries, while the rest of the
+    /// files, including block indexes and the WAL, is counted as garbage.
+    fn status(&mut self) -> Result<Status> {
+        let (mut key_count, mut size, mut live_disk_size) = (0, 0, 0);
+        for result in self.scan(..) {
+            let (key, value) = result?;
+            key_count += 1;
+            size += key.len() as u64 + value.len() as u64;
+            live_disk_size += entry_length(&key, Some(&value));
+        }
+        let total_disk_size = self.tables.iter().map(|table| table.size).sum::<u64>()
+            + self.wal.file.metadata()?.len();
+        Ok(Status {
+            name: self.to_string(),
+            key_count,
+            size,
+            total_disk_size,
+            live_disk_size,
+            garbage_disk_size: total_disk_size.saturating_sub(live_disk_size),
+            // Tombstones aren't tracked in SSTables.
+            tombstone_count: 0,
+            // The SSTables and the WAL.
+            file_count: self.tables.len() as u64 + 1,
+            compaction_recommended: false,
+        })
+    }
+
+    fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.scan_shared(range)
+    }
+}
+
+impl ReadEngine for Lsm {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        for table in self.tables.iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan_shared(&self, range: impl RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        ScanIterator {
+            inner: self.merge_range(clone_bounds(&range), true),
+        }
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        if let Err(error) = self.sync() {
+            log::error!("Failed to sync database: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(memtable_size: u64) -> Result<(tempdir::TempDir, Lsm)> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let s = Lsm::new_with_memtable_size(dir.path().join("lsm"), memtable_size)?;
+        Ok((dir, s))
+    }
+
+    #[test]
+    /// Tests that keys and values too long to encode are rejected before
+    /// they're written to the WAL.
+    fn size_limits() -> Result<()> {
+        let (_dir, mut s) = setup(100)?;
+        let wal_size = s.wal.file.metadata()?.len();
+        assert!(check_size(
+            b"k",
+            MAX_VALUE_SIZE as usize,
+            DEFAULT_MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        )
+        .is_ok());
+        assert!(matches!(
+            check_size(
+                b"k",
+                MAX_VALUE_SIZE as usize + 1,
+                DEFAULT_MAX_KEY_SIZE,
+                MAX_VALUE_SIZE
+            ),
+            Err(Error::Value(_))
+        ));
+
+        let key = vec![0; DEFAULT_MAX_KEY_SIZE + 1];
+        assert!(matches!(s.set(&key, vec![1]), Err(Error::Value(_))));
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", vec![1]);
+        batch.set(&key, vec![1]);
+        assert!(matches!(s.write_batch(batch), Err(Error::Value(_))));
+        assert_eq!(s.wal.file.metadata()?.len(), wal_size);
+        assert_eq!(s.get(b"a")?, None);
+
+        // Keys of the maximum length are accepted.
+        s.set(&key[..DEFAULT_MAX_KEY_SIZE], vec![1])?;
+        assert_eq!(s.get(&key[..DEFAULT_MAX_KEY_SIZE])?, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that a WAL write that fails partway is truncated away, such that
+    /// later writes and replay aren't affected by the torn record.
+    fn wal_write_error() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("lsm");
+        let mut s = Lsm::new_with_memtable_size(path.clone(), 1 << 20)?;
+        s.set(b"a", vec![1])?;
+        let wal_size = s.wal.file.metadata()?.len();
+
+        s.wal.disk_limit = Some(wal_size + 10);
+        assert!(matches!(
+            s.set(b"b", vec![2; 100]),
+            Err(Error::Io {
+                kind: std::io::ErrorKind::WriteZero,
+                ..
+            })
+        ));
+        assert_eq!(s.wal.file.metadata()?.len(), wal_size);
+        assert_eq!(s.get(b"b")?, None);
+
+        s.wal.disk_limit = None;
+        s.set(b"c", vec![3])?;
+        drop(s);
+
+        let mut s = Lsm::new_with_memtable_size(path, 1 << 20)?;
+        assert_eq!(
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![1]), (b"c".to_vec(), vec![3])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the memtable is flushed to SSTables when full, and that
+    /// reads go through to them.
+    fn flush() -> Result<()> {
+        let (_dir, mut s) = setup(100)?;
+        for i in 0..10u8 {
+            s.set(&[i], vec![i; 20])?;
+        }
+        // Each entry is 29 bytes, so the memtable is flushed every 4 writes.
+        assert_eq!(s.table_count(), 2);
+        assert_eq!(s.memtable.len(), 2);
+
+        for i in 0..10u8 {
+            assert_eq!(s.get(&[i])?, Some(vec![i; 20]));
+        }
+        assert_eq!(s.get(&[10])?, None);
+        assert_eq!(s.scan(..).count(), 10);
+
+        s.flush_memtable()?;
+        assert_eq!(s.table_count(), 3);
+        assert!(s.memtable.is_empty());
+        assert_eq!(s.wal.file.metadata()?.len(), 0);
+        assert_eq!(s.scan(..).rev().count(), 10);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that estimate_garbage counts the tombstones and shadowed entries
+    /// in SSTables, which compaction removes.
+    fn estimate_garbage() -> Result<()> {
+        let (_dir, mut s) = setup(1 << 20)?;
+        // Entries take 8 header bytes besides the key and value.
+        s.set(b"a", vec![1, 2, 3])?; // overwritten: 8 + 1 + 3
+        s.set(b"bb", vec![2; 10])?; // deleted: 8 + 2 + 10
+        s.set(b"c", vec![3])?; // overwritten in the memtable: 8 + 1 + 1
+        s.flush_memtable()?;
+        s.set(b"a", vec![4])?;
+        s.delete(b"bb")?; // tombstone: 8 + 2
+        s.delete(b"d")?; // tombstone: 8 + 1
+        s.flush_memtable()?;
+        s.set(b"c", vec![5])?;
+
+        assert_eq!(s.estimate_garbage(..)?, 12 + 20 + 10 + 10 + 9);
+        assert_eq!(s.estimate_garbage(..=b"bb".to_vec())?, 12 + 20 + 10);
+        let range = b"b".to_vec()..b"d".to_vec();
+        assert_eq!(s.estimate_garbage(range)?, 20 + 10 + 10);
+        assert_eq!(s.estimate_garbage(b"e".to_vec()..)?, 0);
+
+        s.compact()?;
+        assert_eq!(s.estimate_garbage(..)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that SSTables span multiple blocks, which are looked up and
+    /// scanned correctly from both ends.
+    fn blocks() -> Result<()> {
+        let (_dir, mut s) = setup(u64::MAX)?;
+        let keys: Vec<Vec<u8>> = (0..1000u32)
+            .map(|i| (i * 2).to_be_bytes().to_vec())
+            .collect();
+        for key in &keys {
+            s.set(key, key.repeat(10))?;
+        }
+        s.flush_memtable()?;
+        assert!(s.tables[0].blocks.len() > 5);
+
+        for key in &keys {
+            assert_eq!(s.get(key)?, Some(key.repeat(10)));
+        }
+        assert_eq!(s.get(&1u32.to_be_bytes())?, None);
+        assert_eq!(s.get(&[])?, None);
+        assert_eq!(s.get(&[0xff; 5])?, None);
+
+        let range = 301u32.to_be_bytes().to_vec()..=1601u32.to_be_bytes().to_vec();
+        let expect: Vec<_> = keys[151..801]
+            .iter()
+            .map(|k| (k.clone(), k.repeat(10)))
+            .collect();
+        assert_eq!(s.scan(range.clone()).collect::<Result<Vec<_>>>()?, expect);
+
+        // Alternate between the ends.
+        let mut iter = s.scan(range);
+        let (mut front, mut back) = (Vec::new(), Vec::new());
+        while let Some(item) = iter.next() {
+            front.push(item?);
+            match iter.next_back() {
+                Some(item) => back.push(item?),
+                None => break,
+            }
+        }
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, expect);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that newer entries and tombstones shadow older ones, both in
+    /// SSTables and the memtable, and that compaction removes them.
+    fn shadowing() -> Result<()> {
+        let (_dir, mut s) = setup(u64::MAX)?;
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![1])?;
+        s.set(b"c", vec![1])?;
+        s.flush_memtable()?;
+
+        s.set(b"a", vec![2])?;
+        s.delete(b"b")?;
+        s.flush_memtable()?;
+
+        s.delete(b"a")?;
+        s.set(b"b", vec![3])?;
+        s.delete(b"c")?;
+        s.set(b"d", vec![3])?;
+
+        let expect = vec![(b"b".to_vec(), vec![3]), (b"d".to_vec(), vec![3])];
+        assert_eq!(s.get(b"a")?, None);
+        assert_eq!(s.get(b"b")?, Some(vec![3]));
+        assert_eq!(s.get(b"c")?, None);
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        let mut reversed = expect.clone();
+        reversed.reverse();
+        assert_eq!(s.scan(..).rev().collect::<Result<Vec<_>>>()?, reversed);
+
+        let status = s.status()?;
+        assert_eq!(status.key_count, 2);
+        assert!(status.garbage_disk_size > 0);
+
+        s.compact()?;
+        assert_eq!(s.table_count(), 1);
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        let entries = s.tables[0].scan((Bound::Unbounded, Bound::Unbounded));
+        assert_eq!(entries.count(), 2, "tombstones not removed");
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that data is retained when reopened, both from SSTables and the
+    /// WAL, and that incomplete WAL records are discarded.
+    fn reopen() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("lsm");
+
+        let mut s = Lsm::new_with_memtable_size(path.clone(), 100)?;
+        for i in 0..10u8 {
+            s.set(&[i], vec![i; 20])?;
+        }
+        s.delete(&[0])?;
+        let mut batch = WriteBatch::new();
+        batch.set(&[1], vec![1]);
+        batch.delete(&[2]);
+        s.write_batch(batch)?;
+        let expect = s.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(expect.len(), 8);
+
+        // A second instance can't be opened while the first is open.
+        assert!(Lsm::new(path.clone()).is_err());
+        drop(s);
+
+        // Append an incomplete record to the WAL, and a stray temp file.
+        let mut wal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(path.join("wal"))?;
+        wal.write_all(&[0, 0, 0, 1, 0, 0])?;
+        drop(wal);
+        std::fs::write(path.join("0000000000000099.tmp"), b"garbage")?;
+
+        let mut s = Lsm::new_with_memtable_size(path.clone(), 100)?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert!(!path.join("0000000000000099.tmp").exists());
+
+        s.compact()?;
+        drop(s);
+        let mut s = Lsm::new(path)?;
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that corrupt SSTable blocks are detected.
+    fn checksum() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let path = dir.path().join("lsm");
+        let mut s = Lsm::new(path.clone())?;
+        s.set(b"a", vec![1, 2, 3])?;
+        s.flush_memtable()?;
+        let table_path = s.tables[0].path.clone();
+        drop(s);
+
+        let mut file = std::fs::OpenOptions::new().write(true).open(&table_path)?;
+        file.seek(SeekFrom::Start(TABLE_HEADER_LENGTH + 8))?;
+        file.write_all(b"b")?;
+        drop(file);
+
+        let mut s = Lsm::new(path)?;
+        assert!(matches!(s.get(b"a"), Err(Error::Internal(_))));
+        assert!(s.scan(..).next().unwrap().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use super::engine::Engine;
+use crate::error::Result;
+
+/// A map-like view of an engine, mirroring the [`std::collections::BTreeMap`]
+/// API for code that uses a `BTreeMap<Vec<u8>, Vec<u8>>`. Keys are borrowed
+/// and values are returned owned, and all methods return a [`Result`] since
+/// engine operations can fail.
+pub struct MapView<'a, E: Engine> {
+    engine: &'a mut E,
+}
+
+impl<'a, E: Engine> MapView<'a, E> {
+    pub fn new(engine: &'a mut E) -> Self {
+        Self { engine }
+    }
+
+    /// Inserts a key/value pair, returning the old value, if any.
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let old = self.engine.get(key)?;
+        self.engine.set(key, value)?;
+        Ok(old)
+    }
+
+    /// Returns the value of a key, if any.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.engine.get(key)
+    }
+
+    /// Removes a key, returning its value, if any.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let old = self.engine.get(key)?;
+        if old.is_some() {
+            self.engine.delete(key)?;
+        }
+        Ok(old)
+    }
+
+    /// Returns whether the map contains the key, without reading its value
+    /// where the engine supports it, see [`Engine::stat_key`].
+    pub fn contains_key(&mut self, key: &[u8]) -> Result<bool> {
+        Ok(self.engine.stat_key(key)?.is_some_and(|stat| stat.live))
+    }
+
+    /// Returns the number of keys, by counting them.
+    pub fn len(&mut self) -> Result<usize> {
+        self.engine.count(..)
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool> {
+        self.engine.is_empty_range(..)
+    }
+
+    /// Removes all keys.
+    pub fn clear(&mut self) -> Result<()> {
+        self.engine.clear()
+    }
+
+    /// Returns the key/value pairs in key order.
+    pub fn iter(&mut self) -> E::ScanIterator<'_> {
+        self.engine.scan(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+    use std::collections::BTreeMap;
+
+    /// Runs the same operations on a map view and a BTreeMap, comparing the
+    /// results.
+    fn map_ops(mut map: MapView<'_, impl Engine>) -> Result<()> {
+        let mut expect = BTreeMap::new();
+        assert!(map.is_empty()?);
+
+        for (key, value) in [(b"a", vec![1]), (b"b", vec![2]), (b"a", vec![3])] {
+            let old = expect.insert(key.to_vec(), value.clone());
+            assert_eq!(map.insert(key, value)?, old);
+        }
+        assert_eq!(map.get(b"a")?.as_ref(), expect.get(b"a".as_slice()));
+        assert_eq!(map.get(b"c")?.as_ref(), expect.get(b"c".as_slice()));
+        assert!(map.contains_key(b"b")?);
+        assert!(!map.contains_key(b"c")?);
+        assert_eq!(map.len()?, expect.len());
+        assert!(!map.is_empty()?);
+
+        assert_eq!(map.remove(b"b")?, expect.remove(b"b".as_slice()));
+        assert_eq!(map.remove(b"b")?, expect.remove(b"b".as_slice()));
+        assert!(!map.contains_key(b"b")?);
+        assert_eq!(map.len()?, expect.len());
+        assert_eq!(
+            map.iter().collect::<Result<Vec<_>>>()?,
+            expect.into_iter().collect::<Vec<_>>()
+        );
+
+        map.clear()?;
+        assert!(map.is_empty()?);
+        assert_eq!(map.len()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn memory() -> Result<()> {
+        map_ops(MapView::new(&mut Memory::new()))
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn bitcask() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = BitCask::new(path)?;
+        map_ops(MapView::new(&mut s))?;
+
+        // Writes through the view reach the engine.
+        MapView::new(&mut s).insert(b"key", vec![1])?;
+        assert_eq!(s.get(b"key")?, Some(vec![1]));
+        Ok(())
+    }
+}
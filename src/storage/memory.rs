@@ -1,17 +1,199 @@
-use crate::error::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
+use super::engine::{KeyStat, ReadEngine};
+use crate::error::{Error, Result};
+
+/// The order in which a capacity-bounded [`Memory`] engine evicts keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the least recently written or read key.
+    Lru,
+    /// Evicts the least recently written key.
+    Fifo,
+}
+
+/// The capacity bound of a [`Memory`] engine, along with the eviction order
+/// of its keys.
+#[derive(Clone)]
+struct Capacity {
+    max_bytes: u64,
+    policy: EvictionPolicy,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, Vec<u8>>,
+    /// The tick each key was last used at.
+    ticks: HashMap<Vec<u8>, u64>,
+    tick: u64,
+}
+
+impl Capacity {
+    /// Marks the key as the most recently used.
+    fn touch(&mut self, key: &[u8]) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(key.to_vec(), self.tick) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+        self.ticks.clear();
+    }
+}
+
+/// An in-memory key-value store. It is unbounded by default, but can be bounded
+/// to a number of key and value bytes with [`Memory::with_capacity`], evicting
+/// keys to stay within the bound.
+///
+/// The data is shared copy-on-write between the engine and its snapshots, see
+/// [`Memory::snapshot`].
 pub struct Memory {
-    data: std::collections::BTreeMap<Vec<u8>, Vec<u8>>,
+    data: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+    /// The total size of the keys and values.
+    size: u64,
+    capacity: Option<Arc<Capacity>>,
 }
 
 impl Memory {
     pub fn new() -> Self {
         Self {
-            data: std::collections::BTreeMap::new(),
+            data: Arc::new(BTreeMap::new()),
+            size: 0,
+            capacity: None,
+        }
+    }
+
+    /// Creates an engine bounded to the given number of key and value bytes,
+    /// evicting the least recently used keys when full.
+    pub fn with_capacity(max_bytes: u64) -> Self {
+        Self::with_capacity_and_policy(max_bytes, EvictionPolicy::Lru)
+    }
+
+    /// Creates an engine bounded to the given number of key and value bytes,
+    /// evicting keys in the order of the given policy when full. Only `get`
+    /// counts as a read for [`EvictionPolicy::Lru`], scans and shared reads
+    /// don't affect the eviction order.
+    pub fn with_capacity_and_policy(max_bytes: u64, policy: EvictionPolicy) -> Self {
+        Self {
+            capacity: Some(Arc::new(Capacity {
+                max_bytes,
+                policy,
+                order: BTreeMap::new(),
+                ticks: HashMap::new(),
+                tick: 0,
+            })),
+            ..Self::new()
+        }
+    }
+
+    /// Returns a snapshot of the engine, i.e. an independent engine with the
+    /// same data and capacity. This is O(1): the snapshot shares the data with
+    /// the engine until either of them is written to, at which point the
+    /// written one copies the data.
+    pub fn snapshot(&self) -> Memory {
+        Self {
+            data: self.data.clone(),
+            size: self.size,
+            capacity: self.capacity.clone(),
+        }
+    }
+
+    /// Returns the total size of the keys and values.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the keys in the order they would be evicted in, i.e. the least
+    /// recently used key first. Unbounded engines don't track the order, and
+    /// return no keys.
+    pub fn eviction_order(&self) -> impl Iterator<Item = &[u8]> {
+        self.capacity
+            .iter()
+            .flat_map(|capacity| capacity.order.values().map(Vec::as_slice))
+    }
+
+    /// Scans the range, yielding keys and values borrowed from the engine
+    /// rather than copies of them, for callers that only read them
+    /// transiently. Unlike [`Engine::scan`](super::engine::Engine::scan),
+    /// this doesn't allocate, and can't fail.
+    pub fn scan_ref(
+        &self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])> + '_ {
+        (self.data.range(range)).map(|(key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    /// Returns the data for writing, copying it if it is shared with a
+    /// snapshot.
+    fn data_mut(&mut self) -> &mut BTreeMap<Vec<u8>, Vec<u8>> {
+        Arc::make_mut(&mut self.data)
+    }
+
+    /// Returns the capacity for writing, copying it if it is shared with a
+    /// snapshot.
+    fn capacity_mut(&mut self) -> Option<&mut Capacity> {
+        self.capacity.as_mut().map(Arc::make_mut)
+    }
+
+    /// Removes a key, returning whether it existed.
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if !self.data.contains_key(key) {
+            return false;
+        }
+        if let Some(value) = self.data_mut().remove(key) {
+            self.size -= (key.len() + value.len()) as u64;
+        }
+        if let Some(capacity) = self.capacity_mut() {
+            capacity.remove(key);
+        }
+        true
+    }
+
+    /// Evicts the least recently used keys until the engine is within its
+    /// capacity.
+    fn evict(&mut self) {
+        while let Some(capacity) = &self.capacity {
+            if self.size <= capacity.max_bytes {
+                break;
+            }
+            let Some((_, key)) = self.capacity_mut().and_then(|c| c.order.pop_first()) else {
+                break;
+            };
+            self.remove(&key);
+        }
+    }
+
+    /// Scans the range, with owned or borrowed bounds.
+    fn scan_range<T>(&self, range: impl std::ops::RangeBounds<T>) -> ScanIterator<'_>
+    where
+        T: Ord + ?Sized,
+        Vec<u8>: std::borrow::Borrow<T>,
+    {
+        use std::ops::Bound::Unbounded;
+        let remaining = match (range.start_bound(), range.end_bound()) {
+            (Unbounded, Unbounded) => Some(self.data.len()),
+            _ => None,
+        };
+        ScanIterator {
+            inner: self.data.range(range),
+            remaining,
         }
     }
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl std::fmt::Display for Memory {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "memory")
@@ -20,11 +202,17 @@ impl std::fmt::Display for Memory {
 
 pub struct ScanIterator<'a> {
     inner: std::collections::btree_map::Range<'a, Vec<u8>, Vec<u8>>,
+    /// The number of entries left to yield, if known. This is only the case
+    /// for full-range scans.
+    remaining: Option<usize>,
 }
 
 impl<'a> ScanIterator<'a> {
-    fn map(item: (&Vec<u8>, &Vec<u8>)) -> <Self as Iterator>::Item {
+    fn map(&mut self, item: (&Vec<u8>, &Vec<u8>)) -> <Self as Iterator>::Item {
         let (key, value) = item;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
         Ok((key.clone(), value.clone()))
     }
 }
@@ -33,53 +221,452 @@ impl<'a> Iterator for ScanIterator<'a> {
     type Item = Result<(Vec<u8>, Vec<u8>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(Self::map)
+        self.inner.next().map(|item| self.map(item))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.remaining {
+            Some(remaining) => (remaining, Some(remaining)),
+            None => (0, self.inner.size_hint().1),
+        }
     }
 }
 
 impl<'a> DoubleEndedIterator for ScanIterator<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        self.inner.next_back().map(Self::map)
+        self.inner.next_back().map(|item| self.map(item))
     }
 }
 
 impl super::engine::Engine for Memory {
     type ScanIterator<'a> = ScanIterator<'a>;
 
+    /// Sets a key, evicting other keys if the engine is over capacity. Fails
+    /// with [`Error::Value`] if the key and value exceed the whole capacity.
     fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.data.insert(key.to_vec(), value);
+        let size = (key.len() + value.len()) as u64;
+        if let Some(capacity) = self.capacity_mut() {
+            if size > capacity.max_bytes {
+                return Err(Error::Value(format!(
+                    "Entry of {size} bytes exceeds capacity of {} bytes",
+                    capacity.max_bytes
+                )));
+            }
+            capacity.touch(key);
+        }
+        if let Some(old) = self.data_mut().insert(key.to_vec(), value) {
+            self.size -= (key.len() + old.len()) as u64;
+        }
+        self.size += size;
+        self.evict();
         Ok(())
     }
 
     fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        Ok(self.data.get(key).cloned())
+        let value = self.data.get(key).cloned();
+        let lru = matches!(&self.capacity, Some(c) if c.policy == EvictionPolicy::Lru);
+        if value.is_some() && lru {
+            if let Some(capacity) = self.capacity_mut() {
+                capacity.touch(key);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Reports the lengths without cloning the value or touching the key.
+    fn stat_key(&mut self, key: &[u8]) -> Result<Option<KeyStat>> {
+        Ok(self.data.get(key).map(|value| KeyStat {
+            key_length: key.len() as u64,
+            value_length: value.len() as u64,
+            live: true,
+            location: None,
+        }))
+    }
+
+    fn set_if_absent(&mut self, key: &[u8], value: Vec<u8>) -> Result<bool> {
+        if self.data.contains_key(key) {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
     }
 
     fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.data.remove(key);
+        self.remove(key);
         Ok(())
     }
 
+    fn first(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .data
+            .first_key_value()
+            .map(|(key, value)| (key.clone(), value.clone())))
+    }
+
+    fn last(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .data
+            .last_key_value()
+            .map(|(key, value)| (key.clone(), value.clone())))
+    }
+
     fn flush(&mut self) -> Result<()> {
         Ok(())
     }
 
+    fn clear(&mut self) -> Result<()> {
+        self.data = Arc::default();
+        self.size = 0;
+        if let Some(capacity) = self.capacity_mut() {
+            capacity.clear();
+        }
+        Ok(())
+    }
+
+    /// Splits the range off the map, rather than deleting keys one by one.
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        use std::ops::Bound;
+        let (start, end) = super::engine::clone_bounds(&range);
+        let data = self.data_mut();
+        let mut deleted = match start {
+            Bound::Included(start) => data.split_off(&start),
+            Bound::Excluded(start) => {
+                let mut deleted = data.split_off(&start);
+                if let Some(value) = deleted.remove(&start) {
+                    data.insert(start, value);
+                }
+                deleted
+            }
+            Bound::Unbounded => std::mem::take(data),
+        };
+        // If the end is before the start, everything is moved back.
+        let mut kept = match end {
+            Bound::Included(end) => {
+                let mut kept = deleted.split_off(&end);
+                if let Some(value) = kept.remove(&end) {
+                    deleted.insert(end, value);
+                }
+                kept
+            }
+            Bound::Excluded(end) => deleted.split_off(&end),
+            Bound::Unbounded => BTreeMap::new(),
+        };
+        data.append(&mut kept);
+        for (key, value) in &deleted {
+            self.size -= (key.len() + value.len()) as u64;
+            if let Some(capacity) = self.capacity_mut() {
+                capacity.remove(key);
+            }
+        }
+        Ok(deleted.len() as u64)
+    }
+
+    /// Counts the key and value bytes, the vector headers of each entry, and
+    /// the eviction order of bounded engines. Data shared with snapshots is
+    /// counted in full.
+    fn approximate_memory_usage(&self) -> u64 {
+        let vec_size = std::mem::size_of::<Vec<u8>>() as u64;
+        let mut usage = self.size + 2 * vec_size * self.data.len() as u64;
+        if let Some(capacity) = &self.capacity {
+            // Each key is held by both the order and the ticks, with a tick.
+            for key in capacity.ticks.keys() {
+                usage += 2 * (key.len() as u64 + vec_size + 8);
+            }
+        }
+        usage
+    }
+
     fn status(&mut self) -> Result<super::engine::Status> {
         Ok(super::engine::Status {
             name: self.to_string(),
             key_count: self.data.len() as u64,
-            size: self.data.iter().fold(0, |size, (key, value)| {
-                size + key.len() as u64 + value.len() as u64
-            }),
+            size: self.size,
             total_disk_size: 0,
             live_disk_size: 0,
             garbage_disk_size: 0,
+            tombstone_count: 0,
+            file_count: 0,
+            compaction_recommended: false,
         })
     }
 
     fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
-        ScanIterator {
-            inner: self.data.range(range),
+        self.scan_shared(range)
+    }
+
+    fn scan_bytes(
+        &mut self,
+        start: std::ops::Bound<&[u8]>,
+        end: std::ops::Bound<&[u8]>,
+    ) -> Self::ScanIterator<'_> {
+        self.scan_range::<[u8]>((start, end))
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        Ok(self.data.range(range).count())
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.data.range(range).map(|(key, _)| Ok(key.clone()))
+    }
+}
+
+impl ReadEngine for Memory {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> ScanIterator<'_> {
+        self.scan_range(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::engine::Engine, *};
+
+    /// Returns the keys in the engine.
+    fn keys(s: &mut Memory) -> Result<Vec<Vec<u8>>> {
+        s.scan_keys(..).collect()
+    }
+
+    #[test]
+    /// Tests basic operations, status and export/import without touching the
+    /// filesystem. This also runs without the std feature, i.e. with
+    /// `cargo test-core`.
+    fn core() -> Result<()> {
+        let mut s = Memory::new();
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2, 2])?;
+        s.delete(b"a")?;
+        assert_eq!(s.get(b"b")?, Some(vec![2, 2]));
+        assert_eq!(keys(&mut s)?, vec![b"b".to_vec()]);
+
+        let status = s.status()?;
+        assert_eq!((status.key_count, status.size), (1, 3));
+        assert_eq!((status.total_disk_size, status.file_count), (0, 0));
+
+        let mut export = Vec::new();
+        s.export(&mut export)?;
+        let mut t = Memory::new();
+        super::super::engine::import(&mut t, export.as_slice())?;
+        assert_eq!(keys(&mut t)?, keys(&mut s)?);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that overwrites and deletes leave no garbage behind.
+    fn estimate_garbage() -> Result<()> {
+        let mut s = Memory::new();
+        s.set(b"a", vec![1])?;
+        s.set(b"a", vec![2])?;
+        s.delete(b"a")?;
+        assert_eq!(s.estimate_garbage(..)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that LRU eviction evicts the least recently written or read keys.
+    fn lru() -> Result<()> {
+        let mut s = Memory::with_capacity(10);
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2])?;
+        s.set(b"c", vec![3])?;
+        s.set(b"d", vec![4])?;
+        s.set(b"e", vec![5])?;
+        assert_eq!(s.status()?.size, 10);
+
+        // Reading a makes b the least recently used.
+        assert_eq!(s.get(b"a")?, Some(vec![1]));
+        s.set(b"f", vec![6])?;
+        assert_eq!(keys(&mut s)?, vec![b"a", b"c", b"d", b"e", b"f"]);
+
+        // A larger value evicts several keys, overwriting c makes it the most
+        // recently used.
+        s.set(b"c", vec![3, 3])?;
+        s.set(b"g", vec![7, 7, 7])?;
+        assert_eq!(keys(&mut s)?, vec![b"c", b"f", b"g"]);
+        assert_eq!(s.status()?.size, 9);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that FIFO eviction evicts the least recently written keys,
+    /// ignoring reads.
+    fn fifo() -> Result<()> {
+        let mut s = Memory::with_capacity_and_policy(6, EvictionPolicy::Fifo);
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2])?;
+        s.set(b"c", vec![3])?;
+        assert_eq!(s.get(b"a")?, Some(vec![1]));
+        s.set(b"d", vec![4])?;
+        assert_eq!(keys(&mut s)?, vec![b"b", b"c", b"d"]);
+
+        // Overwriting a key moves it to the back of the queue, even if it was
+        // at the front.
+        s.set(b"b", vec![2, 2, 2])?;
+        assert_eq!(keys(&mut s)?, vec![b"b", b"d"]);
+        assert_eq!(s.status()?.size, 6);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the size stays consistent across overwrites and deletes,
+    /// and that entries larger than the capacity are rejected.
+    fn accounting() -> Result<()> {
+        let mut s = Memory::with_capacity(10);
+        assert!(matches!(s.set(b"key", vec![0; 8]), Err(Error::Value(_))));
+        assert_eq!(s.status()?.size, 0);
+
+        s.set(b"a", vec![1, 2, 3])?;
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2, 2])?;
+        assert_eq!(s.status()?.size, 5);
+        s.delete(b"a")?;
+        s.delete(b"missing")?;
+        assert_eq!(s.status()?.size, 3);
+
+        // Deleted keys are no longer eviction candidates.
+        s.set(b"c", vec![3; 5])?;
+        assert_eq!(keys(&mut s)?, vec![b"b", b"c"]);
+        assert_eq!(s.status()?.size, 9);
+
+        assert_eq!(s.delete_prefix(b"b")?, 1);
+        assert_eq!(s.status()?.size, 6);
+        s.clear()?;
+        assert_eq!(s.status()?.size, 0);
+        s.set(b"d", vec![4; 9])?;
+        assert_eq!(keys(&mut s)?, vec![b"d"]);
+
+        // An unbounded engine tracks the size too, and accepts any entry.
+        let mut s = Memory::new();
+        s.set(b"a", vec![0; 100])?;
+        s.set(b"a", vec![0; 10])?;
+        assert_eq!(s.status()?.size, 11);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that full-range scans report their exact length.
+    fn scan_size_hint() -> Result<()> {
+        let mut s = Memory::new();
+        for key in [b"a", b"b", b"c"] {
+            s.set(key, vec![0x01])?;
+        }
+        let key_count = s.status()?.key_count as usize;
+        let mut scan = s.scan(..);
+        assert_eq!(scan.size_hint(), (key_count, Some(key_count)));
+        scan.next_back().transpose()?;
+        assert_eq!(scan.size_hint(), (2, Some(2)));
+
+        assert_eq!(s.scan(b"b".to_vec()..).size_hint().0, 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that scan_ref yields the same items as scan in both directions.
+    /// That it doesn't allocate is tested in tests/scan_ref_allocations.rs,
+    /// which needs its own global allocator.
+    fn scan_ref() -> Result<()> {
+        let mut s = Memory::new();
+        for i in 0..1000u32 {
+            s.set(&i.to_be_bytes(), i.to_le_bytes().to_vec())?;
+        }
+        let range = 10u32.to_be_bytes().to_vec()..500u32.to_be_bytes().to_vec();
+        let expect = s.scan(range.clone()).collect::<Result<Vec<_>>>()?;
+        let borrowed = (s.scan_ref(range.clone()))
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(borrowed, expect);
+        let reversed = (s.scan_ref(..).rev().take(2))
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reversed,
+            s.scan(..).rev().take(2).collect::<Result<Vec<_>>>()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that snapshots share data until written to, and that writes to
+    /// a snapshot and its engine don't affect each other.
+    fn snapshot() -> Result<()> {
+        let mut s = Memory::new();
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2])?;
+
+        let mut snapshot = s.snapshot();
+        assert!(Arc::ptr_eq(&s.data, &snapshot.data));
+        assert_eq!(snapshot.get(b"a")?, Some(vec![1]));
+        assert!(Arc::ptr_eq(&s.data, &snapshot.data));
+
+        snapshot.set(b"a", vec![10])?;
+        snapshot.delete(b"b")?;
+        snapshot.set(b"c", vec![30])?;
+        assert!(!Arc::ptr_eq(&s.data, &snapshot.data));
+        s.set(b"d", vec![4])?;
+        s.delete_prefix(b"a")?;
+
+        assert_eq!(
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), vec![2]), (b"d".to_vec(), vec![4])]
+        );
+        assert_eq!(
+            snapshot.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![10]), (b"c".to_vec(), vec![30])]
+        );
+        assert_eq!(s.status()?.size, 4);
+        assert_eq!(snapshot.status()?.size, 4);
+
+        // Clearing the engine leaves the snapshot intact.
+        let mut snapshot = s.snapshot();
+        s.clear()?;
+        assert_eq!(s.count(..)?, 0);
+        assert_eq!(snapshot.count(..)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that snapshots of bounded engines evict independently.
+    fn snapshot_capacity() -> Result<()> {
+        let mut s = Memory::with_capacity(4);
+        s.set(b"a", vec![1])?;
+        s.set(b"b", vec![2])?;
+
+        let mut snapshot = s.snapshot();
+        assert_eq!(snapshot.get(b"a")?, Some(vec![1]));
+        snapshot.set(b"c", vec![3])?;
+        s.set(b"c", vec![3])?;
+        assert_eq!(keys(&mut snapshot)?, vec![b"a", b"c"]);
+        assert_eq!(keys(&mut s)?, vec![b"b", b"c"]);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the memory usage estimate grows proportionally with the
+    /// keys, and exceeds the logical size due to per-entry overhead.
+    fn approximate_memory_usage() -> Result<()> {
+        let mut s = Memory::new();
+        assert_eq!(s.approximate_memory_usage(), 0);
+        for i in 0..1000u32 {
+            s.set(format!("key{i:05}").as_bytes(), vec![0; 16])?;
+        }
+        let usage = s.approximate_memory_usage();
+        assert!(usage > s.status()?.size);
+        for i in 1000..2000u32 {
+            s.set(format!("key{i:05}").as_bytes(), vec![0; 16])?;
         }
+        assert_eq!(s.approximate_memory_usage(), 2 * usage);
+
+        // Bounded engines also track the eviction order.
+        let mut bounded = Memory::with_capacity(1 << 20);
+        bounded.set(b"key00000", vec![0; 16])?;
+        assert!(bounded.approximate_memory_usage() > usage / 1000);
+        Ok(())
     }
 }
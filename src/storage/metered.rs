@@ -0,0 +1,476 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use super::engine::{Engine, ReadEngine, SizeHistogram, Status, WriteBatch};
+use crate::error::{Error, Result};
+
+/// Operation metrics collected by [`Metered`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// The number of keys read, including by `get_many`.
+    pub gets: u64,
+    /// The number of keys written, including in write batches.
+    pub sets: u64,
+    /// The number of keys deleted, including in write batches and by
+    /// `delete_prefix` and `delete_range`.
+    pub deletes: u64,
+    /// The number of scans, including key-only scans and counts.
+    pub scans: u64,
+    /// The number of value bytes read by gets, and key and value bytes
+    /// yielded by scans.
+    pub bytes_read: u64,
+    /// The number of key and value bytes written.
+    pub bytes_written: u64,
+    /// The number of failed operations and scan items. Failed operations are
+    /// otherwise counted like successful ones, except for the bytes read and
+    /// the keys deleted by `delete_prefix` and `delete_range`, which aren't
+    /// known.
+    pub errors: u64,
+    /// The cumulative time spent in the wrapped engine, including iterating
+    /// over scans and failed operations.
+    pub latency: Duration,
+}
+
+/// The counters behind [`Metrics`]. They are atomic, so that reads through a
+/// shared reference can be counted too.
+#[derive(Default)]
+struct Counters {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    scans: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    errors: AtomicU64,
+    latency_nanos: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Adds the time elapsed since the start to the latency, and counts the
+    /// result if it's an error.
+    fn record<T>(&self, start: Instant, result: &Result<T>) {
+        Self::add(&self.latency_nanos, start.elapsed().as_nanos() as u64);
+        if result.is_err() {
+            Self::add(&self.errors, 1);
+        }
+    }
+
+    /// Counts the keys and bytes of a read of the given number of keys.
+    fn read(
+        &self,
+        start: Instant,
+        keys: usize,
+        result: std::result::Result<&[Option<Vec<u8>>], &Error>,
+    ) {
+        Self::add(&self.latency_nanos, start.elapsed().as_nanos() as u64);
+        Self::add(&self.gets, keys as u64);
+        match result {
+            Ok(values) => {
+                let bytes = values
+                    .iter()
+                    .flatten()
+                    .map(|value| value.len() as u64)
+                    .sum();
+                Self::add(&self.bytes_read, bytes);
+            }
+            Err(_) => Self::add(&self.errors, 1),
+        }
+    }
+}
+
+/// An engine wrapper that counts operations, bytes and latency, exposed by
+/// [`Metered::metrics`].
+pub struct Metered<E: Engine> {
+    inner: E,
+    counters: Counters,
+}
+
+impl<E: Engine> Metered<E> {
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Returns the metrics collected so far.
+    pub fn metrics(&self) -> Metrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Metrics {
+            gets: load(&self.counters.gets),
+            sets: load(&self.counters.sets),
+            deletes: load(&self.counters.deletes),
+            scans: load(&self.counters.scans),
+            bytes_read: load(&self.counters.bytes_read),
+            bytes_written: load(&self.counters.bytes_written),
+            errors: load(&self.counters.errors),
+            latency: Duration::from_nanos(load(&self.counters.latency_nanos)),
+        }
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Engine> std::fmt::Display for Metered<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (metered)", self.inner)
+    }
+}
+
+/// A scanned item whose bytes are counted.
+trait Scanned {
+    fn length(&self) -> usize;
+}
+
+impl Scanned for Vec<u8> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Scanned for (Vec<u8>, Vec<u8>) {
+    fn length(&self) -> usize {
+        self.0.len() + self.1.len()
+    }
+}
+
+/// A scan iterator that counts the bytes it yields and the time spent
+/// iterating.
+pub struct ScanIterator<'a, I> {
+    inner: I,
+    counters: &'a Counters,
+}
+
+impl<'a, I> ScanIterator<'a, I> {
+    fn new(inner: I, counters: &'a Counters) -> Self {
+        Counters::add(&counters.scans, 1);
+        Self { inner, counters }
+    }
+
+    fn record<T: Scanned>(&self, start: Instant, item: Option<Result<T>>) -> Option<Result<T>> {
+        match &item {
+            Some(result) => self.counters.record(start, result),
+            None => self.counters.record(start, &Ok(())),
+        }
+        if let Some(Ok(item)) = &item {
+            Counters::add(&self.counters.bytes_read, item.length() as u64);
+        }
+        item
+    }
+}
+
+impl<'a, T: Scanned, I: Iterator<Item = Result<T>>> Iterator for ScanIterator<'a, I> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = Instant::now();
+        let item = self.inner.next();
+        self.record(start, item)
+    }
+}
+
+impl<'a, T: Scanned, I: DoubleEndedIterator<Item = Result<T>>> DoubleEndedIterator
+    for ScanIterator<'a, I>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let start = Instant::now();
+        let item = self.inner.next_back();
+        self.record(start, item)
+    }
+}
+
+impl<E: Engine> Engine for Metered<E> {
+    type ScanIterator<'a>
+        = ScanIterator<'a, E::ScanIterator<'a>>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let length = key.len() + value.len();
+        let result = self.inner.set(key, value);
+        self.counters.record(start, &result);
+        Counters::add(&self.counters.sets, 1);
+        Counters::add(&self.counters.bytes_written, length as u64);
+        result
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let result = self.inner.get(key);
+        let values = result.as_ref().map(std::slice::from_ref);
+        self.counters.read(start, 1, values);
+        result
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let start = Instant::now();
+        let result = self.inner.get_many(keys);
+        self.counters.read(start, keys.len(), result.as_deref());
+        result
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.delete(key);
+        self.counters.record(start, &result);
+        Counters::add(&self.counters.deletes, 1);
+        Counters::add(&self.counters.bytes_written, key.len() as u64);
+        result
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.flush();
+        self.counters.record(start, &result);
+        result
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.sync();
+        self.counters.record(start, &result);
+        result
+    }
+
+    fn sync_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.sync_range(range);
+        self.counters.record(start, &result);
+        result
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.clear();
+        self.counters.record(start, &result);
+        result
+    }
+
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<u64> {
+        let start = Instant::now();
+        let result = self.inner.delete_prefix(prefix);
+        self.counters.record(start, &result);
+        Counters::add(&self.counters.deletes, *result.as_ref().unwrap_or(&0));
+        result
+    }
+
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        let start = Instant::now();
+        let result = self.inner.delete_range(range);
+        self.counters.record(start, &result);
+        Counters::add(&self.counters.deletes, *result.as_ref().unwrap_or(&0));
+        result
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let (mut sets, mut deletes, mut length) = (0, 0, 0);
+        for (key, value) in batch.iter() {
+            match value {
+                Some(value) => {
+                    sets += 1;
+                    length += key.len() + value.len();
+                }
+                None => {
+                    deletes += 1;
+                    length += key.len();
+                }
+            }
+        }
+        let start = Instant::now();
+        let result = self.inner.write_batch(batch);
+        self.counters.record(start, &result);
+        Counters::add(&self.counters.sets, sets);
+        Counters::add(&self.counters.deletes, deletes);
+        Counters::add(&self.counters.bytes_written, length as u64);
+        result
+    }
+
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        self.inner.stats_histogram()
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.estimate_garbage(range)
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        Ok(Status {
+            name: self.to_string(),
+            ..self.inner.status()?
+        })
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        ScanIterator::new(self.inner.scan(range), &self.counters)
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        ScanIterator::new(self.inner.scan_keys(range), &self.counters)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.count(range);
+        self.counters.record(start, &result);
+        Counters::add(&self.counters.scans, 1);
+        result
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for Metered<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let result = self.inner.get_shared(key);
+        let values = result.as_ref().map(std::slice::from_ref);
+        self.counters.read(start, 1, values);
+        result
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        ScanIterator::new(self.inner.scan_shared(range), &self.counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::memory::Memory, *};
+
+    #[test]
+    /// Tests that the counters follow a known sequence of operations.
+    fn metrics() -> Result<()> {
+        let mut s = Metered::new(Memory::new());
+        assert_eq!(s.metrics(), Metrics::default());
+
+        s.set(b"a", vec![1, 2, 3])?;
+        s.set(b"b", vec![4])?;
+        assert_eq!(s.get(b"a")?, Some(vec![1, 2, 3]));
+        assert_eq!(s.get(b"missing")?, None);
+        assert_eq!(s.get_shared(b"b")?, Some(vec![4]));
+        s.get_many(&[b"a".to_vec(), b"b".to_vec()])?;
+        s.delete(b"missing")?;
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", vec![5, 6]);
+        batch.delete(b"b");
+        s.write_batch(batch)?;
+
+        // Scans count the items they yield, not the items in the range.
+        assert_eq!(
+            s.scan(..).next().transpose()?,
+            Some((b"a".to_vec(), vec![1, 2, 3]))
+        );
+        assert_eq!(s.scan_keys(..).rev().collect::<Result<Vec<_>>>()?.len(), 2);
+        assert_eq!(s.count(..)?, 2);
+        assert_eq!(s.delete_prefix(b"c")?, 1);
+
+        let metrics = s.metrics();
+        assert_eq!(
+            metrics,
+            Metrics {
+                gets: 5,
+                sets: 3,
+                deletes: 3,
+                scans: 3,
+                bytes_read: 3 + 1 + (3 + 1) + 4 + (1 + 1),
+                bytes_written: 4 + 2 + 7 + (3 + 1),
+                errors: 0,
+                latency: metrics.latency,
+            }
+        );
+        assert!(metrics.latency > Duration::ZERO);
+        Ok(())
+    }
+    /// An engine whose operations all fail, and whose scans yield an error.
+    struct Failing;
+
+    impl std::fmt::Display for Failing {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "failing")
+        }
+    }
+
+    fn failure() -> Error {
+        Error::Internal("failure".to_string())
+    }
+
+    impl Engine for Failing {
+        type ScanIterator<'a> = std::iter::Once<Result<(Vec<u8>, Vec<u8>)>>;
+
+        fn set(&mut self, _: &[u8], _: Vec<u8>) -> Result<()> {
+            Err(failure())
+        }
+
+        fn get(&mut self, _: &[u8]) -> Result<Option<Vec<u8>>> {
+            Err(failure())
+        }
+
+        fn delete(&mut self, _: &[u8]) -> Result<()> {
+            Err(failure())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Err(failure())
+        }
+
+        fn status(&mut self) -> Result<Status> {
+            Err(failure())
+        }
+
+        fn scan(&mut self, _: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+            std::iter::once(Err(failure()))
+        }
+    }
+
+    #[test]
+    /// Tests that failed operations are counted like successful ones, along
+    /// with their latency, and as errors.
+    fn errors() -> Result<()> {
+        let mut s = Metered::new(Failing);
+        assert!(s.set(b"a", vec![1, 2, 3]).is_err());
+        assert!(s.get(b"a").is_err());
+        assert!(s.get_many(&[b"a".to_vec(), b"b".to_vec()]).is_err());
+        assert!(s.delete(b"a").is_err());
+        assert!(s.delete_prefix(b"a").is_err());
+        assert!(s.delete_range(..).is_err());
+        assert!(s.count(..).is_err());
+        assert!(s.flush().is_err());
+        assert!(s.scan(..).next().unwrap().is_err());
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", vec![5, 6]);
+        batch.delete(b"b");
+        assert!(s.write_batch(batch).is_err());
+
+        let metrics = s.metrics();
+        assert_eq!(
+            metrics,
+            Metrics {
+                gets: 3,
+                sets: 2,
+                deletes: 2,
+                scans: 2,
+                bytes_read: 0,
+                bytes_written: 4 + 1 + (3 + 1),
+                errors: 10,
+                latency: metrics.latency,
+            }
+        );
+        assert!(metrics.latency > Duration::ZERO);
+        Ok(())
+    }
+}
@@ -0,0 +1,780 @@
+/*!
+Multi-version concurrency control on top of an [`Engine`], providing
+snapshot-isolated transactions.
+
+Each write is stored as a new version of the key, tagged with the version of
+the transaction that wrote it. A transaction sees the latest version of each
+key written by a transaction that committed before it began, as well as its
+own writes. Two transactions writing the same key conflict, and the later
+writer fails with [`Error::Serialization`] and should retry.
+
+A transaction can create savepoints with [`Transaction::savepoint`], and undo
+its writes since a savepoint with [`Transaction::rollback_to`] while keeping
+earlier ones. Once a savepoint exists, each write records the transaction's
+previous version of the key in an in-memory undo log, in write order, which
+rolling back replays in reverse. Each savepoint also records the
+transaction's rollback generation, such that a savepoint invalidated by
+rolling back past it stays invalid even once the undo log grows again.
+
+The underlying engine stores the following keys, all of which sort in order:
+- `NextVersion`: the next transaction version.
+- `TxnActive(version)`: marks a transaction as active.
+- `TxnWrite(version, key)`: records a key written by an active transaction,
+  for rollback.
+- `Version(key, version)`: a version of a key, with an empty value for
+  deletions or a 1 byte followed by the value otherwise.
+
+Keys are encoded such that byte slices are escaped (0x00 as 0x00 0xff) and
+terminated by 0x00 0x00, and versions are big-endian u64s, which preserves the
+ordering of `(key, version)` pairs.
+*/
+
+use super::engine::{Engine, WriteBatch};
+use crate::error::{Error, Result};
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// A transaction version.
+pub type Version = u64;
+
+/// A key stored in the underlying engine.
+#[derive(Clone, Debug, PartialEq)]
+enum Key {
+    NextVersion,
+    TxnActive(Version),
+    TxnWrite(Version, Vec<u8>),
+    Version(Vec<u8>, Version),
+}
+
+impl Key {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Self::NextVersion => bytes.push(0x01),
+            Self::TxnActive(version) => {
+                bytes.push(0x02);
+                bytes.extend_from_slice(&version.to_be_bytes());
+            }
+            Self::TxnWrite(version, key) => {
+                bytes.push(0x03);
+                bytes.extend_from_slice(&version.to_be_bytes());
+                encode_bytes(&mut bytes, key);
+            }
+            Self::Version(key, version) => {
+                bytes.push(0x04);
+                encode_bytes(&mut bytes, key);
+                bytes.extend_from_slice(&version.to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let bytes = &mut bytes;
+        let key = match take(bytes, 1)?[0] {
+            0x01 => Self::NextVersion,
+            0x02 => Self::TxnActive(decode_version(bytes)?),
+            0x03 => Self::TxnWrite(decode_version(bytes)?, decode_bytes(bytes)?),
+            0x04 => Self::Version(decode_bytes(bytes)?, decode_version(bytes)?),
+            prefix => return Err(Error::Internal(format!("Invalid key prefix {prefix:#x}"))),
+        };
+        if !bytes.is_empty() {
+            return Err(Error::Internal("Unexpected data after key".to_string()));
+        }
+        Ok(key)
+    }
+}
+
+/// Encodes a byte slice such that the ordering is preserved when followed by
+/// other data.
+pub(super) fn encode_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+    for byte in bytes {
+        match byte {
+            0x00 => output.extend_from_slice(&[0x00, 0xff]),
+            byte => output.push(*byte),
+        }
+    }
+    output.extend_from_slice(&[0x00, 0x00]);
+}
+
+/// The encoded prefix of all versions of a key.
+fn version_prefix(key: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x04];
+    encode_bytes(&mut bytes, key);
+    bytes
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if bytes.len() < length {
+        return Err(Error::Internal("Unexpected end of key".to_string()));
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn decode_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        match take(bytes, 1)?[0] {
+            0x00 => match take(bytes, 1)?[0] {
+                0x00 => return Ok(decoded),
+                0xff => decoded.push(0x00),
+                byte => return Err(Error::Internal(format!("Invalid escape byte {byte:#x}"))),
+            },
+            byte => decoded.push(byte),
+        }
+    }
+}
+
+fn decode_version(bytes: &mut &[u8]) -> Result<Version> {
+    Ok(Version::from_be_bytes(take(bytes, 8)?.try_into().unwrap()))
+}
+
+fn encode_value(value: Option<&[u8]>) -> Vec<u8> {
+    match value {
+        Some(value) => std::iter::once(0x01).chain(value.iter().copied()).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn decode_value(bytes: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    match bytes.split_first() {
+        None => Ok(None),
+        Some((0x01, value)) => Ok(Some(value.to_vec())),
+        Some((prefix, _)) => Err(Error::Internal(format!("Invalid value prefix {prefix:#x}"))),
+    }
+}
+
+/// An MVCC-based transactional key-value store, wrapping an engine. It can be
+/// cloned to share it between threads.
+pub struct MVCC<E: Engine> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E: Engine> Clone for MVCC<E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+        }
+    }
+}
+
+impl<E: Engine> MVCC<E> {
+    pub fn new(engine: E) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// Begins a new read-write transaction.
+    pub fn begin(&self) -> Result<Transaction<E>> {
+        Transaction::begin(self.engine.clone())
+    }
+}
+
+/// Locks the engine, recovering it if another thread panicked while holding
+/// the lock, since the engine itself remains consistent.
+fn lock<E: Engine>(engine: &Mutex<E>) -> MutexGuard<'_, E> {
+    engine.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// An MVCC transaction. It must be finished with [`Transaction::commit`] or
+/// [`Transaction::rollback`], otherwise it remains active and conflicts with
+/// later writes.
+pub struct Transaction<E: Engine> {
+    engine: Arc<Mutex<E>>,
+    version: Version,
+    /// The versions of transactions that were active when this one began,
+    /// whose writes are invisible to it.
+    active: HashSet<Version>,
+    /// The undo log since the first savepoint, or None until a savepoint is
+    /// created.
+    undo: Mutex<Option<UndoLog>>,
+    /// The undo log position rolled back to by each rollback_to call, in
+    /// order. The number of rollbacks is the current savepoint generation.
+    rollbacks: Mutex<Vec<usize>>,
+}
+
+/// The keys written by a transaction, in write order, along with the
+/// transaction's previous encoded version of each, if any.
+type UndoLog = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// A savepoint in a transaction, see [`Transaction::savepoint`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Savepoint {
+    /// The version of the transaction.
+    version: Version,
+    /// The length of the undo log at the savepoint.
+    position: usize,
+    /// The number of rollbacks made before the savepoint was created.
+    generation: usize,
+}
+
+impl<E: Engine> Transaction<E> {
+    fn begin(engine: Arc<Mutex<E>>) -> Result<Self> {
+        let mut session = lock(&engine);
+
+        let version = match session.get(&Key::NextVersion.encode())? {
+            Some(bytes) => Version::from_be_bytes(
+                bytes
+                    .try_into()
+                    .map_err(|_| Error::Internal("Invalid next version".to_string()))?,
+            ),
+            None => 1,
+        };
+
+        let mut active = HashSet::new();
+        let from = Key::TxnActive(0).encode();
+        let to = Key::TxnActive(Version::MAX).encode();
+        for result in session.scan_keys(from..=to) {
+            match Key::decode(&result?)? {
+                Key::TxnActive(version) => active.insert(version),
+                key => return Err(Error::Internal(format!("Expected TxnActive, got {key:?}"))),
+            };
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.set(
+            &Key::NextVersion.encode(),
+            (version + 1).to_be_bytes().to_vec(),
+        );
+        batch.set(&Key::TxnActive(version).encode(), vec![]);
+        session.write_batch(batch)?;
+        drop(session);
+
+        Ok(Self {
+            engine,
+            version,
+            active,
+            undo: Mutex::new(None),
+            rollbacks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The version of the transaction.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns whether a version is visible to this transaction.
+    fn is_visible(&self, version: Version) -> bool {
+        version == self.version || (version < self.version && !self.active.contains(&version))
+    }
+
+    /// Commits the transaction, making its writes visible to transactions
+    /// that begin after it.
+    pub fn commit(self) -> Result<()> {
+        let mut session = lock(&self.engine);
+        let mut batch = WriteBatch::new();
+        for key in self.write_set(&mut session)? {
+            batch.delete(&Key::TxnWrite(self.version, key).encode());
+        }
+        batch.delete(&Key::TxnActive(self.version).encode());
+        session.write_batch(batch)
+    }
+
+    /// Rolls back the transaction, discarding its writes.
+    pub fn rollback(self) -> Result<()> {
+        let mut session = lock(&self.engine);
+        let mut batch = WriteBatch::new();
+        for key in self.write_set(&mut session)? {
+            batch.delete(&Key::Version(key.clone(), self.version).encode());
+            batch.delete(&Key::TxnWrite(self.version, key).encode());
+        }
+        batch.delete(&Key::TxnActive(self.version).encode());
+        session.write_batch(batch)
+    }
+
+    /// Creates a savepoint, which the transaction's later writes can be
+    /// rolled back to with [`Transaction::rollback_to`].
+    pub fn savepoint(&self) -> Savepoint {
+        let mut undo = self.undo.lock().unwrap_or_else(|error| error.into_inner());
+        let rollbacks = self
+            .rollbacks
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        Savepoint {
+            version: self.version,
+            position: undo.get_or_insert_with(Vec::new).len(),
+            generation: rollbacks.len(),
+        }
+    }
+
+    /// Rolls back the writes made since the savepoint, restoring the values
+    /// this transaction had written before it, while keeping earlier writes.
+    /// The savepoint remains valid, but later savepoints are invalidated.
+    /// Returns [`Error::Value`] for an invalid savepoint, e.g. one from
+    /// another transaction or one that a later rollback went past.
+    pub fn rollback_to(&self, savepoint: Savepoint) -> Result<()> {
+        let mut session = lock(&self.engine);
+        let mut undo = self.undo.lock().unwrap_or_else(|error| error.into_inner());
+        let undo = undo.get_or_insert_with(Vec::new);
+        let mut rollbacks = self
+            .rollbacks
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let stale = (rollbacks.get(savepoint.generation..))
+            .is_none_or(|later| later.iter().any(|&position| position < savepoint.position));
+        if savepoint.version != self.version || savepoint.position > undo.len() || stale {
+            return Err(Error::Value("Invalid savepoint".to_string()));
+        }
+
+        // Undo the writes newest first, such that each key ends up with its
+        // value as of the savepoint.
+        let mut batch = WriteBatch::new();
+        for (key, previous) in undo.iter().skip(savepoint.position).rev() {
+            let version_key = Key::Version(key.clone(), self.version).encode();
+            match previous {
+                Some(value) => batch.set(&version_key, value.clone()),
+                None => {
+                    batch.delete(&version_key);
+                    batch.delete(&Key::TxnWrite(self.version, key.clone()).encode());
+                }
+            }
+        }
+        session.write_batch(batch)?;
+        undo.truncate(savepoint.position);
+        rollbacks.push(savepoint.position);
+        Ok(())
+    }
+
+    /// Returns the keys written by this transaction.
+    fn write_set(&self, session: &mut E) -> Result<Vec<Vec<u8>>> {
+        let mut prefix = vec![0x03];
+        prefix.extend_from_slice(&self.version.to_be_bytes());
+        session
+            .scan_prefix(&prefix)
+            .map(|result| match Key::decode(&result?.0)? {
+                Key::TxnWrite(_, key) => Ok(key),
+                key => Err(Error::Internal(format!("Expected TxnWrite, got {key:?}"))),
+            })
+            .collect()
+    }
+
+    /// Sets a key.
+    pub fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.write(key, Some(value))
+    }
+
+    /// Deletes a key.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.write(key, None)
+    }
+
+    /// Writes a new version of a key, or returns [`Error::Serialization`] if
+    /// the latest version was written by a transaction invisible to us.
+    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        let mut session = lock(&self.engine);
+
+        // Only versions written by transactions that were active when we
+        // began, or that began after us, can be invisible.
+        let min = self
+            .active
+            .iter()
+            .min()
+            .copied()
+            .unwrap_or(self.version + 1);
+        let from = Key::Version(key.to_vec(), min).encode();
+        let to = Key::Version(key.to_vec(), Version::MAX).encode();
+        if let Some(result) = session.scan_keys(from..=to).next_back() {
+            match Key::decode(&result?)? {
+                Key::Version(_, version) if !self.is_visible(version) => {
+                    return Err(Error::Serialization)
+                }
+                Key::Version(..) => {}
+                key => return Err(Error::Internal(format!("Expected Version, got {key:?}"))),
+            }
+        }
+
+        let version_key = Key::Version(key.to_vec(), self.version).encode();
+        let mut undo = self.undo.lock().unwrap_or_else(|error| error.into_inner());
+        let previous = match undo.as_ref() {
+            Some(_) => Some(session.get(&version_key)?),
+            None => None,
+        };
+
+        let mut batch = WriteBatch::new();
+        batch.set(&Key::TxnWrite(self.version, key.to_vec()).encode(), vec![]);
+        batch.set(&version_key, encode_value(value.as_deref()));
+        session.write_batch(batch)?;
+        if let (Some(undo), Some(previous)) = (undo.as_mut(), previous) {
+            undo.push((key.to_vec(), previous));
+        }
+        Ok(())
+    }
+
+    /// Fetches the latest visible value of a key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut session = lock(&self.engine);
+        let from = Key::Version(key.to_vec(), 0).encode();
+        let to = Key::Version(key.to_vec(), self.version).encode();
+        let mut scan = session.scan(from..=to).rev();
+        while let Some((key, value)) = scan.next().transpose()? {
+            match Key::decode(&key)? {
+                Key::Version(_, version) if self.is_visible(version) => return decode_value(value),
+                Key::Version(..) => {}
+                key => return Err(Error::Internal(format!("Expected Version, got {key:?}"))),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Scans the latest visible values of the keys in the range. The results
+    /// are collected, since the engine can't stay locked during iteration.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(version_prefix(key)),
+            Bound::Excluded(key) => {
+                Bound::Excluded(Key::Version(key.clone(), Version::MAX).encode())
+            }
+            Bound::Unbounded => Bound::Included(vec![0x04]),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => {
+                Bound::Included(Key::Version(key.clone(), Version::MAX).encode())
+            }
+            Bound::Excluded(key) => Bound::Excluded(version_prefix(key)),
+            Bound::Unbounded => Bound::Excluded(vec![0x05]),
+        };
+
+        // Keep the latest visible version of each key, then drop deletions.
+        let mut session = lock(&self.engine);
+        let mut latest = BTreeMap::new();
+        for result in session.scan((start, end)) {
+            let (key, value) = result?;
+            match Key::decode(&key)? {
+                Key::Version(key, version) if self.is_visible(version) => {
+                    latest.insert(key, value);
+                }
+                Key::Version(..) => {}
+                key => return Err(Error::Internal(format!("Expected Version, got {key:?}"))),
+            }
+        }
+
+        latest
+            .into_iter()
+            .filter_map(|(key, value)| match decode_value(value) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(error) => Some(Err(error)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::memory::Memory;
+    use super::*;
+
+    fn setup() -> MVCC<Memory> {
+        MVCC::new(Memory::new())
+    }
+
+    #[test]
+    /// Tests that keys are encoded in order, and round-trip through decoding.
+    fn key_encoding() -> Result<()> {
+        let keys = vec![
+            Key::NextVersion,
+            Key::TxnActive(1),
+            Key::TxnActive(256),
+            Key::TxnWrite(1, b"".to_vec()),
+            Key::TxnWrite(1, b"\x00".to_vec()),
+            Key::TxnWrite(1, b"\x00\x00".to_vec()),
+            Key::TxnWrite(1, b"a".to_vec()),
+            Key::TxnWrite(2, b"".to_vec()),
+            Key::Version(b"".to_vec(), 1),
+            Key::Version(b"".to_vec(), 2),
+            Key::Version(b"\x00".to_vec(), 1),
+            Key::Version(b"\x00\xff".to_vec(), 1),
+            Key::Version(b"\x01".to_vec(), 1),
+            Key::Version(b"a".to_vec(), 1),
+            Key::Version(b"a".to_vec(), Version::MAX),
+            Key::Version(b"a\x00".to_vec(), 0),
+            Key::Version(b"b".to_vec(), 0),
+            Key::Version(b"\xff".to_vec(), 0),
+        ];
+        for key in &keys {
+            assert_eq!(&Key::decode(&key.encode())?, key);
+        }
+        for pair in keys.windows(2) {
+            assert!(
+                pair[0].encode() < pair[1].encode(),
+                "{:?} < {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Tests basic reads and writes within and across transactions.
+    fn get_set_delete() -> Result<()> {
+        let mvcc = setup();
+
+        let t1 = mvcc.begin()?;
+        assert_eq!(t1.get(b"a")?, None);
+        t1.set(b"a", vec![1])?;
+        t1.set(b"b", vec![2])?;
+        t1.delete(b"b")?;
+        assert_eq!(t1.get(b"a")?, Some(vec![1]));
+        assert_eq!(t1.get(b"b")?, None);
+        t1.commit()?;
+
+        let t2 = mvcc.begin()?;
+        assert_eq!(t2.get(b"a")?, Some(vec![1]));
+        assert_eq!(t2.get(b"b")?, None);
+        t2.delete(b"a")?;
+        assert_eq!(t2.get(b"a")?, None);
+        t2.commit()?;
+
+        let t3 = mvcc.begin()?;
+        assert_eq!(t3.get(b"a")?, None);
+        t3.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that transactions don't see uncommitted writes or writes
+    /// committed after they began.
+    fn isolation() -> Result<()> {
+        let mvcc = setup();
+        let t0 = mvcc.begin()?;
+        t0.set(b"a", vec![0])?;
+        t0.commit()?;
+
+        let t1 = mvcc.begin()?;
+        let t2 = mvcc.begin()?;
+        t1.set(b"a", vec![1])?;
+        t1.set(b"b", vec![1])?;
+        assert_eq!(t2.get(b"a")?, Some(vec![0]));
+        assert_eq!(t2.get(b"b")?, None);
+
+        // A transaction beginning while t1 is active doesn't see its writes,
+        // even after t1 commits.
+        let t3 = mvcc.begin()?;
+        t1.commit()?;
+        assert_eq!(t2.get(b"a")?, Some(vec![0]));
+        assert_eq!(t3.get(b"a")?, Some(vec![0]));
+        assert_eq!(t3.scan(..)?, vec![(b"a".to_vec(), vec![0])]);
+
+        let t4 = mvcc.begin()?;
+        assert_eq!(t4.get(b"a")?, Some(vec![1]));
+        assert_eq!(t4.get(b"b")?, Some(vec![1]));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that concurrent writes to the same key conflict.
+    fn write_conflict() -> Result<()> {
+        let mvcc = setup();
+
+        // Writes by an active transaction conflict.
+        let t1 = mvcc.begin()?;
+        let t2 = mvcc.begin()?;
+        t1.set(b"a", vec![1])?;
+        assert_eq!(t2.set(b"a", vec![2]), Err(Error::Serialization));
+        assert_eq!(t2.delete(b"a"), Err(Error::Serialization));
+        t2.set(b"b", vec![2])?;
+
+        // Writes committed after we began conflict too.
+        t1.commit()?;
+        assert_eq!(t2.set(b"a", vec![2]), Err(Error::Serialization));
+        t2.rollback()?;
+
+        // Writes by a later transaction conflict.
+        let t3 = mvcc.begin()?;
+        let t4 = mvcc.begin()?;
+        t4.set(b"c", vec![4])?;
+        assert_eq!(t3.set(b"c", vec![3]), Err(Error::Serialization));
+        t4.commit()?;
+        t3.rollback()?;
+
+        // Writes committed before we began don't conflict.
+        let t5 = mvcc.begin()?;
+        t5.set(b"a", vec![5])?;
+        t5.set(b"c", vec![5])?;
+        t5.commit()?;
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that rollbacks discard writes, and release conflicts.
+    fn rollback() -> Result<()> {
+        let mvcc = setup();
+        let t0 = mvcc.begin()?;
+        t0.set(b"a", vec![0])?;
+        t0.commit()?;
+
+        let t1 = mvcc.begin()?;
+        t1.set(b"a", vec![1])?;
+        t1.set(b"b", vec![1])?;
+        t1.rollback()?;
+
+        let t2 = mvcc.begin()?;
+        assert_eq!(t2.scan(..)?, vec![(b"a".to_vec(), vec![0])]);
+        t2.set(b"a", vec![2])?;
+        t2.commit()?;
+
+        // Finished transactions leave no bookkeeping records behind.
+        let t3 = mvcc.begin()?;
+        assert_eq!(t3.scan(..)?, vec![(b"a".to_vec(), vec![2])]);
+        t3.commit()?;
+        let mut engine = lock(&mvcc.engine);
+        assert_eq!(engine.scan_prefix(&[0x02]).count(), 0);
+        assert_eq!(engine.scan_prefix(&[0x03]).count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that rolling back to a savepoint only undoes the later writes,
+    /// including nested savepoints, overwrites and deletes.
+    fn savepoints() -> Result<()> {
+        let mvcc = setup();
+        let t0 = mvcc.begin()?;
+        t0.set(b"c", vec![0])?;
+        t0.commit()?;
+
+        let t1 = mvcc.begin()?;
+        t1.set(b"a", vec![1])?;
+        let sp1 = t1.savepoint();
+        t1.set(b"a", vec![2])?;
+        t1.set(b"b", vec![2])?;
+        let sp2 = t1.savepoint();
+        t1.set(b"b", vec![3])?;
+        t1.delete(b"c")?;
+        t1.set(b"d", vec![3])?;
+        assert_eq!(
+            t1.scan(..)?,
+            vec![
+                (b"a".to_vec(), vec![2]),
+                (b"b".to_vec(), vec![3]),
+                (b"d".to_vec(), vec![3]),
+            ]
+        );
+
+        t1.rollback_to(sp2)?;
+        assert_eq!(
+            t1.scan(..)?,
+            vec![
+                (b"a".to_vec(), vec![2]),
+                (b"b".to_vec(), vec![2]),
+                (b"c".to_vec(), vec![0]),
+            ]
+        );
+
+        // The savepoint remains valid, while later savepoints don't.
+        t1.set(b"e", vec![4])?;
+        let sp3 = t1.savepoint();
+        t1.rollback_to(sp1)?;
+        assert_eq!(
+            t1.scan(..)?,
+            vec![(b"a".to_vec(), vec![1]), (b"c".to_vec(), vec![0])]
+        );
+        assert!(matches!(t1.rollback_to(sp3), Err(Error::Value(_))));
+
+        // Invalidated savepoints stay invalid once the undo log grows past
+        // their position again.
+        t1.set(b"x", vec![9])?;
+        t1.set(b"y", vec![9])?;
+        t1.set(b"z", vec![9])?;
+        assert!(matches!(t1.rollback_to(sp2), Err(Error::Value(_))));
+        assert!(matches!(t1.rollback_to(sp3), Err(Error::Value(_))));
+        t1.rollback_to(sp1)?;
+        assert_eq!(
+            t1.scan(..)?,
+            vec![(b"a".to_vec(), vec![1]), (b"c".to_vec(), vec![0])]
+        );
+
+        let t2 = mvcc.begin()?;
+        assert!(matches!(t2.rollback_to(sp1), Err(Error::Value(_))));
+        t2.rollback()?;
+
+        t1.set(b"f", vec![5])?;
+        t1.commit()?;
+        let t3 = mvcc.begin()?;
+        assert_eq!(
+            t3.scan(..)?,
+            vec![
+                (b"a".to_vec(), vec![1]),
+                (b"c".to_vec(), vec![0]),
+                (b"f".to_vec(), vec![5]),
+            ]
+        );
+        t3.commit()?;
+
+        // Rolled back keys leave no bookkeeping records behind.
+        let mut engine = lock(&mvcc.engine);
+        assert_eq!(engine.scan_prefix(&[0x03]).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests scans over ranges, including keys with deletes and keys that
+    /// are prefixes of each other.
+    fn scan() -> Result<()> {
+        let mvcc = setup();
+        let t1 = mvcc.begin()?;
+        t1.set(b"a", vec![1])?;
+        t1.set(b"b", vec![2])?;
+        t1.set(b"ba", vec![2, 1])?;
+        t1.set(b"b\x00", vec![2, 0])?;
+        t1.set(b"c", vec![3])?;
+        t1.commit()?;
+
+        let t2 = mvcc.begin()?;
+        t2.delete(b"c")?;
+        t2.set(b"b", vec![0])?;
+        t2.set(b"d", vec![4])?;
+
+        assert_eq!(
+            t2.scan(..)?,
+            vec![
+                (b"a".to_vec(), vec![1]),
+                (b"b".to_vec(), vec![0]),
+                (b"b\x00".to_vec(), vec![2, 0]),
+                (b"ba".to_vec(), vec![2, 1]),
+                (b"d".to_vec(), vec![4]),
+            ]
+        );
+        assert_eq!(
+            t2.scan(b"b".to_vec()..b"ba".to_vec())?,
+            vec![(b"b".to_vec(), vec![0]), (b"b\x00".to_vec(), vec![2, 0])]
+        );
+        assert_eq!(
+            t2.scan((
+                Bound::Excluded(b"b".to_vec()),
+                Bound::Included(b"ba".to_vec())
+            ))?,
+            vec![
+                (b"b\x00".to_vec(), vec![2, 0]),
+                (b"ba".to_vec(), vec![2, 1])
+            ]
+        );
+        assert_eq!(t2.scan(b"c".to_vec()..=b"c".to_vec())?, vec![]);
+
+        // Another transaction sees the committed state.
+        let t3 = mvcc.begin()?;
+        assert_eq!(
+            t3.scan(b"b".to_vec()..)?,
+            vec![
+                (b"b".to_vec(), vec![2]),
+                (b"b\x00".to_vec(), vec![2, 0]),
+                (b"ba".to_vec(), vec![2, 1]),
+                (b"c".to_vec(), vec![3]),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+/*!
+An engine wrapper that isolates a namespace of keys in a shared engine.
+
+Keys are stored in the wrapped engine with the namespace prefix prepended,
+and the prefix is stripped again from scanned keys, so each namespace sees
+its own keyspace. Ranges are mapped into the prefix range, so scans and range
+deletes never see keys outside the namespace.
+
+The prefixes of namespaces sharing an engine must not be prefixes of one
+another, e.g. `users/` and `posts/`, but not `a` and `ab`, since the keys of
+namespace `ab` would otherwise also be visible in namespace `a`.
+*/
+
+use std::ops::Bound;
+
+use super::engine::{prefix_range, Engine, ReadEngine, Status, WriteBatch};
+use crate::error::Result;
+
+/// An engine wrapper that prepends a namespace prefix to all keys.
+pub struct Prefixed<E: Engine> {
+    inner: E,
+    prefix: Vec<u8>,
+}
+
+impl<E: Engine> Prefixed<E> {
+    pub fn new(inner: E, prefix: &[u8]) -> Self {
+        Self {
+            inner,
+            prefix: prefix.to_vec(),
+        }
+    }
+
+    /// Returns the namespace prefix.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// Returns the inner key for a namespace key.
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [self.prefix.as_slice(), key].concat()
+    }
+
+    /// Maps a range of namespace keys to the range of inner keys, where
+    /// unbounded ends are bounded by the prefix range.
+    fn range(
+        &self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let (prefix_start, prefix_end) = prefix_range(&self.prefix);
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(self.key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)),
+            Bound::Unbounded => prefix_start,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(self.key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)),
+            Bound::Unbounded => prefix_end,
+        };
+        (start, end)
+    }
+}
+
+impl<E: Engine> std::fmt::Display for Prefixed<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (prefix {})", self.inner, self.prefix.escape_ascii())
+    }
+}
+
+pub struct ScanIterator<'a, E: Engine + 'a> {
+    inner: E::ScanIterator<'a>,
+    prefix_length: usize,
+}
+
+impl<'a, E: Engine + 'a> ScanIterator<'a, E> {
+    /// Strips the namespace prefix from a scanned key.
+    fn strip(&self, item: <Self as Iterator>::Item) -> <Self as Iterator>::Item {
+        let (mut key, value) = item?;
+        key.drain(..self.prefix_length);
+        Ok((key, value))
+    }
+}
+
+impl<'a, E: Engine + 'a> Iterator for ScanIterator<'a, E> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|item| self.strip(item))
+    }
+}
+
+impl<'a, E: Engine + 'a> DoubleEndedIterator for ScanIterator<'a, E> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|item| self.strip(item))
+    }
+}
+
+impl<E: Engine> Engine for Prefixed<E> {
+    type ScanIterator<'a>
+        = ScanIterator<'a, E>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.inner.set(&self.key(key), value)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(&self.key(key))
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let keys = keys.iter().map(|key| self.key(key)).collect::<Vec<_>>();
+        self.inner.get_many(&keys)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.inner.delete(&self.key(key))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    /// Only removes the keys in the namespace.
+    fn clear(&mut self) -> Result<()> {
+        self.inner.delete_prefix(&self.prefix)?;
+        Ok(())
+    }
+
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<u64> {
+        self.inner.delete_prefix(&self.key(prefix))
+    }
+
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.delete_range(self.range(range))
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut prefixed = WriteBatch::new();
+        for (key, value) in batch {
+            match value {
+                Some(value) => prefixed.set(&self.key(&key), value),
+                None => prefixed.delete(&self.key(&key)),
+            }
+        }
+        self.inner.write_batch(prefixed)
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.estimate_garbage(self.range(range))
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    /// Returns the key count and size of the namespace, by scanning it. The
+    /// disk sizes are those of the whole inner engine.
+    fn status(&mut self) -> Result<Status> {
+        let (mut key_count, mut size) = (0, 0);
+        for item in self.scan(..) {
+            let (key, value) = item?;
+            key_count += 1;
+            size += (key.len() + value.len()) as u64;
+        }
+        Ok(Status {
+            name: self.to_string(),
+            key_count,
+            size,
+            ..self.inner.status()?
+        })
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        let range = self.range(range);
+        ScanIterator {
+            inner: self.inner.scan(range),
+            prefix_length: self.prefix.len(),
+        }
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        let range = self.range(range);
+        let prefix_length = self.prefix.len();
+        self.inner.scan_keys(range).map(move |key| {
+            let mut key = key?;
+            key.drain(..prefix_length);
+            Ok(key)
+        })
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.inner.count(self.range(range))
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for Prefixed<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_shared(&self.key(key))
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        ScanIterator {
+            inner: self.inner.scan_shared(self.range(range)),
+            prefix_length: self.prefix.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    #[test]
+    /// Tests that two namespaces with the same keys are isolated from each
+    /// other and from keys outside of them in the same engine.
+    fn namespaces() -> Result<()> {
+        let mut inner = Memory::new();
+        inner.set(b"post", vec![0])?;
+        inner.set(b"zzz", vec![0])?;
+
+        let mut users = Prefixed::new(inner, b"users/");
+        users.set(b"a", vec![1])?;
+        users.set(b"b", vec![2])?;
+        users.set(b"c", vec![3])?;
+        let mut posts = Prefixed::new(users.into_inner(), b"posts/");
+        posts.set(b"a", vec![4])?;
+        posts.set(b"b", vec![5])?;
+        posts.delete(b"c")?;
+
+        assert_eq!(posts.get(b"a")?, Some(vec![4]));
+        assert_eq!(posts.get(b"c")?, None);
+        assert_eq!(
+            posts.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![4]), (b"b".to_vec(), vec![5])]
+        );
+        assert_eq!(posts.status()?.key_count, 2);
+        posts.clear()?;
+        assert_eq!(posts.count(..)?, 0);
+
+        let mut users = Prefixed::new(posts.into_inner(), b"users/");
+        assert_eq!(users.get(b"a")?, Some(vec![1]));
+        assert_eq!(
+            users.scan(b"b".to_vec()..).collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), vec![2]), (b"c".to_vec(), vec![3])]
+        );
+        assert_eq!(
+            users
+                .scan(..=b"b".to_vec())
+                .rev()
+                .collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), vec![2]), (b"a".to_vec(), vec![1])]
+        );
+        assert_eq!(
+            users.scan_keys(..).collect::<Result<Vec<_>>>()?,
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(users.delete_range(b"b".to_vec()..)?, 2);
+
+        let mut inner = users.into_inner();
+        assert_eq!(
+            inner.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![
+                (b"post".to_vec(), vec![0]),
+                (b"users/a".to_vec(), vec![1]),
+                (b"zzz".to_vec(), vec![0]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests that estimate_garbage only counts the garbage in the namespace.
+    fn estimate_garbage() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut inner = BitCask::new(path)?;
+        inner.set(b"post", vec![0])?;
+        inner.set(b"post", vec![0])?;
+        let garbage = inner.estimate_garbage(..)?;
+        assert!(garbage > 0);
+
+        let mut users = Prefixed::new(inner, b"users/");
+        assert_eq!(users.estimate_garbage(..)?, 0);
+        users.set(b"a", vec![1])?;
+        users.set(b"a", vec![2])?;
+        users.set(b"b", vec![1])?;
+        users.delete(b"b")?;
+        let a = users.estimate_garbage(..=b"a".to_vec())?;
+        let b = users.estimate_garbage(b"b".to_vec()..)?;
+        assert!(a > 0 && b > a);
+        assert_eq!(users.estimate_garbage(..)?, a + b);
+        assert_eq!(users.into_inner().estimate_garbage(..)?, garbage + a + b);
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use super::engine::{Engine, ReadEngine, SizeHistogram, Status, WriteBatch};
+use crate::error::{Error, Result};
+
+/// An engine wrapper that rejects all writes with [`Error::ReadOnly`], while
+/// reads pass through to the wrapped engine. Flushing and syncing are no-ops,
+/// since nothing can be written.
+pub struct ReadOnly<E: Engine> {
+    inner: E,
+}
+
+impl<E: Engine> ReadOnly<E> {
+    pub fn new(inner: E) -> Self {
+        Self { inner }
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Engine> std::fmt::Display for ReadOnly<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (read-only)", self.inner)
+    }
+}
+
+impl<E: Engine> Engine for ReadOnly<E> {
+    type ScanIterator<'a>
+        = E::ScanIterator<'a>
+    where
+        E: 'a;
+
+    fn set(&mut self, _key: &[u8], _value: Vec<u8>) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.get_many(keys)
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        _key: &[u8],
+        _expected: Option<&[u8]>,
+        _new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        Err(Error::ReadOnly)
+    }
+
+    fn set_if_absent(&mut self, _key: &[u8], _value: Vec<u8>) -> Result<bool> {
+        Err(Error::ReadOnly)
+    }
+
+    fn merge(
+        &mut self,
+        _key: &[u8],
+        _operand: Vec<u8>,
+        _f: impl Fn(Option<Vec<u8>>, Vec<u8>) -> Vec<u8>,
+    ) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn update(
+        &mut self,
+        _key: &[u8],
+        _f: impl FnOnce(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    ) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn delete_prefix(&mut self, _prefix: &[u8]) -> Result<u64> {
+        Err(Error::ReadOnly)
+    }
+
+    fn delete_range(&mut self, _range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        Err(Error::ReadOnly)
+    }
+
+    fn write_batch(&mut self, _batch: WriteBatch) -> Result<()> {
+        Err(Error::ReadOnly)
+    }
+
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        self.inner.stats_histogram()
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.inner.estimate_garbage(range)
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        self.inner.status()
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan(range)
+    }
+
+    fn scan_bytes(
+        &mut self,
+        start: std::ops::Bound<&[u8]>,
+        end: std::ops::Bound<&[u8]>,
+    ) -> Self::ScanIterator<'_> {
+        self.inner.scan_bytes(start, end)
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.inner.scan_keys(range)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        self.inner.count(range)
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for ReadOnly<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_shared(key)
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan_shared(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::memory::Memory, *};
+
+    #[test]
+    /// Tests that writes are rejected while reads pass through.
+    fn read_only() -> Result<()> {
+        let mut inner = Memory::new();
+        inner.set(b"a", vec![1])?;
+        inner.set(b"b", vec![2])?;
+        let mut s = ReadOnly::new(inner);
+
+        assert_eq!(s.set(b"c", vec![3]), Err(Error::ReadOnly));
+        assert_eq!(s.delete(b"a"), Err(Error::ReadOnly));
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", vec![3]);
+        assert_eq!(s.write_batch(batch), Err(Error::ReadOnly));
+        assert_eq!(s.clear(), Err(Error::ReadOnly));
+        assert_eq!(s.delete_prefix(b"a"), Err(Error::ReadOnly));
+        assert_eq!(s.delete_range(..), Err(Error::ReadOnly));
+        assert_eq!(s.compare_and_swap(b"b", None, None), Err(Error::ReadOnly));
+        assert_eq!(s.merge(b"b", vec![], |_, v| v), Err(Error::ReadOnly));
+        assert_eq!(s.update(b"b", |_| None), Err(Error::ReadOnly));
+        assert_eq!(s.set_if_absent(b"c", vec![3]), Err(Error::ReadOnly));
+        s.flush()?;
+        s.sync()?;
+
+        assert_eq!(s.get(b"a")?, Some(vec![1]));
+        assert_eq!(s.get(b"c")?, None);
+        assert_eq!(
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![2])]
+        );
+        assert_eq!(
+            s.scan_prefix(b"b").collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), vec![2])]
+        );
+        assert_eq!(s.count(..)?, 2);
+        assert_eq!(s.status()?.key_count, 2);
+
+        assert_eq!(s.into_inner().get(b"a")?, Some(vec![1]));
+        Ok(())
+    }
+}
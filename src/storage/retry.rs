@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use super::engine::{clone_bounds, Engine, ReadEngine, SizeHistogram, Status, WriteBatch};
+use crate::error::{Error, Result};
+
+/// Returns whether an error is a transient I/O error, which may succeed when
+/// retried: an interrupted, timed out or would-block I/O operation.
+pub fn is_transient(error: &Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        error,
+        Error::Io {
+            kind: ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock,
+            ..
+        }
+    )
+}
+
+/// An engine wrapper that retries operations failing with transient errors,
+/// e.g. on flaky network filesystems. Each operation is retried up to the
+/// given number of times, sleeping for the backoff before the first retry
+/// and doubling it for each further retry. Other errors, e.g. corruption, are
+/// returned immediately.
+///
+/// Writes are retried as a whole, so engines must tolerate repeating a write
+/// that failed partway, which log-based engines do by appending it again.
+/// Errors while iterating over scans are not retried.
+pub struct Retry<E: Engine> {
+    inner: E,
+    max_retries: u32,
+    backoff: Duration,
+    retryable: fn(&Error) -> bool,
+}
+
+impl<E: Engine> Retry<E> {
+    /// Wraps the engine, retrying [transient](is_transient) errors.
+    pub fn new(inner: E, max_retries: u32, backoff: Duration) -> Self {
+        Self {
+            inner,
+            max_retries,
+            backoff,
+            retryable: is_transient,
+        }
+    }
+
+    /// Retries the errors matching the predicate instead.
+    pub fn with_predicate(mut self, retryable: fn(&Error) -> bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    /// Unwraps the inner engine.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// Runs the operation, retrying it on retryable errors.
+    fn retry<T>(&mut self, mut f: impl FnMut(&mut E) -> Result<T>) -> Result<T> {
+        let mut backoff = self.backoff;
+        let mut retries = 0;
+        loop {
+            match f(&mut self.inner) {
+                Err(error) if retries < self.max_retries && (self.retryable)(&error) => {
+                    retries += 1;
+                    log::warn!("Retrying after error (attempt {retries}): {error}");
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<E: Engine> std::fmt::Display for Retry<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (retry)", self.inner)
+    }
+}
+
+impl<E: Engine> Engine for Retry<E> {
+    type ScanIterator<'a>
+        = E::ScanIterator<'a>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.retry(|inner| inner.set(key, value.clone()))
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.retry(|inner| inner.get(key))
+    }
+
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.retry(|inner| inner.get_many(keys))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.retry(|inner| inner.delete(key))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.retry(|inner| inner.flush())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.retry(|inner| inner.sync())
+    }
+
+    fn sync_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        let range = clone_bounds(&range);
+        self.retry(|inner| inner.sync_range(range.clone()))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.retry(|inner| inner.clear())
+    }
+
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<u64> {
+        self.retry(|inner| inner.delete_prefix(prefix))
+    }
+
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        let range = clone_bounds(&range);
+        self.retry(|inner| inner.delete_range(range.clone()))
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.retry(|inner| inner.write_batch(batch.clone()))
+    }
+
+    fn stats_histogram(&mut self) -> Result<SizeHistogram> {
+        self.retry(|inner| inner.stats_histogram())
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        let range = clone_bounds(&range);
+        self.retry(|inner| inner.estimate_garbage(range.clone()))
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.inner.approximate_memory_usage()
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        self.retry(|inner| inner.status())
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan(range)
+    }
+
+    fn scan_bytes(
+        &mut self,
+        start: std::ops::Bound<&[u8]>,
+        end: std::ops::Bound<&[u8]>,
+    ) -> Self::ScanIterator<'_> {
+        self.inner.scan_bytes(start, end)
+    }
+
+    fn scan_keys(
+        &mut self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.inner.scan_keys(range)
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        let range = clone_bounds(&range);
+        self.retry(|inner| inner.count(range.clone()))
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for Retry<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_shared(key)
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        self.inner.scan_shared(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::memory::Memory, *};
+
+    /// An engine that fails the next operations with the given error.
+    struct Flaky {
+        inner: Memory,
+        failures: u32,
+        error: fn() -> Error,
+        attempts: u32,
+    }
+
+    impl Flaky {
+        fn new(failures: u32, error: fn() -> Error) -> Self {
+            Self {
+                inner: Memory::new(),
+                failures,
+                error,
+                attempts: 0,
+            }
+        }
+
+        fn attempt(&mut self) -> Result<()> {
+            self.attempts += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err((self.error)());
+            }
+            Ok(())
+        }
+    }
+
+    impl std::fmt::Display for Flaky {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "flaky")
+        }
+    }
+
+    impl Engine for Flaky {
+        type ScanIterator<'a> = <Memory as Engine>::ScanIterator<'a>;
+
+        fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+            self.attempt()?;
+            self.inner.set(key, value)
+        }
+
+        fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.attempt()?;
+            self.inner.get(key)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<()> {
+            self.attempt()?;
+            self.inner.delete(key)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.inner.flush()
+        }
+
+        fn status(&mut self) -> Result<Status> {
+            self.inner.status()
+        }
+
+        fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+            self.inner.scan(range)
+        }
+    }
+
+    fn interrupted() -> Error {
+        std::io::Error::from(std::io::ErrorKind::Interrupted).into()
+    }
+
+    #[test]
+    /// Tests that transient errors are retried until the operation succeeds.
+    fn retry() -> Result<()> {
+        let mut s = Retry::new(Flaky::new(2, interrupted), 2, Duration::from_millis(1));
+        s.set(b"a", vec![1])?;
+        assert_eq!(s.inner.attempts, 3);
+
+        s.inner.failures = 2;
+        assert_eq!(s.get(b"a")?, Some(vec![1]));
+        assert_eq!(s.inner.attempts, 6);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that errors are returned once the retries are exhausted, and
+    /// that non-retryable errors are returned immediately.
+    fn give_up() -> Result<()> {
+        let mut s = Retry::new(Flaky::new(3, interrupted), 2, Duration::ZERO);
+        assert!(matches!(
+            s.set(b"a", vec![1]),
+            Err(Error::Io {
+                kind: std::io::ErrorKind::Interrupted,
+                ..
+            })
+        ));
+        assert_eq!(s.inner.attempts, 3);
+
+        let corrupt = || Error::Internal("corrupt".to_string());
+        let mut s = Retry::new(Flaky::new(1, corrupt), 2, Duration::ZERO);
+        assert!(matches!(s.set(b"a", vec![1]), Err(Error::Internal(_))));
+        assert_eq!(s.inner.attempts, 1);
+
+        // A custom predicate can retry other errors.
+        let mut s = Retry::new(Flaky::new(1, corrupt), 2, Duration::ZERO)
+            .with_predicate(|error| matches!(error, Error::Internal(_)));
+        s.delete(b"a")?;
+        assert_eq!(s.into_inner().attempts, 2);
+        Ok(())
+    }
+}
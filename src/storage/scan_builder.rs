@@ -0,0 +1,164 @@
+use std::ops::Bound;
+
+use super::engine::{clone_bounds, prefix_range, Engine};
+use crate::error::{Error, Result};
+
+/// The iterator returned by [`ScanBuilder::execute`], boxed since its type
+/// depends on the chosen options.
+pub type ScanBuilderIterator<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// The start and end bounds of a scan.
+type Range = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// A builder for scans with composable options, see [`Engine::scan_builder`].
+/// By default, all keys and values are scanned in ascending key order. For
+/// example, `scan_builder().prefix(b"log/").reverse().limit(10).execute()`
+/// scans the last 10 keys with the prefix.
+pub struct ScanBuilder<'a, E: Engine> {
+    engine: &'a mut E,
+    range: Option<Range>,
+    prefix: Option<Vec<u8>>,
+    reverse: bool,
+    limit: Option<usize>,
+    keys_only: bool,
+}
+
+impl<'a, E: Engine> ScanBuilder<'a, E> {
+    pub fn new(engine: &'a mut E) -> Self {
+        Self {
+            engine,
+            range: None,
+            prefix: None,
+            reverse: false,
+            limit: None,
+            keys_only: false,
+        }
+    }
+
+    /// Scans the given range. Conflicts with [`ScanBuilder::prefix`].
+    pub fn range(mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self {
+        self.range = Some(clone_bounds(&range));
+        self
+    }
+
+    /// Scans the keys with the given prefix. Conflicts with
+    /// [`ScanBuilder::range`].
+    pub fn prefix(mut self, prefix: &[u8]) -> Self {
+        self.prefix = Some(prefix.to_vec());
+        self
+    }
+
+    /// Scans in descending key order.
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    /// Yields at most the given number of items, from the start of the scan
+    /// in its direction.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Scans only the keys, see [`Engine::scan_keys`], yielding empty values.
+    pub fn keys_only(mut self) -> Self {
+        self.keys_only = true;
+        self
+    }
+
+    /// Executes the scan, or returns [`Error::Value`] for conflicting options.
+    pub fn execute(self) -> Result<ScanBuilderIterator<'a>> {
+        let range = match (self.range, self.prefix) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Value(
+                    "Can't scan both a range and a prefix".to_string(),
+                ))
+            }
+            (Some(range), None) => range,
+            (None, Some(prefix)) => prefix_range(&prefix),
+            (None, None) => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let (reverse, limit) = (self.reverse, self.limit.unwrap_or(usize::MAX));
+        if self.keys_only {
+            let keys = self.engine.scan_keys(range);
+            let items = keys.map(|key| key.map(|key| (key, Vec::new())));
+            return Ok(match reverse {
+                true => Box::new(items.rev().take(limit)),
+                false => Box::new(items.take(limit)),
+            });
+        }
+        let items = self.engine.scan(range);
+        Ok(match reverse {
+            true => Box::new(items.rev().take(limit)),
+            false => Box::new(items.take(limit)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::memory::Memory, *};
+
+    /// Collects the keys of a scan.
+    fn keys(scan: ScanBuilder<'_, Memory>) -> Result<Vec<Vec<u8>>> {
+        scan.execute()?
+            .map(|item| item.map(|(key, _)| key))
+            .collect()
+    }
+
+    #[test]
+    /// Tests combinations of scan options.
+    fn scan_builder() -> Result<()> {
+        let mut s = Memory::new();
+        for key in [b"a".as_slice(), b"b/1", b"b/2", b"b/3", b"c"] {
+            s.set(key, key.to_vec())?;
+        }
+
+        let all = s.scan_builder().execute()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(all, s.scan(..).collect::<Result<Vec<_>>>()?);
+        assert_eq!(
+            keys(s.scan_builder().range(b"b/2".to_vec()..))?,
+            vec![b"b/2".to_vec(), b"b/3".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(
+            keys(s.scan_builder().prefix(b"b/").reverse())?,
+            vec![b"b/3".to_vec(), b"b/2".to_vec(), b"b/1".to_vec()]
+        );
+        assert_eq!(
+            keys(s.scan_builder().reverse().limit(2))?,
+            vec![b"c".to_vec(), b"b/3".to_vec()]
+        );
+        assert_eq!(
+            keys(s.scan_builder().prefix(b"b/").limit(0))?,
+            Vec::<Vec<u8>>::new()
+        );
+
+        // Keys-only scans yield empty values.
+        assert_eq!(
+            s.scan_builder()
+                .prefix(b"b/")
+                .limit(2)
+                .keys_only()
+                .execute()?
+                .collect::<Result<Vec<_>>>()?,
+            vec![(b"b/1".to_vec(), vec![]), (b"b/2".to_vec(), vec![])]
+        );
+        assert_eq!(
+            keys(
+                s.scan_builder()
+                    .range(..b"b/2".to_vec())
+                    .keys_only()
+                    .reverse()
+            )?,
+            vec![b"b/1".to_vec(), b"a".to_vec()]
+        );
+
+        // A range and a prefix conflict.
+        assert!(matches!(
+            s.scan_builder().range(..).prefix(b"b/").execute(),
+            Err(Error::Value(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,578 @@
+/*!
+An engine that hash-partitions keys across a set of sub-engines, or shards.
+
+Each key is routed to a shard by a [`Partitioner`]. By default, this is
+[`ModHasher`], which routes keys to shard `crc32(key) % N`. The hash is stable
+across processes and Rust versions, so persistent shards (e.g. one BitCask
+file per shard) can be reopened, as long as the number of shards stays the
+same. Changing the number of shards moves almost all keys, so [`Rendezvous`]
+hashing can be used instead, which only moves about 1/N of the keys when
+adding a shard to N-1 shards.
+
+Scans merge the sorted per-shard scans with a k-way merge, which yields a
+globally sorted stream in both directions.
+*/
+
+use super::engine::{clone_bounds, Engine, ReadEngine, Status, WriteBatch};
+use crate::error::{Error, Result};
+
+/// Routes keys to shards. The routing must be deterministic and stable across
+/// processes, such that persistent shards can be reopened.
+pub trait Partitioner: Send + Sync + 'static {
+    /// Returns the index of the shard that stores the key, out of `n` shards.
+    fn shard(&self, key: &[u8], n: usize) -> usize;
+}
+
+/// Routes keys to shard `crc32(key) % n`. This is cheap, but changing the
+/// number of shards moves almost all keys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModHasher;
+
+impl Partitioner for ModHasher {
+    fn shard(&self, key: &[u8], n: usize) -> usize {
+        crc32fast::hash(key) as usize % n
+    }
+}
+
+/// Routes keys with rendezvous (highest random weight) hashing, to the shard
+/// with the highest score for the key. Adding a shard only moves the keys
+/// for which it scores highest, i.e. about 1/n of them, and removing a shard
+/// only moves its own keys. Routing a key takes O(n) time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rendezvous;
+
+impl Rendezvous {
+    /// Scores a shard for a key hash, by mixing them with the MurmurHash3
+    /// finalizer, such that the scores of different shards are independent.
+    fn score(hash: u32, shard: usize) -> u64 {
+        let mut x = (hash as u64) ^ (shard as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        x ^= x >> 33;
+        x = x.wrapping_mul(0xff51afd7ed558ccd);
+        x ^= x >> 33;
+        x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+        x ^ (x >> 33)
+    }
+}
+
+impl Partitioner for Rendezvous {
+    fn shard(&self, key: &[u8], n: usize) -> usize {
+        let hash = crc32fast::hash(key);
+        (0..n)
+            .max_by_key(|&shard| Self::score(hash, shard))
+            .unwrap_or(0)
+    }
+}
+
+/// An engine that partitions keys across shards.
+pub struct Sharded<E: Engine, P: Partitioner = ModHasher> {
+    shards: Vec<E>,
+    partitioner: P,
+}
+
+impl<E: Engine> Sharded<E> {
+    /// Creates a sharded engine with the given number of shards, building
+    /// each shard with the given closure, which is passed the shard index.
+    /// Keys are routed by [`ModHasher`].
+    pub fn new(shard_count: usize, build: impl Fn(usize) -> Result<E>) -> Result<Self> {
+        Self::with_partitioner(shard_count, ModHasher, build)
+    }
+}
+
+impl<E: Engine, P: Partitioner> Sharded<E, P> {
+    /// Creates a sharded engine with the given number of shards, which routes
+    /// keys with the given partitioner, see [`Sharded::new`].
+    pub fn with_partitioner(
+        shard_count: usize,
+        partitioner: P,
+        build: impl Fn(usize) -> Result<E>,
+    ) -> Result<Self> {
+        if shard_count == 0 {
+            return Err(Error::Config("Shard count must be positive".to_string()));
+        }
+        Ok(Self {
+            shards: (0..shard_count).map(build).collect::<Result<_>>()?,
+            partitioner,
+        })
+    }
+
+    /// Returns the index of the shard that stores the given key.
+    pub fn shard_for(&self, key: &[u8]) -> usize {
+        self.partitioner.shard(key, self.shards.len())
+    }
+
+    /// Returns the shards.
+    pub fn shards(&self) -> &[E] {
+        &self.shards
+    }
+
+    fn shard(&mut self, key: &[u8]) -> &mut E {
+        let index = self.shard_for(key);
+        &mut self.shards[index]
+    }
+}
+
+impl<E: Engine, P: Partitioner> std::fmt::Display for Sharded<E, P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sharded {}x {}", self.shards.len(), self.shards[0])
+    }
+}
+
+type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+/// A shard's scan, with the next items buffered at either end.
+struct Shard<'a, E: Engine + 'a> {
+    iter: E::ScanIterator<'a>,
+    front: Option<Item>,
+    back: Option<Item>,
+}
+
+impl<'a, E: Engine + 'a> Shard<'a, E> {
+    /// Fills the front buffer, taking the back item once the iterator is
+    /// exhausted.
+    fn fill_front(&mut self) {
+        if self.front.is_none() {
+            self.front = self.iter.next().or_else(|| self.back.take());
+        }
+    }
+
+    /// Fills the back buffer, taking the front item once the iterator is
+    /// exhausted.
+    fn fill_back(&mut self) {
+        if self.back.is_none() {
+            self.back = self.iter.next_back().or_else(|| self.front.take());
+        }
+    }
+}
+
+pub struct ScanIterator<'a, E: Engine + 'a> {
+    shards: Vec<Shard<'a, E>>,
+}
+
+impl<'a, E: Engine + 'a> ScanIterator<'a, E> {
+    fn new(iters: impl Iterator<Item = E::ScanIterator<'a>>) -> Self {
+        Self {
+            shards: iters
+                .map(|iter| Shard {
+                    iter,
+                    front: None,
+                    back: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Takes the buffered item that comes next from the given end. Errors are
+    /// returned first, and duplicate keys in other shards are dropped.
+    fn take(&mut self, end: End) -> Option<Item> {
+        if let Some(shard) = self
+            .shards
+            .iter_mut()
+            .find(|shard| matches!(end.buffer(shard), Some(Err(_))))
+        {
+            return end.buffer_mut(shard).take();
+        }
+        let key = self
+            .shards
+            .iter()
+            .filter_map(|shard| match end.buffer(shard) {
+                Some(Ok((key, _))) => Some(key),
+                _ => None,
+            })
+            .reduce(|a, b| if end.precedes(b, a) { b } else { a })?
+            .clone();
+        let mut item = None;
+        for shard in self.shards.iter_mut() {
+            let buffer = end.buffer_mut(shard);
+            if matches!(buffer, Some(Ok((k, _))) if *k == key) {
+                item = item.or(buffer.take());
+            }
+        }
+        item
+    }
+}
+
+/// An end of a scan.
+#[derive(Clone, Copy)]
+enum End {
+    Front,
+    Back,
+}
+
+impl End {
+    fn buffer<'s, 'a, E: Engine + 'a>(self, shard: &'s Shard<'a, E>) -> &'s Option<Item> {
+        match self {
+            Self::Front => &shard.front,
+            Self::Back => &shard.back,
+        }
+    }
+
+    fn buffer_mut<'s, 'a, E: Engine + 'a>(
+        self,
+        shard: &'s mut Shard<'a, E>,
+    ) -> &'s mut Option<Item> {
+        match self {
+            Self::Front => &mut shard.front,
+            Self::Back => &mut shard.back,
+        }
+    }
+
+    /// Returns whether key a comes before key b from this end.
+    fn precedes(self, a: &[u8], b: &[u8]) -> bool {
+        match self {
+            Self::Front => a < b,
+            Self::Back => a > b,
+        }
+    }
+}
+
+impl<'a, E: Engine + 'a> Iterator for ScanIterator<'a, E> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.shards.iter_mut().for_each(Shard::fill_front);
+        self.take(End::Front)
+    }
+}
+
+impl<'a, E: Engine + 'a> DoubleEndedIterator for ScanIterator<'a, E> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.shards.iter_mut().for_each(Shard::fill_back);
+        self.take(End::Back)
+    }
+}
+
+impl<E: Engine, P: Partitioner> Engine for Sharded<E, P> {
+    type ScanIterator<'a>
+        = ScanIterator<'a, E>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.shard(key).set(key, value)
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shard(key).get(key)
+    }
+
+    /// Splits the keys by shard, and gets each shard's keys together.
+    fn get_many(&mut self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut shard_keys: Vec<(Vec<usize>, Vec<Vec<u8>>)> = self
+            .shards
+            .iter()
+            .map(|_| (Vec::new(), Vec::new()))
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            let (indexes, keys) = &mut shard_keys[self.shard_for(key)];
+            indexes.push(i);
+            keys.push(key.clone());
+        }
+        let mut values = vec![None; keys.len()];
+        for (shard, (indexes, keys)) in self.shards.iter_mut().zip(shard_keys) {
+            if keys.is_empty() {
+                continue;
+            }
+            for (i, value) in indexes.into_iter().zip(shard.get_many(&keys)?) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.shard(key).delete(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(|shard| shard.flush())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(|shard| shard.sync())
+    }
+
+    /// Clears each shard. This is not atomic across shards.
+    fn clear(&mut self) -> Result<()> {
+        self.shards.iter_mut().try_for_each(|shard| shard.clear())
+    }
+
+    /// Deletes the prefix in each shard. This is not atomic across shards.
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<u64> {
+        self.shards
+            .iter_mut()
+            .try_fold(0, |count, shard| Ok(count + shard.delete_prefix(prefix)?))
+    }
+
+    fn delete_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        let range = clone_bounds(&range);
+        self.shards.iter_mut().try_fold(0, |count, shard| {
+            Ok(count + shard.delete_range(range.clone())?)
+        })
+    }
+
+    /// Splits the batch by shard, and writes each shard's batch. Each shard's
+    /// writes are applied atomically, but the batch as a whole is not.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut batches: Vec<WriteBatch> = self.shards.iter().map(|_| WriteBatch::new()).collect();
+        for (key, value) in batch {
+            let batch = &mut batches[self.shard_for(&key)];
+            match value {
+                Some(value) => batch.set(&key, value),
+                None => batch.delete(&key),
+            }
+        }
+        for (shard, batch) in self.shards.iter_mut().zip(batches) {
+            if !batch.is_empty() {
+                shard.write_batch(batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        let range = clone_bounds(&range);
+        self.shards.iter_mut().try_fold(0, |garbage, shard| {
+            Ok(garbage + shard.estimate_garbage(range.clone())?)
+        })
+    }
+
+    fn approximate_memory_usage(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(Engine::approximate_memory_usage)
+            .sum()
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        let mut status = Status {
+            name: self.to_string(),
+            key_count: 0,
+            size: 0,
+            total_disk_size: 0,
+            live_disk_size: 0,
+            garbage_disk_size: 0,
+            tombstone_count: 0,
+            file_count: 0,
+            compaction_recommended: false,
+        };
+        for shard in self.shards.iter_mut() {
+            let shard = shard.status()?;
+            status.key_count += shard.key_count;
+            status.size += shard.size;
+            status.total_disk_size += shard.total_disk_size;
+            status.live_disk_size += shard.live_disk_size;
+            status.garbage_disk_size += shard.garbage_disk_size;
+            status.tombstone_count += shard.tombstone_count;
+            status.file_count += shard.file_count;
+            status.compaction_recommended |= shard.compaction_recommended;
+        }
+        Ok(status)
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        let range = clone_bounds(&range);
+        ScanIterator::new(
+            self.shards
+                .iter_mut()
+                .map(move |shard| shard.scan(range.clone())),
+        )
+    }
+
+    fn count(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<usize> {
+        let range = clone_bounds(&range);
+        self.shards
+            .iter_mut()
+            .try_fold(0, |count, shard| Ok(count + shard.count(range.clone())?))
+    }
+}
+
+impl<E: ReadEngine, P: Partitioner> ReadEngine for Sharded<E, P> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shards[self.shard_for(key)].get_shared(key)
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        let range = clone_bounds(&range);
+        ScanIterator::new(
+            self.shards
+                .iter()
+                .map(move |shard| shard.scan_shared(range.clone())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    fn setup() -> Result<Sharded<Memory>> {
+        let mut s = Sharded::new(4, |_| Ok(Memory::new()))?;
+        for i in 0..100u8 {
+            s.set(&[i / 10, i % 10], vec![i])?;
+        }
+        Ok(s)
+    }
+
+    #[test]
+    /// Tests that keys are spread across shards, and that scans are ordered.
+    fn ordering() -> Result<()> {
+        let mut s = setup()?;
+        for shard in s.shards.iter_mut() {
+            assert!(shard.count(..)? > 10, "unbalanced shards");
+        }
+
+        let expect: Vec<_> = (0..100u8)
+            .map(|i| (vec![i / 10, i % 10], vec![i]))
+            .collect();
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+
+        let mut reversed = expect.clone();
+        reversed.reverse();
+        assert_eq!(s.scan(..).rev().collect::<Result<Vec<_>>>()?, reversed);
+
+        // Alternating between the ends meets in the middle.
+        let mut iter = s.scan(..);
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        while let Some(item) = iter.next() {
+            front.push(item?);
+            match iter.next_back() {
+                Some(item) => back.push(item?),
+                None => break,
+            }
+        }
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, expect);
+
+        assert_eq!(
+            s.scan(vec![2, 5]..vec![3, 2]).collect::<Result<Vec<_>>>()?,
+            expect[25..32].to_vec()
+        );
+        assert_eq!(s.count(vec![2, 5]..=vec![3, 2])?, 8);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests prefix scans across shards.
+    fn scan_prefix() -> Result<()> {
+        let mut s = setup()?;
+        let expect: Vec<_> = (40..50u8)
+            .map(|i| (vec![i / 10, i % 10], vec![i]))
+            .collect();
+        assert_eq!(s.scan_prefix(&[4]).collect::<Result<Vec<_>>>()?, expect);
+        assert_eq!(
+            s.scan_prefix(&[4, 7]).collect::<Result<Vec<_>>>()?,
+            vec![(vec![4, 7], vec![47])]
+        );
+        assert_eq!(s.scan_prefix(&[10]).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that duplicate keys in different shards are only returned once.
+    fn deduplicate() -> Result<()> {
+        let mut s = Sharded::new(2, |_| Ok(Memory::new()))?;
+        for shard in s.shards.iter_mut() {
+            shard.set(b"a", vec![1])?;
+        }
+        s.set(b"b", vec![2])?;
+        assert_eq!(
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![2])]
+        );
+        assert_eq!(
+            s.scan(..).rev().collect::<Result<Vec<_>>>()?,
+            vec![(b"b".to_vec(), vec![2]), (b"a".to_vec(), vec![1])]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests one BitCask file per shard, which routes keys to the same shards
+    /// when reopened.
+    fn bitcask() -> Result<()> {
+        let dir = tempdir::TempDir::new("yuudb")?;
+        let open = || Sharded::new(3, |i| BitCask::new(dir.path().join(format!("shard{i}"))));
+
+        let mut s = open()?;
+        let mut batch = WriteBatch::new();
+        for i in 0..30u8 {
+            batch.set(&[i], vec![i]);
+        }
+        s.write_batch(batch)?;
+        drop(s);
+
+        let mut s = open()?;
+        for i in 0..30u8 {
+            assert_eq!(s.get(&[i])?, Some(vec![i]));
+        }
+        assert_eq!(s.status()?.key_count, 30);
+        assert_eq!(s.scan(..).count(), 30);
+
+        // The garbage is summed across shards.
+        for i in 0..30u8 {
+            s.set(&[i], vec![i])?;
+        }
+        let garbage = s.estimate_garbage(..)?;
+        assert!(garbage > 0);
+        let shards = (s.shards.iter_mut())
+            .map(|shard| shard.estimate_garbage(..))
+            .sum::<Result<u64>>()?;
+        assert_eq!(garbage, shards);
+        assert!(s.estimate_garbage(..[15].to_vec())? < garbage);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that rendezvous hashing balances keys across shards, and only
+    /// moves about 1/N of the keys to the new shard when going from N-1 to N
+    /// shards, while modulo hashing moves most keys.
+    fn rendezvous() -> Result<()> {
+        let keys = (0..10000u32).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        let moved_fraction = |partitioner: &dyn Partitioner, n: usize| {
+            let moved = keys
+                .iter()
+                .filter(|key| partitioner.shard(&key[..], n - 1) != partitioner.shard(&key[..], n))
+                .count();
+            moved as f64 / keys.len() as f64
+        };
+        for n in [2, 4, 5, 10] {
+            let expect = 1.0 / n as f64;
+            let moved = moved_fraction(&Rendezvous, n);
+            assert!(
+                (moved - expect).abs() < expect * 0.15,
+                "moved {moved} at {n}"
+            );
+            assert!(moved_fraction(&ModHasher, n) > 0.4);
+        }
+        // Keys only move to the new shard.
+        for key in &keys {
+            let shard = Rendezvous.shard(key, 5);
+            assert!(shard == Rendezvous.shard(key, 4) || shard == 4);
+        }
+
+        let mut s = Sharded::with_partitioner(4, Rendezvous, |_| Ok(Memory::new()))?;
+        for key in &keys {
+            s.set(key, key.to_vec())?;
+        }
+        for shard in s.shards() {
+            let count = shard.size() / 8;
+            assert!((2250..2750).contains(&count), "unbalanced shard {count}");
+        }
+        assert_eq!(s.get(&keys[7])?, Some(keys[7].to_vec()));
+        assert_eq!(s.scan(..).count(), keys.len());
+        Ok(())
+    }
+
+    #[test]
+    fn zero_shards() {
+        assert!(matches!(
+            Sharded::new(0, |_| Ok(Memory::new())),
+            Err(Error::Config(_))
+        ));
+    }
+}
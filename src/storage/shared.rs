@@ -0,0 +1,133 @@
+/*!
+A thread-safe handle for sharing an engine between threads.
+
+Engine methods take `&mut self`, so callers serving requests from several
+threads need to wrap engines in a mutex. [`SharedEngine`] does so, and locks
+the engine for each call. Calls are executed one at a time. Scan iterators
+borrow the engine and would hold the lock while iterating, so scans are
+collected into a `Vec` instead.
+*/
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::engine::{Engine, Status};
+use crate::error::Result;
+
+/// A thread-safe engine handle, which locks the engine for each call.
+/// Cloning it yields another handle to the same engine.
+pub struct SharedEngine<E: Engine> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E: Engine> Clone for SharedEngine<E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+        }
+    }
+}
+
+impl<E: Engine> SharedEngine<E> {
+    pub fn new(engine: E) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// Locks the engine, e.g. to make several calls without other threads
+    /// interleaving. A poisoned lock is ignored, since a panic can't leave
+    /// the engine in an inconsistent state.
+    pub fn lock(&self) -> MutexGuard<'_, E> {
+        self.engine.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock().get(key)
+    }
+
+    pub fn set(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.lock().set(key, value)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.lock().delete(key)
+    }
+
+    /// Scans the range, collecting the key/value pairs into a vector.
+    pub fn scan_collect(
+        &self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.lock().scan(range).collect()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.lock().flush()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.lock().sync()
+    }
+
+    pub fn status(&self) -> Result<Status> {
+        self.lock().status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use super::super::bitcask::BitCask;
+    use super::{super::memory::Memory, *};
+
+    #[cfg(feature = "std")]
+    #[test]
+    /// Tests interleaved writes and reads from several threads.
+    fn threads() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let s = SharedEngine::new(BitCask::new(path)?);
+
+        let threads = (0..8u8)
+            .map(|t| {
+                let s = s.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for i in 0..100u8 {
+                        s.set(&[t, i], vec![t, i])?;
+                        assert_eq!(s.get(&[t, i])?, Some(vec![t, i]));
+                        if i % 2 == 1 {
+                            s.delete(&[t, i])?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        assert_eq!(s.status()?.key_count, 8 * 50);
+        let scan = s.scan_collect(vec![3]..vec![4])?;
+        assert_eq!(scan.len(), 50);
+        assert!(scan
+            .iter()
+            .all(|(key, value)| key == value && key[0] == 3 && key[1] % 2 == 0));
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the lock allows several calls without interleaving.
+    fn lock() -> Result<()> {
+        let s = SharedEngine::new(Memory::new());
+        s.set(b"a", vec![1])?;
+        {
+            let mut engine = s.lock();
+            let value = engine.get(b"a")?.unwrap();
+            engine.set(b"a", vec![value[0] + 1])?;
+        }
+        s.sync()?;
+        assert_eq!(s.get(b"a")?, Some(vec![2]));
+        assert_eq!(s.scan_collect(..)?, vec![(b"a".to_vec(), vec![2])]);
+        Ok(())
+    }
+}
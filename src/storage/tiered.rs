@@ -0,0 +1,413 @@
+/*!
+A tiered engine, which keeps the working set in a hot [`Memory`] tier and
+spills cold data to another engine, typically a [`BitCask`](super::bitcask::BitCask).
+
+Writes go to the hot tier, and reads check the hot tier before the cold tier,
+promoting keys read from the cold tier into the hot tier. Once the hot tier
+exceeds its size threshold, the least recently used keys are evicted to the
+cold tier. A key can exist in both tiers, in which case the hot tier's version
+is the newest and shadows the cold one, also in scans. Deletes are applied to
+both tiers.
+
+Keys written to the hot tier are only written to the cold tier when evicted,
+flushed or synced, or when the engine is dropped. Until then, they're lost on
+a crash.
+*/
+
+use std::collections::HashSet;
+
+use super::engine::{clone_bounds, Engine, ReadEngine, Status};
+use super::memory::{self, Memory};
+use crate::error::Result;
+
+/// A tiered engine with a hot in-memory tier and a cold tier.
+pub struct Tiered<E: Engine> {
+    hot: Memory,
+    cold: E,
+    /// The hot tier size, in key and value bytes, above which keys are
+    /// evicted to the cold tier.
+    max_hot_bytes: u64,
+    /// Keys in the hot tier that are newer than the cold tier, and must be
+    /// written to it when evicted.
+    dirty: HashSet<Vec<u8>>,
+}
+
+impl<E: Engine> Tiered<E> {
+    /// Creates a tiered engine on top of the cold tier, evicting keys to it
+    /// when the hot tier exceeds the given number of key and value bytes.
+    pub fn new(cold: E, max_hot_bytes: u64) -> Self {
+        Self {
+            // The hot tier tracks the LRU order, but never evicts by itself.
+            hot: Memory::with_capacity(u64::MAX),
+            cold,
+            max_hot_bytes,
+            dirty: HashSet::new(),
+        }
+    }
+
+    /// Returns the hot tier.
+    pub fn hot(&self) -> &Memory {
+        &self.hot
+    }
+
+    /// Returns the cold tier.
+    pub fn cold(&self) -> &E {
+        &self.cold
+    }
+
+    /// Evicts the least recently used keys from the hot tier until it's
+    /// within its size threshold, writing them to the cold tier if they're
+    /// newer. This is done automatically on writes and promotions.
+    pub fn evict(&mut self) -> Result<()> {
+        let mut excess = self.hot.size().saturating_sub(self.max_hot_bytes);
+        let mut evicted = Vec::new();
+        for key in self.hot.eviction_order() {
+            if excess == 0 {
+                break;
+            }
+            let value = self.hot.get_shared(key)?.unwrap_or_default();
+            excess = excess.saturating_sub((key.len() + value.len()) as u64);
+            if self.dirty.contains(key) {
+                self.cold.set(key, value)?;
+            }
+            evicted.push(key.to_vec());
+        }
+        for key in evicted {
+            self.dirty.remove(&key);
+            self.hot.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty keys in the range of the hot tier to the cold tier,
+    /// keeping them in the hot tier.
+    fn write_back(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        let keys = (self.dirty.iter())
+            .filter(|key| range.contains(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            if let Some(value) = self.hot.get_shared(&key)? {
+                self.cold.set(&key, value)?;
+            }
+            self.dirty.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+impl<E: Engine> std::fmt::Display for Tiered<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (tiered)", self.cold)
+    }
+}
+
+type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+/// A tier's scan, with the next items buffered at either end.
+struct Tier<I> {
+    iter: I,
+    front: Option<Item>,
+    back: Option<Item>,
+}
+
+impl<I: DoubleEndedIterator<Item = Item>> Tier<I> {
+    fn new(iter: I) -> Self {
+        Self {
+            iter,
+            front: None,
+            back: None,
+        }
+    }
+
+    /// Fills the front buffer, taking the back item once the iterator is
+    /// exhausted.
+    fn fill_front(&mut self) {
+        if self.front.is_none() {
+            self.front = self.iter.next().or_else(|| self.back.take());
+        }
+    }
+
+    /// Fills the back buffer, taking the front item once the iterator is
+    /// exhausted.
+    fn fill_back(&mut self) {
+        if self.back.is_none() {
+            self.back = self.iter.next_back().or_else(|| self.front.take());
+        }
+    }
+}
+
+/// Takes the buffered item that comes next, where `precedes(a, b)` returns
+/// whether key a comes before key b. Errors are returned first, and the cold
+/// item is dropped if both tiers have the same key.
+fn take(
+    hot: &mut Option<Item>,
+    cold: &mut Option<Item>,
+    precedes: fn(&[u8], &[u8]) -> bool,
+) -> Option<Item> {
+    match (&*hot, &*cold) {
+        (Some(Err(_)), _) => hot.take(),
+        (_, Some(Err(_))) => cold.take(),
+        (Some(Ok((hot_key, _))), Some(Ok((cold_key, _)))) => {
+            if hot_key == cold_key {
+                *cold = None;
+                hot.take()
+            } else if precedes(cold_key, hot_key) {
+                cold.take()
+            } else {
+                hot.take()
+            }
+        }
+        (Some(_), None) => hot.take(),
+        (None, _) => cold.take(),
+    }
+}
+
+pub struct ScanIterator<'a, E: Engine + 'a> {
+    hot: Tier<memory::ScanIterator<'a>>,
+    cold: Tier<E::ScanIterator<'a>>,
+}
+
+impl<'a, E: Engine + 'a> Iterator for ScanIterator<'a, E> {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.hot.fill_front();
+        self.cold.fill_front();
+        take(&mut self.hot.front, &mut self.cold.front, |a, b| a < b)
+    }
+}
+
+impl<'a, E: Engine + 'a> DoubleEndedIterator for ScanIterator<'a, E> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.hot.fill_back();
+        self.cold.fill_back();
+        take(&mut self.hot.back, &mut self.cold.back, |a, b| a > b)
+    }
+}
+
+impl<E: Engine> Engine for Tiered<E> {
+    type ScanIterator<'a>
+        = ScanIterator<'a, E>
+    where
+        E: 'a;
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.hot.set(key, value)?;
+        self.dirty.insert(key.to_vec());
+        self.evict()
+    }
+
+    /// Gets a key from the hot tier, or else from the cold tier, promoting it
+    /// into the hot tier.
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.hot.get(key)? {
+            return Ok(Some(value));
+        }
+        let Some(value) = self.cold.get(key)? else {
+            return Ok(None);
+        };
+        self.hot.set(key, value.clone())?;
+        self.evict()?;
+        Ok(Some(value))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.hot.delete(key)?;
+        self.dirty.remove(key);
+        self.cold.delete(key)
+    }
+
+    /// Writes the dirty keys of the hot tier to the cold tier and flushes it.
+    fn flush(&mut self) -> Result<()> {
+        self.write_back(..)?;
+        self.cold.flush()
+    }
+
+    /// Writes the dirty keys of the hot tier to the cold tier and syncs it.
+    fn sync(&mut self) -> Result<()> {
+        self.write_back(..)?;
+        self.cold.sync()
+    }
+
+    /// Only writes back the dirty keys in the range, leaving other keys in
+    /// the hot tier unsynced, and syncs the range in the cold tier.
+    fn sync_range(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<()> {
+        let range = clone_bounds(&range);
+        self.write_back(range.clone())?;
+        self.cold.sync_range(range)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.hot.clear()?;
+        self.dirty.clear();
+        self.cold.clear()
+    }
+
+    /// Returns the cold tier's garbage, like the disk sizes in the status.
+    fn estimate_garbage(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.cold.estimate_garbage(range)
+    }
+
+    /// Includes both tiers, since the cold tier may keep data in memory too.
+    fn approximate_memory_usage(&self) -> u64 {
+        let dirty = (self.dirty.iter())
+            .map(|key| (key.len() + std::mem::size_of::<Vec<u8>>()) as u64)
+            .sum::<u64>();
+        self.hot.approximate_memory_usage() + self.cold.approximate_memory_usage() + dirty
+    }
+
+    /// Returns the engine status. The key count and size are computed by
+    /// scanning both tiers, since keys can exist in both, while the disk sizes
+    /// are the cold tier's.
+    fn status(&mut self) -> Result<Status> {
+        let (mut key_count, mut size) = (0, 0);
+        for result in self.scan(..) {
+            let (key, value) = result?;
+            key_count += 1;
+            size += key.len() as u64 + value.len() as u64;
+        }
+        let cold = self.cold.status()?;
+        Ok(Status {
+            name: self.to_string(),
+            key_count,
+            size,
+            total_disk_size: cold.total_disk_size,
+            live_disk_size: cold.live_disk_size,
+            garbage_disk_size: cold.garbage_disk_size,
+            tombstone_count: cold.tombstone_count,
+            file_count: cold.file_count,
+            compaction_recommended: cold.compaction_recommended,
+        })
+    }
+
+    fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        let range = clone_bounds(&range);
+        ScanIterator {
+            hot: Tier::new(self.hot.scan_shared(range.clone())),
+            cold: Tier::new(self.cold.scan(range)),
+        }
+    }
+}
+
+impl<E: ReadEngine> ReadEngine for Tiered<E> {
+    fn get_shared(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.hot.get_shared(key)? {
+            Some(value) => Ok(Some(value)),
+            None => self.cold.get_shared(key),
+        }
+    }
+
+    fn scan_shared(&self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::ScanIterator<'_> {
+        let range = clone_bounds(&range);
+        ScanIterator {
+            hot: Tier::new(self.hot.scan_shared(range.clone())),
+            cold: Tier::new(self.cold.scan_shared(range)),
+        }
+    }
+}
+
+impl<E: Engine> Drop for Tiered<E> {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            log::error!("Failed to flush hot tier: {}", error);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::{super::bitcask::BitCask, *};
+
+    #[test]
+    /// Tests that keys evicted to the cold tier are read through and promoted
+    /// back into the hot tier.
+    fn read_through() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = Tiered::new(BitCask::new(path.clone())?, 4);
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"c", vec![0x03])?;
+        assert_eq!(s.hot().size(), 4);
+        assert_eq!(s.hot().get_shared(b"a")?, None);
+        assert_eq!(s.cold().get_shared(b"a")?, Some(vec![0x01]));
+
+        // Reading a cold key promotes it, evicting the least recently used.
+        assert_eq!(s.get(b"a")?, Some(vec![0x01]));
+        assert_eq!(s.hot().get_shared(b"a")?, Some(vec![0x01]));
+        assert_eq!(s.hot().get_shared(b"b")?, None);
+        assert_eq!(s.get(b"b")?, Some(vec![0x02]));
+        assert_eq!(s.get(b"missing")?, None);
+
+        // Dropping the engine writes the hot tier to the cold tier.
+        drop(s);
+        let mut s = Tiered::new(BitCask::new(path)?, 4);
+        assert_eq!(
+            s.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![
+                (b"a".to_vec(), vec![0x01]),
+                (b"b".to_vec(), vec![0x02]),
+                (b"c".to_vec(), vec![0x03]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that the hot tier shadows stale versions in the cold tier, both
+    /// for reads and scans, and that deletes remove both.
+    fn shadowing() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = Tiered::new(BitCask::new(path)?, 4);
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02])?;
+        s.set(b"c", vec![0x03])?;
+        s.set(b"a", vec![0x0a])?;
+        assert_eq!(s.cold().get_shared(b"a")?, Some(vec![0x01]));
+        assert_eq!(s.hot().get_shared(b"a")?, Some(vec![0x0a]));
+
+        let expect = vec![
+            (b"a".to_vec(), vec![0x0a]),
+            (b"b".to_vec(), vec![0x02]),
+            (b"c".to_vec(), vec![0x03]),
+        ];
+        assert_eq!(s.get(b"a")?, Some(vec![0x0a]));
+        assert_eq!(s.get_shared(b"a")?, Some(vec![0x0a]));
+        assert_eq!(s.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert_eq!(
+            s.scan(..).rev().collect::<Result<Vec<_>>>()?,
+            expect.into_iter().rev().collect::<Vec<_>>()
+        );
+        assert_eq!(s.status()?.key_count, 3);
+
+        s.delete(b"a")?;
+        assert_eq!(s.get(b"a")?, None);
+        assert_eq!(s.cold().get_shared(b"a")?, None);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that sync_range only writes back the range's keys to the cold
+    /// tier, such that they're on disk while other keys aren't yet.
+    fn sync_range() -> Result<()> {
+        let path = tempdir::TempDir::new("yuudb")?.path().join("yuudb");
+        let mut s = Tiered::new(BitCask::new(path.clone())?, 1024);
+        s.set(b"a/1", vec![1])?;
+        s.set(b"a/2", vec![2])?;
+        s.set(b"b/1", vec![3])?;
+        s.sync_range(b"a/".to_vec()..b"b/".to_vec())?;
+
+        // Read the cold tier from disk while the engine is still open.
+        let mut cold = BitCask::open_read_only(path.clone())?;
+        assert_eq!(
+            cold.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"a/1".to_vec(), vec![1]), (b"a/2".to_vec(), vec![2])]
+        );
+        assert_eq!(s.dirty, HashSet::from([b"b/1".to_vec()]));
+
+        s.sync()?;
+        let mut cold = BitCask::open_read_only(path)?;
+        assert_eq!(cold.get(b"b/1")?, Some(vec![3]));
+        Ok(())
+    }
+}
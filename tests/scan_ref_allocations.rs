@@ -0,0 +1,58 @@
+//! Tests that Memory::scan_ref doesn't allocate while iterating. This lives
+//! in its own test binary since it replaces the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use yuudb::error::Result;
+use yuudb::storage::engine::Engine as _;
+use yuudb::storage::memory::Memory;
+
+/// An allocator that counts the allocations made by each thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations made by the current thread.
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+#[test]
+/// Tests that scan_ref doesn't allocate while iterating, unlike scan.
+fn scan_ref_allocations() -> Result<()> {
+    let mut s = Memory::new();
+    for i in 0..1000u32 {
+        s.set(&i.to_be_bytes(), i.to_le_bytes().to_vec())?;
+    }
+
+    let before = allocations();
+    let mut sum = 0u64;
+    for (key, value) in s.scan_ref(..) {
+        sum += (key[3] ^ value[0]) as u64 + key.len() as u64;
+    }
+    assert_eq!(allocations(), before);
+    assert_eq!(sum, 4000);
+
+    // Cloning scans allocate the keys and values.
+    let before = allocations();
+    assert_eq!(s.scan(..).count(), 1000);
+    assert!(allocations() >= before + 2000);
+    Ok(())
+}